@@ -2,7 +2,7 @@ use std::env;
 
 use oneway::connection::Server;
 use oneway::udp::UdpReader;
-use oneway::{Config, Result};
+use oneway::{ConfigFile, Result};
 
 use tokio::net::UdpSocket;
 
@@ -14,18 +14,27 @@ async fn main() -> Result<()> {
     let progname = args.next().unwrap();
     let config_path = args
         .next()
-        .expect(&format!("Usage: {} CONFIG_FILE", progname));
+        .unwrap_or_else(|| panic!("Usage: {} CONFIG_FILE", progname));
 
-    let config = Config::from_file(config_path)?;
+    let config = ConfigFile::from_file_with_env(config_path)?
+        .server()
+        .clone();
     tracing::info!("config = {:?}", config);
 
     let socket = UdpSocket::bind(config.address).await?;
     tracing::info!("Waiting for new request");
 
-    let mut server = Server::new_with_config(UdpReader::new(socket)?, config);
+    let mut server = Server::new_with_config(UdpReader::new_with_config(socket, &config)?, config)?;
     tracing::trace!("server created");
 
-    server.serve_forever().await?;
+    server
+        .serve_until(async {
+            if let Err(e) = tokio::signal::ctrl_c().await {
+                tracing::error!("Cannot wait for Ctrl-C: {}", e);
+                std::future::pending::<()>().await;
+            }
+        })
+        .await?;
 
     Ok(())
 }
@@ -1,9 +1,10 @@
 use std::env;
 
 use oneway::connection::Client;
-use oneway::tree::find_files;
+use oneway::job::{read_completed_ids, Job};
+use oneway::tree::{walk, Entry, WalkOptions};
 use oneway::udp::UdpWriter;
-use oneway::{Config, Result};
+use oneway::{ConfigFile, Result};
 
 use tokio::net::UdpSocket;
 
@@ -13,11 +14,21 @@ async fn main() -> Result<()> {
 
     let mut args = env::args();
     let progname = args.next().unwrap();
-    let config_path = args
-        .next()
-        .expect(&format!("Usage: {} CONFIG_FILE", progname));
+    let usage = || -> ! { panic!("Usage: {} CONFIG_FILE [--resume-from N]", progname) };
+    let config_path = args.next().unwrap_or_else(|| usage());
+    // Index of the first file to send, the ones before having been sent by an interrupted run
+    let resume_from = match args.next().as_deref() {
+        None => 0,
+        Some("--resume-from") => args
+            .next()
+            .and_then(|n| n.parse().ok())
+            .unwrap_or_else(|| usage()),
+        Some(_) => usage(),
+    };
 
-    let config = Config::from_file(config_path)?;
+    let config = ConfigFile::from_file_with_env(config_path)?
+        .client()
+        .clone();
     tracing::info!("config = {:?}", config);
 
     let socket = UdpSocket::bind("0.0.0.0:0").await?;
@@ -25,11 +36,50 @@ async fn main() -> Result<()> {
 
     tracing::info!("Connected to {}", config.address);
 
-    let files = find_files(&config.root, false, |_| true)?;
-    let mut client = Client::new_with_config(UdpWriter::new(socket)?, config);
+    let socket = UdpWriter::new_with_config(socket, &config)?;
+    let job_file = config.job_file.clone();
+    let state_file = config.state_file.clone();
+    let send_manifest = config.send_manifest;
+    let options = WalkOptions {
+        include_specials: config.preserve.specials,
+        include_empty_dirs: true,
+        symlinks: config.symlinks,
+        ..Default::default()
+    };
+    let walk_root = config.root.clone();
+    let mut client = Client::new_with_config(socket, config)?;
+    client.assert_oneway()?;
+
+    let job = match job_file {
+        Some(ref job_file) if job_file.exists() => {
+            tracing::info!("Resuming job {}", job_file.display());
+            Job::load(job_file)?
+        }
+        _ => {
+            let files: Vec<_> = walk(&walk_root, &options, |_| true)?
+                .into_iter()
+                .map(Entry::into_path)
+                .collect();
+            let job = client.plan_files(&files[..]).await?;
+            if let Some(ref job_file) = job_file {
+                job.save(job_file)?;
+            }
+            job
+        }
+    };
+    let completed = match state_file {
+        Some(ref state_file) => read_completed_ids(state_file)?,
+        None => Default::default(),
+    };
+    let files = job.remaining(&completed);
 
     client.send_hello().await?;
-    client.send_files(&files[..]).await?;
+    client.send_files_from(&files[..], resume_from).await?;
+    if send_manifest {
+        // Files sent by an earlier run of the job are part of the tree as well
+        let all_files: Vec<_> = job.entries.iter().map(|e| e.path.clone()).collect();
+        client.send_manifest(&all_files[..]).await?;
+    }
     client.send_done().await?;
 
     Ok(())
@@ -4,16 +4,11 @@ use oneway::Result;
 fn main() -> Result<()> {
     env_logger::init();
 
-    let path = std::env::args()
-        .skip(1)
-        .next()
-        .unwrap_or_else(|| String::from("."));
+    let path = std::env::args().nth(1).unwrap_or_else(|| String::from("."));
     let path = std::path::PathBuf::from(path);
     println!("path = {}", path.display());
 
-    let entries = find_files(path, true, |_p| {
-        return true;
-    })?;
+    let entries = find_files(path, true, |_p| true)?;
     for entry in &entries {
         println!("{}", entry.display());
     }
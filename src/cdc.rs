@@ -0,0 +1,180 @@
+//! Content-defined chunking, so that identical regions of files are cut into identical chunks
+//! wherever they are, and index of the chunks recently sent
+
+use std::collections::{HashMap, VecDeque};
+
+use sha2::{Digest, Sha256};
+
+/// Chunks remembered by a `ChunkIndex` by default
+pub(crate) const DEFAULT_INDEX_CAPACITY: usize = 1 << 16;
+
+/// Random values mixed into the rolling hash for each byte value
+const GEAR: [u64; 256] = {
+    // splitmix64, seeded with a constant so that every build cuts at the same boundaries
+    let mut table = [0u64; 256];
+    let mut state = 0x6f6e_6577_6179_6364u64;
+    let mut i = 0;
+    while i < table.len() {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+/// Gear hash chunker, in the manner of FastCDC
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Chunker {
+    min_size: usize,
+    max_size: usize,
+
+    /// A boundary is placed where the hash has all these bits cleared
+    mask: u64,
+}
+
+impl Chunker {
+    /// Builds a chunker whose chunks are at most `max_size` bytes
+    pub(crate) fn new(max_size: usize) -> Self {
+        let min_size = max_size / 4;
+        // Boundaries are on average this far after `min_size`
+        let spacing = (max_size / 2).max(2);
+        let bits = usize::BITS - 1 - spacing.leading_zeros();
+        // The mask uses the high bits, which depend on more of the bytes before
+        let mask = ((1u64 << bits) - 1) << (u64::BITS - bits);
+
+        Self {
+            min_size,
+            max_size,
+            mask,
+        }
+    }
+
+    /// Length of the chunk starting `data`
+    ///
+    /// It is `data.len()` when no boundary is found, which callers with more data to come should
+    /// only take when it reaches `max_size`.
+    pub(crate) fn cut(&self, data: &[u8]) -> usize {
+        if data.len() <= self.min_size {
+            return data.len();
+        }
+
+        let end = data.len().min(self.max_size);
+        let mut hash = 0u64;
+        for (i, byte) in data[..end].iter().enumerate().skip(self.min_size) {
+            hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+            if hash & self.mask == 0 {
+                return i + 1;
+            }
+        }
+
+        end
+    }
+
+    pub(crate) fn max_size(&self) -> usize {
+        self.max_size
+    }
+}
+
+/// Where a chunk was sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ChunkLocation {
+    pub id: u64,
+    pub offset: u64,
+}
+
+/// Chunks recently sent, by digest, forgetting the oldest ones beyond its capacity
+#[derive(Debug)]
+pub(crate) struct ChunkIndex {
+    locations: HashMap<[u8; 32], ChunkLocation>,
+    order: VecDeque<[u8; 32]>,
+    capacity: usize,
+}
+
+impl ChunkIndex {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            locations: HashMap::new(),
+            order: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    /// Returns where `content` was already sent, or records that it is sent at `location`
+    pub(crate) fn find_or_insert(
+        &mut self,
+        content: &[u8],
+        location: ChunkLocation,
+    ) -> Option<ChunkLocation> {
+        // The length is part of the key, so that a match always has the same size
+        let mut hasher = Sha256::new();
+        hasher.update((content.len() as u64).to_be_bytes());
+        hasher.update(content);
+        let digest: [u8; 32] = hasher.finalize().into();
+
+        if let Some(found) = self.locations.get(&digest) {
+            return Some(*found);
+        }
+
+        if self.order.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.locations.remove(&oldest);
+            }
+        }
+        self.locations.insert(digest, location);
+        self.order.push_back(digest);
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunks<'a>(chunker: &Chunker, mut data: &'a [u8]) -> Vec<&'a [u8]> {
+        let mut chunks = Vec::new();
+        while !data.is_empty() {
+            let (chunk, rest) = data.split_at(chunker.cut(data));
+            chunks.push(chunk);
+            data = rest;
+        }
+        chunks
+    }
+
+    #[test]
+    fn boundaries_follow_content() {
+        let mut data = vec![0u8; 256 * 1024];
+        getrandom::getrandom(&mut data[..]).unwrap();
+        let chunker = Chunker::new(8192);
+
+        let original = chunks(&chunker, &data[..]);
+        assert!(original.iter().all(|c| c.len() <= 8192));
+
+        // Inserting bytes at the start only changes the first chunks
+        let mut shifted = b"some inserted bytes".to_vec();
+        shifted.extend_from_slice(&data[..]);
+        let shifted = chunks(&chunker, &shifted[..]);
+        let common = original.iter().filter(|c| shifted.contains(c)).count();
+        assert!(
+            common + 3 >= original.len(),
+            "{} of {} chunks in common",
+            common,
+            original.len()
+        );
+    }
+
+    #[test]
+    fn index_forgets_oldest_chunks() {
+        let mut index = ChunkIndex::new(2);
+        let location = |offset| ChunkLocation { id: 1, offset };
+
+        assert_eq!(index.find_or_insert(b"a", location(0)), None);
+        assert_eq!(index.find_or_insert(b"b", location(1)), None);
+        assert_eq!(index.find_or_insert(b"a", location(2)), Some(location(0)));
+        assert_eq!(index.find_or_insert(b"c", location(3)), None);
+        assert_eq!(index.find_or_insert(b"a", location(4)), None);
+    }
+}
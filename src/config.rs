@@ -1,21 +1,177 @@
+use std::fmt;
 use std::io::{BufRead, BufReader, Read};
 use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
 use std::time::Duration;
 
 use crate::error::{Error, Result};
+use crate::tree::SymlinkPolicy;
+
+use sha2::{Digest, Sha256};
+
+/// Largest payload of a UDP datagram over IPv4
+pub const MAX_MTU: usize = 65507;
+
+// The size of a chunk is sent as a u16, which `check_mtu` keeps from overflowing
+const _: () = assert!(
+    crate::messages::Message::get_max_content_size(crate::retransmit::max_payload_size(MAX_MTU))
+        <= u16::MAX as usize
+);
+
+/// Metadata to preserve on top of the files content
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct Preserve {
+    /// POSIX ACLs (requires the `acl` feature)
+    pub acl: bool,
+
+    /// FIFOs and devices (Unix only)
+    pub specials: bool,
+}
+
+impl Preserve {
+    fn parse(value: &str) -> Self {
+        let mut preserve = Self::default();
+
+        for item in value.split(',').map(str::trim).filter(|i| !i.is_empty()) {
+            if item.eq_ignore_ascii_case("acl") {
+                preserve.acl = true;
+            } else if item.eq_ignore_ascii_case("specials") {
+                preserve.specials = true;
+            } else {
+                tracing::warn!("Unknown metadata to preserve {:?}", item);
+            }
+        }
+
+        preserve
+    }
+}
+
+/// How the client computes the id identifying a file on the wire
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum FileIdMode {
+    /// Number allocated by the client in the order it sends files, only meaningful within a
+    /// session
+    #[default]
+    Sequence,
+
+    /// Inode (or file index on Windows) of the file
+    Inode,
+
+    /// Digest of the size, first and last blocks of the file, which survives renames
+    Content,
+}
+
+impl FileIdMode {
+    fn parse(value: &str) -> Option<Self> {
+        if value.eq_ignore_ascii_case("sequence") {
+            Some(Self::Sequence)
+        } else if value.eq_ignore_ascii_case("inode") {
+            Some(Self::Inode)
+        } else if value.eq_ignore_ascii_case("content") {
+            Some(Self::Content)
+        } else {
+            None
+        }
+    }
+}
+
+/// What the server does when a file id being received is announced again with another size
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum DuplicateFilePolicy {
+    /// Close the file being received and honor the new announcement, as the id was reused
+    #[default]
+    Replace,
+
+    /// Ignore the new announcement and keep receiving the first file
+    KeepFirst,
+}
+
+impl DuplicateFilePolicy {
+    fn parse(value: &str) -> Option<Self> {
+        if value.eq_ignore_ascii_case("replace") {
+            Some(Self::Replace)
+        } else if value.eq_ignore_ascii_case("keep_first") {
+            Some(Self::KeepFirst)
+        } else {
+            None
+        }
+    }
+}
+
+/// Digest the server computes over every file it receives
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum HashAlgorithm {
+    Sha256,
+    Sha512,
+}
+
+impl HashAlgorithm {
+    fn parse(value: &str) -> Option<Self> {
+        if value.eq_ignore_ascii_case("sha256") {
+            Some(Self::Sha256)
+        } else if value.eq_ignore_ascii_case("sha512") {
+            Some(Self::Sha512)
+        } else {
+            None
+        }
+    }
+}
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Config {
     pub remission_count: usize,
     pub mtu: usize,
     pub recv_timeout: Duration,
+    pub file_idle_timeout: Duration,
+    pub keep_alive_interval: Option<Duration>,
+    pub progress_interval: u32,
     pub address: SocketAddr,
     pub root: PathBuf,
+    pub staging_dir: Option<PathBuf>,
+    pub commit_on_done: bool,
     pub channel_size: usize,
+    pub drop_when_busy: bool,
+    pub dont_fragment: bool,
+    pub dedup_window: usize,
+    pub preserve: Preserve,
+    pub require_hello: bool,
+    pub file_id: FileIdMode,
+    pub min_free_space: u64,
+    pub symlinks: SymlinkPolicy,
+    pub session_id: Option<String>,
+    pub write_buffer_size: usize,
+    pub small_file_threshold: u64,
+    pub small_file_write_buffer_size: usize,
+    pub pad_datagrams: bool,
+    pub fec_group_size: u16,
+    pub job_file: Option<PathBuf>,
+    pub max_bytes_per_sec: Option<u64>,
+    pub retransmit_rate_limit: Option<u64>,
+    pub state_file: Option<PathBuf>,
+    pub receiver_hash: Option<HashAlgorithm>,
+    pub duplicate_file: DuplicateFilePolicy,
+    pub multicast_ttl: u32,
+    pub recv_buffer_size: Option<usize>,
+    pub send_buffer_size: Option<usize>,
+    pub send_manifest: bool,
+    pub critical_files: Vec<String>,
+    pub critical_file_passes: usize,
+    pub rename_incomplete: bool,
+    pub fsync_on_complete: bool,
+    pub max_file_size: Option<u64>,
+    pub file_mode: Option<u32>,
+    pub dir_mode: Option<u32>,
+    pub allowed_extensions: Vec<String>,
+
+    #[cfg(feature = "compression")]
+    pub compress: bool,
+
+    #[cfg(feature = "dedup")]
+    pub dedup: bool,
 
+    /// Shared by the client and the server, required with the `encryption` feature
     #[cfg(feature = "encryption")]
-    pub key: [u8; 32],
+    pub key: Option<[u8; 32]>,
 }
 
 impl Default for Config {
@@ -24,16 +180,261 @@ impl Default for Config {
             remission_count: 3,
             mtu: 1024,
             recv_timeout: Duration::from_secs(3),
+            file_idle_timeout: Duration::from_secs(60),
+            keep_alive_interval: None,
+            progress_interval: 0,
             address: "0.0.0.0:0".parse().unwrap(),
             root: std::env::current_dir().expect("Cannot get CWD"),
-            channel_size: 10,
+            staging_dir: None,
+            commit_on_done: false,
+            channel_size: 1024,
+            drop_when_busy: true,
+            dont_fragment: false,
+            dedup_window: 1,
+            preserve: Preserve::default(),
+            require_hello: false,
+            file_id: FileIdMode::default(),
+            min_free_space: 0,
+            symlinks: SymlinkPolicy::default(),
+            session_id: None,
+            write_buffer_size: 64 * 1024,
+            small_file_threshold: 1024 * 1024,
+            small_file_write_buffer_size: 8 * 1024,
+            pad_datagrams: false,
+            fec_group_size: 0,
+            job_file: None,
+            max_bytes_per_sec: None,
+            retransmit_rate_limit: None,
+            state_file: None,
+            receiver_hash: None,
+            duplicate_file: DuplicateFilePolicy::default(),
+            multicast_ttl: 1,
+            recv_buffer_size: None,
+            send_buffer_size: None,
+            send_manifest: false,
+            critical_files: Vec::new(),
+            critical_file_passes: 1,
+            rename_incomplete: false,
+            fsync_on_complete: true,
+            max_file_size: None,
+            file_mode: None,
+            dir_mode: None,
+            allowed_extensions: Vec::new(),
+
+            #[cfg(feature = "compression")]
+            compress: false,
+            #[cfg(feature = "dedup")]
+            dedup: false,
 
             #[cfg(feature = "encryption")]
-            key: [0u8; 32],
+            // Unit tests share a key rather than each setting one
+            key: cfg!(test).then_some([0u8; 32]),
         }
     }
 }
 
+/// Parses a 256 bits key written as 64 hexadecimal digits
+#[cfg(feature = "encryption")]
+fn parse_key(value: &str) -> Option<[u8; 32]> {
+    let digits = value.as_bytes();
+    if digits.len() != 64 || !digits.iter().all(u8::is_ascii_hexdigit) {
+        return None;
+    }
+
+    let mut key = [0u8; 32];
+    for (byte, pair) in key.iter_mut().zip(digits.chunks(2)) {
+        let pair = std::str::from_utf8(pair).ok()?;
+        *byte = u8::from_str_radix(pair, 16).ok()?;
+    }
+
+    Some(key)
+}
+
+/// Reads a key stored either as 32 raw bytes or as 64 hexadecimal digits
+#[cfg(feature = "encryption")]
+fn read_key_file(path: &Path) -> Result<Option<[u8; 32]>> {
+    let content = std::fs::read(path)?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mode = std::fs::metadata(path)?.permissions().mode();
+        if mode & 0o004 != 0 {
+            tracing::warn!("Key file {} is world readable", path.display());
+        }
+    }
+
+    if let Ok(key) = <[u8; 32]>::try_from(&content[..]) {
+        return Ok(Some(key));
+    }
+    Ok(std::str::from_utf8(&content[..])
+        .ok()
+        .and_then(|hex| parse_key(hex.trim())))
+}
+
+/// Parses permissions written in octal, like `0640`
+fn parse_mode(value: &str) -> Option<u32> {
+    let digits = value.strip_prefix("0o").unwrap_or(value);
+    u32::from_str_radix(digits, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    const TRUE_VALUES: [&str; 4] = ["true", "yes", "on", "1"];
+    const FALSE_VALUES: [&str; 4] = ["false", "no", "off", "0"];
+
+    if TRUE_VALUES.iter().any(|v| value.eq_ignore_ascii_case(v)) {
+        Some(true)
+    } else if FALSE_VALUES.iter().any(|v| value.eq_ignore_ascii_case(v)) {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+/// Side of a transfer
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Client => f.write_str("client"),
+            Self::Server => f.write_str("server"),
+        }
+    }
+}
+
+/// Section of a configuration file the keys being parsed are in
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Section {
+    /// `[common]`, or before any section
+    Common,
+
+    /// `[client]` or `[server]`
+    Only(Role),
+
+    Unknown,
+}
+
+impl Section {
+    fn parse(name: &str) -> Self {
+        if name.eq_ignore_ascii_case("common") {
+            Self::Common
+        } else if name.eq_ignore_ascii_case("client") {
+            Self::Only(Role::Client)
+        } else if name.eq_ignore_ascii_case("server") {
+            Self::Only(Role::Server)
+        } else {
+            Self::Unknown
+        }
+    }
+}
+
+/// Every key of a configuration, with the side using it or `None` when both sides do
+const KEYS: &[(&str, Option<Role>)] = &[
+    ("remission_count", None),
+    ("mtu", None),
+    ("recv_timeout", Some(Role::Server)),
+    ("file_idle_timeout", Some(Role::Server)),
+    ("keep_alive_interval", Some(Role::Client)),
+    ("progress_interval", Some(Role::Client)),
+    ("address", None),
+    ("root", None),
+    ("staging_dir", Some(Role::Server)),
+    ("commit_on_done", Some(Role::Server)),
+    ("channel_size", Some(Role::Server)),
+    ("drop_when_busy", Some(Role::Server)),
+    ("dont_fragment", Some(Role::Client)),
+    ("dedup_window", Some(Role::Server)),
+    ("preserve", Some(Role::Client)),
+    ("require_hello", Some(Role::Server)),
+    ("file_id", Some(Role::Client)),
+    ("min_free_space", Some(Role::Server)),
+    ("symlinks", Some(Role::Client)),
+    ("session_id", Some(Role::Client)),
+    ("write_buffer_size", Some(Role::Server)),
+    ("small_file_threshold", Some(Role::Server)),
+    ("small_file_write_buffer_size", Some(Role::Server)),
+    ("pad_datagrams", None),
+    ("fec_group_size", None),
+    ("max_bytes_per_sec", Some(Role::Client)),
+    ("retransmit_rate_limit", Some(Role::Client)),
+    ("job_file", Some(Role::Client)),
+    ("state_file", Some(Role::Server)),
+    ("receiver_hash", Some(Role::Server)),
+    ("duplicate_file", Some(Role::Server)),
+    ("multicast_ttl", Some(Role::Client)),
+    ("recv_buffer_size", Some(Role::Server)),
+    ("send_buffer_size", Some(Role::Client)),
+    ("send_manifest", Some(Role::Client)),
+    ("critical_files", Some(Role::Client)),
+    ("critical_file_passes", Some(Role::Client)),
+    ("rename_incomplete", Some(Role::Server)),
+    ("fsync_on_complete", Some(Role::Server)),
+    ("max_file_size", None),
+    ("file_mode", Some(Role::Server)),
+    ("dir_mode", Some(Role::Server)),
+    ("allowed_extensions", Some(Role::Server)),
+    ("compress", Some(Role::Client)),
+    ("dedup", Some(Role::Client)),
+    ("key", None),
+    ("key_file", None),
+];
+
+/// Side using `key`, `None` for the keys used by both sides
+fn key_role(key: &str) -> Option<Role> {
+    KEYS.iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(key))
+        .and_then(|&(_, role)| role)
+}
+
+/// Settings of both sides of a transfer, read from a single file
+///
+/// Keys before any section or in `[common]` apply to both sides, the ones in `[client]` or
+/// `[server]` only to that side.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ConfigFile {
+    client: Config,
+    server: Config,
+}
+
+impl ConfigFile {
+    pub fn from_file(file: impl AsRef<Path>) -> Result<Self> {
+        let content = std::fs::read(file)?;
+        Self::parse(&content[..])
+    }
+
+    /// Loads `file` like `from_file`, then applies the `ONEWAY_*` environment variables to both
+    /// sides
+    pub fn from_file_with_env(file: impl AsRef<Path>) -> Result<Self> {
+        let mut config_file = Self::from_file(file)?;
+        config_file.client.apply_env(std::env::vars())?;
+        config_file.server.apply_env(std::env::vars())?;
+        Ok(config_file)
+    }
+
+    fn parse(content: &[u8]) -> Result<Self> {
+        Ok(Self {
+            client: Config::parse_stream_for(content, Some(Role::Client))?,
+            server: Config::parse_stream_for(content, Some(Role::Server))?,
+        })
+    }
+
+    pub fn client(&self) -> &Config {
+        &self.client
+    }
+
+    pub fn server(&self) -> &Config {
+        &self.server
+    }
+}
+
 enum Line<'s> {
     Key(&'s str),
     KeyValue(&'s str, &'s str),
@@ -47,12 +448,80 @@ impl Config {
         Self::parse_stream(stream)
     }
 
+    /// Loads `file` like `from_file`, once its SHA-256 digest was checked to be `expected_sha256`
+    pub fn from_file_verified(file: impl AsRef<Path>, expected_sha256: &[u8; 32]) -> Result<Self> {
+        let content = std::fs::read(file)?;
+        let actual: [u8; 32] = Sha256::digest(&content[..]).into();
+        if actual != *expected_sha256 {
+            return Err(Error::ConfigDigestMismatch {
+                expected: *expected_sha256,
+                actual,
+            });
+        }
+        Self::parse_stream(&content[..])
+    }
+
+    /// Default configuration with the `ONEWAY_*` environment variables applied, see
+    /// `apply_env`
+    pub fn from_env() -> Result<Self> {
+        let mut config = Self::default();
+        config.apply_env(std::env::vars())?;
+        Ok(config)
+    }
+
+    /// Loads `file` like `from_file`, then applies the `ONEWAY_*` environment variables
+    pub fn from_file_with_env(file: impl AsRef<Path>) -> Result<Self> {
+        let mut config = Self::from_file(file)?;
+        config.apply_env(std::env::vars())?;
+        Ok(config)
+    }
+
+    /// Sets the key named after each variable of `vars` prefixed with `ONEWAY_`, `ONEWAY_MTU`
+    /// setting `mtu`
+    ///
+    /// The variables may override a key or key file of the configuration, but not set both.
+    fn apply_env(&mut self, vars: impl IntoIterator<Item = (String, String)>) -> Result<()> {
+        const PREFIX: &str = "ONEWAY_";
+        #[cfg(feature = "encryption")]
+        let mut key_set = false;
+
+        for (name, value) in vars {
+            let Some(key) = name.strip_prefix(PREFIX) else {
+                continue;
+            };
+            let invalid = || Error::InvalidConfig {
+                linenum: 0,
+                line: format!("{}={}", name, value),
+            };
+            // `key` and `key_file` are exclusive
+            #[cfg(feature = "encryption")]
+            if key.eq_ignore_ascii_case("key") || key.eq_ignore_ascii_case("key_file") {
+                if key_set {
+                    return Err(invalid());
+                }
+                key_set = true;
+            }
+            self.set_key(key, value.trim(), invalid)
+                .map_err(|_| invalid())?;
+        }
+
+        Ok(())
+    }
+
     fn parse_stream<S: Read>(stream: S) -> Result<Self> {
+        Self::parse_stream_for(stream, None)
+    }
+
+    /// Parses the keys of `stream` for `role`, or every key whatever its section without one
+    fn parse_stream_for<S: Read>(stream: S, role: Option<Role>) -> Result<Self> {
         let mut reader = BufReader::new(stream);
         let mut raw_line = String::new();
         let mut linenum = 0usize;
+        let mut section = Section::Common;
 
         let mut config = Self::default();
+        #[cfg(feature = "encryption")]
+        let mut key_set = false;
 
         loop {
             // We use `read_line` instead of `lines` to avoid re-allocations
@@ -68,40 +537,246 @@ impl Config {
                 continue;
             }
 
-            let result = Self::parse_line(&line).ok_or_else(|| Error::InvalidConfig {
+            let invalid_line = || Error::InvalidConfig {
                 linenum,
                 line: String::from(line),
-            })?;
+            };
+            let result = Self::parse_line(line).ok_or_else(invalid_line)?;
+            if let Line::Key(key) | Line::KeyValue(key, _) = result {
+                match section {
+                    Section::Common => {}
+                    Section::Only(side) => {
+                        if key_role(key).is_some_and(|r| r != side) {
+                            tracing::warn!("Key {:?} does not belong to section [{}]", key, side);
+                        }
+                        if role.is_some_and(|r| r != side) {
+                            continue;
+                        }
+                    }
+                    Section::Unknown => continue,
+                }
+            }
             match result {
                 Line::Key(key) => {
                     tracing::warn!("Unknown key {:?}", key);
                 }
                 Line::KeyValue(key, value) => {
-                    if key.eq_ignore_ascii_case("remission_count") {
-                        config.remission_count = value.parse()?;
-                    } else if key.eq_ignore_ascii_case("mtu") {
-                        config.mtu = value.parse()?;
-                    } else if key.eq_ignore_ascii_case("recv_timeout") {
-                        config.recv_timeout = Duration::from_secs(value.parse()?);
-                    } else if key.eq_ignore_ascii_case("address") {
-                        config.address = value.parse()?;
-                    } else if key.eq_ignore_ascii_case("root") {
-                        config.root = PathBuf::from(value);
-                    } else if key.eq_ignore_ascii_case("channel_size") {
-                        config.channel_size = value.parse()?;
-                    } else if key.eq_ignore_ascii_case("key") {
-                        todo!("parse key");
-                    } else {
-                        tracing::warn!("Unknown key {:?}", key);
+                    // `key` and `key_file` are exclusive
+                    #[cfg(feature = "encryption")]
+                    if key.eq_ignore_ascii_case("key") || key.eq_ignore_ascii_case("key_file") {
+                        if key_set {
+                            return Err(invalid_line());
+                        }
+                        key_set = true;
+                    }
+                    config.set_key(key, value, invalid_line)?;
+                }
+                Line::Section(name) => {
+                    section = Section::parse(name);
+                    if section == Section::Unknown {
+                        tracing::warn!("Ignoring keys of unknown section [{}]", name);
                     }
                 }
-                _ => {}
+                Line::Comment => {}
             }
         }
 
         Ok(config)
     }
 
+    /// Sets `key` to `value`, `invalid` building the error of a value which is not valid
+    fn set_key(&mut self, key: &str, value: &str, invalid: impl Fn() -> Error) -> Result<()> {
+        let Some(&(name, _)) = KEYS.iter().find(|(name, _)| name.eq_ignore_ascii_case(key)) else {
+            tracing::warn!("Unknown key {:?}", key);
+            return Ok(());
+        };
+        match name {
+            "remission_count" => self.remission_count = value.parse()?,
+            "mtu" => {
+                self.mtu = value.parse()?;
+                if let Err(e) = self.check_mtu() {
+                    tracing::error!("{}", e);
+                    return Err(invalid());
+                }
+            }
+            "recv_timeout" => self.recv_timeout = Duration::from_secs(value.parse()?),
+            "file_idle_timeout" => self.file_idle_timeout = Duration::from_secs(value.parse()?),
+            "keep_alive_interval" => {
+                self.keep_alive_interval =
+                    Some(Duration::from_secs(value.parse()?)).filter(|i| !i.is_zero());
+            }
+            "progress_interval" => self.progress_interval = value.parse()?,
+            "address" => self.address = value.parse().map_err(|_| invalid())?,
+            "root" => self.root = PathBuf::from(value),
+            "staging_dir" => self.staging_dir = Some(PathBuf::from(value)),
+            "commit_on_done" => self.commit_on_done = parse_bool(value).ok_or_else(&invalid)?,
+            "channel_size" => self.channel_size = value.parse()?,
+            "drop_when_busy" => self.drop_when_busy = parse_bool(value).ok_or_else(&invalid)?,
+            "dont_fragment" => self.dont_fragment = parse_bool(value).ok_or_else(&invalid)?,
+            "dedup_window" => self.dedup_window = value.parse()?,
+            "preserve" => self.preserve = Preserve::parse(value),
+            "require_hello" => self.require_hello = parse_bool(value).ok_or_else(&invalid)?,
+            "file_id" => self.file_id = FileIdMode::parse(value).ok_or_else(&invalid)?,
+            "min_free_space" => self.min_free_space = value.parse()?,
+            "symlinks" => self.symlinks = SymlinkPolicy::parse(value).ok_or_else(&invalid)?,
+            "session_id" => self.session_id = Some(String::from(value)),
+            "write_buffer_size" => self.write_buffer_size = value.parse()?,
+            "small_file_threshold" => self.small_file_threshold = value.parse()?,
+            "small_file_write_buffer_size" => self.small_file_write_buffer_size = value.parse()?,
+            "pad_datagrams" => self.pad_datagrams = parse_bool(value).ok_or_else(&invalid)?,
+            "fec_group_size" => self.fec_group_size = value.parse()?,
+            "max_bytes_per_sec" => self.max_bytes_per_sec = Some(value.parse()?),
+            "retransmit_rate_limit" => self.retransmit_rate_limit = Some(value.parse()?),
+            "job_file" => self.job_file = Some(PathBuf::from(value)),
+            "state_file" => self.state_file = Some(PathBuf::from(value)),
+            "receiver_hash" => {
+                self.receiver_hash = if value.eq_ignore_ascii_case("none") {
+                    None
+                } else {
+                    Some(HashAlgorithm::parse(value).ok_or_else(&invalid)?)
+                };
+            }
+            "duplicate_file" => {
+                self.duplicate_file = DuplicateFilePolicy::parse(value).ok_or_else(&invalid)?;
+            }
+            "multicast_ttl" => self.multicast_ttl = value.parse()?,
+            "recv_buffer_size" => self.recv_buffer_size = Some(value.parse()?),
+            "send_buffer_size" => self.send_buffer_size = Some(value.parse()?),
+            "send_manifest" => self.send_manifest = parse_bool(value).ok_or_else(&invalid)?,
+            "critical_files" => {
+                self.critical_files = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|p| !p.is_empty())
+                    .map(String::from)
+                    .collect();
+            }
+            "critical_file_passes" => self.critical_file_passes = value.parse()?,
+            "rename_incomplete" => {
+                self.rename_incomplete = parse_bool(value).ok_or_else(&invalid)?;
+            }
+            "fsync_on_complete" => {
+                self.fsync_on_complete = parse_bool(value).ok_or_else(&invalid)?;
+            }
+            "max_file_size" => self.max_file_size = Some(value.parse()?),
+            "file_mode" => self.file_mode = Some(parse_mode(value).ok_or_else(&invalid)?),
+            "dir_mode" => self.dir_mode = Some(parse_mode(value).ok_or_else(&invalid)?),
+            "allowed_extensions" => {
+                self.allowed_extensions = value
+                    .split(',')
+                    .map(|e| e.trim().trim_start_matches('.'))
+                    .filter(|e| !e.is_empty())
+                    .map(String::from)
+                    .collect();
+            }
+            "compress" => {
+                #[cfg(feature = "compression")]
+                {
+                    self.compress = parse_bool(value).ok_or_else(&invalid)?;
+                }
+                #[cfg(not(feature = "compression"))]
+                tracing::warn!("Key {:?} requires the `compression` feature", key);
+            }
+            "dedup" => {
+                #[cfg(feature = "dedup")]
+                {
+                    self.dedup = parse_bool(value).ok_or_else(&invalid)?;
+                }
+                #[cfg(not(feature = "dedup"))]
+                tracing::warn!("Key {:?} requires the `dedup` feature", key);
+            }
+            "key" => {
+                #[cfg(feature = "encryption")]
+                {
+                    self.key = Some(parse_key(value).ok_or_else(&invalid)?);
+                }
+                #[cfg(not(feature = "encryption"))]
+                tracing::warn!("Key {:?} requires the `encryption` feature", key);
+            }
+            "key_file" => {
+                #[cfg(feature = "encryption")]
+                {
+                    self.key = Some(read_key_file(Path::new(value))?.ok_or_else(&invalid)?);
+                }
+                #[cfg(not(feature = "encryption"))]
+                tracing::warn!("Key {:?} requires the `encryption` feature", key);
+            }
+            _ => unreachable!("Key {:?} is listed without being parsed", name),
+        }
+
+        Ok(())
+    }
+
+    /// Applies `overrides` to the configuration
+    ///
+    /// The result is checked when building a `Client` or a `Server` from it.
+    pub fn with(mut self, overrides: impl FnOnce(&mut Self)) -> Self {
+        overrides(&mut self);
+        self
+    }
+
+    /// Bytes of contiguous chunks combined before writing a file of `size` bytes
+    ///
+    /// Small files never get a larger buffer than `write_buffer_size`, so that 0 still disables
+    /// combining.
+    pub fn write_buffer_size_for(&self, size: u64) -> usize {
+        if size < self.small_file_threshold {
+            self.small_file_write_buffer_size
+                .min(self.write_buffer_size)
+        } else {
+            self.write_buffer_size
+        }
+    }
+
+    /// Whether the extension of `path` is in `allowed_extensions`, any being allowed when it is
+    /// empty
+    pub fn is_extension_allowed(&self, path: &Path) -> bool {
+        self.allowed_extensions.is_empty()
+            || path.extension().is_some_and(|extension| {
+                self.allowed_extensions
+                    .iter()
+                    .any(|allowed| extension.eq_ignore_ascii_case(allowed))
+            })
+    }
+
+    /// Checks values that a configuration built programmatically may get wrong
+    pub fn validate(&self) -> Result<()> {
+        self.check_mtu()?;
+        if self.remission_count == 0 {
+            return Err(Error::InvalidSetting(String::from(
+                "remission_count must be at least 1",
+            )));
+        }
+        if self.channel_size == 0 {
+            return Err(Error::InvalidSetting(String::from(
+                "channel_size must be at least 1",
+            )));
+        }
+        if self.critical_file_passes == 0 {
+            return Err(Error::InvalidSetting(String::from(
+                "critical_file_passes must be at least 1",
+            )));
+        }
+        #[cfg(feature = "encryption")]
+        if self.key.is_none() {
+            return Err(Error::InvalidSetting(String::from(
+                "key or key_file must be set with the `encryption` feature",
+            )));
+        }
+        Ok(())
+    }
+
+    fn check_mtu(&self) -> Result<()> {
+        let min_mtu = crate::retransmit::min_mtu();
+        if !(min_mtu..=MAX_MTU).contains(&self.mtu) {
+            return Err(Error::InvalidSetting(format!(
+                "MTU must be between {} and {} bytes, got {}",
+                min_mtu, MAX_MTU, self.mtu
+            )));
+        }
+        Ok(())
+    }
+
     fn parse_line<'s>(line: &'s str) -> Option<Line<'s>> {
         const COMMENT_CHARS: [char; 2] = ['#', ';'];
 
@@ -114,12 +789,12 @@ impl Config {
         if line.starts_with(&COMMENT_CHARS[..]) {
             Some(Line::Comment)
         } else if let Some(index) = line.find('=') {
-            let key = (&line[..index]).trim_end();
+            let key = line[..index].trim_end();
 
             if !is_valid_key(key) {
                 None
             } else {
-                let value = (&line[(index + 1)..]).trim();
+                let value = line[(index + 1)..].trim();
 
                 if value.is_empty() {
                     None
@@ -166,8 +841,387 @@ remission_count = 2
             maybe_config.unwrap(),
             Config {
                 remission_count: 2,
-                mtu: 1400
+                mtu: 1400,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn parse_full_config() {
+        let config_content = r#"
+address = 192.0.2.1:4242
+root = /srv/oneway
+mtu = 9000
+remission_count = 5
+"#;
+
+        let stream = std::io::Cursor::new(config_content);
+        let config = Config::parse_stream(stream).unwrap();
+        assert_eq!(
+            config,
+            Config {
+                address: "192.0.2.1:4242".parse().unwrap(),
+                root: PathBuf::from("/srv/oneway"),
+                mtu: 9000,
+                remission_count: 5,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn parse_receiver_hash() {
+        for (value, expected) in [
+            ("SHA512", Some(HashAlgorithm::Sha512)),
+            ("sha256", Some(HashAlgorithm::Sha256)),
+            ("none", None),
+        ] {
+            let stream = std::io::Cursor::new(format!("receiver_hash = {}\n", value));
+            assert_eq!(
+                Config::parse_stream(stream).unwrap().receiver_hash,
+                expected
+            );
+        }
+
+        let stream = std::io::Cursor::new("receiver_hash = md5\n");
+        assert!(matches!(
+            Config::parse_stream(stream),
+            Err(Error::InvalidConfig { linenum: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn too_small_mtu_is_refused() {
+        let config_content = format!("mtu = {}\n", crate::retransmit::min_mtu() - 1);
+
+        let stream = std::io::Cursor::new(config_content);
+        assert!(matches!(
+            Config::parse_stream(stream),
+            Err(Error::InvalidConfig { linenum: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn too_large_mtu_is_refused() {
+        let config_content = format!("mtu = {}\n", MAX_MTU + 1);
+
+        let stream = std::io::Cursor::new(config_content);
+        assert!(matches!(
+            Config::parse_stream(stream),
+            Err(Error::InvalidConfig { linenum: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn mtu_bounds_are_accepted() {
+        for mtu in [crate::retransmit::min_mtu(), MAX_MTU] {
+            let stream = std::io::Cursor::new(format!("mtu = {}\n", mtu));
+            assert_eq!(Config::parse_stream(stream).unwrap().mtu, mtu);
+        }
+    }
+
+    #[test]
+    fn jumbo_mtu_is_refused() {
+        // Loopback interfaces have MTUs this large, but chunk sizes would not fit in a u16
+        let config = Config::default().with(|c| c.mtu = 1 << 20);
+        match config.validate() {
+            Err(Error::InvalidSetting(message)) => assert!(message.contains("MTU")),
+            other => panic!("Unexpected result {:?}", other),
+        }
+        assert!(crate::connection::Client::new_with_config(
+            crate::testing::MemoryTransport::new(),
+            config
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn parse_channel_size() {
+        let stream = std::io::Cursor::new("channel_size = 64\ndrop_when_busy = false\n");
+        let config = Config::parse_stream(stream).unwrap();
+        assert_eq!(config.channel_size, 64);
+        assert!(!config.drop_when_busy);
+
+        let stream = std::io::Cursor::new("channel_size = -1\n");
+        assert!(Config::parse_stream(stream).is_err());
+        assert_eq!(Config::default().channel_size, 1024);
+    }
+
+    #[test]
+    fn overrides_are_validated() {
+        let config = Config::default().with(|c| c.mtu = 1400);
+        assert_eq!(config.mtu, 1400);
+        assert!(config.validate().is_ok());
+
+        let config = Config::default().with(|c| c.mtu = MAX_MTU + 1);
+        assert!(matches!(config.validate(), Err(Error::InvalidSetting(_))));
+        let config = Config::default().with(|c| c.remission_count = 0);
+        assert!(matches!(config.validate(), Err(Error::InvalidSetting(_))));
+        let config = Config::default().with(|c| c.channel_size = 0);
+        assert!(matches!(config.validate(), Err(Error::InvalidSetting(_))));
+        let config = Config::default().with(|c| c.critical_file_passes = 0);
+        assert!(matches!(config.validate(), Err(Error::InvalidSetting(_))));
+        #[cfg(feature = "encryption")]
+        {
+            let config = Config::default().with(|c| c.key = None);
+            assert!(matches!(config.validate(), Err(Error::InvalidSetting(_))));
+        }
+    }
+
+    #[test]
+    fn invalid_address_is_refused() {
+        let config_content = "mtu = 1400\naddress = localhost\n";
+
+        let stream = std::io::Cursor::new(config_content);
+        assert!(matches!(
+            Config::parse_stream(stream),
+            Err(Error::InvalidConfig { linenum: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn parse_preserve() {
+        let config_content = "preserve = acl, unknown\n";
+
+        let stream = std::io::Cursor::new(config_content);
+        let config = Config::parse_stream(stream).unwrap();
+        assert_eq!(
+            config.preserve,
+            Preserve {
+                acl: true,
+                specials: false
             }
         );
     }
+
+    #[test]
+    fn parse_octal_modes() {
+        let config_content = "file_mode = 0640\ndir_mode = 0o750\n";
+
+        let stream = std::io::Cursor::new(config_content);
+        let config = Config::parse_stream(stream).unwrap();
+        assert_eq!(config.file_mode, Some(0o640));
+        assert_eq!(config.dir_mode, Some(0o750));
+
+        for invalid in ["file_mode = 0680\n", "dir_mode = 17777\n"] {
+            let stream = std::io::Cursor::new(invalid);
+            assert!(matches!(
+                Config::parse_stream(stream),
+                Err(Error::InvalidConfig { linenum: 1, .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn parse_allowed_extensions() {
+        let config_content = "allowed_extensions = .json, CSV,\n";
+
+        let stream = std::io::Cursor::new(config_content);
+        let config = Config::parse_stream(stream).unwrap();
+        assert_eq!(config.allowed_extensions, ["json", "CSV"]);
+        assert!(config.is_extension_allowed(Path::new("exports/day.JSON")));
+        assert!(config.is_extension_allowed(Path::new("day.csv")));
+        assert!(!config.is_extension_allowed(Path::new("day.json.exe")));
+        assert!(!config.is_extension_allowed(Path::new("json")));
+        assert!(Config::default().is_extension_allowed(Path::new("json")));
+    }
+
+    #[test]
+    fn parse_critical_files() {
+        let config_content = "critical_files = *.db, etc/**,\ncritical_file_passes = 2\n";
+
+        let stream = std::io::Cursor::new(config_content);
+        let config = Config::parse_stream(stream).unwrap();
+        assert_eq!(config.critical_files, ["*.db", "etc/**"]);
+        assert_eq!(config.critical_file_passes, 2);
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn parse_hex_key() {
+        let config_content =
+            "key = 000102030405060708090a0b0c0d0e0f101112131415161718191A1B1C1D1E1F\n";
+
+        let stream = std::io::Cursor::new(config_content);
+        let config = Config::parse_stream(stream).unwrap();
+        let expected: Vec<u8> = (0..32).collect();
+        assert_eq!(&config.key.unwrap()[..], &expected[..]);
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn short_key_is_refused() {
+        for config_content in [
+            "key = 0001020304\n",
+            &format!("key = {}zz\n", "00".repeat(31)),
+        ] {
+            let stream = std::io::Cursor::new(config_content);
+            assert!(matches!(
+                Config::parse_stream(stream),
+                Err(Error::InvalidConfig { linenum: 1, .. })
+            ));
+        }
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn parse_key_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("key");
+        std::fs::write(&path, [0x42u8; 32]).unwrap();
+
+        let config_content = format!("key_file = {}\n", path.display());
+        let stream = std::io::Cursor::new(config_content);
+        let config = Config::parse_stream(stream).unwrap();
+        assert_eq!(config.key, Some([0x42u8; 32]));
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn parse_hex_key_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("key");
+        std::fs::write(&path, format!("{}\n", "42".repeat(32))).unwrap();
+
+        let config_content = format!("key_file = {}\n", path.display());
+        let stream = std::io::Cursor::new(config_content);
+        let config = Config::parse_stream(stream).unwrap();
+        assert_eq!(config.key, Some([0x42u8; 32]));
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn key_and_key_file_are_exclusive() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("key");
+        std::fs::write(&path, [0x42u8; 32]).unwrap();
+
+        let config_content = format!("key = {}\nkey_file = {}\n", "00".repeat(32), path.display());
+        let stream = std::io::Cursor::new(config_content);
+        assert!(matches!(
+            Config::parse_stream(stream),
+            Err(Error::InvalidConfig { linenum: 2, .. })
+        ));
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn key_and_key_file_are_exclusive_in_the_environment() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("key");
+        std::fs::write(&path, [0x42u8; 32]).unwrap();
+
+        let mut config = Config::default();
+        assert!(matches!(
+            config.apply_env([
+                (String::from("ONEWAY_KEY"), "00".repeat(32)),
+                (String::from("ONEWAY_KEY_FILE"), path.display().to_string()),
+            ]),
+            Err(Error::InvalidConfig { .. })
+        ));
+
+        // Either overrides the key of the configuration file
+        let mut config = Config::default();
+        config
+            .apply_env([(String::from("ONEWAY_KEY_FILE"), path.display().to_string())])
+            .unwrap();
+        assert_eq!(config.key, Some([0x42u8; 32]));
+    }
+
+    #[test]
+    fn every_listed_key_is_parsed() {
+        for &(name, _) in KEYS {
+            let invalid = || Error::InvalidConfig {
+                linenum: 0,
+                line: String::from(name),
+            };
+            // The value may be refused, but the key must be known
+            let _ = Config::default().set_key(&name.to_uppercase(), "", invalid);
+        }
+        assert_eq!(key_role("state_file"), Some(Role::Server));
+        assert_eq!(key_role("max_file_size"), None);
+        assert_eq!(key_role("pad_datagrams"), None);
+        assert_eq!(key_role("MTU"), None);
+    }
+
+    #[test]
+    fn config_file_digest_is_verified() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.ini");
+        let content = "mtu = 1400\nremission_count = 2\n";
+        std::fs::write(&path, content).unwrap();
+        let digest: [u8; 32] = Sha256::digest(content.as_bytes()).into();
+
+        let config = Config::from_file_verified(&path, &digest).unwrap();
+        assert_eq!(config.mtu, 1400);
+        assert_eq!(config.remission_count, 2);
+
+        std::fs::write(&path, "mtu = 1400\nremission_count = 1\n").unwrap();
+        assert!(matches!(
+            Config::from_file_verified(&path, &digest),
+            Err(Error::ConfigDigestMismatch { expected, .. }) if expected == digest
+        ));
+    }
+
+    #[test]
+    fn sections_are_routed_to_their_side() {
+        let config_content = r#"
+mtu = 1400
+
+[common]
+address = 127.0.0.1:4000
+
+[client]
+max_bytes_per_sec = 1000
+root = outgoing/
+
+[server]
+recv_timeout = 10
+root = incoming/
+; Only used by the client, the server ignores it
+max_bytes_per_sec = 2000
+
+[other]
+mtu = 2000
+"#;
+        let config_file = ConfigFile::parse(config_content.as_bytes()).unwrap();
+        let (client, server) = (config_file.client(), config_file.server());
+
+        for config in [client, server] {
+            assert_eq!(config.mtu, 1400);
+            assert_eq!(config.address, "127.0.0.1:4000".parse().unwrap());
+        }
+        assert_eq!(client.root, PathBuf::from("outgoing/"));
+        assert_eq!(client.max_bytes_per_sec, Some(1000));
+        assert_eq!(client.recv_timeout, Config::default().recv_timeout);
+        assert_eq!(server.root, PathBuf::from("incoming/"));
+        assert_eq!(server.recv_timeout, Duration::from_secs(10));
+        assert_eq!(server.max_bytes_per_sec, Some(2000));
+    }
+
+    #[test]
+    fn environment_overrides_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.ini");
+        std::fs::write(&path, "mtu = 1400\nremission_count = 2\n").unwrap();
+
+        let mut config = Config::from_file(&path).unwrap();
+        config
+            .apply_env([
+                (String::from("ONEWAY_MTU"), String::from("1200")),
+                (String::from("ONEWAY_COMMIT_ON_DONE"), String::from("true")),
+                (String::from("HOME"), String::from("/root")),
+            ])
+            .unwrap();
+        assert_eq!(config.mtu, 1200);
+        assert!(config.commit_on_done);
+        assert_eq!(config.remission_count, 2);
+
+        let mut config = Config::default();
+        assert!(matches!(
+            config.apply_env([(String::from("ONEWAY_MTU"), String::from("large"))]),
+            Err(Error::InvalidConfig { .. })
+        ));
+    }
 }
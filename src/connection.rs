@@ -1,5 +1,10 @@
 mod server;
-pub use server::Server;
+pub use server::{
+    ClientHandler, ClientStats, MessageAction, MessageHook, Server, SharedStats, TransferSummary,
+};
 
 mod client;
-pub use client::Client;
+pub use client::{Client, ProgressEvent, StreamSender};
+
+mod sink;
+pub use sink::{FsSink, Sink, WriterSink};
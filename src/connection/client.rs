@@ -1,28 +1,181 @@
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::io::SeekFrom;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 
-use crate::config::Config;
-use crate::messages::Message;
-use crate::retransmit::Retransmit;
-use crate::udp::UdpWriter;
-use crate::{Result, Wire};
+#[cfg(feature = "dedup")]
+use crate::cdc::{ChunkIndex, ChunkLocation, Chunker};
+use crate::config::{Config, FileIdMode};
+use crate::job::{Job, JobEntry, Manifest};
+use crate::messages::{ManifestEntry, Message, ProtocolVersion};
+use crate::retransmit::{ConstantPolicy, FecEncoder, Pacer, Retransmit, RetransmitPolicy};
+use crate::tree::{FilterSet, SymlinkPolicy};
+use crate::udp::{AsyncPacketSink, UdpWriter};
+use crate::{Error, Result};
 
-use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
 
-pub struct Client {
-    socket: UdpWriter,
+/// Progress of a transfer, as reported by `Client::with_progress_channel` and
+/// `Client::send_files_with_progress`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProgressEvent {
+    /// Contents of file `id` start being sent
+    FileStarted { id: u64, name: PathBuf, size: u64 },
+
+    /// A chunk of file `id` was sent, `bytes_so_far` bytes have been sent overall
+    ChunkSent { id: u64, bytes_so_far: u64 },
+
+    /// File `id` was entirely sent
+    FileCompleted { id: u64 },
+
+    /// The session was closed with `Done`
+    SessionDone,
+}
+
+pub struct Client<W = UdpWriter> {
+    socket: W,
     config: Config,
     keep_alive: u64,
+
+    /// When the last keep alive was sent, for `keep_alive_interval`
+    last_keep_alive: Instant,
+
+    /// Chunks sent since the last `Progress`, for `progress_interval`
+    chunks_since_progress: u32,
+
+    /// Sequence number of the next message
+    sequence: u32,
+
+    /// Parity of the messages sent, when `fec_group_size` is set
+    fec: Option<FecEncoder>,
+
+    /// Rate limiter, when `max_bytes_per_sec` is set
+    pacer: Option<Pacer>,
+
+    /// Rate limiter of retransmitted copies, when `retransmit_rate_limit` is set
+    copies_pacer: Option<Pacer>,
+
+    /// How many times each message is sent
+    policy: Box<dyn RetransmitPolicy>,
+    progress: Option<mpsc::Sender<ProgressEvent>>,
+
+    /// Chunks recently sent, when `dedup` is set
+    #[cfg(feature = "dedup")]
+    chunk_index: ChunkIndex,
+
+    /// Files sent `critical_file_passes` times
+    critical_files: FilterSet,
+
+    /// Id of the next file with `FileIdMode::Sequence`
+    next_file_id: u64,
+
+    /// Size and digest of the content sent of each file, for `send_manifest`
+    sent_digests: HashMap<PathBuf, (u64, [u8; 32])>,
+}
+
+/// Content of a file sent as it is produced, see `Client::open_stream`
+pub struct StreamSender<'c, W: AsyncPacketSink> {
+    client: &'c mut Client<W>,
+    name: String,
+    id: u64,
+
+    /// Size announced to the server
+    size: u64,
+
+    /// Bytes sent so far
+    sent: u64,
+
+    /// Content not sent yet, less than a chunk
+    pending: Vec<u8>,
+    content_max_size: usize,
+    hasher: Sha256,
+}
+
+impl<W: AsyncPacketSink> StreamSender<'_, W> {
+    /// Id of the file on the wire
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Sends `data` as the next bytes of the file, once a whole chunk is available
+    pub async fn write(&mut self, mut data: &[u8]) -> Result<()> {
+        if self.sent + (self.pending.len() + data.len()) as u64 > self.size {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "Stream {} exceeds its announced size of {} bytes",
+                    self.name, self.size
+                ),
+            )
+            .into());
+        }
+        self.hasher.update(data);
+
+        while !data.is_empty() {
+            let missing = self.content_max_size - self.pending.len();
+            let (head, tail) = data.split_at(missing.min(data.len()));
+            self.pending.extend_from_slice(head);
+            data = tail;
+
+            if self.pending.len() == self.content_max_size {
+                self.flush_chunk().await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn flush_chunk(&mut self) -> Result<()> {
+        let total = self.size;
+        self.sent = self
+            .client
+            .send_chunk(self.id, self.sent, &self.pending[..], total)
+            .await?;
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// Sends what is left of the content and tells the server that the file is complete
+    pub async fn finish(mut self) -> Result<()> {
+        if !self.pending.is_empty() {
+            self.flush_chunk().await?;
+        }
+        if self.sent != self.size {
+            tracing::warn!(
+                "Stream {} ended after {} of {} bytes",
+                self.name,
+                self.sent,
+                self.size
+            );
+        }
+
+        let digest = self.hasher.finalize().into();
+        self.client
+            .send_file_end(self.id, self.sent, digest)
+            .await?;
+        tracing::info!("Stream {} sent to server ({} bytes)", self.name, self.sent);
+        Ok(())
+    }
 }
 
-impl Client {
-    pub fn new(socket: UdpWriter) -> Self {
-        Self::new_with_config(socket, Config::default())
+impl<W: AsyncPacketSink> Client<W> {
+    /// Builds a client sending with the default configuration
+    ///
+    /// # Panics
+    ///
+    /// With the `encryption` feature, as the default configuration has no key.
+    pub fn new(socket: W) -> Self {
+        Self::new_with_config(socket, Config::default()).expect("Default config is valid")
     }
 
-    pub fn new_with_config(socket: UdpWriter, config: Config) -> Self {
-        // SAFETY: any memory representation of a u64 is a valid one
-        let keep_alive = unsafe { crate::utils::get_random().assume_init() };
+    /// Builds a client sending with `config`, which is validated first
+    pub fn new_with_config(socket: W, config: Config) -> Result<Self> {
+        config.validate()?;
+
+        let keep_alive = crate::utils::get_random()?;
 
         let content_max_size =
             Message::get_max_content_size(crate::retransmit::max_payload_size(config.mtu));
@@ -33,36 +186,112 @@ impl Client {
             config.mtu
         );
 
-        Self {
+        let fec = FecEncoder::new(&config);
+        let pacer = Pacer::new(&config);
+        let copies_pacer = Pacer::for_copies(&config);
+        let policy = Box::new(ConstantPolicy(config.remission_count));
+        let critical_files = config
+            .critical_files
+            .iter()
+            .try_fold(FilterSet::new(), |filters, pattern| {
+                filters.include(pattern)
+            })?;
+
+        Ok(Self {
             socket,
             config,
             keep_alive,
+            last_keep_alive: Instant::now(),
+            chunks_since_progress: 0,
+            sequence: 0,
+            fec,
+            pacer,
+            copies_pacer,
+            policy,
+            progress: None,
+            #[cfg(feature = "dedup")]
+            chunk_index: ChunkIndex::new(crate::cdc::DEFAULT_INDEX_CAPACITY),
+            critical_files,
+            next_file_id: 0,
+            sent_digests: HashMap::new(),
+        })
+    }
+
+    /// Reports progress events to `tx`
+    ///
+    /// Events are sent without waiting: they are dropped if the consumer lags behind.
+    pub fn with_progress_channel(mut self, tx: mpsc::Sender<ProgressEvent>) -> Self {
+        self.progress = Some(tx);
+        self
+    }
+
+    /// Decides how many times each message is sent with `policy` instead of `remission_count`
+    pub fn with_retransmit_policy(mut self, policy: impl RetransmitPolicy + 'static) -> Self {
+        self.policy = Box::new(policy);
+        self
+    }
+
+    pub fn socket(&self) -> &W {
+        &self.socket
+    }
+
+    fn report_progress(&self, event: ProgressEvent) {
+        if let Some(ref progress) = self.progress {
+            if let Err(e) = progress.try_send(event) {
+                tracing::trace!("Dropped progress event: {}", e);
+            }
         }
     }
 
+    /// Reports `event` to `progress` as well as to the progress channel
+    fn report_progress_to(&self, progress: &mut impl FnMut(ProgressEvent), event: ProgressEvent) {
+        self.report_progress(event.clone());
+        progress(event);
+    }
+
     async fn send_message(&mut self, message: &Message) -> Result<()> {
-        let mut raw_message = Vec::new();
+        self.send_message_at(message, 0, 0).await
+    }
+
+    /// Sends `message`, which ends at `offset` of a file of `total` bytes for chunks
+    async fn send_message_at(&mut self, message: &Message, offset: u64, total: u64) -> Result<()> {
         tracing::debug!("Sending message: {:?}", message);
-        message.to_wire(&mut raw_message)?;
+        let raw_message = message.to_bytes()?;
         tracing::debug!(
             "data to transmit: {} bytes (mtu {})",
             raw_message.len(),
             self.config.mtu
         );
 
-        let mut retransmit = Retransmit::new(
-            &raw_message[..],
-            self.config.remission_count,
-            self.config.mtu,
-        )?;
-        retransmit.send(&self.socket).await?;
+        let repeats = self.policy.repeats(message.kind(), offset, total);
+        let mut retransmit =
+            Retransmit::new(&raw_message[..], self.sequence, &self.config)?.with_repeats(repeats);
+        self.sequence = self.sequence.wrapping_add(1);
+        retransmit
+            .send_paced(
+                &self.socket,
+                self.pacer.as_mut(),
+                self.copies_pacer.as_mut(),
+            )
+            .await?;
         tracing::trace!("Retransmits send");
 
+        if let Some(ref mut fec) = self.fec {
+            if let Some(mut parity) = fec.add(&retransmit, &self.config)? {
+                parity
+                    .send_paced(&self.socket, self.pacer.as_mut(), None)
+                    .await?;
+            }
+        }
+
         Ok(())
     }
 
     pub async fn send_hello(&mut self) -> Result<()> {
-        let message = Message::Hello;
+        let message = Message::Hello {
+            session_id: self.config.session_id.clone().unwrap_or_default(),
+            version: ProtocolVersion::CURRENT,
+        };
 
         self.send_message(&message).await?;
         tracing::info!("Send Hello to server");
@@ -73,77 +302,514 @@ impl Client {
         let keep_alive = self.keep_alive;
         let message = Message::KeepAlive(self.keep_alive);
         self.keep_alive = self.keep_alive.wrapping_add(1);
+        self.last_keep_alive = Instant::now();
 
         self.send_message(&message).await?;
         tracing::debug!("Send keep alive ({}) to server", keep_alive);
         Ok(())
     }
 
-    async fn send_file(&mut self, filename: &PathBuf, filepath: &PathBuf, id: u64) -> Result<()> {
-        let mut f = tokio::fs::File::open(filepath).await?;
-        let content = vec![0u8; self.config.mtu];
+    /// Tells the server that `bytes_sent` bytes of file `id` were sent once `progress_interval`
+    /// chunks were sent since the last time
+    async fn send_progress_if_due(&mut self, id: u64, bytes_sent: u64) -> Result<()> {
+        if self.config.progress_interval == 0 {
+            return Ok(());
+        }
 
-        let mut message = Message::FileChunk {
+        self.chunks_since_progress += 1;
+        if self.chunks_since_progress < self.config.progress_interval {
+            return Ok(());
+        }
+        self.chunks_since_progress = 0;
+        self.send_message(&Message::Progress { id, bytes_sent })
+            .await
+    }
+
+    /// Sends a keep alive every `interval` until `until` completes, starting right away
+    ///
+    /// This lets the server know the client is alive between transfers. It runs on the client
+    /// itself rather than in a task of its own, so that keep alives are numbered along the other
+    /// messages.
+    pub async fn keep_alive_until(
+        &mut self,
+        interval: std::time::Duration,
+        until: impl std::future::Future<Output = ()>,
+    ) -> Result<()> {
+        tokio::pin!(until);
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                () = &mut until => return Ok(()),
+                _ = ticker.tick() => self.send_keep_alive().await?,
+            }
+        }
+    }
+
+    /// Sends a keep alive when `keep_alive_interval` elapsed since the last one
+    async fn send_keep_alive_if_due(&mut self) -> Result<()> {
+        match self.config.keep_alive_interval {
+            Some(interval) if self.last_keep_alive.elapsed() >= interval => {
+                self.send_keep_alive().await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Sends the content of `filepath` as file `id`, see `send_content`
+    async fn send_file(
+        &mut self,
+        filename: &Path,
+        filepath: &Path,
+        id: u64,
+        last_pass: bool,
+        progress: &mut impl FnMut(ProgressEvent),
+    ) -> Result<()> {
+        let f = tokio::fs::File::open(filepath).await?;
+        let size = f.metadata().await?.len();
+
+        self.send_content(filename, f, size, id, last_pass, progress)
+            .await
+    }
+
+    /// Sends the `size` bytes of `reader` as the content of file `id`
+    ///
+    /// A single empty read does not end the file: the end is reached once `size` bytes were
+    /// sent, or after `MAX_EMPTY_READS` consecutive empty reads if the file was truncated.
+    ///
+    /// Unless `last_pass` is set, the end of the file is not sent, for the server to keep it open
+    /// until the next pass over the same content fills in the chunks it lost.
+    async fn send_content<R>(
+        &mut self,
+        filename: &Path,
+        mut reader: R,
+        size: u64,
+        id: u64,
+        last_pass: bool,
+        progress: &mut impl FnMut(ProgressEvent),
+    ) -> Result<()>
+    where
+        R: AsyncRead + Unpin,
+    {
+        const MAX_EMPTY_READS: usize = 3;
+
+        self.report_progress_to(
+            progress,
+            ProgressEvent::FileStarted {
+                id,
+                name: filename.to_path_buf(),
+                size,
+            },
+        );
+
+        // Avoid fragmentation and reassemble on the other size
+        let content_max_size =
+            Message::get_max_content_size(crate::retransmit::max_payload_size(self.config.mtu));
+        let mut buffer = vec![0u8; content_max_size];
+        let mut hasher = Sha256::new();
+        let mut bytes_so_far = 0u64;
+        // Position in the chunks sent, which is not `bytes_so_far` once compressed
+        let mut sent = 0u64;
+        let mut empty_reads = 0;
+        #[cfg(feature = "compression")]
+        let mut encoder = if self.config.compress {
+            Some(zstd::stream::write::Encoder::new(Vec::new(), 0)?)
+        } else {
+            None
+        };
+        // Content waiting for a boundary, compressed files not being deduplicated
+        #[cfg(feature = "dedup")]
+        let mut dedup = {
+            #[cfg(feature = "compression")]
+            let compressed = encoder.is_some();
+            #[cfg(not(feature = "compression"))]
+            let compressed = false;
+            (self.config.dedup && !compressed).then(|| (Chunker::new(content_max_size), Vec::new()))
+        };
+
+        while bytes_so_far < size {
+            let to_read = (size - bytes_so_far).min(content_max_size as u64) as usize;
+            let read_size = reader.read(&mut buffer[..to_read]).await?;
+            if read_size == 0 {
+                empty_reads += 1;
+                if empty_reads >= MAX_EMPTY_READS {
+                    tracing::warn!(
+                        "File {} was truncated while being sent ({} of {} bytes)",
+                        filename.display(),
+                        bytes_so_far,
+                        size
+                    );
+                    break;
+                }
+                tracing::debug!(
+                    "Empty read at offset {} of {}, retrying",
+                    bytes_so_far,
+                    filename.display()
+                );
+                continue;
+            }
+            empty_reads = 0;
+
+            self.send_keep_alive_if_due().await?;
+
+            let content = &buffer[..read_size];
+            hasher.update(content);
+            bytes_so_far += read_size as u64;
+
+            #[cfg(feature = "compression")]
+            if let Some(ref mut encoder) = encoder {
+                std::io::Write::write_all(encoder, content)?;
+                // The last chunk is held back until the end of the stream, so that it is known
+                let compressed = encoder.get_mut();
+                while compressed.len() > content_max_size {
+                    let rest = compressed.split_off(content_max_size);
+                    let chunk = std::mem::replace(compressed, rest);
+                    sent = self.send_chunk(id, sent, &chunk[..], u64::MAX).await?;
+                }
+                self.report_progress_to(progress, ProgressEvent::ChunkSent { id, bytes_so_far });
+                continue;
+            }
+
+            #[cfg(feature = "dedup")]
+            if let Some((ref chunker, ref mut pending)) = dedup {
+                pending.extend_from_slice(content);
+                // Without a boundary in `max_size` bytes, the chunk is cut there anyway
+                while pending.len() >= chunker.max_size() {
+                    let cut = chunker.cut(&pending[..]);
+                    sent = self
+                        .send_deduplicated_chunk(id, sent, &pending[..cut], size)
+                        .await?;
+                    pending.drain(..cut);
+                }
+                self.report_progress_to(progress, ProgressEvent::ChunkSent { id, bytes_so_far });
+                continue;
+            }
+
+            sent = self.send_chunk(id, sent, content, size).await?;
+            self.report_progress_to(progress, ProgressEvent::ChunkSent { id, bytes_so_far });
+        }
+
+        #[cfg(feature = "dedup")]
+        if let Some((chunker, mut pending)) = dedup {
+            while !pending.is_empty() {
+                let cut = chunker.cut(&pending[..]);
+                sent = self
+                    .send_deduplicated_chunk(id, sent, &pending[..cut], size)
+                    .await?;
+                pending.drain(..cut);
+            }
+        }
+
+        #[cfg(feature = "compression")]
+        if let Some(encoder) = encoder {
+            let compressed = encoder.finish()?;
+            let total = sent + compressed.len() as u64;
+            for chunk in compressed.chunks(content_max_size) {
+                sent = self.send_chunk(id, sent, chunk, total).await?;
+            }
+        }
+
+        let digest = hasher.finalize().into();
+        self.sent_digests
+            .insert(filename.to_path_buf(), (bytes_so_far, digest));
+        if last_pass {
+            self.send_file_end(id, sent, digest).await?;
+        }
+        if sent == bytes_so_far {
+            tracing::info!(
+                "File {} sent to server ({} bytes)",
+                filename.display(),
+                bytes_so_far
+            );
+        } else {
+            tracing::info!(
+                "File {} sent to server ({} bytes, {} compressed)",
+                filename.display(),
+                bytes_so_far,
+                sent
+            );
+        }
+        self.report_progress_to(progress, ProgressEvent::FileCompleted { id });
+
+        Ok(())
+    }
+
+    /// Sends `content` at `offset` of file `id`, returns the offset following it
+    ///
+    /// `total` is the size of the content of the file, for the `RetransmitPolicy`.
+    async fn send_chunk(
+        &mut self,
+        id: u64,
+        offset: u64,
+        content: &[u8],
+        total: u64,
+    ) -> Result<u64> {
+        let end = offset + content.len() as u64;
+        let message = Message::FileChunk {
             id,
-            offset: 0,
-            content_size: 0,
-            content,
+            offset,
+            content_size: content
+                .len()
+                .try_into()
+                .expect("This should fit into a u16 by construction"),
+            crc32: crc32fast::hash(content),
+            content: content.to_vec(),
         };
-        // Avoid fragmentation and reassemble on the other size
+        self.send_message_at(&message, end, total).await?;
+        self.send_progress_if_due(id, end).await?;
+        Ok(end)
+    }
+
+    /// Sends `content` at `offset` of file `id` like `send_chunk`, or a reference to where it was
+    /// sent when it was recently
+    #[cfg(feature = "dedup")]
+    async fn send_deduplicated_chunk(
+        &mut self,
+        id: u64,
+        offset: u64,
+        content: &[u8],
+        total: u64,
+    ) -> Result<u64> {
+        let source = self
+            .chunk_index
+            .find_or_insert(content, ChunkLocation { id, offset });
+        // A file sent again may find its own content where it is not written yet
+        let Some(source) = source
+            .filter(|source| source.id != id || source.offset + content.len() as u64 <= offset)
+        else {
+            return self.send_chunk(id, offset, content, total).await;
+        };
+
+        let end = offset + content.len() as u64;
+        let message = Message::ChunkCopy {
+            id,
+            offset,
+            source_id: source.id,
+            source_offset: source.offset,
+            size: content
+                .len()
+                .try_into()
+                .expect("This should fit into a u16 by construction"),
+        };
+        self.send_message_at(&message, end, total).await?;
+        self.send_progress_if_due(id, end).await?;
+        Ok(end)
+    }
+
+    /// Tells the server that file `id` is complete after `sent` bytes of chunks, and its digest
+    async fn send_file_end(&mut self, id: u64, sent: u64, digest: [u8; 32]) -> Result<()> {
+        self.send_terminator(id, sent).await?;
+
+        self.send_message(&Message::FileHash { id, digest }).await
+    }
+
+    /// Sends the empty chunk at `offset` which tells the server that file `id` is complete
+    async fn send_terminator(&mut self, id: u64, offset: u64) -> Result<()> {
+        self.send_message_at(
+            &Message::FileChunk {
+                id,
+                offset,
+                content_size: 0,
+                crc32: crc32fast::hash(&[]),
+                content: Vec::new(),
+            },
+            offset,
+            offset,
+        )
+        .await
+    }
+
+    /// Sends bytes `range` of `file`, relative to `root`, for the server to write them over its
+    /// copy of the file
+    ///
+    /// The server keeps the rest of its copy, resized to the size of `file`, and applies the
+    /// update in place even with `commit_on_done`. No digest is sent as the client does not know
+    /// the rest of the server copy.
+    pub async fn send_file_range(&mut self, file: &Path, range: Range<u64>) -> Result<()> {
+        let fullname = self.config.root.join(file);
+        let id = self.file_id(&fullname).await?;
+        self.send_range(file, &fullname, id, range).await
+    }
+
+    /// Sends bytes `range` of `filepath` as a partial update of file `id`
+    async fn send_range(
+        &mut self,
+        file: &Path,
+        fullname: &Path,
+        id: u64,
+        range: Range<u64>,
+    ) -> Result<()> {
+        self.send_file_creation(file, fullname, id, true).await?;
+
+        let mut f = tokio::fs::File::open(fullname).await?;
+        let size = f.metadata().await?.len();
+        let end = range.end.min(size);
+        let mut offset = f.seek(SeekFrom::Start(range.start.min(end))).await?;
         let content_max_size =
             Message::get_max_content_size(crate::retransmit::max_payload_size(self.config.mtu));
-        let mut done = false;
+        let mut buffer = vec![0u8; content_max_size];
 
-        while !done {
-            match message {
-                Message::FileChunk {
-                    ref mut offset,
-                    ref mut content,
-                    ref mut content_size,
-                    ..
-                } => {
-                    *offset = f.stream_position().await?;
-                    let size = f.read(&mut content[..content_max_size]).await?;
-                    if size == 0 {
-                        tracing::info!(
-                            "File {} sent to server ({} bytes)",
-                            filename.display(),
-                            *offset
-                        );
-                        done = true;
-                    }
-                    *content_size = size
-                        .try_into()
-                        .expect("This should fit into a u16 by construction");
-                    // if size != content_max_size {
-                    //     tracing::warn!("Incomplete read at offset {}", *offset);
-                    // }
+        while offset < end {
+            let to_read = (end - offset).min(content_max_size as u64) as usize;
+            let read_size = f.read(&mut buffer[..to_read]).await?;
+            if read_size == 0 {
+                tracing::warn!(
+                    "File {} was truncated while being sent ({} of {} bytes)",
+                    file.display(),
+                    offset,
+                    end
+                );
+                break;
+            }
+            offset = self
+                .send_chunk(id, offset, &buffer[..read_size], end)
+                .await?;
+        }
+
+        self.send_terminator(id, offset).await?;
+        tracing::info!(
+            "Bytes {}..{} of {} sent to server",
+            range.start,
+            offset,
+            file.display()
+        );
+        Ok(())
+    }
+
+    /// Announces a file of `size` bytes named `name`, whose content is then written to the
+    /// returned `StreamSender`
+    ///
+    /// The content does not need to come from a file, nor is it compressed.
+    pub async fn open_stream(
+        &mut self,
+        name: impl Into<String>,
+        size: u64,
+    ) -> Result<StreamSender<'_, W>> {
+        let name = name.into();
+        let id = self.announce_stream(&name, size).await?;
+
+        let content_max_size =
+            Message::get_max_content_size(crate::retransmit::max_payload_size(self.config.mtu));
+        Ok(StreamSender {
+            client: self,
+            name,
+            id,
+            size,
+            sent: 0,
+            pending: Vec::with_capacity(content_max_size),
+            content_max_size,
+            hasher: Sha256::new(),
+        })
+    }
+
+    /// Sends the content of `reader` as a file named `name`, until the end of `reader`
+    ///
+    /// Unlike `open_stream`, the size does not need to be known beforehand: the file is announced
+    /// with `size_hint`, or 0, and the server extends it as chunks arrive, the end of the stream
+    /// telling its actual size. The content is not compressed.
+    pub async fn send_stream(
+        &mut self,
+        name: &str,
+        mut reader: impl AsyncRead + Unpin,
+        size_hint: Option<u64>,
+    ) -> Result<()> {
+        let id = self.announce_stream(name, size_hint.unwrap_or(0)).await?;
+
+        let content_max_size =
+            Message::get_max_content_size(crate::retransmit::max_payload_size(self.config.mtu));
+        let mut buffer = vec![0u8; content_max_size];
+        let mut hasher = Sha256::new();
+        let mut sent = 0u64;
+        loop {
+            // Pipes often return less than asked, only the last chunk may be partial
+            let mut filled = 0;
+            while filled < buffer.len() {
+                let read_size = reader.read(&mut buffer[filled..]).await?;
+                if read_size == 0 {
+                    break;
                 }
-                _ => unreachable!(),
+                filled += read_size;
+            }
+            if filled == 0 {
+                break;
+            }
+
+            hasher.update(&buffer[..filled]);
+            sent = self
+                .send_chunk(id, sent, &buffer[..filled], size_hint.unwrap_or(u64::MAX))
+                .await?;
+            if filled < buffer.len() {
+                break;
             }
-            self.send_message(&message).await?;
+        }
+        if let Some(size) = size_hint.filter(|size| *size != sent) {
+            tracing::warn!(
+                "Stream {} ended after {} bytes instead of the {} announced",
+                name,
+                sent,
+                size
+            );
         }
 
+        self.send_file_end(id, sent, hasher.finalize().into())
+            .await?;
+        tracing::info!("Stream {} sent to server ({} bytes)", name, sent);
         Ok(())
     }
 
+    /// Announces a stream of `size` bytes named `name`, returns the random id it gets
+    async fn announce_stream(&mut self, name: &str, size: u64) -> Result<u64> {
+        let mut id = [0u8; 8];
+        getrandom::getrandom(&mut id[..]).map_err(std::io::Error::from)?;
+        let id = u64::from_ne_bytes(id);
+
+        self.send_message(&Message::File {
+            filename: String::from(name),
+            created: None,
+            modified: std::time::SystemTime::now(),
+            size,
+            id,
+            mode: 0,
+            compressed: false,
+            partial: false,
+        })
+        .await?;
+        tracing::debug!("Notify server of stream {} (id: 0x{:x})", name, id);
+
+        Ok(id)
+    }
+
     async fn send_file_creation(
         &mut self,
-        filename: &PathBuf,
-        filepath: &PathBuf,
+        filename: &Path,
+        filepath: &Path,
         id: u64,
+        partial: bool,
     ) -> Result<()> {
-        let metadata = tokio::fs::symlink_metadata(&filepath).await?;
+        let metadata = tokio::fs::symlink_metadata(filepath).await?;
         let filename = filename.to_string_lossy().to_string();
-        let created = metadata.created()?;
+        // Many filesystems do not record the creation time
+        let created = metadata.created().ok();
+        let modified = metadata.modified()?;
         let size = metadata.len();
+        let mode = crate::utils::fs::get_mode(&metadata);
+        // Chunks of a partial update are at offsets of the file
+        #[cfg(feature = "compression")]
+        let compressed = self.config.compress && !partial;
+        #[cfg(not(feature = "compression"))]
+        let compressed = false;
 
         // First sends the file existance
         self.send_message(&Message::File {
             filename: filename.clone(),
             created,
+            modified,
             size,
             id,
+            mode,
+            compressed,
+            partial,
         })
         .await?;
         tracing::debug!("Notify server of file {}", filename);
@@ -151,36 +817,1236 @@ impl Client {
         Ok(())
     }
 
+    async fn send_file_acl(&mut self, filename: &Path, filepath: &Path, id: u64) -> Result<()> {
+        let acl = match crate::utils::acl::read_acl(filepath) {
+            Ok(acl) => acl,
+            Err(e) => {
+                tracing::warn!("Could not read ACL of {}: {}", filename.display(), e);
+                return Ok(());
+            }
+        };
+
+        self.send_message(&Message::Acl { id, acl }).await?;
+        tracing::debug!("Sent ACL of {}", filename.display());
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    async fn send_special_file(
+        &mut self,
+        filename: &Path,
+        metadata: &std::fs::Metadata,
+    ) -> Result<()> {
+        use crate::messages::SpecialKind;
+        use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+        let file_type = metadata.file_type();
+        let kind = if file_type.is_fifo() {
+            SpecialKind::Fifo
+        } else if file_type.is_char_device() {
+            SpecialKind::CharDevice
+        } else if file_type.is_block_device() {
+            SpecialKind::BlockDevice
+        } else {
+            tracing::warn!("Skipping {}: unsupported file type", filename.display());
+            return Ok(());
+        };
+
+        if !self.config.preserve.specials {
+            tracing::warn!(
+                "Skipping special file {} as specials are not preserved",
+                filename.display()
+            );
+            return Ok(());
+        }
+
+        self.send_message(&Message::SpecialFile {
+            path: filename.to_string_lossy().to_string(),
+            kind,
+            rdev: metadata.rdev(),
+        })
+        .await?;
+        tracing::debug!("Notify server of special file {}", filename.display());
+
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    async fn send_special_file(
+        &mut self,
+        filename: &Path,
+        _metadata: &std::fs::Metadata,
+    ) -> Result<()> {
+        tracing::warn!("Skipping {}: unsupported file type", filename.display());
+        Ok(())
+    }
+
+    async fn send_directory(&mut self, dirname: &Path, metadata: &std::fs::Metadata) -> Result<()> {
+        self.send_message(&Message::Directory {
+            path: dirname.to_string_lossy().to_string(),
+            mode: crate::utils::fs::get_mode(metadata),
+        })
+        .await?;
+        tracing::debug!("Notify server of directory {}", dirname.display());
+
+        Ok(())
+    }
+
+    async fn send_symlink(&mut self, link_path: &Path, filepath: &Path) -> Result<()> {
+        let target = tokio::fs::read_link(filepath).await?;
+
+        self.send_message(&Message::Symlink {
+            link_path: link_path.to_string_lossy().to_string(),
+            target: target.to_string_lossy().to_string(),
+        })
+        .await?;
+        tracing::debug!(
+            "Notify server of symlink {} -> {}",
+            link_path.display(),
+            target.display()
+        );
+
+        Ok(())
+    }
+
+    /// Id of `filepath` derived from the file, `None` when ids are allocated in sequence
+    async fn stable_file_id(&self, filepath: &Path) -> Result<Option<u64>> {
+        match self.config.file_id {
+            FileIdMode::Sequence => Ok(None),
+            FileIdMode::Inode => Ok(Some(crate::utils::get_inode(filepath)?)),
+            FileIdMode::Content => crate::utils::fs::content_id(filepath).await.map(Some),
+        }
+    }
+
+    async fn file_id(&mut self, filepath: &Path) -> Result<u64> {
+        match self.stable_file_id(filepath).await? {
+            Some(id) => Ok(id),
+            None => {
+                let id = self.next_file_id;
+                self.next_file_id += 1;
+                Ok(id)
+            }
+        }
+    }
+
+    /// Id of a file, falling back to its inode when another file of the transfer has the same
+    async fn unique_file_id(
+        &mut self,
+        file: &Path,
+        fullname: &Path,
+        used_ids: &mut HashSet<u64>,
+    ) -> Result<u64> {
+        let id = self.file_id(fullname).await?;
+        Self::deduplicate_file_id(file, fullname, id, used_ids)
+    }
+
+    fn deduplicate_file_id(
+        file: &Path,
+        fullname: &Path,
+        mut id: u64,
+        used_ids: &mut HashSet<u64>,
+    ) -> Result<u64> {
+        if !used_ids.insert(id) {
+            tracing::warn!(
+                "{} has the same id as another file (0x{:x}), using its inode instead",
+                file.display(),
+                id
+            );
+            id = crate::utils::get_inode(fullname)?;
+            used_ids.insert(id);
+        }
+
+        Ok(id)
+    }
+
+    /// Whether regular file `file` is larger than `max_file_size`, warning that it is skipped
+    fn is_too_large(&self, file: &Path, metadata: &std::fs::Metadata) -> bool {
+        match self.config.max_file_size {
+            Some(max_file_size) if metadata.len() > max_file_size => {
+                tracing::warn!(
+                    "Skipping {} ({} bytes), larger than max_file_size ({} bytes)",
+                    file.display(),
+                    metadata.len(),
+                    max_file_size
+                );
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the name of `file` is too long to be announced, warning that it is skipped
+    fn is_name_too_long(&self, file: &Path) -> bool {
+        let len = file.to_string_lossy().len();
+        let max_len = Message::get_max_filename_size(self.config.mtu);
+        if len > max_len {
+            tracing::warn!(
+                "Skipping {}, its name of {} bytes does not fit in a datagram (at most {} bytes)",
+                file.display(),
+                len,
+                max_len
+            );
+            return true;
+        }
+        false
+    }
+
+    /// Lists what `send_files` would send, with the ids and sizes of regular files
+    pub async fn plan_files(&self, files: &[PathBuf]) -> Result<Job> {
+        let mut job = Job::default();
+        let mut used_ids = HashSet::new();
+
+        for file in files {
+            if self.is_name_too_long(file) {
+                continue;
+            }
+            let fullname = self.config.root.join(file);
+            let is_symlink = self.config.symlinks == SymlinkPolicy::Replicate
+                && tokio::fs::symlink_metadata(&fullname).await?.is_symlink();
+            let metadata = if is_symlink {
+                None
+            } else {
+                Some(tokio::fs::metadata(&fullname).await?)
+            };
+
+            let (size, id) = match metadata {
+                Some(ref metadata) if metadata.is_file() && self.is_too_large(file, metadata) => {
+                    continue;
+                }
+                Some(metadata) if metadata.is_file() => {
+                    // Ids allocated in sequence are only known once sent
+                    let id = match self.stable_file_id(&fullname).await? {
+                        Some(id) => Some(Self::deduplicate_file_id(
+                            file,
+                            &fullname,
+                            id,
+                            &mut used_ids,
+                        )?),
+                        None => None,
+                    };
+                    (metadata.len(), id)
+                }
+                _ => (0, None),
+            };
+            job.entries.push(JobEntry {
+                path: file.clone(),
+                size,
+                id,
+            });
+        }
+
+        Ok(job)
+    }
+
     pub async fn send_files(&mut self, files: &[PathBuf]) -> Result<()> {
-        let files_count = files.len().try_into()?;
+        self.send_files_with_progress(files, |_| {}).await
+    }
+
+    /// Sends `files` like `send_files`, skipping the first `start_index` ones
+    ///
+    /// Regular files are sent in the order of `files`, after the other entries: a batch
+    /// interrupted while sending `files[i]` is resumed from `i`, as long as `files` is listed in
+    /// the same order (which `walk` guarantees).
+    pub async fn send_files_from(&mut self, files: &[PathBuf], start_index: usize) -> Result<()> {
+        let start_index = start_index.min(files.len());
+        if start_index != 0 {
+            tracing::info!(
+                "Resuming at file {} of {} ({})",
+                start_index,
+                files.len(),
+                files
+                    .get(start_index)
+                    .map_or_else(|| String::from("none left"), |f| f.display().to_string())
+            );
+        }
+
+        self.send_files(&files[start_index..]).await
+    }
+
+    /// Sends `files` like `send_files`, skipping the regular files `manifest` records with their
+    /// current size and modification time
+    pub async fn send_files_incremental(
+        &mut self,
+        files: &[PathBuf],
+        manifest: &Manifest,
+    ) -> Result<()> {
+        let mut changed = Vec::with_capacity(files.len());
+        for file in files {
+            let metadata = tokio::fs::symlink_metadata(self.config.root.join(file)).await?;
+            if metadata.is_file()
+                && manifest.is_unchanged(file, metadata.len(), metadata.modified()?)
+            {
+                tracing::debug!("Skipping unchanged {}", file.display());
+                continue;
+            }
+            changed.push(file.clone());
+        }
+        tracing::info!(
+            "Sending {} of {} files, the others being unchanged",
+            changed.len(),
+            files.len()
+        );
+
+        self.send_files(&changed[..]).await
+    }
+
+    /// Sends `files` like `send_files`, reporting the progress of their contents to `progress`
+    pub async fn send_files_with_progress(
+        &mut self,
+        files: &[PathBuf],
+        mut progress: impl FnMut(ProgressEvent),
+    ) -> Result<()> {
+        let mut regular_files = Vec::with_capacity(files.len());
+        let mut other_files = Vec::new();
+        let mut symlinks = Vec::new();
+        for file in files {
+            if self.is_name_too_long(file) {
+                continue;
+            }
+            let fullname = self.config.root.join(file);
+            if self.config.symlinks == SymlinkPolicy::Replicate
+                && tokio::fs::symlink_metadata(&fullname).await?.is_symlink()
+            {
+                symlinks.push((file, fullname));
+                continue;
+            }
+
+            let metadata = tokio::fs::metadata(fullname).await?;
+            if metadata.is_file() {
+                if self.is_too_large(file, &metadata) {
+                    continue;
+                }
+                regular_files.push(file);
+            } else {
+                other_files.push((file, metadata));
+            }
+        }
+
+        let files_count = regular_files.len().try_into()?;
         let mut ids = HashMap::new();
+        let mut used_ids = HashSet::new();
 
         self.send_message(&Message::CountFilesToUpload(files_count))
             .await?;
 
-        for file in files {
+        for (file, metadata) in other_files {
+            if metadata.is_dir() {
+                self.send_directory(file, &metadata).await?;
+            } else {
+                self.send_special_file(file, &metadata).await?;
+            }
+        }
+
+        for (file, fullname) in symlinks {
+            self.send_symlink(file, &fullname).await?;
+        }
+
+        for file in regular_files.iter().copied() {
             let fullname = self.config.root.join(file);
-            let id = crate::utils::get_inode(&fullname)?;
+            let id = self.unique_file_id(file, &fullname, &mut used_ids).await?;
 
             tracing::debug!("{} => ({:?}, {})", file.display(), fullname, id);
             ids.insert(file, (fullname, id));
-            let (fullname, _) = ids.get(file).unwrap();
-            self.send_file_creation(file, fullname, id).await?;
         }
 
-        for file in files {
+        // Sent again after every other file, their end is only sent with the last pass
+        let critical_files: Vec<_> = if self.config.critical_file_passes > 1 {
+            regular_files
+                .iter()
+                .copied()
+                .filter(|file| self.critical_files.matches(file))
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        for file in regular_files.iter().copied() {
             let (fullname, id) = ids.get(file).unwrap();
-            self.send_file(file, fullname, *id).await?;
+            // Announced right before its content, so that both travel together
+            self.send_file_creation(file, fullname, *id, false).await?;
+            let last_pass = !critical_files.contains(&file);
+            self.send_file(file, fullname, *id, last_pass, &mut progress)
+                .await?;
+            if self.config.preserve.acl {
+                self.send_file_acl(file, fullname, *id).await?;
+            }
+        }
+
+        // Extra passes come after every file, so that a burst of losses does not hit them all
+        for pass in 2..=self.config.critical_file_passes {
+            for file in critical_files.iter().copied() {
+                let (fullname, id) = ids.get(file).unwrap();
+                tracing::debug!("Sending {} again (pass {})", file.display(), pass);
+                self.send_file_creation(file, fullname, *id, false).await?;
+                let last_pass = pass == self.config.critical_file_passes;
+                self.send_file(file, fullname, *id, last_pass, &mut |_| {})
+                    .await?;
+            }
         }
 
         Ok(())
     }
 
+    /// Sends the size and digest of every regular file among `files`, against which the server
+    /// checks its tree once done
+    ///
+    /// Files sent by this client are described as they were sent, the others are hashed, keep
+    /// alives telling the server that the client is still there meanwhile.
+    pub async fn send_manifest(&mut self, files: &[PathBuf]) -> Result<()> {
+        // Hashing files takes a while, during which the server must not think the client gone
+        const HASHING_KEEP_ALIVE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+        let max_size = crate::retransmit::max_payload_size(self.config.mtu)
+            - Message::TREE_MANIFEST_PREFIX_SIZE;
+        let mut entries = Vec::with_capacity(files.len());
+        for file in files {
+            let fullname = self.config.root.join(file);
+            let metadata = tokio::fs::metadata(&fullname).await?;
+            if !metadata.is_file() {
+                continue;
+            }
+            let Some(path) = file.to_str() else {
+                tracing::warn!(
+                    "{} is not valid UTF-8, leaving it out of the manifest",
+                    file.display()
+                );
+                continue;
+            };
+            // The digest is filled in once the total is known, for the parts to be sent early
+            let entry = ManifestEntry {
+                path: path.to_owned(),
+                size: metadata.len(),
+                digest: [0u8; 32],
+            };
+            if entry.wire_size() > max_size {
+                tracing::warn!(
+                    "Path {} is too long for the manifest, leaving it out",
+                    entry.path
+                );
+                continue;
+            }
+            entries.push((fullname, entry));
+        }
+
+        let total = entries.len() as u64;
+        let keep_alive_interval = self
+            .config
+            .keep_alive_interval
+            .map_or(HASHING_KEEP_ALIVE_INTERVAL, |interval| {
+                interval.min(HASHING_KEEP_ALIVE_INTERVAL)
+            });
+        let mut part = Vec::new();
+        let mut part_size = 0;
+        for (fullname, mut entry) in entries {
+            match self.sent_digests.get(Path::new(&entry.path)) {
+                Some(&(size, digest)) => {
+                    entry.size = size;
+                    entry.digest = digest;
+                }
+                None => {
+                    if self.last_keep_alive.elapsed() >= keep_alive_interval {
+                        self.send_keep_alive().await?;
+                    }
+                    let digest =
+                        crate::utils::fs::hash_file(&fullname, crate::HashAlgorithm::Sha256)
+                            .await?;
+                    entry.digest = digest.try_into().expect("SHA-256 digests are 32 bytes");
+                }
+            }
+
+            if part_size + entry.wire_size() > max_size || part.len() == u16::MAX as usize {
+                let entries = std::mem::take(&mut part);
+                self.send_message(&Message::TreeManifest { total, entries })
+                    .await?;
+                part_size = 0;
+            }
+            part_size += entry.wire_size();
+            part.push(entry);
+        }
+        if !part.is_empty() || total == 0 {
+            self.send_message(&Message::TreeManifest {
+                total,
+                entries: part,
+            })
+            .await?;
+        }
+        tracing::info!("Sent manifest of {} files", total);
+
+        Ok(())
+    }
+
     pub async fn send_done(&mut self) -> Result<()> {
         let message = Message::Done;
 
+        // Protect the last messages even though their group is not complete, the server stops
+        // listening once done
+        if let Some(ref mut fec) = self.fec {
+            if let Some(mut parity) = fec.finish(&self.config)? {
+                parity
+                    .send_paced(&self.socket, self.pacer.as_mut(), None)
+                    .await?;
+            }
+        }
         self.send_message(&message).await?;
         tracing::info!("Send Done to server");
+        self.report_progress(ProgressEvent::SessionDone);
         Ok(())
     }
 }
+
+impl Client<UdpWriter> {
+    /// Fails with `Error::NotOneWay` unless the read half of the socket is shut down
+    ///
+    /// Deployments relying on a data diode can call this before sending anything, so that they
+    /// fail closed instead of leaving a return path open.
+    pub fn assert_oneway(&self) -> Result<()> {
+        if self.socket.is_oneway()? {
+            Ok(())
+        } else {
+            tracing::error!("The read half of the socket is not shut down");
+            Err(Error::NotOneWay)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{decode_datagrams, MemoryTransport};
+
+    #[tokio::test]
+    async fn hello_is_framed() {
+        let config = Config {
+            session_id: Some(String::from("nightly")),
+            ..Default::default()
+        };
+        let mut client = Client::new_with_config(MemoryTransport::new(), config.clone()).unwrap();
+        client.send_hello().await.unwrap();
+
+        let datagrams = client.socket().take_datagrams();
+        assert_eq!(datagrams.len(), config.remission_count);
+        for datagram in &datagrams {
+            assert_eq!(datagram, &datagrams[0]);
+        }
+        // Magic, flags, sequence and size of the payload
+        let (header, payload) = datagrams[0].split_at(11);
+        assert_eq!(&header[..5], b"1WY2\0");
+        assert_eq!(
+            &header[5..9],
+            &client.sequence.wrapping_sub(1).to_be_bytes()[..]
+        );
+        assert_eq!(
+            usize::from(u16::from_be_bytes([header[9], header[10]])),
+            payload.len()
+        );
+
+        assert_eq!(
+            decode_datagrams(&datagrams[..1], &config).unwrap()[0],
+            Message::Hello {
+                session_id: String::from("nightly"),
+                version: ProtocolVersion::CURRENT,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn bidirectional_socket_is_not_oneway() {
+        let receiver = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let connect = || async {
+            let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            socket
+                .connect(receiver.local_addr().unwrap())
+                .await
+                .unwrap();
+            socket
+        };
+
+        // Built without shutting down its read half
+        let client = Client::new(UdpWriter::from(connect().await));
+        assert!(matches!(client.assert_oneway(), Err(Error::NotOneWay)));
+
+        let client = Client::new(UdpWriter::new(connect().await).unwrap());
+        client.assert_oneway().unwrap();
+    }
+
+    #[test]
+    fn invalid_override_is_refused() {
+        let config = Config::default().with(|c| c.mtu = crate::retransmit::min_mtu() - 1);
+        assert!(matches!(
+            Client::new_with_config(MemoryTransport::new(), config),
+            Err(crate::Error::InvalidSetting(_))
+        ));
+    }
+
+    #[test]
+    fn clients_get_distinct_keep_alive_seeds() {
+        let first = Client::new(MemoryTransport::new());
+        let second = Client::new(MemoryTransport::new());
+        // Equal with a probability of 2^-64
+        assert_ne!(first.keep_alive, second.keep_alive);
+    }
+
+    #[tokio::test]
+    async fn stream_is_reconstructed() {
+        let destination = tempfile::tempdir().unwrap();
+        let content: Vec<u8> = (0..3000u32).map(|i| (i % 251) as u8).collect();
+
+        let mut client = Client::new(MemoryTransport::new());
+        let mut stream = client
+            .open_stream("report", content.len() as u64)
+            .await
+            .unwrap();
+        // Writes of any size, not aligned on chunks
+        for part in content.chunks(700) {
+            stream.write(part).await.unwrap();
+        }
+        assert!(stream.write(b"too much").await.is_err());
+        stream.finish().await.unwrap();
+
+        let mut handler = crate::testing::client_handler(Config {
+            root: destination.path().to_path_buf(),
+            ..Default::default()
+        });
+        crate::testing::deliver(&mut handler, client.socket().take_datagrams()).await;
+        assert_eq!(
+            std::fs::read(destination.path().join("report")).unwrap(),
+            content
+        );
+    }
+
+    #[tokio::test]
+    async fn stream_of_unknown_size_is_reconstructed() {
+        let destination = tempfile::tempdir().unwrap();
+        let content: Vec<u8> = (0..3000u32).map(|i| (i % 251) as u8).collect();
+
+        let mut client = Client::new(MemoryTransport::new());
+        client
+            .send_stream("piped", std::io::Cursor::new(&content[..]), None)
+            .await
+            .unwrap();
+        // A hint too small is only a hint
+        client
+            .send_stream("hinted", std::io::Cursor::new(&content[..]), Some(10))
+            .await
+            .unwrap();
+
+        let mut handler = crate::testing::client_handler(Config {
+            root: destination.path().to_path_buf(),
+            ..Default::default()
+        });
+        crate::testing::deliver(&mut handler, client.socket().take_datagrams()).await;
+        for name in ["piped", "hinted"] {
+            assert_eq!(
+                std::fs::read(destination.path().join(name)).unwrap(),
+                content,
+                "{}",
+                name
+            );
+        }
+        assert_eq!(handler.stats().files_completed, 2);
+    }
+
+    #[tokio::test]
+    async fn progress_events_of_one_file() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("file"), vec![0x55u8; 1500]).unwrap();
+        // First id allocated by the client
+        let id = 0;
+
+        let config = Config {
+            mtu: 1024,
+            root: root.path().to_path_buf(),
+            ..Default::default()
+        };
+        let content_max_size =
+            Message::get_max_content_size(crate::retransmit::max_payload_size(config.mtu)) as u64;
+        let (tx, mut rx) = mpsc::channel(16);
+        let mut client = Client::new_with_config(MemoryTransport::new(), config)
+            .unwrap()
+            .with_progress_channel(tx);
+        client.send_files(&[PathBuf::from("file")]).await.unwrap();
+        client.send_done().await.unwrap();
+        drop(client);
+
+        let mut events = Vec::new();
+        while let Some(event) = rx.recv().await {
+            events.push(event);
+        }
+        assert_eq!(
+            events,
+            vec![
+                ProgressEvent::FileStarted {
+                    id,
+                    name: PathBuf::from("file"),
+                    size: 1500
+                },
+                ProgressEvent::ChunkSent {
+                    id,
+                    bytes_so_far: content_max_size
+                },
+                ProgressEvent::ChunkSent {
+                    id,
+                    bytes_so_far: 1500
+                },
+                ProgressEvent::FileCompleted { id },
+                ProgressEvent::SessionDone,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn progress_callback_counts_every_byte() {
+        let root = tempfile::tempdir().unwrap();
+        let sizes = [0usize, 700, 5000];
+        let files: Vec<PathBuf> = sizes
+            .iter()
+            .map(|size| {
+                let name = PathBuf::from(format!("file-{}", size));
+                std::fs::write(root.path().join(&name), vec![0x55u8; *size]).unwrap();
+                name
+            })
+            .collect();
+
+        let mut client = Client::new_with_config(
+            MemoryTransport::new(),
+            Config {
+                root: root.path().to_path_buf(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let mut events = Vec::new();
+        client
+            .send_files_with_progress(&files[..], |event| events.push(event))
+            .await
+            .unwrap();
+
+        let mut started = HashMap::new();
+        let mut sent = HashMap::new();
+        let mut completed = HashSet::new();
+        for event in events {
+            match event {
+                ProgressEvent::FileStarted { id, size, .. } => {
+                    started.insert(id, size);
+                }
+                ProgressEvent::ChunkSent { id, bytes_so_far } => {
+                    sent.insert(id, bytes_so_far);
+                }
+                ProgressEvent::FileCompleted { id } => {
+                    completed.insert(id);
+                }
+                ProgressEvent::SessionDone => panic!("Session is not done"),
+            }
+        }
+
+        assert_eq!(started.len(), sizes.len());
+        assert_eq!(completed.len(), sizes.len());
+        for (id, size) in started {
+            assert_eq!(sent.get(&id).copied().unwrap_or(0), size);
+        }
+    }
+
+    #[tokio::test]
+    async fn files_sharing_an_inode_get_distinct_ids() {
+        let root = tempfile::tempdir().unwrap();
+        // Same inode number, as files of two mounted filesystems may have
+        std::fs::write(root.path().join("a"), b"content").unwrap();
+        std::fs::hard_link(root.path().join("a"), root.path().join("b")).unwrap();
+        let files = [PathBuf::from("a"), PathBuf::from("b")];
+
+        let mut client = Client::new_with_config(
+            MemoryTransport::new(),
+            Config {
+                root: root.path().to_path_buf(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let job = client.plan_files(&files[..]).await.unwrap();
+        assert!(job.entries.iter().all(|entry| entry.id.is_none()));
+
+        let mut ids = Vec::new();
+        client
+            .send_files_with_progress(&files[..], |event| {
+                if let ProgressEvent::FileStarted { id, .. } = event {
+                    ids.push(id);
+                }
+            })
+            .await
+            .unwrap();
+        assert_eq!(ids, [0, 1]);
+    }
+
+    #[tokio::test]
+    async fn files_over_max_file_size_are_skipped() {
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        std::fs::write(source.path().join("small"), vec![0x55u8; 100]).unwrap();
+        std::fs::write(source.path().join("large"), vec![0x55u8; 101]).unwrap();
+        let files = [PathBuf::from("small"), PathBuf::from("large")];
+
+        let mut client = Client::new_with_config(
+            MemoryTransport::new(),
+            Config {
+                root: source.path().to_path_buf(),
+                max_file_size: Some(100),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let job = client.plan_files(&files[..]).await.unwrap();
+        assert_eq!(job.entries.len(), 1);
+        assert_eq!(job.entries[0].path, PathBuf::from("small"));
+
+        client.send_files(&files[..]).await.unwrap();
+        client.send_done().await.unwrap();
+
+        let mut handler = crate::testing::client_handler(Config {
+            root: destination.path().to_path_buf(),
+            ..Default::default()
+        });
+        crate::testing::deliver(&mut handler, client.socket().take_datagrams()).await;
+        assert!(destination.path().join("small").exists());
+        assert!(!destination.path().join("large").exists());
+        assert_eq!(handler.stats().files_announced, Some(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn manifest_reuses_the_digests_of_sent_files() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("sent"), b"sent content").unwrap();
+        std::fs::write(root.path().join("earlier"), b"sent by an earlier run").unwrap();
+
+        let mut client = Client::new_with_config(
+            MemoryTransport::new(),
+            Config {
+                root: root.path().to_path_buf(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        client.send_files(&[PathBuf::from("sent")]).await.unwrap();
+        client.socket().take_datagrams();
+        // The manifest describes what was sent, not what the file became
+        std::fs::write(root.path().join("sent"), b"changed afterwards").unwrap();
+
+        tokio::time::advance(std::time::Duration::from_secs(2)).await;
+        client
+            .send_manifest(&[PathBuf::from("sent"), PathBuf::from("earlier")])
+            .await
+            .unwrap();
+
+        let messages = decode_datagrams(&client.socket().take_datagrams(), &client.config).unwrap();
+        // A keep alive went out before hashing the file not sent, each message being repeated
+        assert!(matches!(messages[0], Message::KeepAlive(_)));
+        let Some(Message::TreeManifest { total, entries }) = messages.last() else {
+            panic!("Expected a manifest, got {:?}", messages.last());
+        };
+        let total = *total;
+        assert_eq!(total, 2);
+        let digest = |content: &[u8]| -> [u8; 32] { Sha256::digest(content).into() };
+        assert_eq!(
+            entries
+                .iter()
+                .map(|e| (e.path.as_str(), e.size, e.digest))
+                .collect::<Vec<_>>(),
+            [
+                ("sent", 12, digest(b"sent content")),
+                ("earlier", 22, digest(b"sent by an earlier run")),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn send_files_from_skips_first_files() {
+        let root = tempfile::tempdir().unwrap();
+        let files: Vec<PathBuf> = (0..4)
+            .map(|i| {
+                let name = PathBuf::from(format!("file-{}", i));
+                std::fs::write(root.path().join(&name), b"content").unwrap();
+                name
+            })
+            .collect();
+
+        let mut client = Client::new_with_config(
+            MemoryTransport::new(),
+            Config {
+                root: root.path().to_path_buf(),
+                remission_count: 1,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        client.send_files_from(&files[..], 3).await.unwrap();
+        client.send_files_from(&files[..], 10).await.unwrap();
+
+        let announced: Vec<_> = decode_datagrams(&client.socket().take_datagrams(), &client.config)
+            .unwrap()
+            .into_iter()
+            .filter_map(|message| match message {
+                Message::File { filename, .. } => Some(filename),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(announced, ["file-3"]);
+    }
+
+    #[tokio::test]
+    async fn file_is_announced_right_before_its_content() {
+        let root = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        let files = [PathBuf::from("first"), PathBuf::from("second")];
+        for file in &files {
+            std::fs::write(root.path().join(file), b"content").unwrap();
+        }
+
+        let mut client = Client::new_with_config(
+            MemoryTransport::new(),
+            Config {
+                root: root.path().to_path_buf(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        client.send_files(&files[..]).await.unwrap();
+        client.send_done().await.unwrap();
+
+        let mut datagrams = client.socket().take_datagrams();
+        let messages = decode_datagrams(&datagrams, &client.config).unwrap();
+        let mut order: Vec<_> = messages
+            .iter()
+            .filter_map(|message| match message {
+                Message::File { id, .. } => Some(("file", *id)),
+                Message::FileChunk { id, .. } => Some(("chunk", *id)),
+                _ => None,
+            })
+            .collect();
+        order.dedup();
+        let ids: Vec<_> = order.iter().map(|(_, id)| *id).collect();
+        assert_eq!(
+            order,
+            [
+                ("file", ids[0]),
+                ("chunk", ids[0]),
+                ("file", ids[2]),
+                ("chunk", ids[2])
+            ]
+        );
+
+        // The first copy of each announcement is lost
+        let mut lost = HashSet::new();
+        let mut index = 0;
+        datagrams.retain(|_| {
+            let keep = match &messages[index] {
+                Message::File { id, .. } => !lost.insert(*id),
+                _ => true,
+            };
+            index += 1;
+            keep
+        });
+
+        let mut handler = crate::testing::client_handler(Config {
+            root: destination.path().to_path_buf(),
+            ..Default::default()
+        });
+        crate::testing::deliver(&mut handler, datagrams).await;
+        for file in &files {
+            assert_eq!(
+                std::fs::read(destination.path().join(file)).unwrap(),
+                b"content"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn files_with_too_long_names_are_skipped() {
+        let root = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        let long_dir: PathBuf = ["a", "b", "c"].iter().map(|c| c.repeat(200)).collect();
+        std::fs::create_dir_all(root.path().join(&long_dir)).unwrap();
+        let long_name = long_dir.join("file");
+        let files = [PathBuf::from("first"), long_name, PathBuf::from("last")];
+        for file in &files {
+            std::fs::write(root.path().join(file), b"content").unwrap();
+        }
+
+        let mut client = Client::new_with_config(
+            MemoryTransport::new(),
+            Config {
+                root: root.path().to_path_buf(),
+                mtu: 512,
+                remission_count: 1,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        client.send_files(&files[..]).await.unwrap();
+        client.send_done().await.unwrap();
+
+        let mut handler = crate::testing::client_handler(Config {
+            root: destination.path().to_path_buf(),
+            mtu: 512,
+            ..Default::default()
+        });
+        crate::testing::deliver(&mut handler, client.socket().take_datagrams()).await;
+        assert!(destination.path().join("first").exists());
+        assert!(destination.path().join("last").exists());
+        assert!(!destination.path().join("a".repeat(200)).exists());
+        assert_eq!(handler.stats().files_announced, Some(2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keep_alives_are_sent_between_transfers() {
+        let mut client = Client::new_with_config(
+            MemoryTransport::new(),
+            Config {
+                remission_count: 1,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        client
+            .keep_alive_until(
+                std::time::Duration::from_secs(1),
+                tokio::time::sleep(std::time::Duration::from_millis(3500)),
+            )
+            .await
+            .unwrap();
+
+        let received = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let ids = received.clone();
+        let mut handler = crate::testing::client_handler(Config::default()).with_message_hook(
+            std::sync::Arc::new(move |message: &Message| {
+                if let Message::KeepAlive(id) = message {
+                    ids.lock().unwrap().push(*id);
+                }
+                crate::connection::MessageAction::Accept
+            }),
+        );
+        crate::testing::deliver(&mut handler, client.socket().take_datagrams()).await;
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 4);
+        assert!(received
+            .windows(2)
+            .all(|ids| ids[1] == ids[0].wrapping_add(1)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keep_alives_are_sent_during_long_files() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("huge"), vec![0x55u8; 20 * 1024]).unwrap();
+
+        // About one datagram per second
+        let mut client = Client::new_with_config(
+            MemoryTransport::new(),
+            Config {
+                root: root.path().to_path_buf(),
+                remission_count: 1,
+                max_bytes_per_sec: Some(1024),
+                keep_alive_interval: Some(std::time::Duration::from_secs(5)),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        client.send_files(&[PathBuf::from("huge")]).await.unwrap();
+
+        // Chunks sent between two keep alives
+        let mut cadence = vec![0];
+        for message in decode_datagrams(&client.socket().take_datagrams(), &client.config).unwrap()
+        {
+            match message {
+                Message::KeepAlive(_) => cadence.push(0),
+                Message::FileChunk { .. } => *cadence.last_mut().unwrap() += 1,
+                _ => {}
+            }
+        }
+        // Compact chunks are a bit smaller than the MTU, one more sometimes fits in 5 seconds
+        #[cfg(feature = "compact")]
+        let expected = 5..=6;
+        #[cfg(not(feature = "compact"))]
+        let expected = 5..=5;
+        assert!(cadence.len() >= 4, "{:?}", cadence);
+        assert!(
+            cadence[1..cadence.len() - 1]
+                .iter()
+                .all(|n| expected.contains(n)),
+            "{:?}",
+            cadence
+        );
+    }
+
+    #[tokio::test]
+    async fn unchanged_files_are_not_sent_again() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("unchanged"), b"content").unwrap();
+        std::fs::write(root.path().join("changed"), b"new content").unwrap();
+        let files = [PathBuf::from("changed"), PathBuf::from("unchanged")];
+
+        let mut manifest = Manifest::new();
+        manifest.record(root.path(), &files[..]).unwrap();
+        let modified = std::fs::metadata(root.path().join("changed"))
+            .unwrap()
+            .modified()
+            .unwrap();
+        manifest.insert("changed", 7, modified);
+
+        let mut client = Client::new_with_config(
+            MemoryTransport::new(),
+            Config {
+                root: root.path().to_path_buf(),
+                remission_count: 1,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        client
+            .send_files_incremental(&files[..], &manifest)
+            .await
+            .unwrap();
+
+        let announced: Vec<_> = decode_datagrams(&client.socket().take_datagrams(), &client.config)
+            .unwrap()
+            .into_iter()
+            .filter_map(|message| match message {
+                Message::File { filename, .. } => Some(filename),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(announced, ["changed"]);
+    }
+
+    #[tokio::test]
+    async fn critical_files_are_sent_every_pass() {
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        let content: Vec<u8> = (0..5000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(source.path().join("state.db"), &content[..]).unwrap();
+        std::fs::write(source.path().join("notes.txt"), b"not critical").unwrap();
+
+        let mut client = Client::new_with_config(
+            MemoryTransport::new(),
+            Config {
+                root: source.path().to_path_buf(),
+                critical_files: vec![String::from("*.db")],
+                critical_file_passes: 3,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        client
+            .send_files(&[PathBuf::from("state.db"), PathBuf::from("notes.txt")])
+            .await
+            .unwrap();
+        client.send_done().await.unwrap();
+
+        let announced = std::sync::Arc::new(std::sync::Mutex::new(HashMap::new()));
+        let counter = announced.clone();
+        let dropped = std::sync::atomic::AtomicBool::new(false);
+        // Extra passes go through staging like the first one
+        let staging = tempfile::tempdir_in(destination.path()).unwrap();
+        let mut handler = crate::testing::client_handler(Config {
+            root: destination.path().to_path_buf(),
+            staging_dir: Some(staging.path().to_path_buf()),
+            commit_on_done: true,
+            ..Default::default()
+        })
+        .with_message_hook(std::sync::Arc::new(move |message: &Message| {
+            let mut counter = counter.lock().unwrap();
+            match message {
+                Message::File { filename, .. } => {
+                    *counter.entry(filename.clone()).or_insert(0) += 1;
+                }
+                // The first pass loses the start of the file, which a later pass fills
+                Message::FileChunk {
+                    offset: 0,
+                    content_size,
+                    ..
+                } if *content_size > 0
+                    && !dropped.swap(true, std::sync::atomic::Ordering::Relaxed) =>
+                {
+                    return crate::connection::MessageAction::Drop;
+                }
+                _ => {}
+            }
+            crate::connection::MessageAction::Accept
+        }));
+        crate::testing::deliver(&mut handler, client.socket().take_datagrams()).await;
+
+        let announced = announced.lock().unwrap();
+        assert_eq!(announced.get("state.db"), Some(&3));
+        assert_eq!(announced.get("notes.txt"), Some(&1));
+        // Later passes only fill in what the first one lost
+        assert!(handler.stats().duplicate_chunks > 0);
+        assert!(handler.stats().incomplete_files.is_empty());
+        assert_eq!(
+            std::fs::read(destination.path().join("state.db")).unwrap(),
+            content
+        );
+        assert_eq!(
+            std::fs::read(destination.path().join("notes.txt")).unwrap(),
+            b"not critical"
+        );
+    }
+
+    /// Reader returning an empty read once, in the middle of its content
+    struct HiccupReader {
+        content: std::io::Cursor<Vec<u8>>,
+        hiccup_at: u64,
+        hiccuped: bool,
+    }
+
+    impl AsyncRead for HiccupReader {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            if !self.hiccuped && self.content.position() >= self.hiccup_at {
+                self.hiccuped = true;
+                return std::task::Poll::Ready(Ok(()));
+            }
+            std::pin::Pin::new(&mut self.content).poll_read(cx, buf)
+        }
+    }
+
+    #[tokio::test]
+    async fn empty_read_does_not_end_file() {
+        use crate::testing::{client_handler, deliver, file_message};
+
+        let destination = tempfile::tempdir().unwrap();
+        let content: Vec<u8> = (0..5000u32).map(|i| (i % 251) as u8).collect();
+        let reader = HiccupReader {
+            content: std::io::Cursor::new(content.clone()),
+            hiccup_at: 2000,
+            hiccuped: false,
+        };
+
+        let mut client = Client::new(MemoryTransport::new());
+        client
+            .send_message(&file_message(1, "file", content.len() as u64))
+            .await
+            .unwrap();
+        client
+            .send_content(
+                Path::new("file"),
+                reader,
+                content.len() as u64,
+                1,
+                true,
+                &mut |_| {},
+            )
+            .await
+            .unwrap();
+
+        let mut handler = client_handler(Config {
+            root: destination.path().to_path_buf(),
+            ..Default::default()
+        });
+        deliver(&mut handler, client.socket().take_datagrams()).await;
+
+        let received = std::fs::read(destination.path().join("file")).unwrap();
+        assert!(received == content, "File was truncated");
+    }
+}
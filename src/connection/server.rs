@@ -1,32 +1,94 @@
-use std::collections::HashMap;
-use std::io::SeekFrom;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::future::Future;
+use std::io;
 use std::net::SocketAddr;
-use std::path::PathBuf;
-use std::sync::Arc;
-use std::time::SystemTime;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
-use crate::config::Config;
-use crate::messages::Message;
+use crate::config::{Config, DuplicateFilePolicy, HashAlgorithm};
+use crate::connection::sink::{FsSink, Sink};
+use crate::messages::{ManifestEntry, Message, ProtocolVersion, SpecialKind};
 use crate::retransmit::Reassembler;
-use crate::udp::UdpReader;
-use crate::{Error, Result, Wire};
+use crate::tree::TreeDiff;
+use crate::udp::{AsyncPacketSource, UdpReader};
+use crate::utils::fs::FileHasher;
+use crate::{Error, Result};
 
-use tokio::fs::File;
-use tokio::io::{AsyncSeekExt, AsyncWriteExt};
 use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use tracing::Instrument;
 
-pub struct Server {
-    socket: UdpReader,
+/// Consecutive failures to create or write files on a full or read-only filesystem after which a
+/// handler gives up
+const MAX_STORAGE_FAILURES: u32 = 5;
+
+/// Appended to the name of a file while it is received, so that it appears once complete
+const TEMPORARY_SUFFIX: &str = ".oneway-tmp";
+
+/// Bytes of chunks received after a gap kept in memory for each file, beyond which they are
+/// written where they belong without waiting for the gap to be filled
+const MAX_BYTES_AHEAD: usize = 4 * 1024 * 1024;
+
+/// What the server does with a message, decided by a `MessageHook`
+#[derive(Debug, PartialEq, Eq)]
+pub enum MessageAction {
+    /// Processes the message as received
+    Accept,
+
+    /// Ignores the message
+    Drop,
+
+    /// Processes the given message instead
+    Rewrite(Message),
+}
+
+/// Consulted for every message before it is processed, see `Server::with_message_hook`
+pub type MessageHook = Arc<dyn Fn(&Message) -> MessageAction + Send + Sync>;
+
+/// Latest stats of the clients of a server, published by their handlers, see `Server::stats`
+pub type SharedStats = Arc<Mutex<HashMap<SocketAddr, ClientStats>>>;
+
+/// Interval at which handlers publish their stats to the server while receiving
+const STATS_PUBLISH_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct Server<S: Sink = FsSink, R: AsyncPacketSource = UdpReader> {
+    socket: R,
     config: Arc<Config>,
     root: PathBuf,
     handlers: HashMap<SocketAddr, mpsc::Sender<Vec<u8>>>,
+
+    /// Tasks running the handlers, awaited on shutdown
+    tasks: JoinSet<()>,
     kill_tx: mpsc::Sender<SocketAddr>,
     kill_rx: mpsc::Receiver<SocketAddr>,
+    message_hook: Option<MessageHook>,
+
+    /// Where the handlers store the content of the files
+    sink: Arc<S>,
+
+    /// Datagrams dropped because the handler of their client was busy, by client
+    dropped_datagrams: HashMap<SocketAddr, u64>,
+
+    /// Stats of every client, including the ones whose handler is done
+    stats: SharedStats,
+}
+
+impl<R: AsyncPacketSource> Server<FsSink, R> {
+    /// Builds a server receiving with `config`, which is validated first
+    pub fn new_with_config(socket: R, config: Config) -> Result<Self> {
+        let sink = FsSink::new(&config);
+        Self::with_sink(socket, config, sink)
+    }
 }
 
-impl Server {
-    pub fn new_with_config(socket: UdpReader, config: Config) -> Self {
-        tracing::trace!("Server::new_with_config");
+impl<S: Sink, R: AsyncPacketSource> Server<S, R> {
+    /// Builds a server storing the content of the files in `sink`
+    pub fn with_sink(socket: R, config: Config, sink: S) -> Result<Self> {
+        tracing::trace!("Server::with_sink");
+        config.validate()?;
         let config_root = PathBuf::from(&config.root);
         let root = if config_root.is_absolute() {
             config_root
@@ -37,303 +99,3956 @@ impl Server {
 
         let (kill_tx, kill_rx) = mpsc::channel(config.channel_size);
 
-        Self {
+        Ok(Self {
             socket,
             config: Arc::new(config),
             root,
             handlers: HashMap::new(),
+            tasks: JoinSet::new(),
             kill_tx,
             kill_rx,
-        }
+            message_hook: None,
+            sink: Arc::new(sink),
+            dropped_datagrams: HashMap::new(),
+            stats: SharedStats::default(),
+        })
+    }
+
+    /// Passes every message through `hook` before processing it, to filter or rewrite them
+    pub fn with_message_hook(
+        mut self,
+        hook: impl Fn(&Message) -> MessageAction + Send + Sync + 'static,
+    ) -> Self {
+        self.message_hook = Some(Arc::new(hook));
+        self
+    }
+
+    /// Snapshot of the stats of every client, as last published by their handler
+    ///
+    /// Handlers publish their stats every `STATS_PUBLISH_INTERVAL` while receiving, and when done.
+    pub fn stats(&self) -> HashMap<SocketAddr, ClientStats> {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// Datagrams dropped because the handler of their client was busy, by client
+    pub fn dropped_datagrams(&self) -> &HashMap<SocketAddr, u64> {
+        &self.dropped_datagrams
     }
 
     pub async fn recv_message(&mut self) -> Result<()> {
         let mut buffer = vec![0u8; self.config.mtu];
         let (size, client_addr) = self.socket.recv_from(&mut buffer[..]).await?;
+        buffer.truncate(size);
+
+        // A handler created again for the same client since it notified its end is kept
+        while let Ok(addr) = self.kill_rx.try_recv() {
+            if self.handlers.get(&addr).is_some_and(|s| s.is_closed()) {
+                tracing::info!("Removing handler for {}", &addr);
+                self.handlers.remove(&addr);
+            }
+        }
+        self.dispatch(client_addr, buffer).await;
+
+        // Reap the tasks of handlers which are done
+        while self.tasks.try_join_next().is_some() {}
+
+        Ok(())
+    }
+
+    /// Passes `buffer` to the handler of `client_addr`, created if needed
+    ///
+    /// A handler which ended, idle, since its last datagram is replaced by a new one.
+    async fn dispatch(&mut self, client_addr: SocketAddr, mut buffer: Vec<u8>) {
+        for _ in 0..2 {
+            match self.try_dispatch(client_addr, buffer).await {
+                Ok(()) => return,
+                Err(mpsc::error::SendError(returned)) => {
+                    tracing::debug!("Handler of {} is gone", &client_addr);
+                    self.handlers.remove(&client_addr);
+                    buffer = returned;
+                }
+            }
+        }
+        tracing::warn!("No handler for {}, dropping datagram", &client_addr);
+    }
 
+    /// Passes `buffer` to the handler of `client_addr`, created if needed, returns it if that
+    /// handler is gone
+    async fn try_dispatch(
+        &mut self,
+        client_addr: SocketAddr,
+        buffer: Vec<u8>,
+    ) -> std::result::Result<(), mpsc::error::SendError<Vec<u8>>> {
         let sender = self.handlers.entry(client_addr).or_insert_with(|| {
             tracing::info!("Creating new handler for {}", &client_addr);
             let (sender, receiver) = mpsc::channel(self.config.channel_size);
 
-            let kill_tx = self.kill_tx.clone();
-            let mut handler = ClientHandler {
-                keep_alive: None,
-                root: self.root.clone(),
-                client_addr: client_addr.clone(),
-                reassembler: Reassembler::new(&self.config),
-                data: Vec::new(),
-                opened_files: HashMap::new(),
+            let mut handler = ClientHandler::with_sink(
+                client_addr,
+                self.root.clone(),
+                Arc::clone(&self.config),
                 receiver,
-                kill_tx,
-                config: Arc::clone(&self.config),
-            };
+                self.kill_tx.clone(),
+                Arc::clone(&self.sink),
+            );
+            if let Some(ref hook) = self.message_hook {
+                handler = handler.with_message_hook(Arc::clone(hook));
+            }
+            handler = handler.with_shared_stats(Arc::clone(&self.stats));
+            self.tasks.spawn(handler.run());
 
-            tokio::spawn(async move {
-                while let Some(buf) = handler.receiver.recv().await {
-                    let done = handler.process_buffer(&buf[..]).await;
-                    if done {
-                        break;
-                    }
-                }
+            sender
+        });
 
-                if let Err(e) = handler.kill_tx.send(handler.client_addr).await {
-                    tracing::error!(
-                        "[{}] Could not notify server of my end: {}",
-                        handler.client_addr,
-                        e
-                    );
-                } else {
-                    tracing::info!("[{}] Handler done", handler.client_addr);
+        // A handler slower than its client fills its channel: waiting for it stalls every client
+        // of the server, dropping the datagram only loses it like the network could
+        if self.config.drop_when_busy {
+            match sender.try_send(buffer) {
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    tracing::debug!("Handler of {} is busy, dropping datagram", &client_addr);
+                    *self.dropped_datagrams.entry(client_addr).or_default() += 1;
+                    Ok(())
                 }
-            });
+                Err(mpsc::error::TrySendError::Closed(buffer)) => {
+                    Err(mpsc::error::SendError(buffer))
+                }
+                Ok(()) => Ok(()),
+            }
+        } else {
+            sender.send(buffer).await
+        }
+    }
 
-            sender
-        });
+    pub async fn serve_forever(&mut self) -> Result<()> {
+        self.serve_until(std::future::pending()).await
+    }
 
-        buffer.truncate(size);
-        if let Err(e) = sender.send(buffer).await {
-            tracing::warn!("Handler is gone for {}: {}", &client_addr, e);
-            self.handlers.remove(&client_addr);
+    /// Serves clients until `shutdown` completes, then waits for the handlers to close their files
+    pub async fn serve_until(&mut self, shutdown: impl Future<Output = ()>) -> Result<()> {
+        tokio::pin!(shutdown);
+
+        loop {
+            tokio::select! {
+                result = self.recv_message() => result?,
+                () = &mut shutdown => break,
+            }
         }
 
-        if let Ok(addr) = self.kill_rx.try_recv() {
-            tracing::info!("Removing handler for {}", &addr);
-            self.handlers.remove(&addr);
+        tracing::info!("Shutting down, waiting for {} handlers", self.tasks.len());
+        // Handlers finalize their files once their channel is closed
+        self.handlers.clear();
+        while !self.tasks.is_empty() {
+            tokio::select! {
+                Some(result) = self.tasks.join_next() => {
+                    if let Err(e) = result {
+                        tracing::error!("Handler failed: {}", e);
+                    }
+                }
+                // Handlers notify their end before returning
+                Some(_) = self.kill_rx.recv() => {}
+            }
         }
 
         Ok(())
     }
+}
 
-    pub async fn serve_forever(&mut self) -> Result<()> {
-        loop {
-            self.recv_message().await?;
+/// Files of a session compared with the number announced by the client, see
+/// `ClientHandler::transfer_summary`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransferSummary {
+    /// Files announced with `CountFilesToUpload`
+    pub expected: Option<u64>,
+
+    /// Files created on `File`
+    pub created: u64,
+
+    /// Files completely received
+    pub completed: u64,
+
+    /// Ids of the files created but never completed, in order
+    pub missing: Vec<u64>,
+}
+
+/// Counters about a client session
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ClientStats {
+    /// Session id announced by the client in its `Hello`
+    pub session_id: Option<String>,
+
+    /// Files refused because free space was below `min_free_space`
+    pub files_rejected_low_space: u64,
+
+    /// Files refused because they were larger than `max_file_size`
+    pub files_rejected_too_large: u64,
+
+    /// Files refused because their extension is not in `allowed_extensions`
+    pub files_rejected_extension: u64,
+
+    /// Writes issued to received files, contiguous chunks being combined up to `write_buffer_size`
+    pub disk_writes: u64,
+
+    /// Chunks of file content received, with their bytes, whether they were written or not
+    pub chunks_received: u64,
+    pub bytes_received: u64,
+
+    /// Chunks dropped because every byte of them was already written
+    pub duplicate_chunks: u64,
+
+    /// Files completely received
+    pub files_completed: u64,
+
+    /// Keep alives skipped by their ids, in total and at most in a row, a sample of the losses
+    pub total_keep_alive_gaps: u64,
+    pub max_keep_alive_gap: u64,
+
+    /// Files announced by the client with `CountFilesToUpload`
+    pub files_announced: Option<u64>,
+
+    /// Digests of the files received, by id, when `receiver_hash` is set
+    pub file_digests: HashMap<u64, Vec<u8>>,
+
+    /// Differences between `root` and the manifest sent by the client, checked once done
+    pub tree_diff: Option<TreeDiff>,
+
+    /// Bytes missing from the files closed before being complete, by id
+    pub incomplete_files: HashMap<u64, u64>,
+
+    /// Bytes missing from the ones sent so far according to the last `Progress`, by file id
+    pub progress_deficits: HashMap<u64, u64>,
+}
+
+/// Whether `error` comes from an operation the `Sink` does not support
+fn is_unsupported(error: &Error) -> bool {
+    matches!(error, Error::IO(e) if e.kind() == io::ErrorKind::Unsupported)
+}
+
+/// How a client appears in logs: its address and, once known, its session id
+#[derive(Debug, Clone)]
+struct Peer {
+    addr: SocketAddr,
+    session_id: Option<Arc<str>>,
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.session_id {
+            Some(ref session_id) => write!(f, "{} {}", self.addr, session_id),
+            None => write!(f, "{}", self.addr),
         }
     }
 }
 
-pub struct ClientHandler {
-    keep_alive: Option<u64>,
-    client_addr: SocketAddr,
-    receiver: mpsc::Receiver<Vec<u8>>,
-    kill_tx: mpsc::Sender<SocketAddr>,
-    reassembler: Reassembler,
-    data: Vec<u8>,
-    root: PathBuf,
-    opened_files: HashMap<u64, (File, u64)>,
-    config: Arc<Config>,
+/// Decompresses the chunks of a file sent compressed
+///
+/// Offsets of the chunks are positions in the compressed stream, which must be decoded in order:
+/// chunks arriving early are kept until the ones before them arrive.
+#[cfg(feature = "compression")]
+struct Decompressor {
+    decoder: zstd::stream::write::Decoder<'static, Vec<u8>>,
+
+    /// Position in the compressed stream of the next chunk to decode
+    offset: u64,
+
+    /// Chunks received ahead of `offset`
+    ahead: std::collections::BTreeMap<u64, Vec<u8>>,
 }
 
-impl ClientHandler {
-    pub fn client_addr(&self) -> &SocketAddr {
-        &self.client_addr
+#[cfg(feature = "compression")]
+impl Decompressor {
+    fn new() -> std::io::Result<Self> {
+        Ok(Self {
+            decoder: zstd::stream::write::Decoder::new(Vec::new())?,
+            offset: 0,
+            ahead: std::collections::BTreeMap::new(),
+        })
     }
 
-    async fn process_message_hello(&mut self) {
-        tracing::info!("[{}] Received hello from client", self.client_addr());
-    }
+    /// Feeds the chunk at `offset` of the compressed stream, returns the content decoded so far
+    fn feed(&mut self, offset: u64, chunk: &[u8]) -> std::io::Result<Vec<u8>> {
+        use std::io::Write;
 
-    async fn process_message_keep_alive(&mut self, id: u64) {
-        if let Some(ref mut prev_id) = self.keep_alive {
-            let expected_id = prev_id.wrapping_add(1);
-            if id != expected_id {
-                tracing::warn!(
-                    "[{}] Got bad keep alive id, expected: {}, got: {}",
-                    self.client_addr,
-                    expected_id,
-                    id
-                );
-            }
-            *prev_id = id;
-        } else {
-            self.keep_alive = Some(id);
+        if offset >= self.offset {
+            self.ahead.insert(offset, chunk.to_vec());
         }
+        while let Some(chunk) = self.ahead.remove(&self.offset) {
+            self.decoder.write_all(&chunk[..])?;
+            self.offset += chunk.len() as u64;
+        }
+
+        Ok(std::mem::take(self.decoder.get_mut()))
     }
 
-    async fn process_message_count_files_to_upload(&self, count: u64) {
-        tracing::info!(
-            "[{}] Will received {} files from client",
-            self.client_addr(),
-            count
-        );
+    /// Returns the content left in the decoder at the end of the file
+    fn finish(&mut self) -> std::io::Result<Vec<u8>> {
+        use std::io::Write;
+
+        if !self.ahead.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("compressed stream is missing data at {}", self.offset),
+            ));
+        }
+        self.decoder.flush()?;
+        Ok(std::mem::take(self.decoder.get_mut()))
     }
+}
 
-    async fn process_message_file(
-        &mut self,
-        filename: String,
-        _created: SystemTime,
-        size: u64,
-        id: u64,
-    ) {
-        let client_addr = self.client_addr().clone();
-        let real_filename = self.root.join(&filename);
-        if !real_filename.starts_with(&self.root) {
-            tracing::warn!("File {} not in {}, ignoring", filename, self.root.display());
+/// Ranges of a file written so far
+#[derive(Debug, Default)]
+struct ReceivedRanges {
+    /// Bytes written from the start of the file without a gap
+    contiguous: u64,
+
+    /// Ends of the ranges written after a gap, by start
+    ahead: BTreeMap<u64, u64>,
+}
+
+impl ReceivedRanges {
+    fn insert(&mut self, start: u64, end: u64) {
+        if start > self.contiguous {
+            let known_end = self.ahead.entry(start).or_insert(end);
+            *known_end = end.max(*known_end);
             return;
         }
 
-        match crate::utils::fs::create_file(&real_filename, size).await {
-            Ok(f) => {
-                tracing::info!(
-                    "[{}] Created file {} of {} bytes (id: 0x{:x})",
-                    client_addr,
-                    real_filename.display(),
-                    size,
-                    id
-                );
-                self.opened_files.insert(id, (f, 0));
-            }
-            Err(e) => {
-                tracing::error!(
-                    "[{}] Could not create file {}: {}",
-                    client_addr,
-                    real_filename.display(),
-                    e
-                );
+        self.contiguous = self.contiguous.max(end);
+        while let Some(entry) = self.ahead.first_entry() {
+            if *entry.key() > self.contiguous {
+                break;
             }
+            self.contiguous = self.contiguous.max(entry.remove());
         }
     }
 
-    async fn process_message_file_chunk(
-        &mut self,
-        id: u64,
-        offset: u64,
-        content_size: u16,
-        content: Vec<u8>,
-    ) {
-        async fn write_chunk_to_file(
-            file: &mut File,
-            file_offset: &mut u64,
-            offset: u64,
-            content: &[u8],
-        ) -> Result<()> {
-            if content.iter().all(|x| *x == 0) {
-                tracing::warn!("Got all zero chunk at {}", offset);
-            }
-
-            if *file_offset != offset {
-                if *file_offset > offset {
-                    tracing::warn!(
-                        "Must have missed a chunk. Expected {}, got {} ({} bytes behind)",
-                        *file_offset,
-                        offset,
-                        *file_offset - offset
-                    );
-                } else {
-                    tracing::warn!(
-                        "Must have missed a chunk. Expected {}, got {} ({} bytes ahead)",
-                        *file_offset,
-                        offset,
-                        offset - *file_offset
-                    );
-                }
-                *file_offset = file.seek(SeekFrom::Start(offset)).await?;
+    /// Bytes written before `end`
+    fn bytes_before(&self, end: u64) -> u64 {
+        let mut bytes = self.contiguous.min(end);
+        // Ranges may overlap, bytes before `covered` are already counted
+        let mut covered = self.contiguous;
+        for (&start, &range_end) in self.ahead.range(..end) {
+            let start = start.max(covered);
+            let range_end = range_end.min(end);
+            if range_end > start {
+                bytes += range_end - start;
+                covered = range_end;
             }
-            file.write_all(content).await?;
-            *file_offset += content.len() as u64;
-            // f.flush().await?;
-
-            Ok(())
         }
+        bytes
+    }
 
-        let client_addr = self.client_addr().clone();
+    /// Whether every byte from `start` to `end` was already written
+    fn contains(&self, start: u64, end: u64) -> bool {
+        end <= self.contiguous
+            || self
+                .ahead
+                .range(..=start)
+                .next_back()
+                .is_some_and(|(_, known_end)| *known_end >= end)
+    }
+}
 
-        // If content_size is 0, then the file has been sent
-        if content_size == 0 {
-            tracing::info!("[{}] Done receiving 0x{:x}", self.client_addr, id);
-            self.opened_files.remove(&id);
+/// A file being received
+struct OpenedFile<S: Sink> {
+    sink: Arc<S>,
+    file: S::Handle,
+
+    /// Position in `file` once `pending` is written
+    offset: u64,
+
+    /// Contiguous chunks not written yet, ending at `offset`
+    pending: Vec<u8>,
+
+    /// Bytes of `pending` above which they are written, depending on the size of the file
+    buffer_size: usize,
+
+    /// Chunks received after a gap, by offset, written once the gap is filled
+    ahead: BTreeMap<u64, Vec<u8>>,
+
+    /// Bytes of the chunks in `ahead`
+    bytes_ahead: usize,
+
+    /// Size announced by the client
+    size: u64,
+
+    /// Bytes of the file written so far
+    received: ReceivedRanges,
+
+    /// Whether only some ranges of the file are received, the rest being kept
+    partial: bool,
+
+    /// Digest of the content, as long as every chunk arrived in order
+    hasher: Option<FileHasher>,
+
+    /// Decoder of the content, when the file is sent compressed
+    #[cfg(feature = "compression")]
+    decompressor: Option<Decompressor>,
+
+    /// Where the file is written while being received
+    path: PathBuf,
+
+    /// Where the file is moved once complete
+    final_path: PathBuf,
+
+    /// ACL to apply once the file is complete
+    acl: Option<Vec<u8>>,
+
+    /// Modification time to apply once the file is complete
+    modified: SystemTime,
+
+    /// When the last chunk was received, `None` until the first one
+    ///
+    /// Clients announce their files before sending any content, the idle timer only starts once
+    /// the content does.
+    last_activity: Option<Instant>,
+}
+
+impl<S: Sink> OpenedFile<S> {
+    /// Bytes missing after the part received without a gap, 0 for partial updates
+    fn missing_bytes(&self) -> u64 {
+        if self.partial {
+            0
+        } else {
+            self.size.saturating_sub(self.received.contiguous)
+        }
+    }
+
+    /// Takes `end`, where the terminator of the file was sent, as its size if it is larger than
+    /// the one announced
+    ///
+    /// Streams of unknown size are announced smaller than they are, their chunks extending the
+    /// file as they arrive. Offsets of compressed files are not positions in the file.
+    fn extend_to(&mut self, end: u64) {
+        #[cfg(feature = "compression")]
+        if self.decompressor.is_some() {
             return;
         }
+        if !self.partial && end > self.size {
+            self.size = end;
+        }
+    }
 
-        let (f, file_offset) = match self.opened_files.get_mut(&id) {
-            Some(f) => f,
-            None => {
-                tracing::error!("[{}] File with id {} was not opened", client_addr, id);
-                return;
-            }
-        };
+    /// Writes combined chunks, returns the number of writes issued
+    async fn flush_pending(&mut self) -> Result<u64> {
+        if self.pending.is_empty() {
+            return Ok(0);
+        }
 
-        // Is content contiguous to out internal buffer?
-        let buffer = &content[..content_size as usize];
-        if let Err(e) = write_chunk_to_file(f, file_offset, offset, buffer).await {
-            tracing::error!(
-                "[{}] Could not write chunk at offset 0x{:x} to {:?}: {}",
-                client_addr,
-                offset,
-                id,
-                e
-            );
+        let start = self.offset - self.pending.len() as u64;
+        self.sink
+            .write_at(&mut self.file, start, &self.pending[..])
+            .await?;
+        self.pending.clear();
+        Ok(1)
+    }
+
+    /// Writes combined chunks and the ones kept after a gap, returns the number of writes issued
+    async fn flush(&mut self) -> Result<u64> {
+        let mut writes = self.write_ahead().await?;
+        writes += self.flush_pending().await?;
+        Ok(writes)
+    }
+
+    /// Bytes received before `end`, whether they were written or kept after a gap
+    ///
+    /// `None` for compressed files, whose chunks are only known once decoded.
+    fn bytes_received_before(&self, end: u64) -> Option<u64> {
+        #[cfg(feature = "compression")]
+        if self.decompressor.is_some() {
+            return None;
         }
+
+        let ahead: u64 = self
+            .ahead
+            .range(..end)
+            .map(|(offset, chunk)| (offset + chunk.len() as u64).min(end) - offset)
+            .sum();
+        Some(self.received.bytes_before(end) + ahead)
     }
 
-    async fn process_message_done(&mut self) {
-        tracing::info!("[{}] Received done from client", self.client_addr());
+    /// Whether `size` bytes at `offset` were already written, so that a chunk sent again can be
+    /// dropped
+    ///
+    /// Offsets of compressed files are positions in the compressed stream, which the decompressor
+    /// deduplicates itself.
+    fn already_received(&self, offset: u64, size: usize) -> bool {
+        #[cfg(feature = "compression")]
+        if self.decompressor.is_some() {
+            return false;
+        }
+        self.received.contains(offset, offset + size as u64)
     }
 
-    pub async fn process_message(&mut self, message: Message) -> bool {
-        match message {
-            Message::Hello => self.process_message_hello().await,
-            Message::KeepAlive(id) => self.process_message_keep_alive(id).await,
-            Message::CountFilesToUpload(count) => {
-                self.process_message_count_files_to_upload(count).await
+    /// Writes the chunk `content` at `offset`, returns the number of writes issued
+    ///
+    /// For compressed files, `offset` is a position in the compressed stream and the content is
+    /// written once decoded.
+    async fn write_chunk(&mut self, offset: u64, content: &[u8]) -> Result<u64> {
+        #[cfg(feature = "compression")]
+        if let Some(ref mut decompressor) = self.decompressor {
+            let decoded = decompressor.feed(offset, content)?;
+            if decoded.is_empty() {
+                return Ok(0);
             }
-            Message::File {
-                filename,
-                created,
-                size,
-                id,
-            } => self.process_message_file(filename, created, size, id).await,
-            Message::FileChunk {
-                id,
+            return self.write_content(self.offset, &decoded[..]).await;
+        }
+
+        self.write_content(offset, content).await
+    }
+
+    /// Decodes what is left of a compressed file, returns the number of writes issued
+    #[cfg(feature = "compression")]
+    async fn finish_decompression(&mut self) -> Result<u64> {
+        let Some(mut decompressor) = self.decompressor.take() else {
+            return Ok(0);
+        };
+
+        let decoded = decompressor.finish()?;
+        if decoded.is_empty() {
+            return Ok(0);
+        }
+        self.write_content(self.offset, &decoded[..]).await
+    }
+
+    /// Writes `content` at `offset`, returns the number of writes issued
+    ///
+    /// Chunks after a gap are kept until the gap is filled, so that the file is written in order,
+    /// unless more than `MAX_BYTES_AHEAD` are kept.
+    async fn write_content(&mut self, offset: u64, content: &[u8]) -> Result<u64> {
+        if content.iter().all(|x| *x == 0) {
+            tracing::warn!("Got all zero chunk at {}", offset);
+        }
+
+        if !self.partial && offset > self.offset {
+            tracing::debug!(
+                "Keeping chunk at {} until the {} bytes before it arrive",
                 offset,
-                content_size,
-                content,
-            } => {
-                self.process_message_file_chunk(id, offset, content_size, content)
-                    .await
+                offset - self.offset
+            );
+            if let Some(previous) = self.ahead.insert(offset, content.to_vec()) {
+                self.bytes_ahead -= previous.len();
             }
-            Message::Done => {
-                self.process_message_done().await;
-                return true;
+            self.bytes_ahead += content.len();
+            if self.bytes_ahead > MAX_BYTES_AHEAD {
+                return self.write_ahead().await;
+            }
+            return Ok(0);
+        }
+
+        let mut writes = self.write_at(offset, content).await?;
+        while let Some(entry) = self.ahead.first_entry() {
+            if *entry.key() > self.offset {
+                break;
+            }
+            let (offset, chunk) = entry.remove_entry();
+            self.bytes_ahead -= chunk.len();
+            // Already written by the chunks before it
+            if offset + chunk.len() as u64 <= self.offset {
+                continue;
             }
+            writes += self.write_at(offset, &chunk[..]).await?;
         }
 
-        return false;
+        Ok(writes)
     }
 
-    async fn process_buffer_internal(&mut self, buffer: &[u8]) -> Result<bool> {
-        self.reassembler.push_data(buffer);
+    /// Writes the chunks kept after a gap where they belong, returns the number of writes issued
+    async fn write_ahead(&mut self) -> Result<u64> {
+        let mut writes = 0;
+        for (offset, chunk) in std::mem::take(&mut self.ahead) {
+            writes += self.write_at(offset, &chunk[..]).await?;
+        }
+        self.bytes_ahead = 0;
 
-        match self.reassembler.get_next_data(&mut self.data) {
-            Ok(()) => {
-                let (rest, message) = Message::from_wire(&self.data[..])?;
-                if !rest.is_empty() {
+        Ok(writes)
+    }
+
+    /// Writes `content` at `offset` right away, returns the number of writes issued
+    ///
+    /// Contiguous chunks are combined until `buffer_size` bytes are pending, a chunk at any other
+    /// offset first flushes them.
+    async fn write_at(&mut self, offset: u64, content: &[u8]) -> Result<u64> {
+        let mut writes = 0;
+
+        if self.offset != offset {
+            if self.partial {
+                tracing::debug!("Updating range at {}", offset);
+            } else if self.offset > offset {
+                tracing::warn!(
+                    "Must have missed a chunk. Expected {}, got {} ({} bytes behind)",
+                    self.offset,
+                    offset,
+                    self.offset - offset
+                );
+            } else {
+                tracing::warn!(
+                    "Must have missed a chunk. Expected {}, got {} ({} bytes ahead)",
+                    self.offset,
+                    offset,
+                    offset - self.offset
+                );
+            }
+            writes += self.flush_pending().await?;
+            self.offset = offset;
+            // The digest will be computed from the file once complete
+            self.hasher = None;
+        }
+
+        if let Some(ref mut hasher) = self.hasher {
+            hasher.update(content);
+        }
+        self.received.insert(offset, offset + content.len() as u64);
+        self.pending.extend_from_slice(content);
+        self.offset += content.len() as u64;
+        if self.pending.len() >= self.buffer_size {
+            writes += self.flush_pending().await?;
+        }
+
+        Ok(writes)
+    }
+}
+
+pub struct ClientHandler<S: Sink = FsSink> {
+    keep_alive: Option<u64>,
+    peer: Peer,
+
+    /// Span of the work of the handler, messages about a file are processed in a child span
+    span: tracing::Span,
+
+    /// Whether a `Hello` was received
+    hello_received: bool,
+
+    /// Messages dropped while waiting for `Hello` when `require_hello` is set
+    dropped_before_hello: u64,
+
+    /// Wire format of the client, as announced in its `Hello`, guessed from each message until
+    /// then
+    protocol_version: ProtocolVersion,
+    client_addr: SocketAddr,
+    receiver: mpsc::Receiver<Vec<u8>>,
+    kill_tx: mpsc::Sender<SocketAddr>,
+    reassembler: Reassembler,
+    data: Vec<u8>,
+    root: PathBuf,
+
+    /// Where files are written until `Done` when `commit_on_done` is set
+    staging: Option<PathBuf>,
+    opened_files: HashMap<u64, OpenedFile<S>>,
+    completed_files: HashMap<u64, PathBuf>,
+
+    /// Size of each completed file, to recognize it when it is sent again
+    completed_sizes: HashMap<u64, u64>,
+
+    /// Ids of the files created on `File`
+    created_files: HashSet<u64>,
+
+    /// Ids of the files refused on `File`, whose chunks are dropped
+    rejected_files: HashSet<u64>,
+
+    /// Files whose content does not match the digest sent by the client
+    corrupted_files: HashSet<u64>,
+
+    /// Entries of the manifest received so far, and the number of entries announced
+    manifest: Vec<ManifestEntry>,
+    manifest_total: Option<u64>,
+
+    /// Failures to create or write files because the filesystem is full or read-only, since the
+    /// last file successfully flushed
+    storage_failures: u32,
+    stats: ClientStats,
+
+    /// Where `stats` is published for `Server::stats`, and when it last was
+    shared_stats: Option<SharedStats>,
+    stats_published: Instant,
+    config: Arc<Config>,
+    message_hook: Option<MessageHook>,
+
+    /// Where the content of the files is stored
+    sink: Arc<S>,
+}
+
+impl ClientHandler {
+    pub fn new(
+        client_addr: SocketAddr,
+        root: PathBuf,
+        config: Arc<Config>,
+        receiver: mpsc::Receiver<Vec<u8>>,
+        kill_tx: mpsc::Sender<SocketAddr>,
+    ) -> Self {
+        let sink = Arc::new(FsSink::new(&config));
+        Self::with_sink(client_addr, root, config, receiver, kill_tx, sink)
+    }
+}
+
+impl<S: Sink> ClientHandler<S> {
+    /// Builds a handler storing the content of the files in `sink`
+    pub fn with_sink(
+        client_addr: SocketAddr,
+        root: PathBuf,
+        config: Arc<Config>,
+        receiver: mpsc::Receiver<Vec<u8>>,
+        kill_tx: mpsc::Sender<SocketAddr>,
+        sink: Arc<S>,
+    ) -> Self {
+        let staging = config.commit_on_done.then(|| {
+            let staging_dir = match config.staging_dir {
+                Some(ref staging_dir) => root.join(staging_dir),
+                None => root.join(".oneway-staging"),
+            };
+            // One directory per client so that concurrent transfers do not mix
+            staging_dir.join(client_addr.to_string().replace([':', '[', ']'], "_"))
+        });
+
+        Self {
+            keep_alive: None,
+            peer: Peer {
+                addr: client_addr,
+                session_id: None,
+            },
+            span: tracing::info_span!("client", addr = %client_addr),
+            hello_received: false,
+            dropped_before_hello: 0,
+            protocol_version: ProtocolVersion::CURRENT,
+            client_addr,
+            receiver,
+            kill_tx,
+            reassembler: Reassembler::new(&config),
+            data: Vec::new(),
+            root,
+            staging,
+            opened_files: HashMap::new(),
+            completed_files: HashMap::new(),
+            completed_sizes: HashMap::new(),
+            created_files: HashSet::new(),
+            rejected_files: HashSet::new(),
+            corrupted_files: HashSet::new(),
+            manifest: Vec::new(),
+            manifest_total: None,
+            storage_failures: 0,
+            stats: ClientStats::default(),
+            shared_stats: None,
+            stats_published: Instant::now(),
+            config,
+            message_hook: None,
+            sink,
+        }
+    }
+
+    /// Passes every message through `hook` before processing it
+    pub fn with_message_hook(mut self, hook: MessageHook) -> Self {
+        self.message_hook = Some(hook);
+        self
+    }
+
+    /// Publishes `stats` to `shared_stats` while receiving and once done
+    pub fn with_shared_stats(mut self, shared_stats: SharedStats) -> Self {
+        self.shared_stats = Some(shared_stats);
+        self
+    }
+
+    /// Copies `stats` to `shared_stats`, if any
+    fn publish_stats(&mut self) {
+        if let Some(ref shared_stats) = self.shared_stats {
+            shared_stats
+                .lock()
+                .unwrap()
+                .insert(self.client_addr, self.stats.clone());
+        }
+        self.stats_published = Instant::now();
+    }
+
+    pub fn client_addr(&self) -> &SocketAddr {
+        &self.client_addr
+    }
+
+    pub fn stats(&self) -> &ClientStats {
+        &self.stats
+    }
+
+    /// Whether free space on the receiving filesystem is above `min_free_space`
+    fn has_free_space(&self) -> bool {
+        if self.config.min_free_space == 0 {
+            return true;
+        }
+
+        match crate::utils::fs::available_space(&self.root) {
+            Ok(available) if available < self.config.min_free_space => {
+                tracing::warn!(
+                    "[{}] Only {} bytes available on {} (min_free_space = {})",
+                    self.peer,
+                    available,
+                    self.root.display(),
+                    self.config.min_free_space
+                );
+                false
+            }
+            Ok(_) => true,
+            Err(e) => {
+                tracing::warn!(
+                    "[{}] Could not get free space of {}: {}",
+                    self.peer,
+                    self.root.display(),
+                    e
+                );
+                true
+            }
+        }
+    }
+
+    /// Processes incoming buffers until the client is done or gone
+    pub async fn run(self) {
+        let span = self.span.clone();
+        self.run_in_span().instrument(span).await
+    }
+
+    async fn run_in_span(mut self) {
+        let mut last_received = Instant::now();
+        let mut released = false;
+        loop {
+            let recv_timeout = self.config.recv_timeout;
+            // Buffers are released once idle for `file_idle_timeout`, before giving up on the client
+            let deadline = if released {
+                last_received + recv_timeout
+            } else {
+                last_received + self.config.file_idle_timeout.min(recv_timeout)
+            };
+            match tokio::time::timeout_at(deadline, self.receiver.recv()).await {
+                Ok(Some(buf)) => {
+                    last_received = Instant::now();
+                    released = false;
+                    let done = self.process_buffer(&buf[..]).await;
+                    if done {
+                        break;
+                    }
+                }
+                Ok(None) => {
+                    tracing::info!("[{}] Server is shutting down", self.peer);
+                    self.close().await;
+                    break;
+                }
+                Err(_) if !released && last_received.elapsed() < recv_timeout => {
+                    tracing::debug!(
+                        "[{}] Idle for {:?}, releasing buffers",
+                        self.peer,
+                        last_received.elapsed()
+                    );
+                    self.reassembler.release();
+                    released = true;
+                }
+                Err(_) => {
+                    tracing::info!(
+                        "[{}] Nothing received for {:?}, closing the connection",
+                        self.peer,
+                        recv_timeout
+                    );
+                    self.close().await;
+                    break;
+                }
+            }
+            self.close_idle_files().await;
+            if self.stats_published.elapsed() >= STATS_PUBLISH_INTERVAL {
+                self.publish_stats();
+            }
+        }
+        self.publish_stats();
+
+        if let Err(e) = self.kill_tx.send(self.client_addr).await {
+            tracing::error!("[{}] Could not notify server of my end: {}", self.peer, e);
+        } else {
+            tracing::info!("[{}] Handler done", self.peer);
+        }
+    }
+
+    /// Counts failures caused by a full or read-only filesystem, from which later files will not
+    /// recover either
+    ///
+    /// A successful write proves nothing, as the error of a write is only reported by the next
+    /// operation on the file, so the count is only reset once a file is flushed.
+    fn record_storage_failure<T>(&mut self, result: &Result<T>) {
+        if let Err(Error::IO(e)) = result {
+            if matches!(
+                e.kind(),
+                io::ErrorKind::ReadOnlyFilesystem | io::ErrorKind::StorageFull
+            ) {
+                self.storage_failures += 1;
+            }
+        }
+    }
+
+    /// Finalizes the files still being received when the client is abandoned
+    async fn close(&mut self) {
+        let ids: Vec<u64> = self.opened_files.keys().copied().collect();
+        for id in ids {
+            self.finalize_file(id).await;
+        }
+        self.report_losses();
+    }
+
+    async fn process_message_hello(&mut self, session_id: String, version: ProtocolVersion) {
+        if !session_id.is_empty() {
+            self.peer.session_id = Some(Arc::from(&session_id[..]));
+            self.stats.session_id = Some(session_id);
+        }
+        tracing::info!(
+            "[{}] Received hello from client (protocol {})",
+            self.peer,
+            version
+        );
+        if version < ProtocolVersion::CURRENT {
+            tracing::warn!(
+                "[{}] Client speaks protocol {}, older than {}",
+                self.peer,
+                version,
+                ProtocolVersion::CURRENT
+            );
+        }
+        self.protocol_version = version;
+        if self.dropped_before_hello != 0 {
+            tracing::warn!(
+                "[{}] Dropped {} messages received before hello",
+                self.peer,
+                self.dropped_before_hello
+            );
+            self.dropped_before_hello = 0;
+        }
+        self.hello_received = true;
+    }
+
+    async fn process_message_keep_alive(&mut self, id: u64) {
+        if let Some(ref mut prev_id) = self.keep_alive {
+            let expected_id = prev_id.wrapping_add(1);
+            if id != expected_id {
+                tracing::warn!(
+                    "[{}] Got bad keep alive id, expected: {}, got: {}",
+                    self.peer,
+                    expected_id,
+                    id
+                );
+                // Serial number arithmetic, so that ids may wrap around. Ids going backwards
+                // are reordered or from a restarted client, and tell nothing about losses
+                let gap = id.wrapping_sub(expected_id);
+                if gap <= u64::MAX / 2 {
+                    self.stats.total_keep_alive_gaps += gap;
+                    self.stats.max_keep_alive_gap = self.stats.max_keep_alive_gap.max(gap);
+                }
+            }
+            *prev_id = id;
+        } else {
+            self.keep_alive = Some(id);
+        }
+    }
+
+    async fn process_message_progress(&mut self, id: u64, bytes_sent: u64) {
+        let Some(opened_file) = self.opened_files.get(&id) else {
+            tracing::debug!(
+                "[{}] Progress of file 0x{:x} which is not open, ignoring",
+                self.peer,
+                id
+            );
+            return;
+        };
+
+        let Some(received) = opened_file.bytes_received_before(bytes_sent) else {
+            return;
+        };
+        let deficit = bytes_sent.saturating_sub(received);
+        if deficit > 0 {
+            tracing::warn!(
+                "[{}] Missing {} of the {} bytes of {} sent so far",
+                self.peer,
+                deficit,
+                bytes_sent,
+                opened_file.path.display()
+            );
+        }
+        self.stats.progress_deficits.insert(id, deficit);
+    }
+
+    async fn process_message_count_files_to_upload(&mut self, count: u64) {
+        tracing::info!("[{}] Will received {} files from client", self.peer, count);
+        self.stats.files_announced = Some(count);
+    }
+
+    /// Advice on the client configuration when too much was lost during the session
+    pub fn loss_report(&self) -> Option<String> {
+        /// Share of lost messages above which retransmission is deemed insufficient
+        const LOSS_THRESHOLD: f64 = 0.01;
+
+        let lost = self.reassembler.missed_messages();
+        let sent = lost + self.reassembler.received_messages();
+        let loss_rate = if sent == 0 {
+            0.0
+        } else {
+            lost as f64 / sent as f64
+        };
+        let received_files = self.completed_files.len() as u64;
+        let missing_files = self
+            .stats
+            .files_announced
+            .map_or(0, |announced| announced.saturating_sub(received_files));
+        let corrupted_files = self.corrupted_files.len();
+
+        if loss_rate <= LOSS_THRESHOLD && missing_files == 0 && corrupted_files == 0 {
+            return None;
+        }
+
+        Some(format!(
+            "{} of {} messages were lost ({:.1}%), {} files are missing and {} corrupted: \
+             increase remission_count or set fec_group_size on the client",
+            lost,
+            sent,
+            loss_rate * 100.0,
+            missing_files,
+            corrupted_files
+        ))
+    }
+
+    /// Files created and completed so far, against the number announced by the client
+    pub fn transfer_summary(&self) -> TransferSummary {
+        let mut missing: Vec<u64> = self
+            .created_files
+            .iter()
+            .filter(|id| !self.completed_files.contains_key(id))
+            .copied()
+            .collect();
+        missing.sort_unstable();
+
+        TransferSummary {
+            expected: self.stats.files_announced,
+            created: self.created_files.len() as u64,
+            completed: self.completed_files.len() as u64,
+            missing,
+        }
+    }
+
+    /// Logs `transfer_summary` at the end of a session
+    fn report_transfer(&self) {
+        let summary = self.transfer_summary();
+        let expected = summary
+            .expected
+            .map_or_else(|| String::from("unknown"), |n| n.to_string());
+        tracing::info!(
+            "[{}] Files expected: {}, created: {}, completed: {}",
+            self.peer,
+            expected,
+            summary.created,
+            summary.completed
+        );
+        if !summary.missing.is_empty() {
+            tracing::warn!(
+                "[{}] {} files were never completed: {:?}",
+                self.peer,
+                summary.missing.len(),
+                summary.missing
+            );
+        }
+    }
+
+    /// Logs `loss_report` at the end of a session
+    fn report_losses(&self) {
+        if let Some(report) = self.loss_report() {
+            tracing::warn!("[{}] {}", self.peer, report);
+        }
+    }
+
+    /// Maps a path sent by the client to where it must be stored, if it is inside `root`
+    ///
+    /// The path is normalized without touching the disk: absolute paths, drive letters and
+    /// parent components are refused rather than resolved.
+    fn resolve_path(&self, filename: &str) -> Option<PathBuf> {
+        use std::path::Component;
+
+        let mut real_filename = self.root.clone();
+        // `C:file` is a plain name to unix paths, but not to the windows client that sent it
+        let mut escapes =
+            matches!(filename.as_bytes(), [letter, b':', ..] if letter.is_ascii_alphabetic());
+        for component in Path::new(filename).components() {
+            match component {
+                Component::Normal(name) => real_filename.push(name),
+                Component::CurDir => {}
+                Component::ParentDir | Component::RootDir | Component::Prefix(_) => {
+                    escapes = true;
+                    break;
+                }
+            }
+        }
+
+        if !escapes && real_filename != self.root && real_filename.starts_with(&self.root) {
+            // A link replicated earlier would let the file be written wherever it points
+            if let Some(parent) = real_filename.parent() {
+                if let Some(link) = self.symlink_below_root(parent) {
+                    tracing::warn!(
+                        "[{}] {} is under symlink {}, ignoring",
+                        self.peer,
+                        filename,
+                        link.display()
+                    );
+                    return None;
+                }
+            }
+            Some(real_filename)
+        } else {
+            tracing::warn!(
+                "[{}] File {} not in {}, ignoring",
+                self.peer,
+                filename,
+                self.root.display()
+            );
+            None
+        }
+    }
+
+    /// First symlink on disk among `path` and its ancestors below `root`, if any
+    fn symlink_below_root<'p>(&self, path: &'p Path) -> Option<&'p Path> {
+        let mut ancestors: Vec<_> = path
+            .ancestors()
+            .take_while(|ancestor| *ancestor != self.root && ancestor.starts_with(&self.root))
+            .collect();
+        ancestors.reverse();
+        ancestors.into_iter().find(|ancestor| {
+            std::fs::symlink_metadata(ancestor).is_ok_and(|metadata| metadata.is_symlink())
+        })
+    }
+
+    /// Where a file whose final path is `real_filename` is written while being received
+    fn storage_path(&self, real_filename: &Path) -> PathBuf {
+        match (&self.staging, real_filename.strip_prefix(&self.root)) {
+            (Some(staging), Ok(relative)) => staging.join(relative),
+            _ => real_filename.to_path_buf(),
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn process_message_file(
+        &mut self,
+        filename: String,
+        _created: Option<SystemTime>,
+        modified: SystemTime,
+        size: u64,
+        id: u64,
+        mode: u32,
+        compressed: bool,
+        partial: bool,
+    ) {
+        let peer = self.peer.clone();
+        let Some(real_filename) = self.resolve_path(&filename) else {
+            return;
+        };
+        if let Some(max_file_size) = self.config.max_file_size.filter(|max| size > *max) {
+            tracing::warn!(
+                "[{}] {} has {} bytes, more than max_file_size ({}), rejecting it",
+                peer,
+                real_filename.display(),
+                size,
+                max_file_size
+            );
+            self.stats.files_rejected_too_large += 1;
+            self.rejected_files.insert(id);
+            return;
+        }
+        if !self.config.is_extension_allowed(&real_filename) {
+            tracing::warn!(
+                "[{}] {} does not have an allowed extension, rejecting it",
+                peer,
+                real_filename.display()
+            );
+            self.stats.files_rejected_extension += 1;
+            self.rejected_files.insert(id);
+            return;
+        }
+        if compressed && partial {
+            tracing::error!(
+                "[{}] {} is a compressed partial update, which cannot be applied, ignoring it",
+                peer,
+                real_filename.display()
+            );
+            return;
+        }
+
+        #[cfg(feature = "compression")]
+        let decompressor = if compressed {
+            match Decompressor::new() {
+                Ok(decompressor) => Some(decompressor),
+                Err(e) => {
+                    tracing::error!("[{}] Could not create decompressor: {}", peer, e);
+                    return;
+                }
+            }
+        } else {
+            None
+        };
+        #[cfg(not(feature = "compression"))]
+        if compressed {
+            tracing::error!(
+                "[{}] {} is compressed, which requires the `compression` feature, ignoring it",
+                peer,
+                real_filename.display()
+            );
+            return;
+        }
+        // Partial updates are applied in place, the rest of the file being at its final path
+        let real_filename = if partial {
+            real_filename
+        } else {
+            self.storage_path(&real_filename)
+        };
+        // Other files are written under a temporary name, renamed once complete
+        let final_path = real_filename.clone();
+        let real_filename = if partial {
+            real_filename
+        } else {
+            let mut temporary_path = real_filename.into_os_string();
+            temporary_path.push(TEMPORARY_SUFFIX);
+            PathBuf::from(temporary_path)
+        };
+        // Staged files are written under the staging directory, which may be out of `root`
+        let base = match self.staging {
+            Some(ref staging) if !partial => staging,
+            _ => &self.root,
+        };
+        if !real_filename.starts_with(base) {
+            tracing::warn!(
+                "[{}] {} not in {}, ignoring",
+                peer,
+                real_filename.display(),
+                base.display()
+            );
+            return;
+        }
+
+        // Critical files are sent again after the other files, by which time they may have been
+        // closed idle: created again, they would be lost if a chunk of that pass went missing
+        let completed = !partial
+            && !self.opened_files.contains_key(&id)
+            && self.completed_files.get(&id) == Some(&final_path)
+            && self.completed_sizes.get(&id) == Some(&size);
+        if completed && compressed {
+            tracing::debug!(
+                "[{}] {} is already complete, ignoring it",
+                peer,
+                final_path.display()
+            );
+            return;
+        }
+        // Completed files are rather updated in place, which keeps what the chunks lost had
+        let (real_filename, partial) = if completed {
+            tracing::debug!(
+                "[{}] {} is already complete, updating it in place",
+                peer,
+                final_path.display()
+            );
+            (final_path.clone(), true)
+        } else {
+            (real_filename, partial)
+        };
+
+        if let Some(opened_file) = self.opened_files.get(&id) {
+            // Clients announce files again along their content
+            if opened_file.size == size && opened_file.path == real_filename {
+                tracing::debug!(
+                    "[{}] File 0x{:x} announced again, keeping {}",
+                    peer,
+                    id,
+                    opened_file.path.display()
+                );
+                return;
+            }
+            if opened_file.size != size {
+                match self.config.duplicate_file {
+                    DuplicateFilePolicy::KeepFirst => {
+                        tracing::warn!(
+                            "[{}] File 0x{:x} announced again with {} bytes instead of {}, \
+                             keeping {}",
+                            peer,
+                            id,
+                            size,
+                            opened_file.size,
+                            opened_file.path.display()
+                        );
+                        return;
+                    }
+                    DuplicateFilePolicy::Replace => {
+                        tracing::warn!(
+                            "[{}] File 0x{:x} announced again with {} bytes instead of {}, \
+                             closing {} for {}",
+                            peer,
+                            id,
+                            size,
+                            opened_file.size,
+                            opened_file.path.display(),
+                            real_filename.display()
+                        );
+                        self.finalize_file(id).await;
+                    }
+                }
+            }
+        }
+
+        if !self.has_free_space() {
+            tracing::warn!(
+                "[{}] Not enough free space, rejecting {}",
+                peer,
+                real_filename.display()
+            );
+            self.stats.files_rejected_low_space += 1;
+            return;
+        }
+
+        let opened = self.sink.create(id, &real_filename, size, partial).await;
+        self.record_storage_failure(&opened);
+        match opened {
+            Ok(f) => {
+                tracing::info!(
+                    "[{}] {} file {} of {} bytes (id: 0x{:x})",
+                    peer,
+                    if partial { "Updating" } else { "Created" },
+                    real_filename.display(),
+                    size,
+                    id
+                );
+                // The operator's mode prevails over the one of the client
+                let mode = self.config.file_mode.unwrap_or(mode);
+                if mode != 0 {
+                    match self.sink.set_mode(&real_filename, mode).await {
+                        Ok(()) => {}
+                        Err(e) if is_unsupported(&e) => tracing::debug!("[{}] {}", peer, e),
+                        Err(e) => tracing::warn!(
+                            "[{}] Could not set mode {:o} on {}: {}",
+                            peer,
+                            mode,
+                            real_filename.display(),
+                            e
+                        ),
+                    }
+                }
+                self.created_files.insert(id);
+                self.opened_files.insert(
+                    id,
+                    OpenedFile {
+                        sink: Arc::clone(&self.sink),
+                        file: f,
+                        offset: 0,
+                        pending: Vec::new(),
+                        buffer_size: self.config.write_buffer_size_for(size),
+                        ahead: BTreeMap::new(),
+                        bytes_ahead: 0,
+                        size,
+                        received: ReceivedRanges::default(),
+                        partial,
+                        // The digest of a partial update is computed from the file
+                        hasher: self
+                            .config
+                            .receiver_hash
+                            .filter(|_| !partial)
+                            .map(FileHasher::new),
+                        #[cfg(feature = "compression")]
+                        decompressor,
+                        path: real_filename,
+                        final_path,
+                        acl: None,
+                        modified,
+                        last_activity: None,
+                    },
+                );
+                // Announced again after being closed incomplete, it gets another chance
+                self.stats.incomplete_files.remove(&id);
+            }
+            Err(e) => {
+                tracing::error!(
+                    "[{}] Could not create file {}: {}",
+                    peer,
+                    real_filename.display(),
+                    e
+                );
+            }
+        }
+    }
+
+    async fn process_message_file_chunk(
+        &mut self,
+        id: u64,
+        offset: u64,
+        content_size: u16,
+        crc32: u32,
+        content: Vec<u8>,
+    ) {
+        let peer = self.peer.clone();
+
+        let buffer = &content[..content_size as usize];
+        let actual_crc32 = crc32fast::hash(buffer);
+        if actual_crc32 != crc32 {
+            tracing::warn!(
+                "[{}] Corrupted chunk at offset 0x{:x} of 0x{:x} (CRC32 {:#010x}, expected {:#010x}), dropping it",
+                peer,
+                offset,
+                id,
+                actual_crc32,
+                crc32
+            );
+            return;
+        }
+
+        // If content_size is 0, then the file has been sent
+        if content_size == 0 {
+            // Terminators are retransmitted like any chunk, the copies find the file closed
+            let Some(opened_file) = self.opened_files.get_mut(&id) else {
+                tracing::debug!(
+                    "[{}] 0x{:x} is not open, dropping its terminator",
+                    self.peer,
+                    id
+                );
+                return;
+            };
+            tracing::info!("[{}] Done receiving 0x{:x}", self.peer, id);
+            opened_file.extend_to(offset);
+            self.finalize_file(id).await;
+            return;
+        }
+
+        self.stats.chunks_received += 1;
+        self.stats.bytes_received += buffer.len() as u64;
+        self.write_file_chunk(id, offset, buffer).await;
+    }
+
+    /// Writes `buffer` at `offset` of file `id`
+    async fn write_file_chunk(&mut self, id: u64, offset: u64, buffer: &[u8]) {
+        let peer = self.peer.clone();
+        let opened_file = match self.opened_files.get_mut(&id) {
+            Some(f) => {
+                f.last_activity = Some(Instant::now());
+                f
+            }
+            None if self.rejected_files.contains(&id) => {
+                tracing::trace!(
+                    "[{}] 0x{:x} was rejected, dropping chunk at offset 0x{:x}",
+                    peer,
+                    id,
+                    offset
+                );
+                return;
+            }
+            // Copies of the last chunks may arrive after the terminator
+            None if self.created_files.contains(&id) => {
+                tracing::debug!(
+                    "[{}] 0x{:x} is already closed, dropping chunk at offset 0x{:x}",
+                    peer,
+                    id,
+                    offset
+                );
+                return;
+            }
+            None => {
+                tracing::error!("[{}] File with id {} was not opened", peer, id);
+                return;
+            }
+        };
+
+        if opened_file.already_received(offset, buffer.len()) {
+            tracing::debug!(
+                "[{}] Chunk at offset 0x{:x} of 0x{:x} was already written, dropping it",
+                peer,
+                offset,
+                id
+            );
+            self.stats.duplicate_chunks += 1;
+            return;
+        }
+
+        let written = opened_file.write_chunk(offset, buffer).await;
+        self.record_storage_failure(&written);
+        match written {
+            Ok(writes) => self.stats.disk_writes += writes,
+            Err(e) => tracing::error!(
+                "[{}] Could not write chunk at offset 0x{:x} to {:?}: {}",
+                peer,
+                offset,
+                id,
+                e
+            ),
+        }
+    }
+
+    async fn process_message_chunk_copy(
+        &mut self,
+        id: u64,
+        offset: u64,
+        source_id: u64,
+        source_offset: u64,
+        size: u16,
+    ) {
+        let source = if let Some(opened_file) = self.opened_files.get_mut(&source_id) {
+            // The source range may still be in the pending writes
+            match opened_file.flush().await {
+                Ok(writes) => self.stats.disk_writes += writes,
+                Err(e) => {
+                    tracing::error!(
+                        "[{}] Could not flush {}: {}",
+                        self.peer,
+                        opened_file.path.display(),
+                        e
+                    );
+                    return;
+                }
+            }
+            opened_file.path.clone()
+        } else if let Some(path) = self.completed_files.get(&source_id) {
+            path.clone()
+        } else {
+            tracing::error!(
+                "[{}] Cannot copy chunk at offset 0x{:x} of 0x{:x} from unknown file 0x{:x}",
+                self.peer,
+                offset,
+                id,
+                source_id
+            );
+            return;
+        };
+
+        match self
+            .sink
+            .read_range(&source, source_offset, size.into())
+            .await
+        {
+            Ok(content) => {
+                tracing::trace!(
+                    "[{}] Copying {} bytes at offset 0x{:x} of {} to 0x{:x}",
+                    self.peer,
+                    size,
+                    source_offset,
+                    source.display(),
+                    id
+                );
+                self.write_file_chunk(id, offset, &content[..]).await;
+            }
+            Err(e) => tracing::error!(
+                "[{}] Could not read {} bytes at offset 0x{:x} of {}: {}",
+                self.peer,
+                size,
+                source_offset,
+                source.display(),
+                e
+            ),
+        }
+    }
+
+    /// Closes a received file and applies its pending metadata
+    async fn finalize_file(&mut self, id: u64) {
+        let Some(mut opened_file) = self.opened_files.remove(&id) else {
+            return;
+        };
+
+        #[cfg(feature = "compression")]
+        match opened_file.finish_decompression().await {
+            Ok(writes) => self.stats.disk_writes += writes,
+            Err(e) => tracing::error!(
+                "[{}] Could not decompress {}: {}",
+                self.peer,
+                opened_file.path.display(),
+                e
+            ),
+        }
+
+        let flushed = opened_file.flush().await;
+        self.record_storage_failure(&flushed);
+        match flushed {
+            Ok(writes) => {
+                self.stats.disk_writes += writes;
+                self.storage_failures = 0;
+            }
+            Err(e) => tracing::error!(
+                "[{}] Could not flush {}: {}",
+                self.peer,
+                opened_file.path.display(),
+                e
+            ),
+        }
+
+        let missing = opened_file.missing_bytes();
+        if missing != 0 {
+            let OpenedFile {
+                file,
+                path,
+                final_path,
+                size,
+                ..
+            } = opened_file;
+            drop(file);
+            self.record_incomplete_file(id, &path, final_path, size, missing)
+                .await;
+            return;
+        }
+
+        if let Some(algorithm) = self.config.receiver_hash {
+            let digest = match opened_file.hasher.take() {
+                Some(hasher) if opened_file.offset == opened_file.size => Ok(hasher.finalize()),
+                _ => {
+                    tracing::debug!(
+                        "[{}] {} was not received in order, hashing it from disk",
+                        self.peer,
+                        opened_file.path.display()
+                    );
+                    self.sink.hash(&opened_file.path, algorithm).await
+                }
+            };
+            match digest {
+                Ok(digest) => {
+                    tracing::info!(
+                        "[{}] Received {} ({:?} {:?})",
+                        self.peer,
+                        opened_file.path.display(),
+                        algorithm,
+                        crate::utils::Hex::new(&digest[..])
+                    );
+                    self.stats.file_digests.insert(id, digest);
+                }
+                Err(e) if is_unsupported(&e) => tracing::debug!("[{}] {}", self.peer, e),
+                Err(e) => tracing::error!(
+                    "[{}] Could not hash {}: {}",
+                    self.peer,
+                    opened_file.path.display(),
+                    e
+                ),
+            }
+        }
+
+        if let Some(ref acl) = opened_file.acl {
+            self.apply_acl(&opened_file.path, acl).await;
+        }
+        if let Err(e) = self
+            .sink
+            .finish(opened_file.file, opened_file.modified)
+            .await
+        {
+            tracing::error!(
+                "[{}] Could not complete {}: {}",
+                self.peer,
+                opened_file.path.display(),
+                e
+            );
+        }
+
+        // The file is closed by now, which Windows requires to rename it
+        let path = if self
+            .rename_received(&opened_file.path, &opened_file.final_path)
+            .await
+        {
+            opened_file.final_path
+        } else {
+            opened_file.path
+        };
+        self.completed_sizes.insert(id, opened_file.size);
+        // Updated in place, a file is not completed twice
+        if self.completed_files.insert(id, path).is_none() {
+            self.stats.files_completed += 1;
+        }
+        self.stats.incomplete_files.remove(&id);
+    }
+
+    /// Renames a received file from `path` to `new_path`, returns whether it is at `new_path`
+    async fn rename_received(&self, path: &Path, new_path: &Path) -> bool {
+        if path == new_path {
+            return true;
+        }
+
+        match self.sink.rename(path, new_path).await {
+            Ok(()) => true,
+            Err(e) => {
+                tracing::error!(
+                    "[{}] Could not rename {} to {}: {}",
+                    self.peer,
+                    path.display(),
+                    new_path.display(),
+                    e
+                );
+                false
+            }
+        }
+    }
+
+    /// Reports file `id`, closed with `missing` of its `size` bytes not received, and moves it
+    /// from `path` to `final_path`, with a `.partial` suffix when `rename_incomplete` is set
+    async fn record_incomplete_file(
+        &mut self,
+        id: u64,
+        path: &Path,
+        final_path: PathBuf,
+        size: u64,
+        missing: u64,
+    ) {
+        tracing::error!(
+            "[{}] {} is incomplete, {} of its {} bytes are missing",
+            self.peer,
+            final_path.display(),
+            missing,
+            size
+        );
+        self.stats.incomplete_files.insert(id, missing);
+
+        let new_path = if self.config.rename_incomplete {
+            let mut partial_path = final_path.into_os_string();
+            partial_path.push(".partial");
+            PathBuf::from(partial_path)
+        } else {
+            final_path
+        };
+        self.rename_received(path, &new_path).await;
+    }
+
+    /// Finalizes files which did not receive any chunk for `file_idle_timeout` since their last one
+    async fn close_idle_files(&mut self) {
+        let now = Instant::now();
+        let idle_files: Vec<u64> = self
+            .opened_files
+            .iter()
+            .filter(|(_, f)| {
+                f.last_activity.is_some_and(|last_activity| {
+                    now.duration_since(last_activity) >= self.config.file_idle_timeout
+                })
+            })
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in idle_files {
+            tracing::warn!(
+                "[{}] No chunk received for 0x{:x} in {:?}, its terminator was likely lost",
+                self.peer,
+                id,
+                self.config.file_idle_timeout
+            );
+            self.finalize_file(id).await;
+        }
+    }
+
+    async fn process_message_file_hash(&mut self, id: u64, digest: [u8; 32]) {
+        let path = if let Some(opened_file) = self.opened_files.get_mut(&id) {
+            // The terminator was lost or is late, check what was written so far
+            match opened_file.flush().await {
+                Ok(writes) => self.stats.disk_writes += writes,
+                Err(e) => tracing::error!(
+                    "[{}] Could not flush {}: {}",
+                    self.peer,
+                    opened_file.path.display(),
+                    e
+                ),
+            }
+            opened_file.path.clone()
+        } else if let Some(path) = self.completed_files.get(&id) {
+            path.clone()
+        } else if self.stats.incomplete_files.contains_key(&id) {
+            tracing::error!(
+                "[{}] File 0x{:x} is incomplete, it cannot match its hash",
+                self.peer,
+                id
+            );
+            self.corrupted_files.insert(id);
+            return;
+        } else {
+            tracing::warn!(
+                "[{}] Got hash for unknown file with id 0x{:x}",
+                self.peer,
+                id
+            );
+            return;
+        };
+
+        match self.sink.hash(&path, HashAlgorithm::Sha256).await {
+            Ok(actual) if actual[..] == digest[..] => {
+                tracing::debug!("[{}] Verified {}", self.peer, path.display());
+                self.corrupted_files.remove(&id);
+                // Staged files are recorded once committed
+                if self.staging.is_none() {
+                    self.record_completed([id]);
+                }
+            }
+            Ok(actual) => {
+                tracing::error!(
+                    "[{}] {} is corrupted: SHA-256 is {:?}, expected {:?}",
+                    self.peer,
+                    path.display(),
+                    crate::utils::Hex::new(&actual[..]),
+                    crate::utils::Hex::new(&digest[..])
+                );
+                self.corrupted_files.insert(id);
+            }
+            Err(e) if is_unsupported(&e) => {
+                tracing::debug!("[{}] {}, {} is not verified", self.peer, e, path.display())
+            }
+            Err(e) => tracing::error!("[{}] Could not hash {}: {}", self.peer, path.display(), e),
+        }
+    }
+
+    /// Adds `ids` to the `state_file`, if any
+    fn record_completed(&self, ids: impl IntoIterator<Item = u64>) {
+        let Some(ref state_file) = self.config.state_file else {
+            return;
+        };
+
+        if let Err(e) = crate::job::record_completed_ids(state_file, ids) {
+            tracing::warn!(
+                "[{}] Could not record completed files in {}: {}",
+                self.peer,
+                state_file.display(),
+                e
+            );
+        }
+    }
+
+    async fn apply_acl(&self, path: &Path, acl: &[u8]) {
+        match self.sink.set_acl(path, acl).await {
+            Ok(()) => tracing::debug!("[{}] Applied ACL to {}", self.peer, path.display()),
+            Err(e) if is_unsupported(&e) => tracing::debug!("[{}] {}", self.peer, e),
+            Err(e) => tracing::warn!(
+                "[{}] Could not apply ACL to {}: {}",
+                self.peer,
+                path.display(),
+                e
+            ),
+        }
+    }
+
+    async fn process_message_acl(&mut self, id: u64, acl: Vec<u8>) {
+        if let Some(opened_file) = self.opened_files.get_mut(&id) {
+            // The file is still being received, the ACL will be applied once it is complete
+            opened_file.acl = Some(acl);
+        } else if let Some(path) = self.completed_files.get(&id) {
+            self.apply_acl(path, &acl[..]).await;
+        } else {
+            tracing::warn!(
+                "[{}] Got ACL for unknown file with id 0x{:x}",
+                self.peer,
+                id
+            );
+        }
+    }
+
+    async fn process_message_special_file(&mut self, path: String, kind: SpecialKind, rdev: u64) {
+        let Some(real_path) = self.resolve_path(&path) else {
+            return;
+        };
+
+        match crate::utils::fs::create_special_file(&real_path, kind, rdev, self.config.dir_mode)
+            .await
+        {
+            Ok(()) => tracing::info!("[{}] Created {:?} {}", self.peer, kind, real_path.display()),
+            Err(Error::IO(e)) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                tracing::warn!(
+                    "[{}] Not allowed to create {:?} {}, skipping",
+                    self.peer,
+                    kind,
+                    real_path.display()
+                )
+            }
+            Err(e) => tracing::error!(
+                "[{}] Could not create {:?} {}: {}",
+                self.peer,
+                kind,
+                real_path.display(),
+                e
+            ),
+        }
+    }
+
+    async fn process_message_directory(&mut self, path: String, mode: u32) {
+        let Some(real_path) = self.resolve_path(&path) else {
+            return;
+        };
+
+        let mode = self.config.dir_mode.unwrap_or(mode);
+        match crate::utils::fs::create_directory(&real_path, mode, self.config.dir_mode).await {
+            Ok(()) => tracing::info!("[{}] Created directory {}", self.peer, real_path.display()),
+            Err(e) => tracing::error!(
+                "[{}] Could not create directory {}: {}",
+                self.peer,
+                real_path.display(),
+                e
+            ),
+        }
+    }
+
+    /// Whether `target`, relative to the directory of `link_path`, stays inside `root`
+    ///
+    /// Links already on disk are not followed: a target going through one is refused, as `..`
+    /// after it would not be where the path reads.
+    fn is_target_inside_root(&self, link_path: &Path, target: &Path) -> bool {
+        use std::path::Component;
+
+        if target.is_absolute() {
+            return false;
+        }
+
+        let mut resolved = link_path.parent().unwrap_or(&self.root).to_path_buf();
+        for component in target.components() {
+            match component {
+                Component::ParentDir => {
+                    if !resolved.pop() {
+                        return false;
+                    }
+                }
+                Component::Normal(name) => {
+                    resolved.push(name);
+                    if self.symlink_below_root(&resolved).is_some() {
+                        return false;
+                    }
+                }
+                Component::CurDir => {}
+                Component::RootDir | Component::Prefix(_) => return false,
+            }
+        }
+
+        resolved.starts_with(&self.root)
+    }
+
+    async fn process_message_symlink(&mut self, link_path: String, target: String) {
+        let Some(real_path) = self.resolve_path(&link_path) else {
+            return;
+        };
+        let target = PathBuf::from(target);
+
+        // A link escaping root would let later files be written outside of it
+        if !self.is_target_inside_root(&real_path, &target) {
+            tracing::warn!(
+                "[{}] Symlink {} -> {} points outside of {}, ignoring",
+                self.peer,
+                real_path.display(),
+                target.display(),
+                self.root.display()
+            );
+            return;
+        }
+
+        match crate::utils::fs::create_symlink(&real_path, &target, self.config.dir_mode).await {
+            Ok(()) => tracing::info!(
+                "[{}] Created symlink {} -> {}",
+                self.peer,
+                real_path.display(),
+                target.display()
+            ),
+            Err(e) => tracing::error!(
+                "[{}] Could not create symlink {}: {}",
+                self.peer,
+                real_path.display(),
+                e
+            ),
+        }
+    }
+
+    /// Moves staged files to `root`, only if the whole set was received
+    async fn commit_staged_files(&mut self) {
+        let Some(ref staging) = self.staging else {
+            return;
+        };
+
+        let incomplete_files = self.opened_files.len() + self.stats.incomplete_files.len();
+        if incomplete_files != 0 {
+            tracing::error!(
+                "[{}] {} files are incomplete, leaving received files in {}",
+                self.peer,
+                incomplete_files,
+                staging.display()
+            );
+            return;
+        }
+
+        if !self.corrupted_files.is_empty() {
+            tracing::error!(
+                "[{}] {} files are corrupted, leaving received files in {}",
+                self.peer,
+                self.corrupted_files.len(),
+                staging.display()
+            );
+            return;
+        }
+
+        for path in self.completed_files.values_mut() {
+            let Ok(relative) = path.strip_prefix(staging) else {
+                continue;
+            };
+            let final_path = self.root.join(relative);
+
+            match crate::utils::fs::move_file(path, &final_path, self.config.dir_mode).await {
+                Ok(()) => *path = final_path,
+                Err(e) => tracing::error!(
+                    "[{}] Could not move {} to {}: {}",
+                    self.peer,
+                    path.display(),
+                    final_path.display(),
+                    e
+                ),
+            }
+        }
+        tracing::info!(
+            "[{}] Committed {} files",
+            self.peer,
+            self.completed_files.len()
+        );
+        self.record_completed(self.completed_files.keys().copied());
+    }
+
+    async fn process_message_tree_manifest(&mut self, total: u64, entries: Vec<ManifestEntry>) {
+        tracing::debug!(
+            "[{}] Received {} manifest entries of {}",
+            self.peer,
+            entries.len(),
+            total
+        );
+        self.manifest_total = Some(total);
+        self.manifest.extend(entries);
+    }
+
+    /// Compares `root` with the manifest sent by the client, if any
+    async fn check_tree(&mut self) {
+        let Some(total) = self.manifest_total else {
+            return;
+        };
+        if self.manifest.len() as u64 != total {
+            tracing::warn!(
+                "[{}] Only {} manifest entries of {} were received, the others will be reported \
+                 as extra files",
+                self.peer,
+                self.manifest.len(),
+                total
+            );
+        }
+
+        let root = self.root.clone();
+        let staging = self.staging.clone();
+        let manifest = std::mem::take(&mut self.manifest);
+        let compared = tokio::task::spawn_blocking(move || {
+            crate::tree::compare_tree(&root, &manifest[..], |path| {
+                staging
+                    .as_ref()
+                    .is_none_or(|staging| !path.starts_with(staging))
+            })
+        })
+        .await;
+
+        match compared {
+            Ok(Ok(diff)) if diff.is_empty() => {
+                tracing::info!("[{}] Tree matches the manifest of the client", self.peer);
+                self.stats.tree_diff = Some(diff);
+            }
+            Ok(Ok(diff)) => {
+                tracing::error!(
+                    "[{}] Tree does not match the manifest of the client: {} missing, {} extra \
+                     and {} mismatched files",
+                    self.peer,
+                    diff.missing.len(),
+                    diff.extra.len(),
+                    diff.mismatched.len()
+                );
+                for path in &diff.missing {
+                    tracing::warn!("[{}] Missing {}", self.peer, path.display());
+                }
+                for path in &diff.extra {
+                    tracing::warn!("[{}] Extra {}", self.peer, path.display());
+                }
+                for path in &diff.mismatched {
+                    tracing::warn!("[{}] Mismatched {}", self.peer, path.display());
+                }
+                self.stats.tree_diff = Some(diff);
+            }
+            Ok(Err(e)) => tracing::error!("[{}] Could not check tree: {}", self.peer, e),
+            Err(e) => tracing::error!("[{}] Could not check tree: {}", self.peer, e),
+        }
+    }
+
+    async fn process_message_done(&mut self) {
+        tracing::info!("[{}] Received done from client", self.peer);
+        // No chunk follows `Done`, files still open lost their terminator or some chunks
+        let ids: Vec<u64> = self.opened_files.keys().copied().collect();
+        for id in ids {
+            self.finalize_file(id).await;
+        }
+        self.commit_staged_files().await;
+        self.report_transfer();
+        self.report_losses();
+        self.check_tree().await;
+    }
+
+    pub async fn process_message(&mut self, message: Message) -> bool {
+        let span = match message.file_id() {
+            Some(id) => tracing::info_span!(parent: &self.span, "file", id),
+            None => self.span.clone(),
+        };
+        self.process_message_in_span(message).instrument(span).await
+    }
+
+    async fn process_message_in_span(&mut self, message: Message) -> bool {
+        if self.config.require_hello
+            && !self.hello_received
+            && !matches!(message, Message::Hello { .. })
+        {
+            if self.dropped_before_hello == 0 {
+                tracing::warn!("[{}] Dropping messages until hello is received", self.peer);
+            }
+            tracing::debug!("[{}] Dropped {:?}", self.peer, message);
+            self.dropped_before_hello += 1;
+            return false;
+        }
+
+        let message = match self.message_hook {
+            Some(ref hook) => match hook(&message) {
+                MessageAction::Accept => message,
+                MessageAction::Drop => {
+                    tracing::debug!("[{}] Hook dropped {:?}", self.peer, message);
+                    return false;
+                }
+                MessageAction::Rewrite(rewritten) => {
+                    tracing::debug!("[{}] Hook rewrote {:?}", self.peer, message);
+                    rewritten
+                }
+            },
+            None => message,
+        };
+
+        match message {
+            Message::Hello {
+                session_id,
+                version,
+            } => self.process_message_hello(session_id, version).await,
+            Message::KeepAlive(id) => self.process_message_keep_alive(id).await,
+            Message::CountFilesToUpload(count) => {
+                self.process_message_count_files_to_upload(count).await
+            }
+            Message::File {
+                filename,
+                created,
+                modified,
+                size,
+                id,
+                mode,
+                compressed,
+                partial,
+            } => {
+                self.process_message_file(
+                    filename, created, modified, size, id, mode, compressed, partial,
+                )
+                .await
+            }
+            Message::FileChunk {
+                id,
+                offset,
+                content_size,
+                crc32,
+                content,
+            } => {
+                self.process_message_file_chunk(id, offset, content_size, crc32, content)
+                    .await
+            }
+            Message::Done => {
+                self.process_message_done().await;
+                return true;
+            }
+            Message::FileHash { id, digest } => self.process_message_file_hash(id, digest).await,
+            Message::Acl { id, acl } => self.process_message_acl(id, acl).await,
+            Message::SpecialFile { path, kind, rdev } => {
+                self.process_message_special_file(path, kind, rdev).await
+            }
+            Message::Directory { path, mode } => self.process_message_directory(path, mode).await,
+            Message::Symlink { link_path, target } => {
+                self.process_message_symlink(link_path, target).await
+            }
+            Message::ChunkCopy {
+                id,
+                offset,
+                source_id,
+                source_offset,
+                size,
+            } => {
+                self.process_message_chunk_copy(id, offset, source_id, source_offset, size)
+                    .await
+            }
+            Message::TreeManifest { total, entries } => {
+                self.process_message_tree_manifest(total, entries).await
+            }
+            Message::Progress { id, bytes_sent } => {
+                self.process_message_progress(id, bytes_sent).await
+            }
+        }
+
+        if self.storage_failures >= MAX_STORAGE_FAILURES {
+            tracing::error!(
+                "[{}] {} consecutive writes failed under {}, the filesystem is full or read-only, \
+                 giving up",
+                self.peer,
+                self.storage_failures,
+                self.root.display()
+            );
+            return true;
+        }
+
+        false
+    }
+
+    async fn process_buffer_internal(&mut self, buffer: &[u8]) -> Result<bool> {
+        self.reassembler.push_data(buffer);
+
+        match self.reassembler.get_next_data(&mut self.data) {
+            Ok(()) => {
+                // Clients older than `ProtocolVersion::V11`, or whose `Hello` was lost, did
+                // not tell their version
+                let (rest, message) = if self.hello_received {
+                    Message::from_wire_versioned(self.protocol_version, &self.data[..])?
+                } else {
+                    Message::from_wire_any_version(&self.data[..])?
+                };
+                if !rest.is_empty() {
                     tracing::warn!("Got extra data at the end of the message");
                     tracing::warn!("Extra data: {:x?}", rest);
                 }
-                self.data.clear();
-                let done = self.process_message(message).await;
-                Ok(done)
+                self.data.clear();
+                if self.reassembler.is_late() {
+                    // Only a restarted client sends a `Hello` after other messages
+                    if !matches!(message, Message::Hello { .. }) {
+                        tracing::debug!("[{}] Dropping late {:?}", self.peer, message);
+                        return Ok(false);
+                    }
+                    self.reassembler.restart();
+                }
+                let done = self.process_message(message).await;
+                Ok(done)
+            }
+            Err(Error::NoData) => Ok(false),
+            Err(Error::Deserialize(nom::Err::Incomplete(n))) => {
+                match n {
+                    nom::Needed::Unknown => tracing::trace!("Missing some bytes"),
+                    nom::Needed::Size(s) => tracing::trace!("Missing {} bytes", s),
+                }
+
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    pub async fn process_buffer(&mut self, buffer: &[u8]) -> bool {
+        match self.process_buffer_internal(buffer).await {
+            Ok(done) => done,
+            Err(e) => {
+                tracing::error!("[{}] error: {}", self.peer, e);
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{chunk_message, client_handler, deliver, file_message, message_datagrams};
+    use tokio::io::AsyncWriteExt;
+
+    async fn send(handler: &mut ClientHandler, message: Message) {
+        let datagrams = message_datagrams(&message, &handler.config).await.unwrap();
+        deliver(handler, datagrams).await;
+    }
+
+    #[tokio::test]
+    async fn busy_handler_does_not_stall_other_clients() {
+        let root = tempfile::tempdir().unwrap();
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut server = Server::new_with_config(
+            UdpReader::new(socket).unwrap(),
+            Config {
+                root: root.path().to_path_buf(),
+                channel_size: 1,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        // The handler of `stalled` never reads its channel
+        let stalled: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let other: SocketAddr = "127.0.0.1:2".parse().unwrap();
+        let (stalled_tx, _stalled_rx) = mpsc::channel(1);
+        let (other_tx, mut other_rx) = mpsc::channel(1);
+        server.handlers.insert(stalled, stalled_tx);
+        server.handlers.insert(other, other_tx);
+
+        for i in 0..3 {
+            tokio::time::timeout(Duration::from_secs(1), server.dispatch(stalled, vec![i]))
+                .await
+                .expect("Dispatching to a busy handler blocked");
+        }
+        tokio::time::timeout(Duration::from_secs(1), server.dispatch(other, vec![42]))
+            .await
+            .unwrap();
+
+        assert_eq!(other_rx.recv().await, Some(vec![42]));
+        assert_eq!(server.dropped_datagrams().get(&stalled), Some(&2));
+        assert_eq!(server.dropped_datagrams().get(&other), None);
+    }
+
+    #[tokio::test]
+    async fn stream_of_unknown_size_ends_at_its_terminator() {
+        let root = tempfile::tempdir().unwrap();
+        let mut handler = client_handler(Config {
+            root: root.path().to_path_buf(),
+            ..Default::default()
+        });
+
+        for (id, filename) in [(1, "complete"), (2, "lossy")] {
+            send(&mut handler, file_message(id, filename, 0)).await;
+        }
+        send(&mut handler, chunk_message(1, 0, b"head")).await;
+        send(&mut handler, chunk_message(1, 4, b"tail")).await;
+        send(&mut handler, chunk_message(1, 8, b"")).await;
+        // The first chunk of the second stream is lost
+        send(&mut handler, chunk_message(2, 4, b"tail")).await;
+        send(&mut handler, chunk_message(2, 8, b"")).await;
+
+        assert_eq!(
+            std::fs::read(root.path().join("complete")).unwrap(),
+            b"headtail"
+        );
+        assert_eq!(handler.stats.incomplete_files.get(&1), None);
+        // Counted from the first gap
+        assert_eq!(handler.stats.incomplete_files.get(&2), Some(&8));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn abandoned_file_is_finalized_after_idle_timeout() {
+        let root = tempfile::tempdir().unwrap();
+        let mut handler = client_handler(Config {
+            root: root.path().to_path_buf(),
+            file_idle_timeout: Duration::from_secs(5),
+            ..Default::default()
+        });
+
+        send(&mut handler, file_message(1, "abandoned", 8)).await;
+        send(&mut handler, chunk_message(1, 0, b"half")).await;
+
+        tokio::time::advance(Duration::from_secs(4)).await;
+        handler.close_idle_files().await;
+        assert!(handler.opened_files.contains_key(&1));
+
+        tokio::time::advance(Duration::from_secs(2)).await;
+        handler.close_idle_files().await;
+        assert!(!handler.opened_files.contains_key(&1));
+        assert_eq!(handler.stats.incomplete_files.get(&1), Some(&4));
+        assert!(root.path().join("abandoned").exists());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn announced_file_waits_for_its_first_chunk() {
+        let root = tempfile::tempdir().unwrap();
+        let mut handler = client_handler(Config {
+            root: root.path().to_path_buf(),
+            file_idle_timeout: Duration::from_secs(5),
+            ..Default::default()
+        });
+
+        send(&mut handler, file_message(1, "queued", 4)).await;
+
+        // Other files are sent before its content
+        tokio::time::advance(Duration::from_secs(60)).await;
+        handler.close_idle_files().await;
+        assert!(handler.opened_files.contains_key(&1));
+
+        send(&mut handler, chunk_message(1, 0, b"data")).await;
+        send(&mut handler, chunk_message(1, 4, b"")).await;
+        assert_eq!(std::fs::read(root.path().join("queued")).unwrap(), b"data");
+        assert_eq!(handler.stats.incomplete_files.get(&1), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn file_announced_again_is_no_longer_incomplete() {
+        let root = tempfile::tempdir().unwrap();
+        let staging = tempfile::tempdir_in(root.path()).unwrap();
+        let mut handler = client_handler(Config {
+            root: root.path().to_path_buf(),
+            staging_dir: Some(staging.path().to_path_buf()),
+            commit_on_done: true,
+            file_idle_timeout: Duration::from_secs(5),
+            ..Default::default()
+        });
+
+        send(&mut handler, file_message(1, "file", 8)).await;
+        send(&mut handler, chunk_message(1, 0, b"half")).await;
+        tokio::time::advance(Duration::from_secs(6)).await;
+        handler.close_idle_files().await;
+        assert_eq!(handler.stats.incomplete_files.get(&1), Some(&4));
+
+        // A later pass sends it again
+        send(&mut handler, file_message(1, "file", 8)).await;
+        assert_eq!(handler.stats.incomplete_files.get(&1), None);
+        send(&mut handler, chunk_message(1, 0, b"halfhalf")).await;
+
+        send(&mut handler, Message::Done).await;
+        assert_eq!(
+            std::fs::read(root.path().join("file")).unwrap(),
+            b"halfhalf"
+        );
+    }
+
+    #[tokio::test]
+    async fn summary_reports_files_never_completed() {
+        let root = tempfile::tempdir().unwrap();
+        let mut handler = client_handler(Config {
+            root: root.path().to_path_buf(),
+            ..Default::default()
+        });
+
+        send(&mut handler, Message::CountFilesToUpload(3)).await;
+        for id in 1..=3 {
+            send(&mut handler, file_message(id, &format!("file{}", id), 4)).await;
+        }
+        // The last one never gets its chunk
+        for id in 1..=2 {
+            send(&mut handler, chunk_message(id, 0, b"data")).await;
+        }
+        send(&mut handler, Message::Done).await;
+
+        assert_eq!(
+            handler.transfer_summary(),
+            TransferSummary {
+                expected: Some(3),
+                created: 3,
+                completed: 2,
+                missing: vec![3],
+            }
+        );
+    }
+
+    #[tokio::test]
+    #[tracing_test::traced_test]
+    async fn logs_are_in_client_and_file_spans() {
+        let root = tempfile::tempdir().unwrap();
+        let mut handler = client_handler(Config {
+            root: root.path().to_path_buf(),
+            ..Default::default()
+        });
+
+        send(&mut handler, Message::CountFilesToUpload(1)).await;
+        send(&mut handler, file_message(7, "file", 4)).await;
+
+        let addr = handler.client_addr().to_string();
+        assert!(logs_contain(&format!("client{{addr={}}}: ", addr)));
+        assert!(logs_contain(&format!(
+            "client{{addr={}}}:file{{id=7}}: ",
+            addr
+        )));
+        // The message text is kept as it was
+        assert!(logs_contain(&format!("[{}] Will received 1 files", addr)));
+    }
+
+    #[tokio::test]
+    #[tracing_test::traced_test]
+    async fn repeated_terminator_is_not_an_error() {
+        let root = tempfile::tempdir().unwrap();
+        let mut handler = client_handler(Config {
+            root: root.path().to_path_buf(),
+            ..Default::default()
+        });
+        let chunk = |offset, content: &[u8]| chunk_message(1, offset, content);
+
+        send(&mut handler, file_message(1, "file", 4)).await;
+        send(&mut handler, chunk(0, b"data")).await;
+        send(&mut handler, chunk(4, b"")).await;
+        send(&mut handler, chunk(4, b"")).await;
+        // A copy of the last chunk delayed past the terminator
+        send(&mut handler, chunk(0, b"data")).await;
+
+        assert_eq!(std::fs::read(root.path().join("file")).unwrap(), b"data");
+        assert_eq!(handler.stats().files_completed, 1);
+        assert!(logs_contain("0x1 is not open, dropping its terminator"));
+        assert!(!logs_contain("ERROR"));
+    }
+
+    #[tokio::test]
+    async fn keep_alive_gaps_are_counted() {
+        let mut handler = client_handler(Config::default());
+
+        for id in [1, 2, 5, u64::MAX, 0] {
+            send(&mut handler, Message::KeepAlive(id)).await;
+        }
+
+        // 3 and 4, u64::MAX being taken as going backwards from 5
+        assert_eq!(handler.stats().total_keep_alive_gaps, 2);
+        assert_eq!(handler.stats().max_keep_alive_gap, 2);
+
+        send(&mut handler, Message::KeepAlive(2)).await;
+        // 1 is skipped across the wrap around
+        assert_eq!(handler.stats().total_keep_alive_gaps, 3);
+    }
+
+    #[tokio::test]
+    async fn progress_tells_the_bytes_missing() {
+        let root = tempfile::tempdir().unwrap();
+        let mut handler = client_handler(Config {
+            root: root.path().to_path_buf(),
+            ..Default::default()
+        });
+        let chunk = |offset: u64| chunk_message(1, offset, b"data");
+
+        send(&mut handler, file_message(1, "file", 16)).await;
+        // The chunk at 4 is lost, the one at 8 waits for it
+        send(&mut handler, chunk(0)).await;
+        send(&mut handler, chunk(8)).await;
+        send(
+            &mut handler,
+            Message::Progress {
+                id: 1,
+                bytes_sent: 12,
+            },
+        )
+        .await;
+        assert_eq!(handler.stats().progress_deficits.get(&1), Some(&4));
+
+        send(&mut handler, chunk(4)).await;
+        send(
+            &mut handler,
+            Message::Progress {
+                id: 1,
+                bytes_sent: 12,
+            },
+        )
+        .await;
+        assert_eq!(handler.stats().progress_deficits.get(&1), Some(&0));
+    }
+
+    #[tokio::test]
+    async fn chunks_before_hello_are_ignored() {
+        let root = tempfile::tempdir().unwrap();
+        let mut handler = client_handler(Config {
+            root: root.path().to_path_buf(),
+            require_hello: true,
+            ..Default::default()
+        });
+
+        let file = |id| file_message(id, &format!("file{}", id), 4);
+        let chunk = |id| chunk_message(id, 0, b"data");
+
+        send(&mut handler, file(1)).await;
+        send(&mut handler, chunk(1)).await;
+        assert!(!root.path().join("file1").exists());
+        assert_eq!(handler.dropped_before_hello, 2);
+
+        send(
+            &mut handler,
+            Message::Hello {
+                session_id: String::new(),
+                version: ProtocolVersion::CURRENT,
+            },
+        )
+        .await;
+        send(&mut handler, file(2)).await;
+        send(&mut handler, chunk(2)).await;
+        assert!(!root.path().join("file1").exists());
+        assert!(handler.opened_files.contains_key(&2));
+    }
+
+    #[tokio::test]
+    async fn hook_drops_messages() {
+        let root = tempfile::tempdir().unwrap();
+        let mut handler = client_handler(Config {
+            root: root.path().to_path_buf(),
+            ..Default::default()
+        })
+        .with_message_hook(Arc::new(|message: &Message| match message {
+            Message::File { filename, .. } if filename.ends_with(".tmp") => MessageAction::Drop,
+            _ => MessageAction::Accept,
+        }));
+
+        let file = |id, filename: &str| file_message(id, filename, 4);
+
+        send(&mut handler, file(1, "scratch.tmp")).await;
+        send(&mut handler, file(2, "kept")).await;
+        assert!(!handler.opened_files.contains_key(&1));
+        assert!(!root.path().join("scratch.tmp").exists());
+        assert!(handler.opened_files.contains_key(&2));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn handler_gives_up_when_filesystem_is_full() {
+        let root = tempfile::tempdir().unwrap();
+        let mut handler = client_handler(Config {
+            root: root.path().to_path_buf(),
+            write_buffer_size: 0,
+            ..Default::default()
+        });
+
+        handler.process_message(file_message(1, "file", 1024)).await;
+        // Every write to /dev/full fails with ENOSPC, like on a full or read-only filesystem
+        handler.opened_files.get_mut(&1).unwrap().file = std::fs::OpenOptions::new()
+            .write(true)
+            .open("/dev/full")
+            .unwrap()
+            .into();
+
+        let chunk = |offset| chunk_message(1, offset, b"data");
+        // Tokio reports a failed write on the next operation on the file, so every other chunk
+        // fails
+        let mut chunks = 0;
+        while !handler.process_message(chunk(chunks * 4)).await {
+            chunks += 1;
+            assert!(
+                chunks <= 2 * u64::from(MAX_STORAGE_FAILURES),
+                "handler did not give up"
+            );
+        }
+        assert!(chunks + 1 >= u64::from(MAX_STORAGE_FAILURES));
+        assert_eq!(handler.storage_failures, MAX_STORAGE_FAILURES);
+    }
+
+    #[tokio::test]
+    async fn new_files_are_rejected_on_low_free_space() {
+        let root = tempfile::tempdir().unwrap();
+        let mut handler = client_handler(Config {
+            root: root.path().to_path_buf(),
+            ..Default::default()
+        });
+        let file = |id| file_message(id, &format!("file{}", id), 4);
+
+        send(&mut handler, file(1)).await;
+        assert!(handler.opened_files.contains_key(&1));
+
+        // No filesystem has that much free space
+        Arc::get_mut(&mut handler.config).unwrap().min_free_space = u64::MAX;
+        send(&mut handler, file(2)).await;
+        assert!(!root.path().join("file2").exists());
+        assert_eq!(handler.stats().files_rejected_low_space, 1);
+
+        // In-progress files continue
+        send(&mut handler, chunk_message(1, 0, b"data")).await;
+        send(&mut handler, chunk_message(1, 4, b"")).await;
+        assert_eq!(std::fs::read(root.path().join("file1")).unwrap(), b"data");
+    }
+
+    #[tokio::test]
+    async fn session_id_is_reported() {
+        let mut handler = client_handler(Config::default());
+
+        send(
+            &mut handler,
+            Message::Hello {
+                session_id: String::from("backup-42"),
+                version: ProtocolVersion::CURRENT,
+            },
+        )
+        .await;
+
+        assert_eq!(handler.stats().session_id.as_deref(), Some("backup-42"));
+        assert_eq!(handler.peer.to_string(), "127.0.0.1:1 backup-42");
+    }
+
+    #[tokio::test]
+    async fn files_over_max_file_size_are_rejected() {
+        let root = tempfile::tempdir().unwrap();
+        let mut handler = client_handler(Config {
+            root: root.path().to_path_buf(),
+            max_file_size: Some(1024),
+            ..Default::default()
+        });
+
+        for (id, size) in [(1, 1024), (2, 1025)] {
+            send(&mut handler, file_message(id, &format!("file{}", id), size)).await;
+        }
+
+        assert!(handler.opened_files.contains_key(&1));
+        assert!(!handler.opened_files.contains_key(&2));
+        assert!(!root.path().join("file2").exists());
+        assert_eq!(handler.stats().files_rejected_too_large, 1);
+    }
+
+    #[tokio::test]
+    #[tracing_test::traced_test]
+    async fn files_without_an_allowed_extension_are_rejected() {
+        let root = tempfile::tempdir().unwrap();
+        let mut handler = client_handler(Config {
+            root: root.path().to_path_buf(),
+            allowed_extensions: vec![String::from("json")],
+            ..Default::default()
+        });
+
+        for (id, filename) in [(1, "export.json"), (2, "export.exe")] {
+            send(&mut handler, file_message(id, filename, 2)).await;
+            for (offset, content) in [(0, &b"{}"[..]), (2, &b""[..])] {
+                send(&mut handler, chunk_message(id, offset, content)).await;
+            }
+        }
+
+        assert!(!handler.opened_files.contains_key(&2));
+        assert_eq!(
+            std::fs::read(root.path().join("export.json")).unwrap(),
+            b"{}"
+        );
+        assert_eq!(
+            std::fs::read_dir(root.path()).unwrap().count(),
+            1,
+            "only export.json is created"
+        );
+        assert_eq!(handler.stats().files_rejected_extension, 1);
+        assert_eq!(handler.stats().files_completed, 1);
+        assert!(!logs_contain("ERROR"));
+    }
+
+    #[test]
+    fn received_ranges_join_out_of_order_chunks() {
+        let mut received = ReceivedRanges::default();
+        received.insert(8, 12);
+        received.insert(16, 20);
+        assert_eq!(received.contiguous, 0);
+        assert!(received.contains(9, 12));
+        assert!(!received.contains(9, 13));
+        assert!(!received.contains(0, 4));
+
+        received.insert(0, 4);
+        assert_eq!(received.contiguous, 4);
+        received.insert(4, 8);
+        assert_eq!(received.contiguous, 12);
+        received.insert(12, 16);
+        assert_eq!(received.contiguous, 20);
+        assert!(received.ahead.is_empty());
+    }
+
+    #[tokio::test]
+    async fn incomplete_file_is_renamed_on_done() {
+        let root = tempfile::tempdir().unwrap();
+        let mut handler = client_handler(Config {
+            root: root.path().to_path_buf(),
+            rename_incomplete: true,
+            ..Default::default()
+        });
+
+        send(&mut handler, file_message(1, "file", 16)).await;
+        // Only the first and the third of four chunks arrive
+        for offset in [0, 8] {
+            send(&mut handler, chunk_message(1, offset, b"data")).await;
+        }
+        send(&mut handler, Message::Done).await;
+
+        assert_eq!(handler.stats.incomplete_files.get(&1), Some(&12));
+        assert!(!root.path().join("file").exists());
+        assert!(root.path().join("file.partial").exists());
+        assert!(handler.completed_files.is_empty());
+    }
+
+    // Raw messages are built with big-endian integers, which `compact` does not decode
+    #[cfg(not(feature = "compact"))]
+    #[tokio::test]
+    async fn messages_are_decoded_with_the_version_of_hello() {
+        let root = tempfile::tempdir().unwrap();
+        let mut handler = client_handler(Config {
+            root: root.path().to_path_buf(),
+            ..Default::default()
+        });
+
+        send(
+            &mut handler,
+            Message::Hello {
+                session_id: String::new(),
+                version: ProtocolVersion::V0,
+            },
+        )
+        .await;
+        assert_eq!(handler.protocol_version, ProtocolVersion::V0);
+
+        // `File` and `FileChunk` as the first version encoded them
+        let mut file = vec![3u8];
+        file.extend_from_slice(&4u16.to_be_bytes()[..]);
+        file.extend_from_slice(b"file");
+        file.extend_from_slice(&1_600_000_000u64.to_be_bytes()[..]);
+        file.extend_from_slice(&4u64.to_be_bytes()[..]);
+        file.extend_from_slice(&42u64.to_be_bytes()[..]);
+        let mut chunk = vec![4u8];
+        chunk.extend_from_slice(&42u64.to_be_bytes()[..]);
+        chunk.extend_from_slice(&0u64.to_be_bytes()[..]);
+        chunk.extend_from_slice(&4u16.to_be_bytes()[..]);
+        chunk.extend_from_slice(b"data");
+        for raw in [file, chunk] {
+            let datagrams = crate::testing::raw_message_datagrams(&raw[..], &handler.config)
+                .await
+                .unwrap();
+            deliver(&mut handler, datagrams).await;
+        }
+        handler.finalize_file(42).await;
+
+        assert_eq!(std::fs::read(root.path().join("file")).unwrap(), b"data");
+    }
+
+    // Legacy chunks are built in clear, and raw messages with big-endian integers
+    #[cfg(not(any(feature = "compact", feature = "encryption")))]
+    #[tokio::test]
+    async fn legacy_client_without_hello_is_understood() {
+        let root = tempfile::tempdir().unwrap();
+        let mut handler = client_handler(Config {
+            root: root.path().to_path_buf(),
+            ..Default::default()
+        });
+
+        // `File` and `FileChunk` as the first version encoded and framed them
+        let mut file = vec![3u8];
+        file.extend_from_slice(&4u16.to_be_bytes()[..]);
+        file.extend_from_slice(b"file");
+        file.extend_from_slice(&1_600_000_000u64.to_be_bytes()[..]);
+        file.extend_from_slice(&4u64.to_be_bytes()[..]);
+        file.extend_from_slice(&42u64.to_be_bytes()[..]);
+        let mut chunk = vec![4u8];
+        chunk.extend_from_slice(&42u64.to_be_bytes()[..]);
+        chunk.extend_from_slice(&0u64.to_be_bytes()[..]);
+        chunk.extend_from_slice(&4u16.to_be_bytes()[..]);
+        chunk.extend_from_slice(b"data");
+        for raw in [file, chunk] {
+            let mut datagram = b"1WAY".to_vec();
+            datagram.extend_from_slice(&(raw.len() as u16).to_be_bytes()[..]);
+            datagram.extend_from_slice(&raw[..]);
+            deliver(&mut handler, [datagram]).await;
+        }
+        handler.finalize_file(42).await;
+
+        assert_eq!(std::fs::read(root.path().join("file")).unwrap(), b"data");
+    }
+
+    #[tokio::test]
+    async fn corrupted_chunk_is_dropped() {
+        let root = tempfile::tempdir().unwrap();
+        let mut handler = client_handler(Config {
+            root: root.path().to_path_buf(),
+            ..Default::default()
+        });
+
+        send(&mut handler, file_message(1, "file", 4)).await;
+
+        let chunk = |content: &[u8]| chunk_message(1, 0, content);
+
+        // Content is last in the datagram, flip its last byte on every copy
+        let mut datagrams = message_datagrams(&chunk(b"data"), &handler.config)
+            .await
+            .unwrap();
+        for datagram in &mut datagrams {
+            *datagram.last_mut().unwrap() ^= 0xff;
+        }
+        deliver(&mut handler, datagrams).await;
+        handler
+            .opened_files
+            .get_mut(&1)
+            .unwrap()
+            .file
+            .flush()
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read(root.path().join("file.oneway-tmp")).unwrap(),
+            [0; 4]
+        );
+
+        send(&mut handler, chunk(b"data")).await;
+        send(&mut handler, chunk(b"")).await;
+        assert_eq!(std::fs::read(root.path().join("file")).unwrap(), b"data");
+    }
+
+    #[tokio::test]
+    async fn chunks_after_a_gap_wait_for_it() {
+        let root = tempfile::tempdir().unwrap();
+        let mut handler = client_handler(Config {
+            root: root.path().to_path_buf(),
+            mtu: 2048,
+            receiver_hash: Some(crate::HashAlgorithm::Sha256),
+            ..Default::default()
+        });
+        let content: Vec<u8> = (0..3072u32).map(|i| (i % 251) as u8).collect();
+
+        send(&mut handler, file_message(1, "file", content.len() as u64)).await;
+        for offset in [0, 2048, 1024] {
+            let chunk = &content[offset..offset + 1024];
+            send(&mut handler, chunk_message(1, offset as u64, chunk)).await;
+            if offset == 2048 {
+                assert_eq!(handler.opened_files[&1].bytes_ahead, 1024);
+            }
+        }
+
+        // Written in order, so the digest did not need the file to be read again
+        let opened_file = &handler.opened_files[&1];
+        assert!(opened_file.ahead.is_empty());
+        assert!(opened_file.hasher.is_some());
+
+        handler.finalize_file(1).await;
+        assert_eq!(std::fs::read(root.path().join("file")).unwrap(), content);
+    }
+
+    #[tokio::test]
+    async fn chunks_sent_again_are_written_once() {
+        let root = tempfile::tempdir().unwrap();
+        let mut handler = client_handler(Config {
+            root: root.path().to_path_buf(),
+            ..Default::default()
+        });
+        let content = b"written once".to_vec();
+
+        send(&mut handler, file_message(1, "file", content.len() as u64)).await;
+        for _ in 0..3 {
+            send(&mut handler, chunk_message(1, 0, &content)).await;
+        }
+        handler.finalize_file(1).await;
+
+        assert_eq!(handler.stats.duplicate_chunks, 2);
+        assert_eq!(handler.stats.disk_writes, 1);
+        assert_eq!(std::fs::read(root.path().join("file")).unwrap(), content);
+    }
+
+    #[tokio::test]
+    async fn complete_file_is_readable_after_done() {
+        let root = tempfile::tempdir().unwrap();
+        let mut handler = client_handler(Config {
+            root: root.path().to_path_buf(),
+            fsync_on_complete: true,
+            ..Default::default()
+        });
+        let content = b"synced to disk".to_vec();
+
+        send(&mut handler, file_message(1, "file", content.len() as u64)).await;
+        send(&mut handler, chunk_message(1, 0, &content)).await;
+        send(&mut handler, Message::Done).await;
+
+        assert_eq!(std::fs::read(root.path().join("file")).unwrap(), content);
+    }
+
+    #[tokio::test]
+    async fn file_appears_once_complete() {
+        let root = tempfile::tempdir().unwrap();
+        let mut handler = client_handler(Config {
+            root: root.path().to_path_buf(),
+            ..Default::default()
+        });
+        let names = || {
+            let mut names: Vec<_> = std::fs::read_dir(root.path())
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .collect();
+            names.sort();
+            names
+        };
+
+        send(&mut handler, file_message(1, "file", 8)).await;
+        for (offset, content) in [(0, &b"data"[..]), (4, b"more"), (8, b"")] {
+            assert_eq!(names(), ["file.oneway-tmp"]);
+            send(&mut handler, chunk_message(1, offset, content)).await;
+        }
+
+        assert_eq!(names(), ["file"]);
+        assert_eq!(
+            std::fs::read(root.path().join("file")).unwrap(),
+            b"datamore"
+        );
+        assert_eq!(handler.completed_files[&1], root.path().join("file"));
+    }
+
+    #[tokio::test]
+    async fn reannounced_file_follows_duplicate_policy() {
+        for (duplicate_file, expected) in [
+            (DuplicateFilePolicy::KeepFirst, ("first", 8)),
+            (DuplicateFilePolicy::Replace, ("second", 4)),
+        ] {
+            let root = tempfile::tempdir().unwrap();
+            let mut handler = client_handler(Config {
+                root: root.path().to_path_buf(),
+                duplicate_file,
+                ..Default::default()
+            });
+
+            for (filename, size) in [("first", 8), ("second", 4)] {
+                send(&mut handler, file_message(1, filename, size)).await;
+            }
+
+            let opened_file = &handler.opened_files[&1];
+            assert_eq!(opened_file.final_path, root.path().join(expected.0));
+            assert_eq!(opened_file.size, expected.1);
+            assert_eq!(handler.opened_files.len(), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn file_hash_is_verified() {
+        let root = tempfile::tempdir().unwrap();
+        let mut handler = client_handler(Config {
+            root: root.path().to_path_buf(),
+            ..Default::default()
+        });
+
+        for (id, filename, content) in [(1, "good", &b"datadata"[..]), (2, "truncated", b"data")] {
+            send(&mut handler, file_message(id, filename, 8)).await;
+            for content in [content, b""] {
+                send(&mut handler, chunk_message(id, 0, content)).await;
+            }
+
+            let content = std::fs::read(root.path().join("good")).unwrap();
+            let digest = <sha2::Sha256 as sha2::Digest>::digest(content).into();
+            send(&mut handler, Message::FileHash { id, digest }).await;
+        }
+
+        assert!(!handler.corrupted_files.contains(&1));
+        assert!(handler.corrupted_files.contains(&2));
+    }
+
+    #[tokio::test]
+    async fn received_files_are_hashed() {
+        let root = tempfile::tempdir().unwrap();
+        let mut handler = client_handler(Config {
+            root: root.path().to_path_buf(),
+            mtu: 1024,
+            receiver_hash: Some(crate::HashAlgorithm::Sha256),
+            ..Default::default()
+        });
+        let content: Vec<u8> = (0..3000u32).map(|i| (i % 251) as u8).collect();
+
+        // The first file arrives in order, the second one backwards
+        for (id, filename, reversed) in [(1, "in order", false), (2, "reversed", true)] {
+            send(
+                &mut handler,
+                file_message(id, filename, content.len() as u64),
+            )
+            .await;
+            let mut offsets: Vec<usize> = (0..content.len()).step_by(512).collect();
+            if reversed {
+                offsets.reverse();
+            }
+            offsets.push(content.len());
+            for offset in offsets {
+                let chunk = &content[offset..(offset + 512).min(content.len())];
+                send(&mut handler, chunk_message(id, offset as u64, chunk)).await;
+            }
+        }
+
+        for (id, filename) in [(1, "in order"), (2, "reversed")] {
+            let content = std::fs::read(root.path().join(filename)).unwrap();
+            let digest = <sha2::Sha256 as sha2::Digest>::digest(content);
+            assert_eq!(handler.stats().file_digests[&id], digest[..]);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    #[tracing_test::traced_test]
+    async fn idle_handler_releases_its_buffers() {
+        let root = tempfile::tempdir().unwrap();
+        let config = Arc::new(Config {
+            root: root.path().to_path_buf(),
+            file_idle_timeout: Duration::from_secs(5),
+            recv_timeout: Duration::from_secs(60),
+            ..Default::default()
+        });
+        let (sender, receiver) = mpsc::channel(1);
+        let (kill_tx, _kill_rx) = mpsc::channel(1);
+        let handler = ClientHandler::new(
+            "127.0.0.1:1".parse().unwrap(),
+            config.root.clone(),
+            config.clone(),
+            receiver,
+            kill_tx,
+        );
+        let handler = tokio::spawn(handler.run());
+
+        for datagram in message_datagrams(&Message::KeepAlive(0), &config)
+            .await
+            .unwrap()
+        {
+            sender.send(datagram).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_secs(4)).await;
+        assert!(!logs_contain("releasing buffers"));
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert!(logs_contain("releasing buffers"));
+        assert!(!handler.is_finished());
+
+        drop(sender);
+        handler.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_handler_exits_after_recv_timeout() {
+        let root = tempfile::tempdir().unwrap();
+        let config = Arc::new(Config {
+            root: root.path().to_path_buf(),
+            recv_timeout: Duration::from_secs(3),
+            ..Default::default()
+        });
+        let client_addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let (sender, receiver) = mpsc::channel(1);
+        let (kill_tx, mut kill_rx) = mpsc::channel(1);
+        let handler = ClientHandler::new(
+            client_addr,
+            config.root.clone(),
+            config.clone(),
+            receiver,
+            kill_tx,
+        );
+
+        // Keep-alives arriving within `recv_timeout` keep the handler running, then it stalls
+        let keep_alives = tokio::spawn({
+            let config = config.clone();
+            async move {
+                for i in 0..4 {
+                    tokio::time::sleep(Duration::from_secs(2)).await;
+                    let datagrams = message_datagrams(&Message::KeepAlive(i), &config)
+                        .await
+                        .unwrap();
+                    for datagram in datagrams {
+                        sender.send(datagram).await.unwrap();
+                    }
+                }
+                sender
             }
-            Err(Error::NoData) => Ok(false),
-            Err(Error::Deserialize(nom::Err::Incomplete(n))) => {
-                match n {
-                    nom::Needed::Unknown => tracing::trace!("Missing some bytes"),
-                    nom::Needed::Size(s) => tracing::trace!("Missing {} bytes", s),
+        });
+
+        let start = Instant::now();
+        handler.run().await;
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_secs(8 + 3));
+        assert!(elapsed < Duration::from_secs(8 + 4));
+        assert_eq!(kill_rx.recv().await, Some(client_addr));
+        drop(keep_alives.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn server_receives_from_any_datagram_reader() {
+        use crate::connection::Client;
+        use crate::testing::{MemoryReader, MemoryTransport};
+
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        std::fs::write(source.path().join("file"), b"no socket involved").unwrap();
+
+        let mut client = Client::new_with_config(
+            MemoryTransport::new(),
+            Config {
+                root: source.path().to_path_buf(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        client.send_hello().await.unwrap();
+        client.send_files(&[PathBuf::from("file")]).await.unwrap();
+        client.send_done().await.unwrap();
+
+        let (sender, reader) = MemoryReader::new();
+        let mut server = Server::new_with_config(
+            reader,
+            Config {
+                root: destination.path().to_path_buf(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let client_addr: SocketAddr = "192.0.2.1:4242".parse().unwrap();
+        let datagrams = client.socket().take_datagrams();
+        for datagram in &datagrams {
+            sender.send((client_addr, datagram.clone())).unwrap();
+        }
+        for _ in &datagrams {
+            server.recv_message().await.unwrap();
+        }
+        // Nothing else arrives, the handler is waited for
+        server.serve_until(std::future::ready(())).await.unwrap();
+
+        assert_eq!(
+            std::fs::read(destination.path().join("file")).unwrap(),
+            b"no socket involved"
+        );
+        assert_eq!(server.stats()[&client_addr].files_completed, 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn file_closed_idle_between_passes_is_kept() {
+        let root = tempfile::tempdir().unwrap();
+        let mut handler = client_handler(Config {
+            root: root.path().to_path_buf(),
+            ..Default::default()
+        });
+
+        // The first pass does not end the file, which is closed idle while others are sent
+        for message in [
+            file_message(1, "critical", 8),
+            chunk_message(1, 0, b"abcd"),
+            chunk_message(1, 4, b"efgh"),
+        ] {
+            send(&mut handler, message).await;
+        }
+        tokio::time::advance(handler.config.file_idle_timeout + Duration::from_secs(1)).await;
+        handler.close_idle_files().await;
+        assert_eq!(
+            std::fs::read(root.path().join("critical")).unwrap(),
+            b"abcdefgh"
+        );
+
+        // The last pass loses its first chunk
+        for message in [
+            file_message(1, "critical", 8),
+            chunk_message(1, 4, b"efgh"),
+            chunk_message(1, 8, b""),
+        ] {
+            send(&mut handler, message).await;
+        }
+
+        assert_eq!(
+            std::fs::read(root.path().join("critical")).unwrap(),
+            b"abcdefgh"
+        );
+        assert_eq!(handler.stats().files_completed, 1);
+        assert!(handler.stats().incomplete_files.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn client_resuming_after_idle_gets_a_new_handler() {
+        use crate::testing::MemoryReader;
+
+        let destination = tempfile::tempdir().unwrap();
+        let (sender, reader) = MemoryReader::new();
+        let config = Config {
+            root: destination.path().to_path_buf(),
+            remission_count: 1,
+            recv_timeout: Duration::from_secs(1),
+            ..Default::default()
+        };
+        let mut server = Server::new_with_config(reader, config.clone()).unwrap();
+        let client_addr: SocketAddr = "192.0.2.1:4242".parse().unwrap();
+
+        for (id, name) in [(1, "before"), (2, "after")] {
+            for message in [
+                file_message(id, name, 4),
+                chunk_message(id, 0, b"data"),
+                chunk_message(id, 4, b""),
+            ] {
+                for datagram in message_datagrams(&message, &config).await.unwrap() {
+                    sender.send((client_addr, datagram)).unwrap();
+                    server.recv_message().await.unwrap();
                 }
+            }
+            // The handler ends once idle, the next datagram finds its channel closed
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        }
+        server.serve_until(std::future::ready(())).await.unwrap();
 
-                Ok(false)
+        for name in ["before", "after"] {
+            assert_eq!(
+                std::fs::read(destination.path().join(name)).unwrap(),
+                b"data"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_flushes_open_files() {
+        use tokio::net::UdpSocket;
+        use tokio::sync::oneshot;
+
+        let destination = tempfile::tempdir().unwrap();
+        let content = b"flushed on shutdown";
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = socket.local_addr().unwrap();
+        let mut server = Server::new_with_config(
+            UdpReader::new(socket).unwrap(),
+            Config {
+                root: destination.path().to_path_buf(),
+                recv_timeout: Duration::from_secs(60),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let serving = tokio::spawn(async move {
+            server
+                .serve_until(async {
+                    let _ = shutdown_rx.await;
+                })
+                .await
+        });
+
+        // The terminator of the file is never sent, it stays open on the server
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(server_addr).await.unwrap();
+        for message in [
+            file_message(1, "data", content.len() as u64),
+            chunk_message(1, 0, content),
+        ] {
+            for datagram in message_datagrams(&message, &Config::default())
+                .await
+                .unwrap()
+            {
+                socket.send(&datagram[..]).await.unwrap();
             }
-            Err(e) => Err(e),
         }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        shutdown_tx.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), serving)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            std::fs::read(destination.path().join("data")).unwrap(),
+            content
+        );
     }
 
-    pub async fn process_buffer(&mut self, buffer: &[u8]) -> bool {
-        match self.process_buffer_internal(buffer).await {
-            Ok(done) => done,
-            Err(e) => {
-                tracing::error!("[{}] error: {}", self.client_addr(), e);
-                true
+    #[tokio::test]
+    async fn stats_count_received_bytes() {
+        use tokio::net::UdpSocket;
+        use tokio::sync::oneshot;
+
+        let destination = tempfile::tempdir().unwrap();
+        let content: Vec<u8> = (0..1200u32).map(|i| i as u8).collect();
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = socket.local_addr().unwrap();
+        let mut server = Server::new_with_config(
+            UdpReader::new(socket).unwrap(),
+            Config {
+                root: destination.path().to_path_buf(),
+                recv_timeout: Duration::from_secs(60),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let serving = tokio::spawn(async move {
+            server
+                .serve_until(async {
+                    let _ = shutdown_rx.await;
+                })
+                .await
+                .map(|()| server)
+        });
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(server_addr).await.unwrap();
+        let client_addr = socket.local_addr().unwrap();
+        let mut messages = vec![file_message(1, "data", content.len() as u64)];
+        for (i, chunk) in content.chunks(400).enumerate() {
+            messages.push(chunk_message(1, (i * 400) as u64, chunk));
+        }
+        messages.push(Message::Done);
+        for message in messages {
+            for datagram in message_datagrams(&message, &Config::default())
+                .await
+                .unwrap()
+            {
+                socket.send(&datagram[..]).await.unwrap();
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        shutdown_tx.send(()).unwrap();
+        let server = tokio::time::timeout(Duration::from_secs(5), serving)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        let stats = server.stats();
+        let stats = &stats[&client_addr];
+        assert_eq!(stats.bytes_received, content.len() as u64);
+        assert_eq!(stats.chunks_received, 3);
+        assert_eq!(stats.duplicate_chunks, 0);
+        assert_eq!(stats.files_completed, 1);
+    }
+
+    #[tokio::test]
+    async fn write_buffer_depends_on_file_size() {
+        let root = tempfile::tempdir().unwrap();
+        let mut handler = client_handler(Config {
+            root: root.path().to_path_buf(),
+            write_buffer_size: 1024 * 1024,
+            small_file_threshold: 64 * 1024,
+            small_file_write_buffer_size: 4096,
+            ..Default::default()
+        });
+        let file = |id, size| file_message(id, &format!("file{}", id), size);
+
+        send(&mut handler, file(1, 1000)).await;
+        send(&mut handler, file(2, 10 * 1024 * 1024)).await;
+        assert_eq!(handler.opened_files[&1].buffer_size, 4096);
+        assert_eq!(handler.opened_files[&2].buffer_size, 1024 * 1024);
+    }
+
+    #[tokio::test]
+    async fn contiguous_chunks_are_combined() {
+        let root = tempfile::tempdir().unwrap();
+        let mut handler = client_handler(Config {
+            root: root.path().to_path_buf(),
+            write_buffer_size: 4096,
+            ..Default::default()
+        });
+
+        const CHUNKS: u64 = 16;
+        const CHUNK_SIZE: u64 = 100;
+        send(
+            &mut handler,
+            file_message(1, "combined", CHUNKS * CHUNK_SIZE),
+        )
+        .await;
+        for i in 0..=CHUNKS {
+            let content = if i < CHUNKS {
+                vec![i as u8 + 1; CHUNK_SIZE as usize]
+            } else {
+                Vec::new()
+            };
+            send(&mut handler, chunk_message(1, i * CHUNK_SIZE, &content)).await;
+        }
+
+        assert!(handler.stats().disk_writes < CHUNKS);
+        let content = std::fs::read(root.path().join("combined")).unwrap();
+        assert_eq!(content.len() as u64, CHUNKS * CHUNK_SIZE);
+        for (i, chunk) in content.chunks(CHUNK_SIZE as usize).enumerate() {
+            assert!(chunk.iter().all(|b| *b == i as u8 + 1));
+        }
+    }
+
+    #[tokio::test]
+    async fn padded_transfer_reconstructs_files() {
+        use crate::connection::Client;
+        use crate::testing::MemoryTransport;
+
+        let source = tempfile::tempdir().unwrap();
+        let content: Vec<u8> = (0..5000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(source.path().join("data"), &content[..]).unwrap();
+
+        for pad_datagrams in [false, true] {
+            let destination = tempfile::tempdir().unwrap();
+            let mut client = Client::new_with_config(
+                MemoryTransport::new(),
+                Config {
+                    root: source.path().to_path_buf(),
+                    pad_datagrams,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            client.send_files(&[PathBuf::from("data")]).await.unwrap();
+
+            let mut handler = client_handler(Config {
+                root: destination.path().to_path_buf(),
+                pad_datagrams,
+                ..Default::default()
+            });
+            deliver(&mut handler, client.socket().take_datagrams()).await;
+
+            let received = std::fs::read(destination.path().join("data")).unwrap();
+            assert_eq!(received, content);
+        }
+    }
+
+    #[cfg(feature = "compression")]
+    #[tokio::test]
+    async fn compressed_transfer_reconstructs_files() {
+        use crate::connection::Client;
+        use crate::testing::MemoryTransport;
+
+        let source = tempfile::tempdir().unwrap();
+        let content = b"a highly compressible line\n".repeat(10_000);
+        std::fs::write(source.path().join("data"), &content[..]).unwrap();
+
+        let mut datagrams_sent = Vec::new();
+        for compress in [false, true] {
+            let destination = tempfile::tempdir().unwrap();
+            let mut client = Client::new_with_config(
+                MemoryTransport::new(),
+                Config {
+                    root: source.path().to_path_buf(),
+                    compress,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            client.send_files(&[PathBuf::from("data")]).await.unwrap();
+
+            let mut handler = client_handler(Config {
+                root: destination.path().to_path_buf(),
+                ..Default::default()
+            });
+            let datagrams = client.socket().take_datagrams();
+            datagrams_sent.push(datagrams.len());
+            deliver(&mut handler, datagrams).await;
+
+            let received = std::fs::read(destination.path().join("data")).unwrap();
+            assert_eq!(received, content);
+            assert!(handler.corrupted_files.is_empty());
+        }
+        assert!(datagrams_sent[1] * 10 < datagrams_sent[0]);
+    }
+
+    #[cfg(feature = "dedup")]
+    #[tokio::test]
+    async fn shared_regions_are_sent_once() {
+        use crate::connection::Client;
+        use crate::testing::MemoryTransport;
+        use std::sync::atomic::{AtomicU64, Ordering};
+
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        let random = |size| {
+            let mut content = vec![0u8; size];
+            getrandom::getrandom(&mut content[..]).unwrap();
+            content
+        };
+        // The shared region is at different offsets in each file
+        let shared = random(200_000);
+        let first = [random(10_000), shared.clone()].concat();
+        let second = [random(5_000), shared, random(3_000)].concat();
+        std::fs::write(source.path().join("first"), &first[..]).unwrap();
+        std::fs::write(source.path().join("second"), &second[..]).unwrap();
+
+        let mut client = Client::new_with_config(
+            MemoryTransport::new(),
+            Config {
+                root: source.path().to_path_buf(),
+                dedup: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        client
+            .send_files(&[PathBuf::from("first"), PathBuf::from("second")])
+            .await
+            .unwrap();
+
+        let chunk_bytes = Arc::new(AtomicU64::new(0));
+        let counter = Arc::clone(&chunk_bytes);
+        let mut handler = client_handler(Config {
+            root: destination.path().to_path_buf(),
+            ..Default::default()
+        })
+        .with_message_hook(Arc::new(move |message: &Message| {
+            if let Message::FileChunk { content_size, .. } = message {
+                counter.fetch_add(u64::from(*content_size), Ordering::Relaxed);
+            }
+            MessageAction::Accept
+        }));
+        deliver(&mut handler, client.socket().take_datagrams()).await;
+
+        assert_eq!(
+            std::fs::read(destination.path().join("first")).unwrap(),
+            first
+        );
+        assert_eq!(
+            std::fs::read(destination.path().join("second")).unwrap(),
+            second
+        );
+        assert!(handler.corrupted_files.is_empty());
+        let chunk_bytes = chunk_bytes.load(Ordering::Relaxed);
+        let combined = (first.len() + second.len()) as u64;
+        assert!(
+            chunk_bytes < combined - 150_000,
+            "{} bytes of chunks sent for {} bytes of files",
+            chunk_bytes,
+            combined
+        );
+    }
+
+    #[tokio::test]
+    async fn tree_is_checked_against_manifest() {
+        use crate::connection::Client;
+        use crate::testing::MemoryTransport;
+
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        for name in ["a", "b", "dir/c"] {
+            let path = source.path().join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, name.as_bytes()).unwrap();
+        }
+        std::fs::write(destination.path().join("stale"), b"stale").unwrap();
+
+        let mut client = Client::new_with_config(
+            MemoryTransport::new(),
+            Config {
+                root: source.path().to_path_buf(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        // "b" is lost on the way
+        client
+            .send_files(&[PathBuf::from("a"), PathBuf::from("dir/c")])
+            .await
+            .unwrap();
+        client
+            .send_manifest(&[
+                PathBuf::from("a"),
+                PathBuf::from("b"),
+                PathBuf::from("dir/c"),
+            ])
+            .await
+            .unwrap();
+        client.send_done().await.unwrap();
+
+        let mut handler = client_handler(Config {
+            root: destination.path().to_path_buf(),
+            ..Default::default()
+        });
+        deliver(&mut handler, client.socket().take_datagrams()).await;
+
+        assert_eq!(
+            handler.stats().tree_diff,
+            Some(TreeDiff {
+                missing: vec![PathBuf::from("b")],
+                extra: vec![PathBuf::from("stale")],
+                mismatched: Vec::new(),
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn partial_update_only_writes_its_range() {
+        use crate::connection::Client;
+        use crate::testing::MemoryTransport;
+
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        let original = vec![b'a'; 4000];
+        std::fs::write(destination.path().join("db"), &original[..]).unwrap();
+        // Bytes changed outside of the range are not sent
+        let mut updated = original.clone();
+        updated[1000..2500].fill(b'b');
+        updated[3000] = b'c';
+        std::fs::write(source.path().join("db"), &updated[..]).unwrap();
+
+        let mut client = Client::new_with_config(
+            MemoryTransport::new(),
+            Config {
+                root: source.path().to_path_buf(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        client
+            .send_file_range(Path::new("db"), 1000..2500)
+            .await
+            .unwrap();
+
+        let mut handler = client_handler(Config {
+            root: destination.path().to_path_buf(),
+            ..Default::default()
+        });
+        deliver(&mut handler, client.socket().take_datagrams()).await;
+
+        let mut expected = original;
+        expected[1000..2500].fill(b'b');
+        assert_eq!(
+            std::fs::read(destination.path().join("db")).unwrap(),
+            expected
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn permissions_are_preserved() {
+        use crate::connection::Client;
+        use crate::testing::MemoryTransport;
+        use std::os::unix::fs::PermissionsExt;
+
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        let path = source.path().join("script");
+        std::fs::write(&path, b"#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o750)).unwrap();
+
+        let mut client = Client::new_with_config(
+            MemoryTransport::new(),
+            Config {
+                root: source.path().to_path_buf(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        client.send_files(&[PathBuf::from("script")]).await.unwrap();
+
+        let mut handler = client_handler(Config {
+            root: destination.path().to_path_buf(),
+            ..Default::default()
+        });
+        deliver(&mut handler, client.socket().take_datagrams()).await;
+
+        let metadata = std::fs::metadata(destination.path().join("script")).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o7777, 0o750);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn configured_modes_replace_the_client_ones() {
+        use crate::connection::Client;
+        use crate::testing::MemoryTransport;
+        use std::os::unix::fs::PermissionsExt;
+
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        std::fs::create_dir(source.path().join("dir")).unwrap();
+        let path = source.path().join("dir/script");
+        std::fs::write(&path, b"#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let mut client = Client::new_with_config(
+            MemoryTransport::new(),
+            Config {
+                root: source.path().to_path_buf(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        client
+            .send_files(&[PathBuf::from("dir/script")])
+            .await
+            .unwrap();
+
+        let mut handler = client_handler(Config {
+            root: destination.path().to_path_buf(),
+            file_mode: Some(0o640),
+            dir_mode: Some(0o700),
+            ..Default::default()
+        });
+        deliver(&mut handler, client.socket().take_datagrams()).await;
+
+        let mode = |path: &str| {
+            let metadata = std::fs::metadata(destination.path().join(path)).unwrap();
+            metadata.permissions().mode() & 0o7777
+        };
+        assert_eq!(mode("dir/script"), 0o640);
+        assert_eq!(mode("dir"), 0o700);
+    }
+
+    #[tokio::test]
+    async fn modification_time_is_preserved() {
+        use crate::connection::Client;
+        use crate::testing::MemoryTransport;
+
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        let file = tempfile::NamedTempFile::new_in(source.path()).unwrap();
+        std::fs::write(file.path(), b"content").unwrap();
+        file.as_file().set_modified(modified).unwrap();
+        let files = [PathBuf::from(file.path().file_name().unwrap())];
+
+        // The creation time is not available everywhere, the transfer must not depend on it
+        let mut client = Client::new_with_config(
+            MemoryTransport::new(),
+            Config {
+                root: source.path().to_path_buf(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        client.send_files(&files).await.unwrap();
+
+        let mut handler = client_handler(Config {
+            root: destination.path().to_path_buf(),
+            ..Default::default()
+        });
+        deliver(&mut handler, client.socket().take_datagrams()).await;
+
+        let metadata = std::fs::metadata(destination.path().join(&files[0])).unwrap();
+        assert_eq!(metadata.modified().unwrap(), modified);
+        assert_eq!(
+            std::fs::read(destination.path().join(&files[0])).unwrap(),
+            b"content"
+        );
+    }
+
+    #[tokio::test]
+    async fn empty_directories_are_recreated() {
+        use crate::connection::Client;
+        use crate::testing::MemoryTransport;
+        use crate::tree::{walk, Entry, WalkOptions};
+
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(source.path().join("a/b/empty")).unwrap();
+        std::fs::write(source.path().join("a/file"), b"content").unwrap();
+
+        let options = WalkOptions {
+            include_empty_dirs: true,
+            ..Default::default()
+        };
+        let files: Vec<_> = walk(source.path(), &options, |_| true)
+            .unwrap()
+            .into_iter()
+            .map(Entry::into_path)
+            .collect();
+        let mut client = Client::new_with_config(
+            MemoryTransport::new(),
+            Config {
+                root: source.path().to_path_buf(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        client.send_files(&files[..]).await.unwrap();
+
+        let mut handler = client_handler(Config {
+            root: destination.path().to_path_buf(),
+            ..Default::default()
+        });
+        deliver(&mut handler, client.socket().take_datagrams()).await;
+
+        assert!(destination.path().join("a/b/empty").is_dir());
+        assert_eq!(
+            std::fs::read(destination.path().join("a/file")).unwrap(),
+            b"content"
+        );
+    }
+
+    #[tokio::test]
+    async fn paths_outside_root_are_refused() {
+        let parent = tempfile::tempdir().unwrap();
+        let root = parent.path().join("root");
+        std::fs::create_dir(&root).unwrap();
+        let mut handler = client_handler(Config {
+            root: root.clone(),
+            ..Default::default()
+        });
+        let absolute = parent.path().join("absolute");
+
+        for (id, filename) in [
+            "../escape",
+            "dir/../../escape",
+            absolute.to_str().unwrap(),
+            "C:escape",
+            "dir/sub/./file",
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            send(&mut handler, file_message(id as u64, filename, 0)).await;
+        }
+
+        // Files are kept under a temporary name until complete, only directories are compared
+        let names = |path: &Path| -> Vec<_> {
+            std::fs::read_dir(path)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name())
+                .collect()
+        };
+        assert_eq!(names(parent.path()), ["root"]);
+        assert_eq!(names(&root), ["dir"]);
+        assert_eq!(names(&root.join("dir")), ["sub"]);
+        assert_eq!(names(&root.join("dir/sub")).len(), 1);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn symlinks_are_replicated_inside_root() {
+        let root = tempfile::tempdir().unwrap();
+        let mut handler = client_handler(Config {
+            root: root.path().to_path_buf(),
+            ..Default::default()
+        });
+
+        for (link_path, target) in [
+            ("dir/link", "../file"),
+            ("dir/escape", "../../outside"),
+            ("absolute", "/etc/passwd"),
+        ] {
+            send(
+                &mut handler,
+                Message::Symlink {
+                    link_path: String::from(link_path),
+                    target: String::from(target),
+                },
+            )
+            .await;
+        }
+
+        assert_eq!(
+            std::fs::read_link(root.path().join("dir/link")).unwrap(),
+            PathBuf::from("../file")
+        );
+        assert!(std::fs::symlink_metadata(root.path().join("dir/escape")).is_err());
+        assert!(std::fs::symlink_metadata(root.path().join("absolute")).is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn chained_symlinks_cannot_escape_root() {
+        let parent = tempfile::tempdir().unwrap();
+        let root = parent.path().join("root");
+        std::fs::create_dir(&root).unwrap();
+        let mut handler = client_handler(Config {
+            root: root.clone(),
+            ..Default::default()
+        });
+
+        for (link_path, target) in [("a", "."), ("a/b", ".."), ("c", "a/..")] {
+            send(
+                &mut handler,
+                Message::Symlink {
+                    link_path: String::from(link_path),
+                    target: String::from(target),
+                },
+            )
+            .await;
+        }
+        send(&mut handler, file_message(1, "a/b/x", 0)).await;
+
+        // Only the link pointing at root itself is created, nothing is written through it
+        assert_eq!(
+            std::fs::read_link(root.join("a")).unwrap(),
+            PathBuf::from(".")
+        );
+        assert!(std::fs::symlink_metadata(root.join("b")).is_err());
+        assert!(std::fs::symlink_metadata(root.join("c")).is_err());
+        let names: Vec<_> = std::fs::read_dir(parent.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, ["root"]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn fifo_is_recreated() {
+        use std::os::unix::fs::FileTypeExt;
+
+        let root = tempfile::tempdir().unwrap();
+        let mut handler = client_handler(Config {
+            root: root.path().to_path_buf(),
+            ..Default::default()
+        });
+
+        send(
+            &mut handler,
+            Message::SpecialFile {
+                path: String::from("dir/fifo"),
+                kind: SpecialKind::Fifo,
+                rdev: 0,
+            },
+        )
+        .await;
+
+        let metadata = std::fs::symlink_metadata(root.path().join("dir/fifo")).unwrap();
+        assert!(metadata.file_type().is_fifo());
+    }
+
+    #[tokio::test]
+    async fn staged_files_are_committed_on_done() {
+        let root = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        let inside = tempfile::tempdir_in(root.path()).unwrap();
+
+        for staging in [inside.path(), outside.path()] {
+            let mut handler = client_handler(Config {
+                root: root.path().to_path_buf(),
+                staging_dir: Some(staging.to_path_buf()),
+                commit_on_done: true,
+                ..Default::default()
+            });
+
+            for (id, filename) in [(1, "a"), (2, "dir/b")] {
+                send(&mut handler, file_message(id, filename, 4)).await;
+                for content in [&b"data"[..], b""] {
+                    send(&mut handler, chunk_message(id, 0, content)).await;
+                }
             }
+
+            assert!(!root.path().join("a").exists());
+            assert!(!root.path().join("dir/b").exists());
+
+            send(&mut handler, Message::Done).await;
+            assert_eq!(std::fs::read(root.path().join("a")).unwrap(), b"data");
+            assert_eq!(std::fs::read(root.path().join("dir/b")).unwrap(), b"data");
+
+            std::fs::remove_file(root.path().join("a")).unwrap();
+            std::fs::remove_dir_all(root.path().join("dir")).unwrap();
         }
     }
 }
@@ -0,0 +1,378 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::io::SeekFrom;
+use std::path::Path;
+use std::time::SystemTime;
+
+use crate::config::HashAlgorithm;
+use crate::{Config, Result};
+
+use sha2::{Digest, Sha256, Sha512};
+use tokio::fs::File;
+use tokio::io::{AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::Mutex;
+
+/// Error of the operations a sink does not support, which the handler skips
+fn unsupported(operation: &str) -> crate::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        format!("{} is not supported by this sink", operation),
+    )
+    .into()
+}
+
+/// Where a `ClientHandler` stores the content of the files it receives
+///
+/// Paths are the ones the files would have under `root`. The content, permissions, ACLs, chunk
+/// copies and digests of the files go through the sink, whose operations returning an
+/// `Unsupported` error are skipped. Only `commit_on_done` and the tree check still work on the
+/// filesystem, so they only apply with `FsSink`.
+pub trait Sink: Send + Sync + 'static {
+    /// File being received
+    type Handle: Send + Sync + 'static;
+
+    /// Opens `path` to receive file `id` of `size` bytes, keeping its content for a `partial`
+    /// update
+    fn create(
+        &self,
+        id: u64,
+        path: &Path,
+        size: u64,
+        partial: bool,
+    ) -> impl Future<Output = Result<Self::Handle>> + Send;
+
+    /// Writes `bytes` at `offset` of the file
+    fn write_at(
+        &self,
+        handle: &mut Self::Handle,
+        offset: u64,
+        bytes: &[u8],
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// Completes a file received entirely, last modified at `modified`
+    fn finish(
+        &self,
+        handle: Self::Handle,
+        modified: SystemTime,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// Moves a file from `from` to `to`
+    fn rename(&self, from: &Path, to: &Path) -> impl Future<Output = Result<()>> + Send;
+
+    /// Applies the Unix permission bits `mode` to the file at `path`
+    fn set_mode(&self, _path: &Path, _mode: u32) -> impl Future<Output = Result<()>> + Send {
+        std::future::ready(Err(unsupported("Setting permissions")))
+    }
+
+    /// Applies the serialized POSIX `acl` to the file at `path`
+    fn set_acl(&self, _path: &Path, _acl: &[u8]) -> impl Future<Output = Result<()>> + Send {
+        std::future::ready(Err(unsupported("Setting ACLs")))
+    }
+
+    /// Reads the `size` bytes at `offset` of the file at `path`, for chunk copies
+    fn read_range(
+        &self,
+        _path: &Path,
+        _offset: u64,
+        _size: usize,
+    ) -> impl Future<Output = Result<Vec<u8>>> + Send {
+        std::future::ready(Err(unsupported("Reading files")))
+    }
+
+    /// Digest of the content of the file at `path`
+    fn hash(
+        &self,
+        _path: &Path,
+        _algorithm: HashAlgorithm,
+    ) -> impl Future<Output = Result<Vec<u8>>> + Send {
+        std::future::ready(Err(unsupported("Hashing files")))
+    }
+}
+
+/// Stores files on the filesystem, the default `Sink`
+#[derive(Debug, Clone)]
+pub struct FsSink {
+    /// Whether complete files are synced to disk, see `fsync_on_complete`
+    fsync: bool,
+
+    /// Mode of the directories created to hold the files, see `dir_mode`
+    dir_mode: Option<u32>,
+}
+
+impl FsSink {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            fsync: config.fsync_on_complete,
+            dir_mode: config.dir_mode,
+        }
+    }
+}
+
+impl Sink for FsSink {
+    type Handle = File;
+
+    async fn create(&self, _id: u64, path: &Path, size: u64, partial: bool) -> Result<File> {
+        if partial {
+            crate::utils::fs::open_file_for_update(path, size, self.dir_mode).await
+        } else {
+            crate::utils::fs::create_file(path, size, self.dir_mode).await
+        }
+    }
+
+    async fn write_at(&self, file: &mut File, offset: u64, bytes: &[u8]) -> Result<()> {
+        file.seek(SeekFrom::Start(offset)).await?;
+        file.write_all(bytes).await?;
+        // Errors of the write are only reported once flushed
+        file.flush().await?;
+        Ok(())
+    }
+
+    async fn finish(&self, mut file: File, modified: SystemTime) -> Result<()> {
+        file.flush().await?;
+        if self.fsync {
+            file.sync_all().await?;
+        }
+        crate::utils::fs::set_modified(file, modified).await
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        tokio::fs::rename(from, to).await?;
+        Ok(())
+    }
+
+    async fn set_mode(&self, path: &Path, mode: u32) -> Result<()> {
+        crate::utils::fs::set_mode(path, mode).await
+    }
+
+    async fn set_acl(&self, path: &Path, acl: &[u8]) -> Result<()> {
+        Ok(crate::utils::acl::write_acl(path, acl)?)
+    }
+
+    async fn read_range(&self, path: &Path, offset: u64, size: usize) -> Result<Vec<u8>> {
+        crate::utils::fs::read_range(path, offset, size).await
+    }
+
+    async fn hash(&self, path: &Path, algorithm: HashAlgorithm) -> Result<Vec<u8>> {
+        crate::utils::fs::hash_file(path, algorithm).await
+    }
+}
+
+/// Writes the content of a single file to a stream, such as stdout for `receiver | tar -x`
+///
+/// Bytes are emitted in order: chunks arriving early are kept until the ones before them arrive.
+/// They are hashed as they are emitted, for the digests to be checked.
+/// Creating a file with another id than the first one fails, as does a partial update. Renames
+/// are ignored, the file having no path.
+#[derive(Debug)]
+pub struct WriterSink<W> {
+    state: Mutex<WriterState<W>>,
+}
+
+#[derive(Debug)]
+struct WriterState<W> {
+    writer: W,
+
+    /// Id of the file written, once created
+    id: Option<u64>,
+
+    /// Bytes emitted so far
+    offset: u64,
+
+    /// Chunks received ahead of `offset`
+    ahead: BTreeMap<u64, Vec<u8>>,
+
+    /// Digests of the bytes emitted, the file being nowhere to be read again
+    sha256: Sha256,
+    sha512: Sha512,
+}
+
+impl<W> WriterSink<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            state: Mutex::new(WriterState {
+                writer,
+                id: None,
+                offset: 0,
+                ahead: BTreeMap::new(),
+                sha256: Sha256::new(),
+                sha512: Sha512::new(),
+            }),
+        }
+    }
+
+    /// Gives the stream back, once the server is done with the sink
+    pub fn into_inner(self) -> W {
+        self.state.into_inner().writer
+    }
+}
+
+impl<W: AsyncWrite + Unpin> WriterState<W> {
+    /// Emits the part of `bytes` at `offset` not emitted yet, then the chunks it makes contiguous
+    async fn write_at(&mut self, offset: u64, bytes: &[u8]) -> Result<()> {
+        if offset > self.offset {
+            self.ahead.insert(offset, bytes.to_vec());
+            return Ok(());
+        }
+        self.emit(offset, bytes).await?;
+
+        while let Some(entry) = self.ahead.first_entry() {
+            if *entry.key() > self.offset {
+                break;
+            }
+            let (offset, chunk) = entry.remove_entry();
+            self.emit(offset, &chunk[..]).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the bytes of `bytes`, at `offset` before or at `self.offset`, past `self.offset`
+    async fn emit(&mut self, offset: u64, bytes: &[u8]) -> Result<()> {
+        let skipped = (self.offset - offset).min(bytes.len() as u64) as usize;
+        let bytes = &bytes[skipped..];
+        self.writer.write_all(bytes).await?;
+        self.sha256.update(bytes);
+        self.sha512.update(bytes);
+        self.offset += bytes.len() as u64;
+        Ok(())
+    }
+}
+
+impl<W: AsyncWrite + Unpin + Send + Sync + 'static> Sink for WriterSink<W> {
+    /// Id of the file
+    type Handle = u64;
+
+    async fn create(&self, id: u64, path: &Path, _size: u64, partial: bool) -> Result<u64> {
+        let mut state = self.state.lock().await;
+        if partial {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                format!("Cannot update {} in place in a stream", path.display()),
+            )
+            .into());
+        }
+        match state.id {
+            Some(expected) if expected != id => Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!(
+                    "Cannot write {} (id: 0x{:x}) to a stream which received file 0x{:x}",
+                    path.display(),
+                    id,
+                    expected
+                ),
+            )
+            .into()),
+            _ => {
+                state.id = Some(id);
+                Ok(id)
+            }
+        }
+    }
+
+    async fn write_at(&self, _id: &mut u64, offset: u64, bytes: &[u8]) -> Result<()> {
+        self.state.lock().await.write_at(offset, bytes).await
+    }
+
+    async fn finish(&self, _id: u64, _modified: SystemTime) -> Result<()> {
+        let mut state = self.state.lock().await;
+        state.writer.flush().await?;
+        if let Some((offset, _)) = state.ahead.first_key_value() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!(
+                    "Stream ended after {} bytes, {} bytes before offset {} are missing",
+                    state.offset,
+                    offset - state.offset,
+                    offset
+                ),
+            )
+            .into());
+        }
+
+        Ok(())
+    }
+
+    async fn rename(&self, _from: &Path, _to: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    async fn hash(&self, _path: &Path, algorithm: HashAlgorithm) -> Result<Vec<u8>> {
+        let state = self.state.lock().await;
+        Ok(match algorithm {
+            HashAlgorithm::Sha256 => state.sha256.clone().finalize().to_vec(),
+            HashAlgorithm::Sha512 => state.sha512.clone().finalize().to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::connection::Client;
+    use crate::testing::{client_handler_with_sink, deliver, MemoryTransport};
+
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    #[tracing_test::traced_test]
+    async fn file_is_written_to_the_stream() {
+        let source = tempfile::tempdir().unwrap();
+        let content: Vec<u8> = (0..5000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(source.path().join("archive.tar"), &content).unwrap();
+
+        let mut client = Client::new_with_config(
+            MemoryTransport::new(),
+            Config {
+                root: source.path().to_path_buf(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        client
+            .send_files(&[PathBuf::from("archive.tar")])
+            .await
+            .unwrap();
+        client.send_done().await.unwrap();
+
+        let (writer, mut reader) = tokio::io::duplex(2 * content.len());
+        let mut handler = client_handler_with_sink(
+            Config {
+                root: source.path().join("received"),
+                receiver_hash: Some(HashAlgorithm::Sha512),
+                ..Default::default()
+            },
+            WriterSink::new(writer),
+        );
+        assert!(deliver(&mut handler, client.socket().take_datagrams()).await);
+        // Checked against the digest the client sent as well
+        assert_eq!(
+            handler.stats().file_digests.values().next().unwrap()[..],
+            Sha512::digest(&content[..])[..]
+        );
+        assert_eq!(handler.loss_report(), None);
+        assert!(!logs_contain("ERROR"));
+        drop(handler);
+
+        let mut received = Vec::new();
+        reader.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, content);
+    }
+
+    #[tokio::test]
+    async fn chunks_are_reordered_and_other_files_refused() {
+        let sink = WriterSink::new(Vec::new());
+        let mut id = sink.create(1, Path::new("a"), 0, false).await.unwrap();
+
+        sink.write_at(&mut id, 8, b"end").await.unwrap();
+        sink.write_at(&mut id, 4, b"body").await.unwrap();
+        sink.write_at(&mut id, 0, b"head").await.unwrap();
+        // Sent again, overlapping what was emitted
+        sink.write_at(&mut id, 2, b"adbo").await.unwrap();
+        assert!(sink.create(2, Path::new("b"), 0, false).await.is_err());
+        sink.finish(id, SystemTime::now()).await.unwrap();
+
+        assert_eq!(sink.into_inner(), b"headbodyend");
+    }
+}
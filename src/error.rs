@@ -21,6 +21,15 @@ pub enum Error {
     /// Configuration error,
     InvalidConfig { linenum: usize, line: String },
 
+    /// Invalid value in a configuration built programmatically
+    InvalidSetting(String),
+
+    /// The SHA-256 digest of a configuration file is not the expected one
+    ConfigDigestMismatch {
+        expected: [u8; 32],
+        actual: [u8; 32],
+    },
+
     /// ParseIntError
     ParseInt(std::num::ParseIntError),
 
@@ -38,6 +47,19 @@ pub enum Error {
 
     /// Payload exceed maximum MTU
     PayloadTooLarge(usize),
+
+    /// Bytes left after a complete message
+    TrailingBytes(usize),
+
+    /// Invalid glob pattern
+    Pattern(glob::PatternError),
+
+    /// The socket of the client can receive datagrams
+    NotOneWay,
+
+    /// Encryption or decryption (authentication) failure
+    #[cfg(feature = "encryption")]
+    Crypto(chacha20poly1305::Error),
 }
 
 pub type Result<T> = ::std::result::Result<T, Error>;
@@ -105,6 +127,19 @@ impl From<std::net::AddrParseError> for Error {
     }
 }
 
+impl From<glob::PatternError> for Error {
+    fn from(e: glob::PatternError) -> Self {
+        Self::Pattern(e)
+    }
+}
+
+#[cfg(feature = "encryption")]
+impl From<chacha20poly1305::Error> for Error {
+    fn from(e: chacha20poly1305::Error) -> Self {
+        Self::Crypto(e)
+    }
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -115,12 +150,81 @@ impl fmt::Display for Error {
             Self::InvalidConfig { linenum, ref line } => {
                 write!(f, "Invalid line ({}) found in config: {}", linenum, line)
             }
+            Self::InvalidSetting(ref reason) => write!(f, "Invalid configuration: {}", reason),
+            Self::ConfigDigestMismatch {
+                ref expected,
+                ref actual,
+            } => {
+                write!(f, "Configuration file digest is ")?;
+                actual.iter().try_for_each(|b| write!(f, "{:02x}", b))?;
+                write!(f, ", expected ")?;
+                expected.iter().try_for_each(|b| write!(f, "{:02x}", b))
+            }
             Self::ParseInt(ref e) => fmt::Display::fmt(e, f),
             Self::NoData => write!(f, "No chunk was received"),
             Self::MissingData(ref r) => write!(f, "Missing data from {} to {}", r.start, r.end),
             Self::UTF8(ref e) => fmt::Display::fmt(e, f),
             Self::Address(ref e) => fmt::Display::fmt(e, f),
-            Self::PayloadTooLarge(size) => write!(f, "Payload exceed maximum MTU: {} bytes", size),
+            Self::PayloadTooLarge(size) => write!(
+                f,
+                "Datagram of {} bytes exceeds the MTU, send smaller contents or raise `mtu`",
+                size
+            ),
+            Self::TrailingBytes(count) => write!(f, "{} bytes left after the message", count),
+            Self::Pattern(ref e) => write!(f, "Invalid pattern: {}", e),
+            Self::NotOneWay => write!(f, "The read half of the socket is not shut down"),
+            #[cfg(feature = "encryption")]
+            Self::Crypto(ref e) => write!(f, "Cryptographic error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::IO(ref e) => Some(e),
+            Self::Deserialize(ref e) => Some(e),
+            Self::IntegerConversion(ref e) => Some(e),
+            Self::Time(ref e) => Some(e),
+            Self::ParseInt(ref e) => Some(e),
+            Self::UTF8(ref e) => Some(e),
+            Self::Address(ref e) => Some(e),
+            Self::Pattern(ref e) => Some(e),
+            // Does not implement `std::error::Error` without the `std` feature of `aead`
+            #[cfg(feature = "encryption")]
+            Self::Crypto(_) => None,
+            Self::InvalidConfig { .. }
+            | Self::InvalidSetting(_)
+            | Self::ConfigDigestMismatch { .. }
+            | Self::NoData
+            | Self::MissingData(_)
+            | Self::PayloadTooLarge(_)
+            | Self::TrailingBytes(_)
+            | Self::NotOneWay => None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn source_chain_reaches_wrapped_error() {
+        let parse_error = "not a number".parse::<u8>().unwrap_err();
+        let inner = io::Error::new(io::ErrorKind::InvalidData, Error::from(parse_error.clone()));
+        let error: Box<dyn std::error::Error> = Box::new(Error::from(inner));
+
+        let chain: Vec<&dyn std::error::Error> =
+            std::iter::successors(Some(&*error), |e| e.source()).collect();
+        assert_eq!(chain.len(), 3);
+        let io_error = chain[1].downcast_ref::<io::Error>().unwrap();
+        assert_eq!(io_error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            chain[2].downcast_ref::<std::num::ParseIntError>(),
+            Some(&parse_error)
+        );
+
+        assert!(std::error::Error::source(&Error::NoData).is_none());
+    }
+}
@@ -0,0 +1,305 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::error::Result;
+
+/// An entry planned to be sent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JobEntry {
+    /// Path relative to the client root
+    pub path: PathBuf,
+
+    /// Size of regular files, 0 for other entries
+    pub size: u64,
+
+    /// Id of regular files on the wire, `None` for other entries
+    pub id: Option<u64>,
+}
+
+/// Entries a client plans to send, saved so that an interrupted transfer can be resumed
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Job {
+    pub entries: Vec<JobEntry>,
+}
+
+fn invalid_job_line(line: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid job line {:?}", line),
+    )
+}
+
+/// `path` as the last field of a line
+fn line_name(path: &Path) -> io::Result<&str> {
+    match path.to_str() {
+        Some(name) if !name.contains('\n') => Ok(name),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Cannot save {} in a job", path.display()),
+        )),
+    }
+}
+
+/// Writes `path` through `write` to a temporary file, renamed once synced
+fn save_atomically(
+    path: &Path,
+    write: impl FnOnce(&mut io::BufWriter<fs::File>) -> io::Result<()>,
+) -> Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let mut writer = io::BufWriter::new(fs::File::create(&tmp_path)?);
+
+    write(&mut writer)?;
+    writer
+        .into_inner()
+        .map_err(|e| e.into_error())?
+        .sync_all()?;
+    fs::rename(&tmp_path, path)?;
+
+    Ok(())
+}
+
+impl Job {
+    /// Atomically writes the job to `path`, one `id size path` line per entry
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        save_atomically(path.as_ref(), |writer| {
+            for entry in &self.entries {
+                let name = line_name(&entry.path)?;
+                match entry.id {
+                    Some(id) => writeln!(writer, "{} {} {}", id, entry.size, name)?,
+                    None => writeln!(writer, "- {} {}", entry.size, name)?,
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Reads a job written by `save`
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let mut job = Self::default();
+
+        for line in BufReader::new(fs::File::open(path)?).lines() {
+            let line = line?;
+            let mut fields = line.splitn(3, ' ');
+            let (Some(id), Some(size), Some(name)) = (fields.next(), fields.next(), fields.next())
+            else {
+                return Err(invalid_job_line(&line).into());
+            };
+
+            let id = match id {
+                "-" => None,
+                id => Some(id.parse().map_err(|_| invalid_job_line(&line))?),
+            };
+            job.entries.push(JobEntry {
+                path: PathBuf::from(name),
+                size: size.parse().map_err(|_| invalid_job_line(&line))?,
+                id,
+            });
+        }
+
+        Ok(job)
+    }
+
+    /// Paths of the entries left to send, regular files in `completed` being skipped
+    pub fn remaining(&self, completed: &HashSet<u64>) -> Vec<PathBuf> {
+        self.entries
+            .iter()
+            .filter(|entry| !entry.id.is_some_and(|id| completed.contains(&id)))
+            .map(|entry| entry.path.clone())
+            .collect()
+    }
+}
+
+/// Regular files already sent, with the size and modification time they had, so that a later run
+/// over the same tree skips the unchanged ones (see `Client::send_files_incremental`)
+///
+/// Nothing confirms that the server received them: the manifest is only as good as the
+/// transfers it records.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    /// Size and modification time, by path relative to the client root
+    files: HashMap<PathBuf, (u64, SystemTime)>,
+}
+
+impl Manifest {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records `path` as sent with `size` bytes, last modified at `modified`
+    pub fn insert(&mut self, path: impl Into<PathBuf>, size: u64, modified: SystemTime) {
+        self.files.insert(path.into(), (size, modified));
+    }
+
+    /// Records `files`, relative to `root`, with their current size and modification time
+    pub fn record(&mut self, root: &Path, files: &[PathBuf]) -> Result<()> {
+        for file in files {
+            let metadata = fs::symlink_metadata(root.join(file))?;
+            if metadata.is_file() {
+                self.insert(file.clone(), metadata.len(), metadata.modified()?);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `path` was sent with the same `size` and `modified` time
+    pub fn is_unchanged(&self, path: &Path, size: u64, modified: SystemTime) -> bool {
+        self.files.get(path) == Some(&(size, modified))
+    }
+
+    /// Atomically writes the manifest to `path`, one `size mtime path` line per file, `mtime`
+    /// being in nanoseconds since the UNIX epoch
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let mut files: Vec<_> = self.files.iter().collect();
+        files.sort();
+
+        save_atomically(path.as_ref(), |writer| {
+            for (path, (size, modified)) in files {
+                let mtime = modified
+                    .duration_since(UNIX_EPOCH)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                writeln!(writer, "{} {} {}", size, mtime.as_nanos(), line_name(path)?)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Reads a manifest written by `save`
+    ///
+    /// A missing manifest means that nothing was sent yet.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let file = match fs::File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut manifest = Self::new();
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            let mut fields = line.splitn(3, ' ');
+            let (Some(size), Some(mtime), Some(name)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                return Err(invalid_job_line(&line).into());
+            };
+
+            let size = size.parse().map_err(|_| invalid_job_line(&line))?;
+            let mtime: u128 = mtime.parse().map_err(|_| invalid_job_line(&line))?;
+            let modified = UNIX_EPOCH
+                + Duration::new(
+                    (mtime / 1_000_000_000) as u64,
+                    (mtime % 1_000_000_000) as u32,
+                );
+            manifest.insert(name, size, modified);
+        }
+
+        Ok(manifest)
+    }
+}
+
+/// Reads the ids of files a server recorded as complete in its `state_file`
+///
+/// A missing state file means that nothing was received yet.
+pub fn read_completed_ids(path: impl AsRef<Path>) -> Result<HashSet<u64>> {
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashSet::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut ids = HashSet::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        ids.insert(line.trim().parse().map_err(|_| invalid_job_line(&line))?);
+    }
+
+    Ok(ids)
+}
+
+/// Appends `ids` to the `state_file` of a server
+pub(crate) fn record_completed_ids(
+    path: &Path,
+    ids: impl IntoIterator<Item = u64>,
+) -> io::Result<()> {
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    let mut lines = String::new();
+    for id in ids {
+        lines.push_str(&format!("{}\n", id));
+    }
+    file.write_all(lines.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::Client;
+    use crate::testing::MemoryTransport;
+    use crate::Config;
+
+    #[tokio::test]
+    async fn job_round_trips_planned_files() {
+        let root = tempfile::tempdir().unwrap();
+        let work = tempfile::tempdir().unwrap();
+        fs::create_dir(root.path().join("empty dir")).unwrap();
+        fs::write(root.path().join("a file"), b"content").unwrap();
+        fs::write(root.path().join("other"), b"other content").unwrap();
+        let files = [
+            PathBuf::from("a file"),
+            PathBuf::from("empty dir"),
+            PathBuf::from("other"),
+        ];
+
+        let client = Client::new_with_config(
+            MemoryTransport::new(),
+            Config {
+                root: root.path().to_path_buf(),
+                // Ids must survive the session for the server state to apply to the job
+                file_id: crate::FileIdMode::Inode,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let job = client.plan_files(&files[..]).await.unwrap();
+        assert_eq!(job.entries.len(), files.len());
+        assert_eq!(job.entries[0].size, 7);
+        assert_eq!(job.entries[1].id, None);
+
+        let job_path = work.path().join("job");
+        job.save(&job_path).unwrap();
+        assert_eq!(Job::load(&job_path).unwrap(), job);
+
+        // Resuming skips files the server recorded as complete
+        let state_path = work.path().join("state");
+        assert!(read_completed_ids(&state_path).unwrap().is_empty());
+        record_completed_ids(&state_path, job.entries[2].id).unwrap();
+        let completed = read_completed_ids(&state_path).unwrap();
+        assert_eq!(
+            job.remaining(&completed),
+            vec![PathBuf::from("a file"), PathBuf::from("empty dir")]
+        );
+    }
+
+    #[test]
+    fn manifest_round_trips() {
+        let work = tempfile::tempdir().unwrap();
+        let modified = UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789);
+        let mut manifest = Manifest::new();
+        manifest.insert("dir/a file", 7, modified);
+        manifest.insert("other", 0, UNIX_EPOCH);
+
+        let path = work.path().join("manifest");
+        assert_eq!(Manifest::load(&path).unwrap(), Manifest::new());
+        manifest.save(&path).unwrap();
+        let loaded = Manifest::load(&path).unwrap();
+        assert_eq!(loaded, manifest);
+        assert!(loaded.is_unchanged(Path::new("dir/a file"), 7, modified));
+        assert!(!loaded.is_unchanged(Path::new("dir/a file"), 8, modified));
+    }
+}
@@ -1,17 +1,41 @@
+#[cfg(feature = "dedup")]
+mod cdc;
 pub mod connection;
 // pub mod envelope;
 mod config;
 mod error;
+pub mod job;
 pub mod messages;
 pub mod retransmit;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 pub mod tree;
 pub mod udp;
 mod utils;
 
-pub use config::Config;
+pub use config::{
+    Config, ConfigFile, DuplicateFilePolicy, FileIdMode, HashAlgorithm, Preserve, Role,
+};
 pub use error::{Error, Result};
 
 /// Trait used to serialize/deserialize data to/from wire
+///
+/// Types borrowing from the input (like the header of datagrams) tie their lifetime to `'a`, owned
+/// types implement it for any lifetime.
+///
+/// ```
+/// use oneway::messages::Message;
+/// use oneway::Wire;
+///
+/// let message = Message::KeepAlive(42);
+/// let mut raw = Vec::new();
+/// let size = message.to_wire(&mut raw).unwrap();
+/// assert_eq!(size, raw.len());
+///
+/// let (rest, decoded) = Message::from_wire(&raw[..]).unwrap();
+/// assert!(rest.is_empty());
+/// assert_eq!(decoded, message);
+/// ```
 pub trait Wire<'a>: Sized {
     /// Deserialization function
     fn from_wire(input: &'a [u8]) -> Result<(&'a [u8], Self)>;
@@ -5,15 +5,158 @@ use std::mem::{size_of, size_of_val};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use nom::bytes::streaming::take;
-use nom::combinator::{map, map_opt, map_res};
+use nom::combinator::{map, map_opt, map_res, verify};
 use nom::error::context;
-use nom::number::streaming::{be_u16, be_u64, be_u8};
+use nom::number::streaming::{be_u16, be_u32, be_u64, be_u8};
+
+// `id`, `offset` and `size` fields are big-endian, or LEB128 with the `compact` feature
+#[cfg(not(feature = "compact"))]
+use nom::number::streaming::be_u64 as uint;
+
+#[cfg(feature = "compact")]
+use crate::utils::varint::parse as uint;
+
+/// Bytes taken by an `id`, `offset` or `size` field at most
+#[cfg(not(feature = "compact"))]
+const UINT_MAX_SIZE: usize = size_of::<u64>();
+
+#[cfg(feature = "compact")]
+const UINT_MAX_SIZE: usize = crate::utils::varint::MAX_LEN;
+
+/// Writes an `id`, `offset` or `size` field, returns the number of bytes written
+#[cfg(not(feature = "compact"))]
+fn write_uint<W: std::io::Write>(mut writer: W, value: u64) -> Result<usize> {
+    writer.write_all(&value.to_be_bytes()[..])?;
+    Ok(size_of_val(&value))
+}
+
+#[cfg(feature = "compact")]
+fn write_uint<W: std::io::Write>(writer: W, value: u64) -> Result<usize> {
+    crate::utils::varint::VarInt(value).to_wire(writer)
+}
+
+/// Version of the wire format, bumped on every incompatible change
+pub const PROTOCOL_VERSION: u8 = ProtocolVersion::CURRENT as u8;
+
+/// Versions of the wire format, with what each one changed
+///
+/// Only `Hello`, `File` and `FileChunk` changed their encoding, other messages are decoded the
+/// same way by every version.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ProtocolVersion {
+    /// Initial format
+    V0 = 0,
+    /// `FileChunk` carries the CRC32 of its content
+    V1 = 1,
+    /// `File` carries Unix permission bits
+    V2 = 2,
+    /// `File` carries the modification time, the creation time becoming optional
+    V3 = 3,
+    /// `Hello` carries a session id
+    V4 = 4,
+    /// Datagrams are numbered, their header starting with another magic
+    V5 = 5,
+    /// Parity datagrams rebuild lost ones
+    V6 = 6,
+    /// `File` tells whether its content is compressed
+    V7 = 7,
+    /// `File` carries flags, adding partial updates
+    V8 = 8,
+    /// `ChunkCopy` is added
+    V9 = 9,
+    /// `TreeManifest` is added
+    V10 = 10,
+    /// `Hello` carries the version of the sender
+    V11 = 11,
+    /// `Progress` is added
+    V12 = 12,
+}
+
+impl ProtocolVersion {
+    /// Version sent by this build
+    pub const CURRENT: Self = Self::V12;
+
+    pub fn from_u8(version: u8) -> Option<Self> {
+        match version {
+            0 => Some(Self::V0),
+            1 => Some(Self::V1),
+            2 => Some(Self::V2),
+            3 => Some(Self::V3),
+            4 => Some(Self::V4),
+            5 => Some(Self::V5),
+            6 => Some(Self::V6),
+            7 => Some(Self::V7),
+            8 => Some(Self::V8),
+            9 => Some(Self::V9),
+            10 => Some(Self::V10),
+            11 => Some(Self::V11),
+            12 => Some(Self::V12),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "v{}", *self as u8)
+    }
+}
+
+/// Flag of `Message::File` set when the file is compressed
+const FILE_COMPRESSED: u8 = 0x01;
+
+/// Flag of `Message::File` set when the file is a partial update
+const FILE_PARTIAL: u8 = 0x02;
+
+/// Kind of special file
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SpecialKind {
+    Fifo = 0,
+    CharDevice = 1,
+    BlockDevice = 2,
+}
+
+impl SpecialKind {
+    fn from_u8(kind: u8) -> Option<Self> {
+        match kind {
+            0 => Some(Self::Fifo),
+            1 => Some(Self::CharDevice),
+            2 => Some(Self::BlockDevice),
+            _ => None,
+        }
+    }
+}
+
+/// A regular file listed in a `Message::TreeManifest`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    /// Path relative to the root
+    pub path: String,
+    pub size: u64,
+    /// SHA-256 digest of the content
+    pub digest: [u8; 32],
+}
+
+impl ManifestEntry {
+    /// Bytes taken by the entry in a `Message::TreeManifest`
+    pub fn wire_size(&self) -> usize {
+        size_of::<u16>() + self.path.len() + size_of::<u64>() + self.digest.len()
+    }
+}
 
 /// Message send from the client to server
-#[derive(PartialEq, Eq)]
+#[derive(Clone, Eq)]
 pub enum Message {
     /// Hello message to start a new session
-    Hello,
+    ///
+    /// `session_id` is an operator supplied id to correlate logs, empty if none was given.
+    /// `version` is the wire format of the messages that follow.
+    Hello {
+        session_id: String,
+        version: ProtocolVersion,
+    },
 
     /// KeepAlive message with an incrementing ID
     KeepAlive(u64),
@@ -24,9 +167,18 @@ pub enum Message {
     /// A single file to crate
     File {
         filename: String,
-        created: SystemTime,
+        /// Creation time, if the sender filesystem records it
+        created: Option<SystemTime>,
+        modified: SystemTime,
         size: u64,
         id: u64,
+        /// Unix permission bits, 0 when unknown (e.g. sent from Windows)
+        mode: u32,
+        /// Whether chunks carry a zstd stream of the content, their offsets being positions in
+        /// that stream
+        compressed: bool,
+        /// Whether only some ranges of the file follow, to be written over the receiver copy
+        partial: bool,
     },
 
     /// A chunk of data from a file
@@ -36,98 +188,218 @@ pub enum Message {
         id: u64,
         offset: u64,
         content_size: u16,
+        /// CRC32 of `content[..content_size]`
+        crc32: u32,
         content: Vec<u8>,
     },
 
     /// Client is done
     Done,
+
+    /// SHA-256 digest of a file, sent once all its chunks were sent
+    FileHash { id: u64, digest: [u8; 32] },
+
+    /// POSIX ACL of a file, to apply once its content has been received
+    Acl { id: u64, acl: Vec<u8> },
+
+    /// A FIFO or device to create
+    SpecialFile {
+        path: String,
+        kind: SpecialKind,
+        rdev: u64,
+    },
+
+    /// An empty directory to create
+    Directory { path: String, mode: u32 },
+
+    /// A symbolic link to create
+    Symlink { link_path: String, target: String },
+
+    /// Chunk of file `id` at `offset` identical to the `size` bytes already sent at
+    /// `source_offset` of file `source_id`, which the server copies instead of receiving them again
+    ChunkCopy {
+        id: u64,
+        offset: u64,
+        source_id: u64,
+        source_offset: u64,
+        size: u16,
+    },
+
+    /// Part of the list of every file of the sender tree, `total` being the number of entries in
+    /// all parts, so that the server checks its tree against it once done
+    TreeManifest {
+        total: u64,
+        entries: Vec<ManifestEntry>,
+    },
+
+    /// Bytes of file `id` sent so far, so that the server tells how much it is missing
+    Progress { id: u64, bytes_sent: u64 },
 }
 
 impl Message {
-    pub const fn get_max_content_size(mtu: usize) -> usize {
+    /// Bytes preceding the content of a `FileChunk`
+    pub const FILE_CHUNK_PREFIX_SIZE: usize = {
         let mut prefix_size = size_of::<u8>(); // MesageKind
-        prefix_size += size_of::<u64>(); // filename id
-        prefix_size += size_of::<u64>(); // offset
+        prefix_size += UINT_MAX_SIZE; // filename id
+        prefix_size += UINT_MAX_SIZE; // offset
         prefix_size += size_of::<u16>(); // content_size
-        mtu - prefix_size
+        prefix_size += size_of::<u32>(); // crc32
+        prefix_size
+    };
+
+    /// Bytes of a `File` besides its name
+    pub const FILE_PREFIX_SIZE: usize = {
+        let mut prefix_size = size_of::<u8>(); // MesageKind
+        prefix_size += size_of::<u16>(); // filename length
+        prefix_size += size_of::<u64>(); // created
+        prefix_size += size_of::<u64>(); // modified
+        prefix_size += UINT_MAX_SIZE; // size
+        prefix_size += UINT_MAX_SIZE; // id
+        prefix_size += size_of::<u32>(); // mode
+        prefix_size += size_of::<u8>(); // flags
+        prefix_size
+    };
+
+    /// Longest name of a file announced in a datagram of `mtu` bytes
+    pub const fn get_max_filename_size(mtu: usize) -> usize {
+        let max_size = crate::retransmit::max_payload_size(mtu) - Self::FILE_PREFIX_SIZE;
+        if max_size > u16::MAX as usize {
+            u16::MAX as usize
+        } else {
+            max_size
+        }
     }
-}
 
-impl fmt::Debug for Message {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    /// Bytes of a `TreeManifest` preceding its entries
+    pub const TREE_MANIFEST_PREFIX_SIZE: usize =
+        size_of::<u8>() + size_of::<u64>() + size_of::<u16>();
+
+    pub const fn get_max_content_size(mtu: usize) -> usize {
+        mtu - Self::FILE_CHUNK_PREFIX_SIZE
+    }
+
+    pub fn kind(&self) -> MessageKind {
         match self {
-            Self::Hello => write!(f, "Hello"),
-            Self::KeepAlive(ka) => f.debug_tuple("KeepAlive").field(ka).finish(),
-            Self::CountFilesToUpload(count) => {
-                f.debug_tuple("CountFilesToUpload").field(count).finish()
-            }
-            Self::File {
-                filename,
-                created,
-                size,
-                id,
-            } => f
-                .debug_struct("File")
-                .field("filename", filename)
-                .field("created", created)
-                .field("size", size)
-                .field("id", id)
-                .finish(),
-            Self::FileChunk {
-                id,
-                offset,
-                content_size,
-                content,
-            } => f
-                .debug_struct("FileChunk")
-                .field("id", id)
-                .field("offset", offset)
-                .field("content_size", content_size)
-                .field(
-                    "content",
-                    &crate::utils::Hex::new(&content[..*content_size as usize]),
-                )
-                .finish(),
-            Self::Done => write!(f, "Done"),
+            Self::Hello { .. } => MessageKind::Hello,
+            Self::KeepAlive(_) => MessageKind::KeepAlive,
+            Self::CountFilesToUpload(_) => MessageKind::CountFilesToUpload,
+            Self::File { .. } => MessageKind::File,
+            Self::FileChunk { .. } => MessageKind::FileChunk,
+            Self::Done => MessageKind::Done,
+            Self::FileHash { .. } => MessageKind::FileHash,
+            Self::Acl { .. } => MessageKind::Acl,
+            Self::SpecialFile { .. } => MessageKind::SpecialFile,
+            Self::Directory { .. } => MessageKind::Directory,
+            Self::Symlink { .. } => MessageKind::Symlink,
+            Self::ChunkCopy { .. } => MessageKind::ChunkCopy,
+            Self::TreeManifest { .. } => MessageKind::TreeManifest,
+            Self::Progress { .. } => MessageKind::Progress,
         }
     }
-}
 
-#[repr(u8)]
-#[derive(Copy, Clone)]
-enum MessageKind {
-    Hello,
-    KeepAlive,
-    CountFilesToUpload,
-    File,
-    FileChunk,
-    Done,
-}
+    /// Id of the file the message is about, if any
+    pub fn file_id(&self) -> Option<u64> {
+        match self {
+            Self::File { id, .. }
+            | Self::FileChunk { id, .. }
+            | Self::FileHash { id, .. }
+            | Self::Acl { id, .. }
+            | Self::ChunkCopy { id, .. }
+            | Self::Progress { id, .. } => Some(*id),
+            Self::Hello { .. }
+            | Self::KeepAlive(_)
+            | Self::CountFilesToUpload(_)
+            | Self::Done
+            | Self::SpecialFile { .. }
+            | Self::Directory { .. }
+            | Self::Symlink { .. }
+            | Self::TreeManifest { .. } => None,
+        }
+    }
 
-impl MessageKind {
-    fn from_u8(mk: u8) -> Option<Self> {
-        match mk {
-            0 => Some(Self::Hello),
-            1 => Some(Self::KeepAlive),
-            2 => Some(Self::CountFilesToUpload),
-            3 => Some(Self::File),
-            4 => Some(Self::FileChunk),
-            5 => Some(Self::Done),
-            _ => None,
+    /// Serializes the message into a new buffer
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut raw = Vec::new();
+        self.to_wire(&mut raw)?;
+        Ok(raw)
+    }
+
+    /// Decodes a message taking all of `input`, in the current wire format
+    pub fn from_bytes(input: &[u8]) -> Result<Self> {
+        let (rest, message) = Self::from_wire(input)?;
+        if !rest.is_empty() {
+            return Err(crate::Error::TrailingBytes(rest.len()));
         }
+        Ok(message)
     }
 
-    fn to_u8(&self) -> u8 {
-        *self as u8
+    /// Decodes a message sent by a peer which did not tell its version of the wire format
+    ///
+    /// The current version is tried first, then older ones from the most recent. The first one
+    /// decoding the whole of `input` is assumed, the current one otherwise.
+    pub fn from_wire_any_version(input: &[u8]) -> Result<(&[u8], Self)> {
+        let current = Self::from_wire(input);
+        if matches!(current, Ok((rest, _)) if rest.is_empty()) {
+            return current;
+        }
+
+        (0..ProtocolVersion::CURRENT as u8)
+            .rev()
+            .filter_map(ProtocolVersion::from_u8)
+            .find_map(|version| match Self::from_wire_versioned(version, input) {
+                Ok(([], message)) => {
+                    tracing::debug!("Decoded {:?} as protocol {}", message.kind(), version);
+                    Some((&input[input.len()..], message))
+                }
+                _ => None,
+            })
+            .map_or(current, Ok)
     }
-}
 
-impl Wire<'_> for Message {
-    fn from_wire(input: &[u8]) -> Result<(&[u8], Self)> {
+    /// Decodes a message sent by a peer speaking `version` of the wire format
+    ///
+    /// `Hello` is decoded the same way whatever `version` is, as it tells the version of the
+    /// messages that follow. Senders older than `ProtocolVersion::V11` do not announce their
+    /// version, the most recent one matching their `Hello` is assumed.
+    pub fn from_wire_versioned(version: ProtocolVersion, input: &[u8]) -> Result<(&[u8], Self)> {
         let (rest, message_kind) =
             context("Message/kind", map_opt(be_u8, MessageKind::from_u8))(input)?;
         match message_kind {
-            MessageKind::Hello => Ok((rest, Self::Hello)),
+            MessageKind::Hello => {
+                // Hellos of `V3` and before are empty
+                if rest.is_empty() {
+                    return Ok((
+                        rest,
+                        Self::Hello {
+                            session_id: String::new(),
+                            version: ProtocolVersion::V3,
+                        },
+                    ));
+                }
+                let (rest, session_id_len) = context("Message/Hello/session_id_len", be_u16)(rest)?;
+                let (rest, session_id) = context(
+                    "Message/Hello/session_id",
+                    map(
+                        map_res(take(session_id_len), std::str::from_utf8),
+                        String::from,
+                    ),
+                )(rest)?;
+                let (rest, version) = if rest.is_empty() {
+                    (rest, ProtocolVersion::V10)
+                } else {
+                    context(
+                        "Message/Hello/version",
+                        map_opt(be_u8, ProtocolVersion::from_u8),
+                    )(rest)?
+                };
+                Ok((
+                    rest,
+                    Self::Hello {
+                        session_id,
+                        version,
+                    },
+                ))
+            }
             MessageKind::KeepAlive => {
                 let (rest, id) = context("Message/KeepAlive/id", be_u64)(rest)?;
                 Ok((rest, Message::KeepAlive(id)))
@@ -146,50 +418,525 @@ impl Wire<'_> for Message {
                     ),
                 )(rest)?;
 
-                let (rest, created) = context(
-                    "Message/File/created",
-                    map_opt(be_u64, |offset| {
-                        UNIX_EPOCH.checked_add(Duration::from_secs(offset))
-                    }),
-                )(rest)?;
+                let timestamp = |offset| UNIX_EPOCH.checked_add(Duration::from_secs(offset));
+
+                // Before `V3`, the creation time is always given and there is no modification
+                // time, the creation time standing for it
+                let (rest, created, modified) = if version < ProtocolVersion::V3 {
+                    let (rest, created) =
+                        context("Message/File/created", map_opt(be_u64, timestamp))(rest)?;
+                    (rest, Some(created), created)
+                } else {
+                    // 0 means that the creation time is unknown
+                    let (rest, created) = context(
+                        "Message/File/created",
+                        map_opt(be_u64, |offset| match offset {
+                            0 => Some(None),
+                            _ => timestamp(offset).map(Some),
+                        }),
+                    )(rest)?;
+                    let (rest, modified) =
+                        context("Message/File/modified", map_opt(be_u64, timestamp))(rest)?;
+                    (rest, created, modified)
+                };
+
+                let (rest, size) = context("Message/File/size", uint)(rest)?;
+
+                let (rest, id) = context("Message/File/id", uint)(rest)?;
 
-                let (rest, size) = context("Message/File/size", be_u64)(rest)?;
+                let (rest, mode) = if version < ProtocolVersion::V2 {
+                    (rest, 0)
+                } else {
+                    context("Message/File/mode", be_u32)(rest)?
+                };
 
-                let (rest, id) = context("Message/File/id", be_u64)(rest)?;
+                // `V7` only had the compressed flag, as a boolean
+                let known_flags = match version {
+                    v if v < ProtocolVersion::V7 => 0,
+                    ProtocolVersion::V7 => FILE_COMPRESSED,
+                    _ => FILE_COMPRESSED | FILE_PARTIAL,
+                };
+                let (rest, flags) = if known_flags == 0 {
+                    (rest, 0)
+                } else {
+                    context(
+                        "Message/File/flags",
+                        verify(be_u8, |flags| flags & !known_flags == 0),
+                    )(rest)?
+                };
 
                 Ok((
                     rest,
                     Self::File {
                         filename,
                         created,
+                        modified,
                         size,
                         id,
+                        mode,
+                        compressed: flags & FILE_COMPRESSED != 0,
+                        partial: flags & FILE_PARTIAL != 0,
                     },
                 ))
             }
             MessageKind::FileChunk => {
-                let (rest, id) = context("Message/FileChunk/id", be_u64)(rest)?;
+                let (rest, id) = context("Message/FileChunk/id", uint)(rest)?;
 
-                let (rest, offset) = context("Message/FileChunk/offeet", be_u64)(rest)?;
+                let (rest, offset) = context("Message/FileChunk/offeet", uint)(rest)?;
 
                 let (rest, content_size) = context("Message/FileChunk/content_size", be_u16)(rest)?;
+                let (rest, crc32) = if version < ProtocolVersion::V1 {
+                    (rest, None)
+                } else {
+                    map(context("Message/FileChunk/crc32", be_u32), Some)(rest)?
+                };
                 let (rest, content) = context(
                     "Message/FileChunk/content",
                     map(take(content_size as usize), |slice: &[u8]| slice.to_vec()),
                 )(rest)?;
+                // Chunks without a CRC32 are trusted as they are
+                let crc32 = crc32.unwrap_or_else(|| crc32fast::hash(&content[..]));
                 Ok((
                     rest,
                     Self::FileChunk {
                         id,
                         offset,
                         content_size,
+                        crc32,
                         content,
                     },
                 ))
             }
             MessageKind::Done => Ok((rest, Self::Done)),
+            MessageKind::FileHash => {
+                let (rest, id) = context("Message/FileHash/id", uint)(rest)?;
+                let (rest, digest) = context(
+                    "Message/FileHash/digest",
+                    map_res(take(32usize), <[u8; 32]>::try_from),
+                )(rest)?;
+                Ok((rest, Self::FileHash { id, digest }))
+            }
+            MessageKind::Acl => {
+                let (rest, id) = context("Message/Acl/id", uint)(rest)?;
+                let (rest, acl_len) = context("Message/Acl/acl_len", be_u16)(rest)?;
+                let (rest, acl) = context(
+                    "Message/Acl/acl",
+                    map(take(acl_len), |slice: &[u8]| slice.to_vec()),
+                )(rest)?;
+                Ok((rest, Self::Acl { id, acl }))
+            }
+            MessageKind::SpecialFile => {
+                let (rest, path_len) = context("Message/SpecialFile/path_len", be_u16)(rest)?;
+                let (rest, path) = context(
+                    "Message/SpecialFile/path",
+                    map(map_res(take(path_len), std::str::from_utf8), String::from),
+                )(rest)?;
+                let (rest, kind) = context(
+                    "Message/SpecialFile/kind",
+                    map_opt(be_u8, SpecialKind::from_u8),
+                )(rest)?;
+                let (rest, rdev) = context("Message/SpecialFile/rdev", be_u64)(rest)?;
+                Ok((rest, Self::SpecialFile { path, kind, rdev }))
+            }
+            MessageKind::Directory => {
+                let (rest, path_len) = context("Message/Directory/path_len", be_u16)(rest)?;
+                let (rest, path) = context(
+                    "Message/Directory/path",
+                    map(map_res(take(path_len), std::str::from_utf8), String::from),
+                )(rest)?;
+                let (rest, mode) = context("Message/Directory/mode", be_u32)(rest)?;
+                Ok((rest, Self::Directory { path, mode }))
+            }
+            MessageKind::Symlink => {
+                let (rest, link_path_len) = context("Message/Symlink/link_path_len", be_u16)(rest)?;
+                let (rest, link_path) = context(
+                    "Message/Symlink/link_path",
+                    map(
+                        map_res(take(link_path_len), std::str::from_utf8),
+                        String::from,
+                    ),
+                )(rest)?;
+                let (rest, target_len) = context("Message/Symlink/target_len", be_u16)(rest)?;
+                let (rest, target) = context(
+                    "Message/Symlink/target",
+                    map(map_res(take(target_len), std::str::from_utf8), String::from),
+                )(rest)?;
+                Ok((rest, Self::Symlink { link_path, target }))
+            }
+            MessageKind::ChunkCopy => {
+                let (rest, id) = context("Message/ChunkCopy/id", uint)(rest)?;
+                let (rest, offset) = context("Message/ChunkCopy/offset", uint)(rest)?;
+                let (rest, source_id) = context("Message/ChunkCopy/source_id", uint)(rest)?;
+                let (rest, source_offset) = context("Message/ChunkCopy/source_offset", uint)(rest)?;
+                let (rest, size) = context("Message/ChunkCopy/size", be_u16)(rest)?;
+                Ok((
+                    rest,
+                    Self::ChunkCopy {
+                        id,
+                        offset,
+                        source_id,
+                        source_offset,
+                        size,
+                    },
+                ))
+            }
+            MessageKind::TreeManifest => {
+                let (rest, total) = context("Message/TreeManifest/total", be_u64)(rest)?;
+                let (mut rest, count) = context("Message/TreeManifest/count", be_u16)(rest)?;
+                let mut entries = Vec::with_capacity(count.into());
+                for _ in 0..count {
+                    let (r, path_len) = context("Message/TreeManifest/path_len", be_u16)(rest)?;
+                    let (r, path) = context(
+                        "Message/TreeManifest/path",
+                        map(map_res(take(path_len), std::str::from_utf8), String::from),
+                    )(r)?;
+                    let (r, size) = context("Message/TreeManifest/size", be_u64)(r)?;
+                    let (r, digest) = context(
+                        "Message/TreeManifest/digest",
+                        map_res(take(32usize), <[u8; 32]>::try_from),
+                    )(r)?;
+                    entries.push(ManifestEntry { path, size, digest });
+                    rest = r;
+                }
+                Ok((rest, Self::TreeManifest { total, entries }))
+            }
+            MessageKind::Progress => {
+                let (rest, id) = context("Message/Progress/id", uint)(rest)?;
+                let (rest, bytes_sent) = context("Message/Progress/bytes_sent", uint)(rest)?;
+                Ok((rest, Self::Progress { id, bytes_sent }))
+            }
+        }
+    }
+}
+
+/// Chunks are compared on the `content_size` bytes of their content which are sent, like any
+/// other message once decoded
+impl PartialEq for Message {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (
+                Self::Hello {
+                    session_id: a,
+                    version: a_version,
+                },
+                Self::Hello {
+                    session_id: b,
+                    version: b_version,
+                },
+            ) => a == b && a_version == b_version,
+            (Self::KeepAlive(a), Self::KeepAlive(b)) => a == b,
+            (Self::CountFilesToUpload(a), Self::CountFilesToUpload(b)) => a == b,
+            (
+                Self::File {
+                    filename: a_filename,
+                    created: a_created,
+                    modified: a_modified,
+                    size: a_size,
+                    id: a_id,
+                    mode: a_mode,
+                    compressed: a_compressed,
+                    partial: a_partial,
+                },
+                Self::File {
+                    filename: b_filename,
+                    created: b_created,
+                    modified: b_modified,
+                    size: b_size,
+                    id: b_id,
+                    mode: b_mode,
+                    compressed: b_compressed,
+                    partial: b_partial,
+                },
+            ) => {
+                a_filename == b_filename
+                    && a_created == b_created
+                    && a_modified == b_modified
+                    && a_size == b_size
+                    && a_id == b_id
+                    && a_mode == b_mode
+                    && a_compressed == b_compressed
+                    && a_partial == b_partial
+            }
+            (
+                Self::FileChunk {
+                    id: a_id,
+                    offset: a_offset,
+                    content_size: a_size,
+                    crc32: a_crc32,
+                    content: a_content,
+                },
+                Self::FileChunk {
+                    id: b_id,
+                    offset: b_offset,
+                    content_size: b_size,
+                    crc32: b_crc32,
+                    content: b_content,
+                },
+            ) => {
+                a_id == b_id
+                    && a_offset == b_offset
+                    && a_size == b_size
+                    && a_crc32 == b_crc32
+                    && a_content.get(..usize::from(*a_size))
+                        == b_content.get(..usize::from(*b_size))
+            }
+            (Self::Done, Self::Done) => true,
+            (
+                Self::FileHash {
+                    id: a_id,
+                    digest: a_digest,
+                },
+                Self::FileHash {
+                    id: b_id,
+                    digest: b_digest,
+                },
+            ) => a_id == b_id && a_digest == b_digest,
+            (
+                Self::Acl {
+                    id: a_id,
+                    acl: a_acl,
+                },
+                Self::Acl {
+                    id: b_id,
+                    acl: b_acl,
+                },
+            ) => a_id == b_id && a_acl == b_acl,
+            (
+                Self::SpecialFile {
+                    path: a_path,
+                    kind: a_kind,
+                    rdev: a_rdev,
+                },
+                Self::SpecialFile {
+                    path: b_path,
+                    kind: b_kind,
+                    rdev: b_rdev,
+                },
+            ) => a_path == b_path && a_kind == b_kind && a_rdev == b_rdev,
+            (
+                Self::Directory {
+                    path: a_path,
+                    mode: a_mode,
+                },
+                Self::Directory {
+                    path: b_path,
+                    mode: b_mode,
+                },
+            ) => a_path == b_path && a_mode == b_mode,
+            (
+                Self::Symlink {
+                    link_path: a_link_path,
+                    target: a_target,
+                },
+                Self::Symlink {
+                    link_path: b_link_path,
+                    target: b_target,
+                },
+            ) => a_link_path == b_link_path && a_target == b_target,
+            (
+                Self::ChunkCopy {
+                    id: a_id,
+                    offset: a_offset,
+                    source_id: a_source_id,
+                    source_offset: a_source_offset,
+                    size: a_size,
+                },
+                Self::ChunkCopy {
+                    id: b_id,
+                    offset: b_offset,
+                    source_id: b_source_id,
+                    source_offset: b_source_offset,
+                    size: b_size,
+                },
+            ) => {
+                a_id == b_id
+                    && a_offset == b_offset
+                    && a_source_id == b_source_id
+                    && a_source_offset == b_source_offset
+                    && a_size == b_size
+            }
+            (
+                Self::TreeManifest {
+                    total: a_total,
+                    entries: a_entries,
+                },
+                Self::TreeManifest {
+                    total: b_total,
+                    entries: b_entries,
+                },
+            ) => a_total == b_total && a_entries == b_entries,
+            (
+                Self::Progress {
+                    id: a_id,
+                    bytes_sent: a_bytes_sent,
+                },
+                Self::Progress {
+                    id: b_id,
+                    bytes_sent: b_bytes_sent,
+                },
+            ) => a_id == b_id && a_bytes_sent == b_bytes_sent,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Debug for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Hello {
+                session_id,
+                version,
+            } => f
+                .debug_struct("Hello")
+                .field("session_id", session_id)
+                .field("version", version)
+                .finish(),
+            Self::KeepAlive(ka) => f.debug_tuple("KeepAlive").field(ka).finish(),
+            Self::CountFilesToUpload(count) => {
+                f.debug_tuple("CountFilesToUpload").field(count).finish()
+            }
+            Self::File {
+                filename,
+                created,
+                modified,
+                size,
+                id,
+                mode,
+                compressed,
+                partial,
+            } => f
+                .debug_struct("File")
+                .field("filename", filename)
+                .field("created", created)
+                .field("modified", modified)
+                .field("size", size)
+                .field("id", id)
+                .field("mode", &format_args!("{:#o}", mode))
+                .field("compressed", compressed)
+                .field("partial", partial)
+                .finish(),
+            Self::FileChunk {
+                id,
+                offset,
+                content_size,
+                crc32,
+                content,
+            } => f
+                .debug_struct("FileChunk")
+                .field("id", id)
+                .field("offset", offset)
+                .field("content_size", content_size)
+                .field("crc32", &format_args!("{:#010x}", crc32))
+                .field(
+                    "content",
+                    &crate::utils::Hex::new(&content[..*content_size as usize]),
+                )
+                .finish(),
+            Self::Done => write!(f, "Done"),
+            Self::FileHash { id, digest } => f
+                .debug_struct("FileHash")
+                .field("id", id)
+                .field("digest", &crate::utils::Hex::new(&digest[..]))
+                .finish(),
+            Self::Acl { id, acl } => f
+                .debug_struct("Acl")
+                .field("id", id)
+                .field("acl", &crate::utils::Hex::new(&acl[..]))
+                .finish(),
+            Self::SpecialFile { path, kind, rdev } => f
+                .debug_struct("SpecialFile")
+                .field("path", path)
+                .field("kind", kind)
+                .field("rdev", rdev)
+                .finish(),
+            Self::Directory { path, mode } => f
+                .debug_struct("Directory")
+                .field("path", path)
+                .field("mode", &format_args!("{:#o}", mode))
+                .finish(),
+            Self::Symlink { link_path, target } => f
+                .debug_struct("Symlink")
+                .field("link_path", link_path)
+                .field("target", target)
+                .finish(),
+            Self::ChunkCopy {
+                id,
+                offset,
+                source_id,
+                source_offset,
+                size,
+            } => f
+                .debug_struct("ChunkCopy")
+                .field("id", id)
+                .field("offset", offset)
+                .field("source_id", source_id)
+                .field("source_offset", source_offset)
+                .field("size", size)
+                .finish(),
+            Self::TreeManifest { total, entries } => f
+                .debug_struct("TreeManifest")
+                .field("total", total)
+                .field("entries", &entries.len())
+                .finish(),
+            Self::Progress { id, bytes_sent } => f
+                .debug_struct("Progress")
+                .field("id", id)
+                .field("bytes_sent", bytes_sent)
+                .finish(),
         }
     }
+}
+
+/// Kind of a `Message`, as encoded on the wire
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MessageKind {
+    Hello = 0,
+    KeepAlive = 1,
+    CountFilesToUpload = 2,
+    File = 3,
+    FileChunk = 4,
+    Done = 5,
+    FileHash = 6,
+    Progress = 7,
+    Acl = 8,
+    SpecialFile = 9,
+    Directory = 10,
+    Symlink = 11,
+    ChunkCopy = 12,
+    TreeManifest = 13,
+}
+
+impl MessageKind {
+    fn from_u8(mk: u8) -> Option<Self> {
+        match mk {
+            0 => Some(Self::Hello),
+            1 => Some(Self::KeepAlive),
+            2 => Some(Self::CountFilesToUpload),
+            3 => Some(Self::File),
+            4 => Some(Self::FileChunk),
+            5 => Some(Self::Done),
+            6 => Some(Self::FileHash),
+            7 => Some(Self::Progress),
+            8 => Some(Self::Acl),
+            9 => Some(Self::SpecialFile),
+            10 => Some(Self::Directory),
+            11 => Some(Self::Symlink),
+            12 => Some(Self::ChunkCopy),
+            13 => Some(Self::TreeManifest),
+            _ => None,
+        }
+    }
+
+    fn to_u8(self) -> u8 {
+        self as u8
+    }
+}
+
+impl Wire<'_> for Message {
+    fn from_wire(input: &[u8]) -> Result<(&[u8], Self)> {
+        Self::from_wire_versioned(ProtocolVersion::CURRENT, input)
+    }
 
     fn to_wire<W>(&self, mut writer: W) -> Result<usize>
     where
@@ -197,10 +944,24 @@ impl Wire<'_> for Message {
     {
         let mut total_size = 0;
         match self {
-            Self::Hello => {
+            Self::Hello {
+                ref session_id,
+                ref version,
+            } => {
                 let mk = MessageKind::Hello.to_u8();
                 total_size += size_of_val(&mk);
                 writer.write_all(&[mk])?;
+
+                let session_id_len: u16 = session_id.len().try_into()?;
+                total_size += size_of_val(&session_id_len);
+                writer.write_all(&session_id_len.to_be_bytes()[..])?;
+
+                total_size += session_id.len();
+                writer.write_all(session_id.as_bytes())?;
+
+                let version = *version as u8;
+                total_size += size_of_val(&version);
+                writer.write_all(&[version])?;
             }
             Self::KeepAlive(ref id) => {
                 let mk = MessageKind::KeepAlive.to_u8();
@@ -220,8 +981,12 @@ impl Wire<'_> for Message {
             Self::File {
                 ref filename,
                 ref created,
+                ref modified,
                 ref size,
                 ref id,
+                ref mode,
+                ref compressed,
+                ref partial,
             } => {
                 let mk = MessageKind::File.to_u8();
                 total_size += size_of_val(&mk);
@@ -231,49 +996,813 @@ impl Wire<'_> for Message {
                 total_size += size_of_val(&filename_len);
                 writer.write_all(&filename_len.to_be_bytes()[..])?;
 
-                total_size += filename.as_bytes().len();
+                total_size += filename.len();
                 writer.write_all(filename.as_bytes())?;
 
-                let offset = created.duration_since(UNIX_EPOCH)?.as_secs();
+                let offset = match created {
+                    Some(created) => created.duration_since(UNIX_EPOCH)?.as_secs(),
+                    None => 0,
+                };
                 total_size += size_of_val(&offset);
                 writer.write_all(&offset.to_be_bytes()[..])?;
 
-                total_size += size_of_val(size);
-                writer.write_all(&size.to_be_bytes()[..])?;
+                let offset = modified.duration_since(UNIX_EPOCH)?.as_secs();
+                total_size += size_of_val(&offset);
+                writer.write_all(&offset.to_be_bytes()[..])?;
 
-                total_size += size_of_val(id);
-                writer.write_all(&id.to_be_bytes()[..])?;
+                total_size += write_uint(&mut writer, *size)?;
+
+                total_size += write_uint(&mut writer, *id)?;
+
+                total_size += size_of_val(mode);
+                writer.write_all(&mode.to_be_bytes()[..])?;
+
+                let mut flags = 0u8;
+                if *compressed {
+                    flags |= FILE_COMPRESSED;
+                }
+                if *partial {
+                    flags |= FILE_PARTIAL;
+                }
+                total_size += size_of_val(&flags);
+                writer.write_all(&[flags])?;
             }
             Self::FileChunk {
                 ref id,
                 ref offset,
                 ref content_size,
+                ref crc32,
                 ref content,
             } => {
                 let mk = MessageKind::FileChunk.to_u8();
                 total_size += size_of_val(&mk);
                 writer.write_all(&[mk])?;
 
-                total_size += size_of_val(id);
-                writer.write_all(&id.to_be_bytes()[..])?;
+                total_size += write_uint(&mut writer, *id)?;
 
-                total_size += size_of_val(offset);
-                writer.write_all(&offset.to_be_bytes()[..])?;
+                total_size += write_uint(&mut writer, *offset)?;
 
                 total_size += size_of_val(content_size);
                 writer.write_all(&content_size.to_be_bytes()[..])?;
 
+                total_size += size_of_val(crc32);
+                writer.write_all(&crc32.to_be_bytes()[..])?;
+
                 let buffer = &content[..*content_size as usize];
                 total_size += buffer.len();
-                writer.write_all(&buffer[..])?;
+                writer.write_all(buffer)?;
             }
             Self::Done => {
                 let mk = MessageKind::Done.to_u8();
                 total_size += size_of_val(&mk);
                 writer.write_all(&[mk])?;
             }
+            Self::FileHash { ref id, ref digest } => {
+                let mk = MessageKind::FileHash.to_u8();
+                total_size += size_of_val(&mk);
+                writer.write_all(&[mk])?;
+
+                total_size += write_uint(&mut writer, *id)?;
+
+                total_size += digest.len();
+                writer.write_all(&digest[..])?;
+            }
+            Self::Acl { ref id, ref acl } => {
+                let mk = MessageKind::Acl.to_u8();
+                total_size += size_of_val(&mk);
+                writer.write_all(&[mk])?;
+
+                total_size += write_uint(&mut writer, *id)?;
+
+                let acl_len: u16 = acl.len().try_into()?;
+                total_size += size_of_val(&acl_len);
+                writer.write_all(&acl_len.to_be_bytes()[..])?;
+
+                total_size += acl.len();
+                writer.write_all(&acl[..])?;
+            }
+            Self::SpecialFile {
+                ref path,
+                ref kind,
+                ref rdev,
+            } => {
+                let mk = MessageKind::SpecialFile.to_u8();
+                total_size += size_of_val(&mk);
+                writer.write_all(&[mk])?;
+
+                let path_len: u16 = path.len().try_into()?;
+                total_size += size_of_val(&path_len);
+                writer.write_all(&path_len.to_be_bytes()[..])?;
+
+                total_size += path.len();
+                writer.write_all(path.as_bytes())?;
+
+                total_size += size_of::<u8>();
+                writer.write_all(&[*kind as u8])?;
+
+                total_size += size_of_val(rdev);
+                writer.write_all(&rdev.to_be_bytes()[..])?;
+            }
+            Self::Directory { ref path, ref mode } => {
+                let mk = MessageKind::Directory.to_u8();
+                total_size += size_of_val(&mk);
+                writer.write_all(&[mk])?;
+
+                let path_len: u16 = path.len().try_into()?;
+                total_size += size_of_val(&path_len);
+                writer.write_all(&path_len.to_be_bytes()[..])?;
+
+                total_size += path.len();
+                writer.write_all(path.as_bytes())?;
+
+                total_size += size_of_val(mode);
+                writer.write_all(&mode.to_be_bytes()[..])?;
+            }
+            Self::Symlink {
+                ref link_path,
+                ref target,
+            } => {
+                let mk = MessageKind::Symlink.to_u8();
+                total_size += size_of_val(&mk);
+                writer.write_all(&[mk])?;
+
+                let link_path_len: u16 = link_path.len().try_into()?;
+                total_size += size_of_val(&link_path_len);
+                writer.write_all(&link_path_len.to_be_bytes()[..])?;
+
+                total_size += link_path.len();
+                writer.write_all(link_path.as_bytes())?;
+
+                let target_len: u16 = target.len().try_into()?;
+                total_size += size_of_val(&target_len);
+                writer.write_all(&target_len.to_be_bytes()[..])?;
+
+                total_size += target.len();
+                writer.write_all(target.as_bytes())?;
+            }
+            Self::ChunkCopy {
+                ref id,
+                ref offset,
+                ref source_id,
+                ref source_offset,
+                ref size,
+            } => {
+                let mk = MessageKind::ChunkCopy.to_u8();
+                total_size += size_of_val(&mk);
+                writer.write_all(&[mk])?;
+
+                for value in [id, offset, source_id, source_offset] {
+                    total_size += write_uint(&mut writer, *value)?;
+                }
+
+                total_size += size_of_val(size);
+                writer.write_all(&size.to_be_bytes()[..])?;
+            }
+            Self::TreeManifest {
+                ref total,
+                ref entries,
+            } => {
+                let mk = MessageKind::TreeManifest.to_u8();
+                total_size += size_of_val(&mk);
+                writer.write_all(&[mk])?;
+
+                total_size += size_of_val(total);
+                writer.write_all(&total.to_be_bytes()[..])?;
+
+                let count: u16 = entries.len().try_into()?;
+                total_size += size_of_val(&count);
+                writer.write_all(&count.to_be_bytes()[..])?;
+
+                for entry in entries {
+                    let path_len: u16 = entry.path.len().try_into()?;
+                    writer.write_all(&path_len.to_be_bytes()[..])?;
+                    writer.write_all(entry.path.as_bytes())?;
+                    writer.write_all(&entry.size.to_be_bytes()[..])?;
+                    writer.write_all(&entry.digest[..])?;
+                    total_size += entry.wire_size();
+                }
+            }
+            Self::Progress {
+                ref id,
+                ref bytes_sent,
+            } => {
+                let mk = MessageKind::Progress.to_u8();
+                total_size += size_of_val(&mk);
+                writer.write_all(&[mk])?;
+
+                for value in [id, bytes_sent] {
+                    total_size += write_uint(&mut writer, *value)?;
+                }
+            }
         }
 
         Ok(total_size)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_chunk_round_trip() {
+        let content = b"some content".to_vec();
+        let message = Message::FileChunk {
+            id: 42,
+            offset: 0x1000,
+            content_size: content.len() as u16,
+            crc32: crc32fast::hash(&content[..]),
+            content,
+        };
+
+        let mut raw = Vec::new();
+        let size = message.to_wire(&mut raw).unwrap();
+        assert_eq!(size, raw.len());
+
+        let (rest, decoded) = Message::from_wire(&raw[..]).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(decoded, message);
+    }
+
+    #[cfg(feature = "compact")]
+    #[test]
+    fn compact_chunks_are_smaller() {
+        let message = Message::FileChunk {
+            id: 42,
+            offset: 0x1000,
+            content_size: 4,
+            crc32: crc32fast::hash(b"data"),
+            content: b"data".to_vec(),
+        };
+
+        let mut raw = Vec::new();
+        let size = message.to_wire(&mut raw).unwrap();
+        // One byte for the id and two for the offset, instead of eight each
+        assert_eq!(size, 1 + 1 + 2 + 2 + 4 + 4);
+
+        let (rest, decoded) = Message::from_wire(&raw[..]).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(decoded, message);
+    }
+
+    #[test]
+    fn every_message_round_trips_through_bytes() {
+        let modified = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let messages = [
+            Message::Hello {
+                session_id: String::from("session"),
+                version: ProtocolVersion::CURRENT,
+            },
+            Message::KeepAlive(42),
+            Message::CountFilesToUpload(3),
+            Message::File {
+                filename: String::from("dir/file"),
+                created: Some(modified),
+                modified,
+                size: 1234,
+                id: 42,
+                mode: 0o644,
+                compressed: false,
+                partial: true,
+            },
+            Message::FileChunk {
+                id: 42,
+                offset: 0x1000,
+                content_size: 4,
+                crc32: crc32fast::hash(b"data"),
+                content: b"data".to_vec(),
+            },
+            Message::Done,
+            Message::FileHash {
+                id: 42,
+                digest: [0xa5; 32],
+            },
+            Message::Acl {
+                id: 42,
+                acl: vec![1, 2, 3],
+            },
+            Message::SpecialFile {
+                path: String::from("fifo"),
+                kind: SpecialKind::Fifo,
+                rdev: 0,
+            },
+            Message::Directory {
+                path: String::from("dir"),
+                mode: 0o755,
+            },
+            Message::Symlink {
+                link_path: String::from("link"),
+                target: String::from("dir/file"),
+            },
+            Message::ChunkCopy {
+                id: 42,
+                offset: 0x2000,
+                source_id: 7,
+                source_offset: 0x1000,
+                size: 4,
+            },
+            Message::TreeManifest {
+                total: 1,
+                entries: vec![ManifestEntry {
+                    path: String::from("dir/file"),
+                    size: 1234,
+                    digest: [0x5a; 32],
+                }],
+            },
+            Message::Progress {
+                id: 42,
+                bytes_sent: 0x1000,
+            },
+        ];
+
+        for message in messages {
+            let raw = message.to_bytes().unwrap();
+            assert_eq!(Message::from_bytes(&raw[..]).unwrap(), message);
+        }
+    }
+
+    mod properties {
+        use super::*;
+        use proptest::collection::vec;
+        use proptest::prelude::*;
+
+        /// Short valid UTF-8, whose length fits in the u16 prefixes
+        fn name() -> impl Strategy<Value = String> {
+            "\\PC{0,32}"
+        }
+
+        /// Times are sent with second granularity
+        fn time(min_secs: u64) -> impl Strategy<Value = SystemTime> {
+            (min_secs..=u64::from(u32::MAX)).prop_map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+        }
+
+        fn file() -> impl Strategy<Value = Message> {
+            (
+                name(),
+                // 0 stands for an unknown creation time
+                proptest::option::of(time(1)),
+                time(0),
+                any::<u64>(),
+                any::<u64>(),
+                any::<u32>(),
+                any::<bool>(),
+                any::<bool>(),
+            )
+                .prop_map(
+                    |(filename, created, modified, size, id, mode, compressed, partial)| {
+                        Message::File {
+                            filename,
+                            created,
+                            modified,
+                            size,
+                            id,
+                            mode,
+                            compressed,
+                            partial,
+                        }
+                    },
+                )
+        }
+
+        fn file_chunk() -> impl Strategy<Value = Message> {
+            (
+                any::<u64>(),
+                any::<u64>(),
+                any::<u32>(),
+                vec(any::<u8>(), 0..256),
+                // Chunks may be cut from a larger buffer, only `content_size` bytes being sent
+                vec(any::<u8>(), 0..16),
+            )
+                .prop_map(|(id, offset, crc32, mut content, unsent)| {
+                    let content_size = content.len() as u16;
+                    content.extend_from_slice(&unsent[..]);
+                    Message::FileChunk {
+                        id,
+                        offset,
+                        content_size,
+                        crc32,
+                        content,
+                    }
+                })
+        }
+
+        fn manifest_entry() -> impl Strategy<Value = ManifestEntry> {
+            (name(), any::<u64>(), any::<[u8; 32]>())
+                .prop_map(|(path, size, digest)| ManifestEntry { path, size, digest })
+        }
+
+        fn message() -> impl Strategy<Value = Message> {
+            let version = (0..=ProtocolVersion::CURRENT as u8)
+                .prop_map(|version| ProtocolVersion::from_u8(version).unwrap());
+            let kind = prop_oneof![
+                Just(SpecialKind::Fifo),
+                Just(SpecialKind::CharDevice),
+                Just(SpecialKind::BlockDevice),
+            ];
+
+            prop_oneof![
+                (name(), version).prop_map(|(session_id, version)| Message::Hello {
+                    session_id,
+                    version
+                }),
+                any::<u64>().prop_map(Message::KeepAlive),
+                any::<u64>().prop_map(Message::CountFilesToUpload),
+                file(),
+                file_chunk(),
+                Just(Message::Done),
+                (any::<u64>(), any::<[u8; 32]>())
+                    .prop_map(|(id, digest)| Message::FileHash { id, digest }),
+                (any::<u64>(), vec(any::<u8>(), 0..128))
+                    .prop_map(|(id, acl)| Message::Acl { id, acl }),
+                (name(), kind, any::<u64>()).prop_map(|(path, kind, rdev)| Message::SpecialFile {
+                    path,
+                    kind,
+                    rdev
+                }),
+                (name(), any::<u32>()).prop_map(|(path, mode)| Message::Directory { path, mode }),
+                (name(), name())
+                    .prop_map(|(link_path, target)| Message::Symlink { link_path, target }),
+                (
+                    any::<u64>(),
+                    any::<u64>(),
+                    any::<u64>(),
+                    any::<u64>(),
+                    any::<u16>()
+                )
+                    .prop_map(|(id, offset, source_id, source_offset, size)| {
+                        Message::ChunkCopy {
+                            id,
+                            offset,
+                            source_id,
+                            source_offset,
+                            size,
+                        }
+                    }),
+                (any::<u64>(), vec(manifest_entry(), 0..4))
+                    .prop_map(|(total, entries)| Message::TreeManifest { total, entries }),
+                (any::<u64>(), any::<u64>())
+                    .prop_map(|(id, bytes_sent)| Message::Progress { id, bytes_sent }),
+            ]
+        }
+
+        proptest! {
+            #[test]
+            fn wire_round_trip(message in message()) {
+                let mut raw = Vec::new();
+                let size = message.to_wire(&mut raw).unwrap();
+                prop_assert_eq!(size, raw.len());
+
+                let (rest, decoded) = Message::from_wire(&raw[..]).unwrap();
+                prop_assert!(rest.is_empty());
+                prop_assert_eq!(decoded, message);
+            }
+        }
+    }
+
+    #[test]
+    fn chunk_in_a_larger_buffer_round_trips() {
+        let mut content = vec![0u8; 1500];
+        content[..4].copy_from_slice(b"data");
+        let message = Message::FileChunk {
+            id: 42,
+            offset: 0x1000,
+            content_size: 4,
+            crc32: crc32fast::hash(b"data"),
+            content,
+        };
+
+        let decoded = Message::from_bytes(&message.to_bytes().unwrap()[..]).unwrap();
+        assert_eq!(decoded, message);
+        assert_eq!(message, decoded);
+        assert_ne!(
+            decoded,
+            Message::FileChunk {
+                id: 42,
+                offset: 0x1000,
+                content_size: 4,
+                crc32: crc32fast::hash(b"data"),
+                content: b"atad".to_vec(),
+            }
+        );
+    }
+
+    #[test]
+    fn longest_filename_fits_in_a_datagram() {
+        for mtu in [crate::retransmit::min_mtu() + 64, 512, 1500] {
+            let config = crate::Config {
+                mtu,
+                ..Default::default()
+            };
+            let file = |len| Message::File {
+                filename: "x".repeat(len),
+                created: Some(SystemTime::now()),
+                modified: SystemTime::now(),
+                size: u64::MAX,
+                id: u64::MAX,
+                mode: 0o644,
+                compressed: false,
+                partial: false,
+            };
+
+            let max_len = Message::get_max_filename_size(mtu);
+            let raw = file(max_len).to_bytes().unwrap();
+            assert!(crate::retransmit::Retransmit::new(&raw[..], 0, &config).is_ok());
+            let raw = file(max_len + 1).to_bytes().unwrap();
+            assert!(crate::retransmit::Retransmit::new(&raw[..], 0, &config).is_err());
+        }
+    }
+
+    #[test]
+    fn trailing_bytes_are_refused() {
+        let mut raw = Message::KeepAlive(42).to_bytes().unwrap();
+        raw.push(0);
+        assert!(matches!(
+            Message::from_bytes(&raw[..]),
+            Err(crate::Error::TrailingBytes(1))
+        ));
+    }
+
+    #[test]
+    fn file_hash_round_trip() {
+        let message = Message::FileHash {
+            id: 42,
+            digest: [0xa5; 32],
+        };
+
+        let mut raw = Vec::new();
+        let size = message.to_wire(&mut raw).unwrap();
+        assert_eq!(size, raw.len());
+
+        let (rest, decoded) = Message::from_wire(&raw[..]).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(decoded, message);
+    }
+
+    #[test]
+    fn chunk_copy_round_trip() {
+        let message = Message::ChunkCopy {
+            id: 42,
+            offset: 0x2000,
+            source_id: 7,
+            source_offset: 0x1000,
+            size: 1234,
+        };
+
+        let mut raw = Vec::new();
+        let size = message.to_wire(&mut raw).unwrap();
+        assert_eq!(size, raw.len());
+
+        let (rest, decoded) = Message::from_wire(&raw[..]).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(decoded, message);
+    }
+
+    #[test]
+    fn progress_round_trip() {
+        let message = Message::Progress {
+            id: 42,
+            bytes_sent: 0x1234_5678_9abc,
+        };
+
+        let mut raw = Vec::new();
+        let size = message.to_wire(&mut raw).unwrap();
+        assert_eq!(size, raw.len());
+        assert_eq!(raw[0], 7);
+
+        let (rest, decoded) = Message::from_wire(&raw[..]).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(decoded, message);
+    }
+
+    #[test]
+    fn tree_manifest_round_trip() {
+        let entries = vec![
+            ManifestEntry {
+                path: String::from("dir/file"),
+                size: 1234,
+                digest: [0xa5; 32],
+            },
+            ManifestEntry {
+                path: String::from("other"),
+                size: 0,
+                digest: [0x5a; 32],
+            },
+        ];
+        let message = Message::TreeManifest {
+            total: 3,
+            entries: entries.clone(),
+        };
+
+        let mut raw = Vec::new();
+        let size = message.to_wire(&mut raw).unwrap();
+        assert_eq!(size, raw.len());
+        assert_eq!(
+            size,
+            Message::TREE_MANIFEST_PREFIX_SIZE
+                + entries.iter().map(|e| e.wire_size()).sum::<usize>()
+        );
+
+        let (rest, decoded) = Message::from_wire(&raw[..]).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(decoded, message);
+    }
+
+    #[test]
+    fn file_round_trip() {
+        let message = Message::File {
+            filename: String::from("dir/file"),
+            created: Some(UNIX_EPOCH + Duration::from_secs(1_600_000_000)),
+            modified: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            size: 1234,
+            id: 42,
+            mode: 0o750,
+            compressed: true,
+            partial: true,
+        };
+
+        let mut raw = Vec::new();
+        let size = message.to_wire(&mut raw).unwrap();
+        assert_eq!(size, raw.len());
+
+        let (rest, decoded) = Message::from_wire(&raw[..]).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(decoded, message);
+    }
+
+    #[test]
+    fn unknown_creation_time_round_trip() {
+        let message = Message::File {
+            filename: String::from("file"),
+            created: None,
+            modified: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            size: 0,
+            id: 1,
+            mode: 0,
+            compressed: false,
+            partial: false,
+        };
+
+        let mut raw = Vec::new();
+        message.to_wire(&mut raw).unwrap();
+
+        let (_, decoded) = Message::from_wire(&raw[..]).unwrap();
+        assert_eq!(decoded, message);
+    }
+
+    /// `File` as encoded before `V3`, with `mode` from `V2` on
+    #[cfg(not(feature = "compact"))]
+    fn old_file(mode: Option<u32>) -> Vec<u8> {
+        let mut raw = vec![MessageKind::File.to_u8()];
+        raw.extend_from_slice(&4u16.to_be_bytes()[..]);
+        raw.extend_from_slice(b"file");
+        raw.extend_from_slice(&1_600_000_000u64.to_be_bytes()[..]);
+        raw.extend_from_slice(&1234u64.to_be_bytes()[..]);
+        raw.extend_from_slice(&42u64.to_be_bytes()[..]);
+        if let Some(mode) = mode {
+            raw.extend_from_slice(&mode.to_be_bytes()[..]);
+        }
+        raw
+    }
+
+    // Raw messages are built with big-endian integers, which `compact` does not decode
+    #[cfg(not(feature = "compact"))]
+    #[test]
+    fn decode_old_files() {
+        let created = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let expected = |mode| Message::File {
+            filename: String::from("file"),
+            created: Some(created),
+            modified: created,
+            size: 1234,
+            id: 42,
+            mode,
+            compressed: false,
+            partial: false,
+        };
+
+        for version in [ProtocolVersion::V0, ProtocolVersion::V1] {
+            let raw = old_file(None);
+            let (rest, decoded) = Message::from_wire_versioned(version, &raw[..]).unwrap();
+            assert!(rest.is_empty());
+            assert_eq!(decoded, expected(0));
+        }
+
+        let raw = old_file(Some(0o640));
+        let (rest, decoded) = Message::from_wire_versioned(ProtocolVersion::V2, &raw[..]).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(decoded, expected(0o640));
+
+        // The current decoder expects the fields added since
+        assert!(Message::from_wire(&old_file(Some(0o640))[..]).is_err());
+    }
+
+    #[test]
+    fn decode_v7_compressed_file() {
+        let message = Message::File {
+            filename: String::from("file"),
+            created: None,
+            modified: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            size: 1234,
+            id: 42,
+            mode: 0o644,
+            compressed: true,
+            partial: false,
+        };
+        let mut raw = Vec::new();
+        message.to_wire(&mut raw).unwrap();
+
+        let (_, decoded) = Message::from_wire_versioned(ProtocolVersion::V7, &raw[..]).unwrap();
+        assert_eq!(decoded, message);
+
+        // `V7` had no partial updates
+        *raw.last_mut().unwrap() = FILE_COMPRESSED | FILE_PARTIAL;
+        assert!(Message::from_wire_versioned(ProtocolVersion::V7, &raw[..]).is_err());
+    }
+
+    // Raw messages are built with big-endian integers, which `compact` does not decode
+    #[cfg(not(feature = "compact"))]
+    #[test]
+    fn decode_v0_file_chunk() {
+        let mut raw = vec![MessageKind::FileChunk.to_u8()];
+        raw.extend_from_slice(&42u64.to_be_bytes()[..]);
+        raw.extend_from_slice(&0x1000u64.to_be_bytes()[..]);
+        raw.extend_from_slice(&4u16.to_be_bytes()[..]);
+        raw.extend_from_slice(b"data");
+
+        let (rest, decoded) = Message::from_wire_versioned(ProtocolVersion::V0, &raw[..]).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(
+            decoded,
+            Message::FileChunk {
+                id: 42,
+                offset: 0x1000,
+                content_size: 4,
+                crc32: crc32fast::hash(b"data"),
+                content: b"data".to_vec(),
+            }
+        );
+    }
+
+    // Raw messages are built with big-endian integers, which `compact` does not decode
+    #[cfg(not(feature = "compact"))]
+    #[test]
+    fn version_is_guessed_without_hello() {
+        let chunk = Message::FileChunk {
+            id: 42,
+            offset: 0x1000,
+            content_size: 4,
+            crc32: crc32fast::hash(b"data"),
+            content: b"data".to_vec(),
+        };
+        let mut raw = Vec::new();
+        chunk.to_wire(&mut raw).unwrap();
+        let (rest, decoded) = Message::from_wire_any_version(&raw[..]).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(decoded, chunk);
+
+        // As `V0` encoded it, without its CRC32
+        let mut raw = vec![MessageKind::FileChunk.to_u8()];
+        raw.extend_from_slice(&42u64.to_be_bytes()[..]);
+        raw.extend_from_slice(&0x1000u64.to_be_bytes()[..]);
+        raw.extend_from_slice(&4u16.to_be_bytes()[..]);
+        raw.extend_from_slice(b"data");
+        assert!(Message::from_wire(&raw[..]).is_err());
+        let (rest, decoded) = Message::from_wire_any_version(&raw[..]).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(decoded, chunk);
+    }
+
+    #[test]
+    fn hello_tells_the_version() {
+        let message = Message::Hello {
+            session_id: String::from("backup"),
+            version: ProtocolVersion::CURRENT,
+        };
+        let mut raw = Vec::new();
+        message.to_wire(&mut raw).unwrap();
+
+        // Whatever the version expected
+        let (rest, decoded) = Message::from_wire_versioned(ProtocolVersion::V0, &raw[..]).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(decoded, message);
+
+        // Older senders do not tell their version
+        raw.pop();
+        let (_, decoded) = Message::from_wire(&raw[..]).unwrap();
+        assert_eq!(
+            decoded,
+            Message::Hello {
+                session_id: String::from("backup"),
+                version: ProtocolVersion::V10,
+            }
+        );
+        let (_, decoded) = Message::from_wire(&[MessageKind::Hello.to_u8()][..]).unwrap();
+        assert_eq!(
+            decoded,
+            Message::Hello {
+                session_id: String::new(),
+                version: ProtocolVersion::V3,
+            }
+        );
+    }
+}
@@ -1,19 +1,116 @@
+use std::collections::VecDeque;
 use std::io;
 use std::mem::size_of;
+use std::time::Duration;
 
-use crate::udp::UdpWriter;
+use tokio::time::Instant;
+
+use crate::messages::{Message, MessageKind};
+use crate::udp::AsyncPacketSink;
 use crate::{Config, Error, Result, Wire};
 
 use nom::bytes::complete::{tag, take};
 use nom::error::context;
-use nom::number::complete::be_u16;
+use nom::number::complete::{be_u16, be_u32, be_u8};
+
+/// Magic value "1WY2", changed along with the layout of `RetransmitHeader`
+const RETRANSMIT_MAGIC: &[u8; 4] = b"1WY2";
+
+/// Magic value "1WAY" of the framing used before `ProtocolVersion::V5`, where the magic is only
+/// followed by the size of the chunk
+const LEGACY_MAGIC: &[u8; 4] = b"1WAY";
+
+/// Size of the header of the legacy framing
+const LEGACY_HEADER_SIZE: usize = LEGACY_MAGIC.len() + size_of::<u16>();
+
+/// Payloads are sealed with ChaCha20-Poly1305 under `Config::key`
+///
+/// A sealed payload is a random nonce followed by the ciphertext and its authentication tag.
+#[cfg(feature = "encryption")]
+mod encryption {
+    use chacha20poly1305::aead::{AeadCore, AeadInPlace, KeyInit, OsRng};
+    use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+
+    use crate::Result;
+
+    const NONCE_SIZE: usize = 12;
+    const TAG_SIZE: usize = 16;
+
+    /// Bytes added to each payload
+    pub const OVERHEAD: usize = NONCE_SIZE + TAG_SIZE;
+
+    /// Keyed cipher, which does not show the key when debugged
+    pub struct Cipher(ChaCha20Poly1305);
+
+    impl std::fmt::Debug for Cipher {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.write_str("Cipher(ChaCha20Poly1305)")
+        }
+    }
+
+    pub fn cipher(key: &[u8; 32]) -> Cipher {
+        Cipher(ChaCha20Poly1305::new(Key::from_slice(&key[..])))
+    }
+
+    /// Encrypts `data` under a fresh random nonce, authenticating `associated_data` along
+    pub fn seal(Cipher(cipher): &Cipher, associated_data: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let mut sealed = Vec::with_capacity(data.len() + OVERHEAD);
+        sealed.extend_from_slice(&nonce[..]);
+        sealed.extend_from_slice(data);
+
+        let mut ciphertext = sealed.split_off(NONCE_SIZE);
+        cipher.encrypt_in_place(&nonce, associated_data, &mut ciphertext)?;
+        sealed.append(&mut ciphertext);
+
+        Ok(sealed)
+    }
+
+    /// Decrypts `data` in place, leaving it empty if it was not authenticated
+    pub fn open(Cipher(cipher): &Cipher, associated_data: &[u8], data: &mut Vec<u8>) -> Result<()> {
+        if data.len() < OVERHEAD {
+            data.clear();
+            return Err(chacha20poly1305::Error.into());
+        }
+
+        let nonce = *Nonce::from_slice(&data[..NONCE_SIZE]);
+        data.drain(..NONCE_SIZE);
+        if let Err(e) = cipher.decrypt_in_place(&nonce, associated_data, data) {
+            data.clear();
+            return Err(e.into());
+        }
 
-/// Magic value "1WAY"
-const RETRANSMIT_MAGIC: &[u8; 4] = b"1WAY";
+        Ok(())
+    }
+}
+
+/// Bytes added by encryption to each payload
+#[cfg(feature = "encryption")]
+const ENCRYPTION_OVERHEAD: usize = encryption::OVERHEAD;
+
+#[cfg(not(feature = "encryption"))]
+const ENCRYPTION_OVERHEAD: usize = 0;
+
+/// Bytes a parity chunk needs on top of the chunks it protects: their count and XORed sizes
+const FEC_OVERHEAD: usize = 2 * size_of::<u16>();
+
+/// Flag of chunks holding the XOR parity of a group of messages
+const FLAG_PARITY: u8 = 0x01;
+
+/// Sequences further behind the latest one are taken as a restart of the client
+const RESTART_DISTANCE: u32 = u32::MAX / 4;
 
 /// The actual Retransmit header being set as a prefix for each data send/received
 #[derive(Debug, Clone)]
 struct RetransmitHeader<'a> {
+    /// Combination of `FLAG_*`
+    flags: u8,
+
+    /// Sequence number of the message, incremented for each message sent
+    ///
+    /// Parity chunks carry the sequence of the first message of their group.
+    sequence: u32,
+
     /// Size of the chunk
     size: u16,
     data: &'a [u8],
@@ -22,20 +119,50 @@ struct RetransmitHeader<'a> {
 impl<'a> RetransmitHeader<'a> {
     const fn size() -> usize {
         let magic_size = RETRANSMIT_MAGIC.len();
+        let flags_size = size_of::<u8>();
+        let sequence_size = size_of::<u32>();
         let size_size = size_of::<u16>();
 
-        magic_size + size_size
+        magic_size + flags_size + sequence_size + size_size
     }
 
     fn len(&self) -> usize {
         Self::size() + self.data.len()
     }
+
+    /// Parses a header whose chunk fits in `mtu`
+    ///
+    /// `size` comes from the wire, a larger one is refused with `Error::PayloadTooLarge` instead
+    /// of waiting for bytes which will never be part of the chunk.
+    fn from_wire_bounded(input: &'a [u8], mtu: usize) -> Result<(&'a [u8], Self)> {
+        if input.len() >= Self::size() && input.starts_with(&RETRANSMIT_MAGIC[..]) {
+            let at = Self::size() - size_of::<u16>();
+            let size = usize::from(u16::from_be_bytes([input[at], input[at + 1]]));
+            if size > max_chunk_size(mtu) {
+                return Err(Error::PayloadTooLarge(size));
+            }
+        }
+        Self::from_wire(input)
+    }
+}
+
+/// Bytes added to each payload once framed (and encrypted)
+const FRAMING_OVERHEAD: usize = RetransmitHeader::size() + ENCRYPTION_OVERHEAD + FEC_OVERHEAD;
+
+/// Smallest MTU leaving room for one byte of file content once framed (and encrypted)
+pub const fn min_mtu() -> usize {
+    FRAMING_OVERHEAD + Message::FILE_CHUNK_PREFIX_SIZE + 1
 }
 
 pub const fn max_payload_size(mtu: usize) -> usize {
-    debug_assert!(mtu > RetransmitHeader::size());
+    debug_assert!(mtu > FRAMING_OVERHEAD);
 
-    mtu - RetransmitHeader::size()
+    mtu - FRAMING_OVERHEAD
+}
+
+/// Largest chunk following a header: a payload once encrypted, or a parity chunk
+const fn max_chunk_size(mtu: usize) -> usize {
+    max_payload_size(mtu) + ENCRYPTION_OVERHEAD + FEC_OVERHEAD
 }
 
 impl<'a> Wire<'a> for RetransmitHeader<'a> {
@@ -47,14 +174,26 @@ impl<'a> Wire<'a> for RetransmitHeader<'a> {
         }
 
         let (rest, _magic) = context("RetransmitHeader/MAGIC", tag(RETRANSMIT_MAGIC))(input)?;
+        let (rest, flags) = context("RetransmitHeader/flags", be_u8)(rest)?;
+        let (rest, sequence) = context("RetransmitHeader/sequence", be_u32)(rest)?;
         let (rest, size) = context("RetransmitHeader/size", be_u16)(rest)?;
         let (rest, data) = context("RetransmitHeader/data", take(size as usize))(rest)?;
 
-        Ok((rest, Self { size, data }))
+        Ok((
+            rest,
+            Self {
+                flags,
+                sequence,
+                size,
+                data,
+            },
+        ))
     }
 
     fn to_wire<W: io::Write>(&self, mut writer: W) -> Result<usize> {
         writer.write_all(&RETRANSMIT_MAGIC[..])?;
+        writer.write_all(&[self.flags][..])?;
+        writer.write_all(&self.sequence.to_be_bytes()[..])?;
         writer.write_all(&self.size.to_be_bytes()[..])?;
         writer.write_all(self.data)?;
 
@@ -77,28 +216,61 @@ pub struct Retransmit {
 
 impl Retransmit {
     /// Construct new `Retransmit` with specified configuration
-    pub fn new(data: &[u8], remission_count: usize, mtu: usize) -> Result<Self> {
-        let buffer_size = data.len() + RetransmitHeader::size();
-        if buffer_size > mtu {
+    ///
+    /// `sequence` is expected to be incremented for each message, so that the receiver drops
+    /// copies and notices lost messages. With the `encryption` feature, `data` is encrypted under
+    /// `config.key`. With `config.pad_datagrams`, random bytes are appended up to `config.mtu`.
+    pub fn new(data: &[u8], sequence: u32, config: &Config) -> Result<Self> {
+        let buffer_size = data.len() + ENCRYPTION_OVERHEAD + RetransmitHeader::size();
+        if buffer_size + FEC_OVERHEAD > config.mtu {
             return Err(Error::PayloadTooLarge(buffer_size));
         }
-        let mut buffer = Vec::with_capacity(buffer_size);
+
+        #[cfg(feature = "encryption")]
+        let key = config.key.as_ref().ok_or_else(|| {
+            Error::InvalidSetting(String::from("No key to encrypt with, set key or key_file"))
+        })?;
+        #[cfg(feature = "encryption")]
+        let sealed = encryption::seal(&encryption::cipher(key), &sequence.to_be_bytes(), data)?;
+        #[cfg(feature = "encryption")]
+        let data = &sealed[..];
+
         let header = RetransmitHeader {
+            flags: 0,
+            sequence,
             size: data.len().try_into()?,
             data,
         };
+        Self::from_header(&header, config)
+    }
+
+    /// Construct the `Retransmit` sending `header`
+    fn from_header(header: &RetransmitHeader, config: &Config) -> Result<Self> {
+        let buffer_size = header.len();
+        let mut buffer = Vec::with_capacity(buffer_size.max(config.mtu));
         header.to_wire(&mut buffer)?;
         assert_eq!(buffer.len(), buffer_size);
 
+        if config.pad_datagrams {
+            buffer.resize(config.mtu, 0);
+            getrandom::getrandom(&mut buffer[buffer_size..]).map_err(io::Error::from)?;
+        }
+
         Ok(Self {
             current_emission: 1,
-            total_emissions: remission_count,
+            total_emissions: config.remission_count,
             buffer,
         })
     }
 
+    /// Sends the message `repeats` times instead of `remission_count`
+    pub fn with_repeats(mut self, repeats: usize) -> Self {
+        self.total_emissions = repeats;
+        self
+    }
+
     /// Yeilds each chunk to send prefixed with a `RetransmitHeader`
-    fn get_next_chunk<'s>(&'s mut self) -> Option<&'s [u8]> {
+    fn get_next_chunk(&mut self) -> Option<&[u8]> {
         // First advance current_emission
         if self.current_emission <= self.total_emissions {
             self.current_emission += 1;
@@ -109,23 +281,246 @@ impl Retransmit {
         None
     }
 
+    /// Header and (encrypted) payload of the message
+    fn header(&self) -> RetransmitHeader<'_> {
+        let (_rest, header) = RetransmitHeader::from_wire(&self.buffer[..])
+            .expect("Retransmit buffers hold a valid header");
+        header
+    }
+
     /// Reset counters of `RetransmitHeader`
     fn reset(&mut self) {
         self.current_emission = 1;
     }
 
     /// Sends current request with repetitions
-    pub async fn send(&mut self, socket: &UdpWriter) -> Result<()> {
+    pub async fn send<W: AsyncPacketSink>(&mut self, socket: &W) -> Result<()> {
+        self.send_paced(socket, None, None).await
+    }
+
+    /// Sends current request with repetitions, holding the rate of `pacer` if any
+    ///
+    /// Copies after the first emission also hold the rate of `copies_pacer`.
+    pub async fn send_paced<W: AsyncPacketSink>(
+        &mut self,
+        socket: &W,
+        mut pacer: Option<&mut Pacer>,
+        mut copies_pacer: Option<&mut Pacer>,
+    ) -> Result<()> {
         self.reset();
 
-        while let Some(chunk) = self.get_next_chunk() {
+        loop {
+            let is_copy = self.current_emission > 1;
+            let Some(chunk) = self.get_next_chunk() else {
+                break;
+            };
+            if is_copy {
+                if let Some(copies_pacer) = copies_pacer.as_mut() {
+                    copies_pacer.consume(chunk.len()).await;
+                }
+            }
+            if let Some(pacer) = pacer.as_mut() {
+                pacer.consume(chunk.len()).await;
+            }
             tracing::debug!("Sending {} bytes chunk", chunk.len());
-            socket.send(chunk).await?;
+            socket.send(chunk).await.map_err(|e| {
+                if crate::utils::is_message_too_long(&e) {
+                    Error::PayloadTooLarge(chunk.len())
+                } else {
+                    Error::IO(e)
+                }
+            })?;
         }
         Ok(())
     }
 }
 
+/// Decides how many times each message is sent
+pub trait RetransmitPolicy: std::fmt::Debug + Send + Sync {
+    /// Emissions of a message of kind `kind`
+    ///
+    /// For file chunks, `offset` is where the chunk ends in the file and `total` the size of the
+    /// file, so that the last chunk and the empty terminator are at `offset == total`. Both are 0
+    /// otherwise.
+    fn repeats(&self, kind: MessageKind, offset: u64, total: u64) -> usize;
+}
+
+/// Sends every message the same number of times, `remission_count` by default
+#[derive(Debug, Clone, Copy)]
+pub struct ConstantPolicy(pub usize);
+
+impl RetransmitPolicy for ConstantPolicy {
+    fn repeats(&self, _kind: MessageKind, _offset: u64, _total: u64) -> usize {
+        self.0
+    }
+}
+
+/// Sends file contents `bulk` times, but control messages and the end of files `tail` times
+///
+/// Losing a chunk in the middle of a file only corrupts it, while losing a file announcement or
+/// its end leaves the server waiting for `file_idle_timeout`.
+#[derive(Debug, Clone, Copy)]
+pub struct TailHeavyPolicy {
+    pub bulk: usize,
+    pub tail: usize,
+}
+
+impl RetransmitPolicy for TailHeavyPolicy {
+    fn repeats(&self, kind: MessageKind, offset: u64, total: u64) -> usize {
+        let is_content = matches!(kind, MessageKind::FileChunk | MessageKind::ChunkCopy);
+        if is_content && offset < total {
+            self.bulk
+        } else {
+            self.tail
+        }
+    }
+}
+
+/// Token bucket limiting the rate at which datagrams are sent
+///
+/// The bucket holds at most one datagram worth of bytes, so that datagrams are evenly spaced
+/// instead of being sent in bursts.
+#[derive(Debug)]
+pub struct Pacer {
+    /// Bytes allowed per second
+    rate: u64,
+
+    /// Maximum number of tokens
+    capacity: f64,
+
+    /// Bytes which can be sent right away
+    tokens: f64,
+
+    /// When `tokens` was last refilled
+    last_refill: Instant,
+}
+
+impl Pacer {
+    /// Returns `None` when `config.max_bytes_per_sec` is not set
+    pub fn new(config: &Config) -> Option<Self> {
+        Self::with_rate(config.max_bytes_per_sec, config)
+    }
+
+    /// Pacer of the retransmitted copies, `None` when `config.retransmit_rate_limit` is not set
+    pub fn for_copies(config: &Config) -> Option<Self> {
+        Self::with_rate(config.retransmit_rate_limit, config)
+    }
+
+    fn with_rate(rate: Option<u64>, config: &Config) -> Option<Self> {
+        let rate = rate.filter(|rate| *rate > 0)?;
+        let capacity = config.mtu as f64;
+
+        Some(Self {
+            rate,
+            capacity,
+            tokens: capacity,
+            last_refill: Instant::now(),
+        })
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.capacity);
+        self.last_refill = now;
+    }
+
+    /// Waits until `bytes` can be sent
+    pub async fn consume(&mut self, bytes: usize) {
+        self.refill();
+
+        let missing = bytes as f64 - self.tokens;
+        if missing > 0.0 {
+            tokio::time::sleep(Duration::from_secs_f64(missing / self.rate as f64)).await;
+            self.refill();
+        }
+        // Datagrams larger than the bucket leave a debt paid by the next ones
+        self.tokens -= bytes as f64;
+    }
+}
+
+/// Builds the parity chunks sent after every `fec_group_size` messages
+///
+/// A parity chunk holds the number of messages in its group, the XOR of their sizes and the XOR of
+/// their (encrypted) payloads, so that the receiver can rebuild one lost message per group.
+#[derive(Debug)]
+pub struct FecEncoder {
+    group_size: usize,
+
+    /// Sequence of the first message of the current group
+    first_sequence: u32,
+
+    /// Messages in the current group
+    count: u16,
+
+    /// XOR of the sizes of the messages in the current group
+    sizes: u16,
+
+    /// XOR of the payloads of the messages in the current group
+    parity: Vec<u8>,
+}
+
+impl FecEncoder {
+    /// Returns `None` when forward error correction is disabled
+    pub fn new(config: &Config) -> Option<Self> {
+        if config.fec_group_size == 0 {
+            return None;
+        }
+
+        Some(Self {
+            group_size: config.fec_group_size.into(),
+            first_sequence: 0,
+            count: 0,
+            sizes: 0,
+            parity: Vec::with_capacity(config.mtu),
+        })
+    }
+
+    /// Adds a message to the current group, returns its parity chunk once the group is complete
+    pub fn add(&mut self, retransmit: &Retransmit, config: &Config) -> Result<Option<Retransmit>> {
+        let header = retransmit.header();
+        if self.count == 0 {
+            self.first_sequence = header.sequence;
+        }
+        self.count += 1;
+        self.sizes ^= header.size;
+        if self.parity.len() < header.data.len() {
+            self.parity.resize(header.data.len(), 0);
+        }
+        for (p, d) in self.parity.iter_mut().zip(header.data) {
+            *p ^= d;
+        }
+
+        if usize::from(self.count) >= self.group_size {
+            self.finish(config)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Returns the parity chunk of the current group, if it holds any message
+    pub fn finish(&mut self, config: &Config) -> Result<Option<Retransmit>> {
+        if self.count == 0 {
+            return Ok(None);
+        }
+
+        let mut data = Vec::with_capacity(FEC_OVERHEAD + self.parity.len());
+        data.extend_from_slice(&self.count.to_be_bytes()[..]);
+        data.extend_from_slice(&self.sizes.to_be_bytes()[..]);
+        data.append(&mut self.parity);
+        self.count = 0;
+        self.sizes = 0;
+
+        let header = RetransmitHeader {
+            flags: FLAG_PARITY,
+            sequence: self.first_sequence,
+            size: data.len().try_into()?,
+            data: &data[..],
+        };
+        Retransmit::from_header(&header, config).map(Some)
+    }
+}
+
 #[derive(Debug)]
 pub struct Reassembler {
     /// Buffer for data being passed to this struct
@@ -137,8 +532,39 @@ pub struct Reassembler {
     /// MTU configured
     mtu: usize,
 
-    /// Previous chunk seen
-    previous_chunk: Vec<u8>,
+    /// Highest sequence number accepted so far
+    last_sequence: Option<u32>,
+
+    /// Sequence numbers accepted among the `dedup_window` ones before `last_sequence` and itself
+    ///
+    /// A larger window catches retransmissions that were interleaved with other messages. A
+    /// message further behind is late, unless it is so far behind that the client restarted.
+    recent_sequences: VecDeque<u32>,
+
+    /// Sequence of the message last returned although it was behind the window
+    late_sequence: Option<u32>,
+
+    /// Number of messages before the latest one whose copies are dropped
+    dedup_window: usize,
+
+    /// Distinct messages accepted so far
+    received_messages: u64,
+
+    /// Messages skipped by sequence numbers and not received since
+    missed_messages: u64,
+
+    /// Number of messages protected by each parity chunk, 0 when disabled
+    fec_group_size: usize,
+
+    /// (Encrypted) payloads of recent messages, to rebuild a lost one from a parity chunk
+    fec_payloads: VecDeque<(u32, Vec<u8>)>,
+
+    /// Whether datagrams are padded up to `mtu`
+    padded: bool,
+
+    /// Cipher of `config.key`, every datagram being dropped without one
+    #[cfg(feature = "encryption")]
+    cipher: Option<encryption::Cipher>,
 }
 
 impl Reassembler {
@@ -147,7 +573,201 @@ impl Reassembler {
             buffer: Vec::with_capacity(config.mtu * 2),
             offset: 0,
             mtu: config.mtu,
-            previous_chunk: Vec::with_capacity(config.mtu),
+            last_sequence: None,
+            recent_sequences: VecDeque::with_capacity(config.dedup_window + 1),
+            late_sequence: None,
+            dedup_window: config.dedup_window,
+            received_messages: 0,
+            missed_messages: 0,
+            padded: config.pad_datagrams,
+            fec_group_size: config.fec_group_size.into(),
+            fec_payloads: VecDeque::new(),
+            #[cfg(feature = "encryption")]
+            cipher: config.key.as_ref().map(encryption::cipher),
+        }
+    }
+
+    /// Distinct messages received so far
+    pub fn received_messages(&self) -> u64 {
+        self.received_messages
+    }
+
+    /// Messages which were skipped and never arrived, as far as sequence numbers tell
+    pub fn missed_messages(&self) -> u64 {
+        self.missed_messages
+    }
+
+    /// Number of bytes currently retained for duplicate detection, parity recovery and
+    /// unconsumed data
+    pub fn retained_bytes(&self) -> usize {
+        let fec_payloads: usize = self
+            .fec_payloads
+            .iter()
+            .map(|(_, payload)| size_of::<u32>() + payload.capacity())
+            .sum();
+        self.buffer.capacity() + self.recent_sequences.capacity() * size_of::<u32>() + fec_payloads
+    }
+
+    /// Releases memory held for duplicate detection and unconsumed data
+    ///
+    /// This is meant to be called when the stream goes idle: a retransmission arriving afterwards
+    /// will not be recognized as such.
+    pub fn release(&mut self) {
+        self.last_sequence = None;
+        self.recent_sequences = VecDeque::new();
+        self.fec_payloads = VecDeque::new();
+        self.buffer.drain(..self.offset);
+        self.buffer.shrink_to_fit();
+        self.offset = 0;
+    }
+
+    /// Keeps the payload of an accepted message for parity chunks to come
+    fn remember_payload(&mut self, sequence: u32, payload: Vec<u8>) {
+        // Parity chunks may arrive after some messages of the next group
+        if self.fec_payloads.len() >= 2 * self.fec_group_size {
+            self.fec_payloads.pop_front();
+        }
+        self.fec_payloads.push_back((sequence, payload));
+    }
+
+    /// Rebuilds the only message of the group of `first_sequence` which was not received
+    fn recover(&self, first_sequence: u32, parity: &[u8]) -> Option<(u32, Vec<u8>)> {
+        if self.fec_group_size == 0 || parity.len() < FEC_OVERHEAD {
+            return None;
+        }
+
+        let count = u16::from_be_bytes([parity[0], parity[1]]);
+        let mut size = u16::from_be_bytes([parity[2], parity[3]]);
+        let mut payload = parity[FEC_OVERHEAD..].to_vec();
+        let mut missing = None;
+
+        for sequence in (0..u32::from(count)).map(|i| first_sequence.wrapping_add(i)) {
+            match self.fec_payloads.iter().find(|(s, _)| *s == sequence) {
+                Some((_, received)) if received.len() <= payload.len() => {
+                    size ^= received.len() as u16;
+                    for (p, r) in payload.iter_mut().zip(received) {
+                        *p ^= r;
+                    }
+                }
+                Some(_) => return None,
+                // Accepted but already forgotten, nothing to rebuild
+                None if self.is_duplicate(sequence) => return None,
+                None => {
+                    if missing.replace(sequence).is_some() {
+                        // A single parity chunk cannot rebuild two messages
+                        return None;
+                    }
+                }
+            }
+        }
+
+        let sequence = missing?;
+        if usize::from(size) > payload.len() {
+            return None;
+        }
+        payload.truncate(size.into());
+        Some((sequence, payload))
+    }
+
+    fn is_duplicate(&self, sequence: u32) -> bool {
+        self.recent_sequences.contains(&sequence)
+    }
+
+    /// Whether `sequence` is behind the window, without being far enough for a restart
+    fn is_behind_window(&self, sequence: u32) -> bool {
+        self.last_sequence.is_some_and(|last| {
+            let behind = last.wrapping_sub(sequence);
+            behind as usize > self.dedup_window && behind <= RESTART_DISTANCE
+        })
+    }
+
+    /// Whether the message last returned was behind the window
+    ///
+    /// Such a message may be a copy of one already returned, it should be dropped unless it tells
+    /// that the client restarted, which `restart` then records.
+    pub fn is_late(&self) -> bool {
+        self.late_sequence.is_some()
+    }
+
+    /// Accepts the late message last returned as the first one of a restarted client
+    pub fn restart(&mut self) {
+        let Some(sequence) = self.late_sequence.take() else {
+            return;
+        };
+        tracing::warn!(
+            "Sequence {} is behind {:?}, the client restarted",
+            sequence,
+            self.last_sequence
+        );
+        self.last_sequence = None;
+        self.recent_sequences.clear();
+        self.accept_sequence(sequence);
+    }
+
+    /// Records an accepted message, warning about the ones skipped before it
+    fn accept_sequence(&mut self, sequence: u32) {
+        self.received_messages += 1;
+        if let Some(last) = self.last_sequence {
+            // Serial number arithmetic, so that sequences may wrap around
+            let ahead = sequence.wrapping_sub(last) as i32;
+            if ahead > 1 {
+                tracing::warn!(
+                    "Missed {} messages (sequences {} to {})",
+                    ahead - 1,
+                    last.wrapping_add(1),
+                    sequence.wrapping_sub(1)
+                );
+                self.missed_messages += u64::from(ahead.unsigned_abs() - 1);
+            }
+
+            if ahead > 0 {
+                self.last_sequence = Some(sequence);
+            } else if ahead == 0 {
+                // A copy which was not remembered, without `dedup_window`
+            } else if last.wrapping_sub(sequence) <= RESTART_DISTANCE {
+                // A late message within the window fills a gap
+                self.missed_messages = self.missed_messages.saturating_sub(1);
+            } else {
+                tracing::warn!(
+                    "Sequence {} is far behind {}, assuming the client restarted",
+                    sequence,
+                    last
+                );
+                self.last_sequence = Some(sequence);
+                self.recent_sequences.clear();
+            }
+        } else {
+            self.last_sequence = Some(sequence);
+        }
+
+        if self.dedup_window == 0 {
+            return;
+        }
+        self.recent_sequences.push_back(sequence);
+        // Forget sequences which fell out of the window
+        let last = self.last_sequence.unwrap_or(sequence);
+        self.recent_sequences
+            .retain(|s| last.wrapping_sub(*s) as usize <= self.dedup_window);
+    }
+
+    /// Bytes taken by a chunk of `len` bytes, including its padding
+    ///
+    /// Padding is told from the next chunk by its lack of magic, so that either side may set
+    /// `pad_datagrams` without the other.
+    fn datagram_len(&self, len: usize) -> usize {
+        let available = self.get_available_data();
+        let padded = available.get(len..).is_some_and(|rest| {
+            !rest.is_empty()
+                && !rest.starts_with(&RETRANSMIT_MAGIC[..])
+                && !rest.starts_with(&LEGACY_MAGIC[..])
+        });
+        if padded {
+            if !self.padded {
+                tracing::debug!("Skipping the padding of a datagram");
+            }
+            len.max(self.mtu.min(available.len()))
+        } else {
+            len
         }
     }
 
@@ -180,6 +800,74 @@ impl Reassembler {
         }
     }
 
+    /// Skips to the next magic after garbage or a header which cannot be valid
+    ///
+    /// Everything available is dropped when no other magic is found, as datagrams are pushed
+    /// whole.
+    fn resync(&mut self) {
+        let available = self.get_available_data();
+        let skipped = available[1..]
+            .windows(RETRANSMIT_MAGIC.len())
+            .position(|window| window == &RETRANSMIT_MAGIC[..] || window == &LEGACY_MAGIC[..])
+            .map_or(available.len(), |position| position + 1);
+        tracing::warn!(
+            "Skipped {} bytes to resynchronize on the next chunk",
+            skipped
+        );
+        self.consume(skipped);
+    }
+
+    /// Reads the chunk of the legacy framing at the start of the buffer into `data`, returns
+    /// whether `data` holds a message
+    ///
+    /// Legacy chunks are not numbered, so their copies are not dropped and their losses are not
+    /// noticed.
+    fn next_legacy_data(&mut self, data: &mut Vec<u8>) -> Result<bool> {
+        let available = self.get_available_data();
+        if available.len() < LEGACY_HEADER_SIZE {
+            return Err(Error::Deserialize(nom::Err::Incomplete(nom::Needed::new(
+                LEGACY_HEADER_SIZE - available.len(),
+            ))));
+        }
+        let size = usize::from(u16::from_be_bytes([available[4], available[5]]));
+        if size > max_chunk_size(self.mtu) {
+            tracing::warn!(
+                "Dropping chunk of {} bytes which cannot fit in {} bytes datagrams",
+                size,
+                self.mtu
+            );
+            self.resync();
+            return Ok(false);
+        }
+        let Some(content) = available.get(LEGACY_HEADER_SIZE..LEGACY_HEADER_SIZE + size) else {
+            return Err(Error::Deserialize(nom::Err::Incomplete(nom::Needed::new(
+                LEGACY_HEADER_SIZE + size - available.len(),
+            ))));
+        };
+        data.extend_from_slice(content);
+        let len = self.datagram_len(LEGACY_HEADER_SIZE + size);
+        self.consume(len);
+
+        // Nothing was authenticated along the payload
+        #[cfg(feature = "encryption")]
+        match self.cipher {
+            Some(ref cipher) => {
+                if let Err(e) = encryption::open(cipher, b"", data) {
+                    tracing::warn!("Dropping chunk which could not be decrypted: {}", e);
+                    return Ok(false);
+                }
+            }
+            None => {
+                tracing::warn!("Dropping chunk as no key is set to decrypt it");
+                data.clear();
+                return Ok(false);
+            }
+        }
+
+        self.received_messages += 1;
+        Ok(true)
+    }
+
     pub fn push_data(&mut self, data: &[u8]) {
         self.buffer.extend_from_slice(data);
         tracing::trace!(
@@ -190,32 +878,728 @@ impl Reassembler {
     }
 
     /// Reassemble and returns next data
+    ///
+    /// A message behind the window is returned without being accepted, see `is_late`.
     pub fn get_next_data(&mut self, data: &mut Vec<u8>) -> Result<()> {
         data.clear();
+        self.late_sequence = None;
         // We could re-parse the header each time, but it is so small and cheap that caching it
         // would not worth it
         loop {
-            let (_rest, retransmit) = RetransmitHeader::from_wire(self.get_available_data())?;
+            let available = self.get_available_data();
+            if available.starts_with(&LEGACY_MAGIC[..]) {
+                if self.next_legacy_data(data)? {
+                    return Ok(());
+                }
+                continue;
+            }
+            if available.len() >= RETRANSMIT_MAGIC.len()
+                && !available.starts_with(&RETRANSMIT_MAGIC[..])
+            {
+                self.resync();
+                continue;
+            }
+
+            let (_rest, retransmit) =
+                match RetransmitHeader::from_wire_bounded(self.get_available_data(), self.mtu) {
+                    Err(Error::PayloadTooLarge(size)) => {
+                        tracing::warn!(
+                            "Dropping chunk of {} bytes which cannot fit in {} bytes datagrams",
+                            size,
+                            self.mtu
+                        );
+                        self.resync();
+                        continue;
+                    }
+                    result => result?,
+                };
             // let retransmit_len = retransmit.len();
             // data.extend_from_slice(retransmit.data);
             // self.consume(retransmit_len);
             // return Ok(());
 
-            if &self.previous_chunk[..] == retransmit.data {
-                // If we just yield this chunk, ignore it but still consume the chunk from our
+            let retransmit_len = self.datagram_len(retransmit.len());
+            let mut sequence = retransmit.sequence;
+            let parity = retransmit.flags & FLAG_PARITY != 0;
+            if parity {
+                let recovered = self.recover(sequence, retransmit.data);
+                self.consume(retransmit_len);
+                match recovered {
+                    Some((recovered_sequence, payload)) => {
+                        tracing::info!("Rebuilt lost message {}", recovered_sequence);
+                        sequence = recovered_sequence;
+                        data.extend_from_slice(&payload[..]);
+                    }
+                    None => continue,
+                }
+            } else if self.is_duplicate(sequence) {
+                // If we already yielded this chunk, ignore it but still consume the chunk from our
                 // buffer
-                let retransmit_len = retransmit.len();
                 self.consume(retransmit_len);
+                continue;
             } else {
-                // The chunk does not match the previous one
                 data.extend_from_slice(retransmit.data);
-                let mut previous_chunk = retransmit.data.to_vec();
-                let retransmit_len = retransmit.len();
                 self.consume(retransmit_len);
-                std::mem::swap(&mut self.previous_chunk, &mut previous_chunk);
+            }
+
+            // Parity chunks cover payloads as they were sent
+            let payload = (self.fec_group_size > 0).then(|| data.clone());
+
+            // Without acknowledgments, a forged or corrupted chunk can only be dropped
+            #[cfg(feature = "encryption")]
+            match self.cipher {
+                Some(ref cipher) => {
+                    if let Err(e) = encryption::open(cipher, &sequence.to_be_bytes(), data) {
+                        tracing::warn!("Dropping chunk which could not be decrypted: {}", e);
+                        continue;
+                    }
+                }
+                None => {
+                    tracing::warn!("Dropping chunk as no key is set to decrypt it");
+                    data.clear();
+                    continue;
+                }
+            }
 
+            // A rebuilt message was found missing among the payloads kept, beyond the window
+            if !parity && self.is_behind_window(sequence) {
+                self.late_sequence = Some(sequence);
                 return Ok(());
             }
+            if let Some(payload) = payload {
+                self.remember_payload(sequence, payload);
+            }
+            self.accept_sequence(sequence);
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn datagram(sequence: u32, data: &[u8]) -> Vec<u8> {
+        let config = Config {
+            remission_count: 1,
+            ..Default::default()
+        };
+        Retransmit::new(data, sequence, &config).unwrap().buffer
+    }
+
+    fn reassemble(reassembler: &mut Reassembler, datagrams: &[Vec<u8>]) -> Vec<Vec<u8>> {
+        let mut messages = Vec::new();
+        let mut data = Vec::new();
+        for datagram in datagrams {
+            reassembler.push_data(&datagram[..]);
+            match reassembler.get_next_data(&mut data) {
+                Ok(()) if reassembler.is_late() => {}
+                Ok(()) => messages.push(data.clone()),
+                Err(Error::Deserialize(nom::Err::Incomplete(_))) => {}
+                Err(e) => panic!("Unexpected error {}", e),
+            }
         }
+        messages
+    }
+
+    #[test]
+    fn oversized_payload_is_refused() {
+        let config = Config::default();
+        let data = vec![0u8; max_payload_size(config.mtu) + 1];
+
+        match Retransmit::new(&data[..], 0, &config) {
+            Err(e @ Error::PayloadTooLarge(size)) => {
+                assert!(size > config.mtu - FEC_OVERHEAD);
+                assert!(e.to_string().contains("`mtu`"));
+            }
+            other => panic!("Expected PayloadTooLarge, got {:?}", other.map(|_| ())),
+        }
+        Retransmit::new(&data[1..], 0, &config).unwrap();
+    }
+
+    #[test]
+    fn oversized_header_is_skipped() {
+        let config = Config {
+            mtu: 1024,
+            ..Default::default()
+        };
+        let mut reassembler = Reassembler::new(&config);
+
+        let mut bogus = Vec::new();
+        bogus.extend_from_slice(&RETRANSMIT_MAGIC[..]);
+        bogus.push(0);
+        bogus.extend_from_slice(&1u32.to_be_bytes()[..]);
+        bogus.extend_from_slice(&0xffffu16.to_be_bytes()[..]);
+        bogus.extend_from_slice(b"garbage");
+
+        let messages = reassemble(
+            &mut reassembler,
+            &[bogus, datagram(2, b"first"), datagram(3, b"second")],
+        );
+        assert_eq!(messages, [b"first".to_vec(), b"second".to_vec()]);
+        assert!(reassembler.get_available_data().is_empty());
+    }
+
+    #[test]
+    fn copies_after_garbage_are_dropped() {
+        let mut reassembler = Reassembler::new(&Config::default());
+
+        let messages = reassemble(
+            &mut reassembler,
+            &[
+                datagram(1, b"first"),
+                b"garbage".to_vec(),
+                datagram(1, b"first"),
+                datagram(2, b"second"),
+            ],
+        );
+        assert_eq!(messages, [b"first".to_vec(), b"second".to_vec()]);
+        assert_eq!(reassembler.received_messages(), 2);
+    }
+
+    // Legacy chunks are built in clear
+    #[cfg(not(feature = "encryption"))]
+    #[test]
+    fn legacy_chunks_are_reassembled() {
+        let legacy = |data: &[u8]| {
+            let mut chunk = LEGACY_MAGIC.to_vec();
+            chunk.extend_from_slice(&(data.len() as u16).to_be_bytes()[..]);
+            chunk.extend_from_slice(data);
+            chunk
+        };
+        let mut reassembler = Reassembler::new(&Config::default());
+
+        // Unnumbered, their copies are all delivered
+        let messages = reassemble(
+            &mut reassembler,
+            &[
+                legacy(b"first"),
+                legacy(b"first"),
+                b"junk".to_vec(),
+                datagram(1, b"second"),
+                legacy(b"third"),
+            ],
+        );
+        assert_eq!(
+            messages,
+            [
+                b"first".to_vec(),
+                b"first".to_vec(),
+                b"second".to_vec(),
+                b"third".to_vec()
+            ]
+        );
+        assert!(reassembler.get_available_data().is_empty());
+    }
+
+    #[test]
+    fn junk_between_chunks_is_skipped() {
+        let mut reassembler = Reassembler::new(&Config::default());
+
+        // The start of a magic in the junk must not be taken for a chunk either
+        let messages = reassemble(
+            &mut reassembler,
+            &[
+                datagram(1, b"first"),
+                b"junk".to_vec(),
+                b"1W".to_vec(),
+                datagram(2, b"second"),
+            ],
+        );
+        assert_eq!(messages, [b"first".to_vec(), b"second".to_vec()]);
+        assert!(reassembler.get_available_data().is_empty());
+    }
+
+    #[test]
+    fn retained_memory_is_bounded_by_dedup_window() {
+        let config = Config {
+            mtu: 64,
+            dedup_window: 4,
+            ..Default::default()
+        };
+        let mut reassembler = Reassembler::new(&config);
+        let mut data = Vec::new();
+
+        for i in 0..32u8 {
+            reassembler.push_data(&datagram(i.into(), &[i; 16]));
+            reassembler.get_next_data(&mut data).unwrap();
+            assert_eq!(&data[..], &[i; 16]);
+            assert!(reassembler.recent_sequences.len() <= config.dedup_window + 1);
+            assert!(reassembler.retained_bytes() <= config.dedup_window * config.mtu);
+        }
+
+        reassembler.release();
+        assert_eq!(reassembler.retained_bytes(), 0);
+    }
+
+    #[test]
+    fn retained_memory_includes_parity_payloads() {
+        let config = Config {
+            mtu: 256,
+            remission_count: 1,
+            dedup_window: 4,
+            fec_group_size: 4,
+            ..Default::default()
+        };
+        let mut encoder = FecEncoder::new(&config).unwrap();
+        let mut reassembler = Reassembler::new(&config);
+
+        for sequence in 0..32u32 {
+            let retransmit = Retransmit::new(&[0x42; 100], sequence, &config).unwrap();
+            let mut datagrams = vec![retransmit.buffer.clone()];
+            if let Some(parity) = encoder.add(&retransmit, &config).unwrap() {
+                datagrams.push(parity.buffer);
+            }
+            reassemble(&mut reassembler, &datagrams[..]);
+
+            let kept = reassembler.fec_payloads.len();
+            assert!(kept <= 2 * usize::from(config.fec_group_size));
+            assert!(reassembler.retained_bytes() >= kept * 100);
+            assert!(
+                reassembler.retained_bytes()
+                    <= (config.dedup_window + 2 * usize::from(config.fec_group_size) + 2)
+                        * config.mtu
+            );
+        }
+        assert!(!reassembler.fec_payloads.is_empty());
+
+        reassembler.release();
+        assert_eq!(reassembler.retained_bytes(), 0);
+    }
+
+    #[test]
+    fn dedup_window_catches_interleaved_retransmissions() {
+        let config = Config {
+            mtu: 64,
+            dedup_window: 2,
+            ..Default::default()
+        };
+        let mut reassembler = Reassembler::new(&config);
+        let mut data = Vec::new();
+
+        // Retransmissions are copies of the same datagram
+        let first = datagram(1, b"first");
+        let second = datagram(2, b"second");
+        let third = datagram(3, b"third");
+        for chunk in [&first, &second, &first, &second, &third] {
+            reassembler.push_data(chunk);
+        }
+
+        reassembler.get_next_data(&mut data).unwrap();
+        assert_eq!(&data[..], b"first");
+        reassembler.get_next_data(&mut data).unwrap();
+        assert_eq!(&data[..], b"second");
+        reassembler.get_next_data(&mut data).unwrap();
+        assert_eq!(&data[..], b"third");
+    }
+
+    #[test]
+    fn duplicates_are_found_by_sequence() {
+        let mut reassembler = Reassembler::new(&Config::default());
+
+        // Same content under different sequences are distinct messages
+        let messages = reassemble(
+            &mut reassembler,
+            &[
+                datagram(1, b"ping"),
+                datagram(1, b"ping"),
+                datagram(2, b"ping"),
+                datagram(2, b"ping"),
+            ],
+        );
+        assert_eq!(messages, vec![b"ping".to_vec(), b"ping".to_vec()]);
+    }
+
+    #[test]
+    fn reordered_messages_are_accepted_once() {
+        let config = Config {
+            dedup_window: 2,
+            ..Default::default()
+        };
+        let mut reassembler = Reassembler::new(&config);
+
+        let messages = reassemble(
+            &mut reassembler,
+            &[
+                datagram(1, b"first"),
+                datagram(3, b"third"),
+                datagram(2, b"second"),
+                datagram(1, b"first"),
+                datagram(3, b"third"),
+                datagram(2, b"second"),
+            ],
+        );
+        assert_eq!(
+            messages,
+            vec![b"first".to_vec(), b"third".to_vec(), b"second".to_vec()]
+        );
+        assert_eq!(reassembler.last_sequence, Some(3));
+    }
+
+    #[test]
+    fn gaps_in_sequences_are_tolerated() {
+        let mut reassembler = Reassembler::new(&Config::default());
+
+        let messages = reassemble(
+            &mut reassembler,
+            &[
+                datagram(u32::MAX, b"before wrap"),
+                datagram(3, b"after gap"),
+                datagram(3, b"after gap"),
+            ],
+        );
+        assert_eq!(
+            messages,
+            vec![b"before wrap".to_vec(), b"after gap".to_vec()]
+        );
+        assert_eq!(reassembler.last_sequence, Some(3));
+
+        // A sequence far behind is a restarted client
+        let restart = 3u32.wrapping_sub(RESTART_DISTANCE + 1);
+        let messages = reassemble(&mut reassembler, &[datagram(restart, b"restart")]);
+        assert_eq!(messages, vec![b"restart".to_vec()]);
+        assert_eq!(reassembler.last_sequence, Some(restart));
+    }
+
+    #[test]
+    fn late_messages_do_not_rewind_sequences() {
+        let mut reassembler = Reassembler::new(&Config::default());
+
+        let messages = reassemble(
+            &mut reassembler,
+            &[0, 2, 3, 1, 4, 5, 3].map(|sequence| datagram(sequence, &[sequence as u8])),
+        );
+        assert_eq!(messages, [0u8, 2, 3, 4, 5].map(|sequence| vec![sequence]));
+        assert_eq!(reassembler.last_sequence, Some(5));
+        // Message 1 arrived too late to be told from a copy
+        assert_eq!(reassembler.missed_messages(), 1);
+        assert_eq!(reassembler.received_messages(), 5);
+
+        // Unless the caller takes it as the restart of the client
+        let mut data = Vec::new();
+        reassembler.push_data(&datagram(0, b"hello")[..]);
+        reassembler.get_next_data(&mut data).unwrap();
+        assert!(reassembler.is_late());
+        reassembler.restart();
+        assert!(!reassembler.is_late());
+        assert_eq!(reassembler.last_sequence, Some(0));
+        let messages = reassemble(
+            &mut reassembler,
+            &[datagram(0, b"hello"), datagram(1, b"next")],
+        );
+        assert_eq!(messages, vec![b"next".to_vec()]);
+        assert_eq!(reassembler.missed_messages(), 1);
+    }
+
+    #[test]
+    fn copies_without_window_are_not_gaps() {
+        let config = Config {
+            dedup_window: 0,
+            ..Default::default()
+        };
+        let mut reassembler = Reassembler::new(&config);
+
+        let messages = reassemble(
+            &mut reassembler,
+            &[
+                datagram(0, b"first"),
+                datagram(2, b"third"),
+                datagram(2, b"third"),
+            ],
+        );
+        assert_eq!(messages.len(), 3);
+        assert_eq!(reassembler.missed_messages(), 1);
+    }
+
+    #[test]
+    fn parity_rebuilds_one_lost_message_per_group() {
+        let config = Config {
+            remission_count: 1,
+            fec_group_size: 4,
+            ..Default::default()
+        };
+        let messages: Vec<Vec<u8>> = (0..10u8).map(|i| vec![i; 10 + 7 * i as usize]).collect();
+
+        // Lose a different message of each group, including the last incomplete one
+        let lost = [1, 6, 8];
+        let mut encoder = FecEncoder::new(&config).unwrap();
+        let mut datagrams = Vec::new();
+        for (sequence, message) in messages.iter().enumerate() {
+            let retransmit = Retransmit::new(&message[..], sequence as u32, &config).unwrap();
+            if !lost.contains(&sequence) {
+                datagrams.push(retransmit.buffer.clone());
+            }
+            if let Some(parity) = encoder.add(&retransmit, &config).unwrap() {
+                datagrams.push(parity.buffer);
+            }
+        }
+        datagrams.push(encoder.finish(&config).unwrap().unwrap().buffer);
+
+        let mut reassembler = Reassembler::new(&config);
+        let mut received = reassemble(&mut reassembler, &datagrams[..]);
+        received.sort();
+        assert_eq!(received, messages);
+    }
+
+    #[test]
+    fn parity_cannot_rebuild_two_lost_messages() {
+        let config = Config {
+            remission_count: 1,
+            fec_group_size: 4,
+            ..Default::default()
+        };
+
+        let mut encoder = FecEncoder::new(&config).unwrap();
+        let mut datagrams = Vec::new();
+        for sequence in 0..4u32 {
+            let retransmit = Retransmit::new(b"message", sequence, &config).unwrap();
+            if sequence >= 2 {
+                datagrams.push(retransmit.buffer.clone());
+            }
+            if let Some(parity) = encoder.add(&retransmit, &config).unwrap() {
+                datagrams.push(parity.buffer);
+            }
+        }
+
+        let mut reassembler = Reassembler::new(&config);
+        assert_eq!(reassemble(&mut reassembler, &datagrams[..]).len(), 2);
+    }
+
+    #[test]
+    fn tail_heavy_policy_repeats_last_chunk_more() {
+        let policy = TailHeavyPolicy { bulk: 1, tail: 5 };
+
+        assert_eq!(policy.repeats(MessageKind::FileChunk, 1024, 4096), 1);
+        assert_eq!(policy.repeats(MessageKind::FileChunk, 3072, 4096), 1);
+        // Last chunk and terminator of the file, even an empty one
+        assert_eq!(policy.repeats(MessageKind::FileChunk, 4096, 4096), 5);
+        assert_eq!(policy.repeats(MessageKind::FileChunk, 0, 0), 5);
+        assert_eq!(policy.repeats(MessageKind::File, 0, 0), 5);
+        assert_eq!(policy.repeats(MessageKind::Done, 0, 0), 5);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pacer_holds_the_rate() {
+        let config = Config {
+            mtu: 1024,
+            remission_count: 3,
+            max_bytes_per_sec: Some(10_000),
+            ..Default::default()
+        };
+        let transport = crate::testing::MemoryTransport::new();
+        let mut pacer = Pacer::new(&config).unwrap();
+
+        let start = Instant::now();
+        for sequence in 0..20 {
+            Retransmit::new(&[0u8; 900][..], sequence, &config)
+                .unwrap()
+                .send_paced(&transport, Some(&mut pacer), None)
+                .await
+                .unwrap();
+        }
+        let elapsed = start.elapsed();
+
+        // Every emission counts, headers included, only the first datagram goes out right away
+        let sent: usize = transport.take_datagrams().iter().map(Vec::len).sum();
+        assert_eq!(
+            sent,
+            20 * 3 * (900 + RetransmitHeader::size() + ENCRYPTION_OVERHEAD)
+        );
+        let minimum = (sent - config.mtu) as f64 / 10_000.0;
+        assert!(
+            elapsed.as_secs_f64() >= minimum,
+            "{:?} < {}",
+            elapsed,
+            minimum
+        );
+        assert!(elapsed.as_secs_f64() < minimum + 1.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn copies_are_paced_apart_from_originals() {
+        let config = Config {
+            mtu: 1024,
+            remission_count: 3,
+            retransmit_rate_limit: Some(10_000),
+            ..Default::default()
+        };
+        let transport = crate::testing::MemoryTransport::new();
+        let mut copies_pacer = Pacer::for_copies(&config).unwrap();
+        let datagram_size = 900 + RetransmitHeader::size() + ENCRYPTION_OVERHEAD;
+
+        let start = Instant::now();
+        for sequence in 0..20 {
+            Retransmit::new(&[0u8; 900][..], sequence, &config)
+                .unwrap()
+                .send_paced(&transport, None, Some(&mut copies_pacer))
+                .await
+                .unwrap();
+        }
+        let elapsed = start.elapsed();
+
+        // Only the 40 copies count against the limit, the first one going out right away
+        let minimum = (40 * datagram_size - config.mtu) as f64 / 10_000.0;
+        assert!(
+            elapsed.as_secs_f64() >= minimum,
+            "{:?} < {}",
+            elapsed,
+            minimum
+        );
+        assert!(elapsed.as_secs_f64() < minimum + 1.0);
+
+        // Originals alone are not held back, even though copies exhausted their budget
+        let start = Instant::now();
+        for sequence in 20..40 {
+            Retransmit::new(&[0u8; 900][..], sequence, &config)
+                .unwrap()
+                .with_repeats(1)
+                .send_paced(&transport, None, Some(&mut copies_pacer))
+                .await
+                .unwrap();
+        }
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        assert_eq!(transport.take_datagrams().len(), 20 * 3 + 20);
+    }
+
+    #[test]
+    fn padded_datagrams_fill_the_mtu() {
+        let config = Config {
+            mtu: 64,
+            pad_datagrams: true,
+            ..Default::default()
+        };
+        let mut reassembler = Reassembler::new(&config);
+        let mut data = Vec::new();
+
+        for (sequence, message) in [&b"short"[..], b"a bit longer message"]
+            .into_iter()
+            .enumerate()
+        {
+            let datagram = Retransmit::new(message, sequence as u32, &config)
+                .unwrap()
+                .buffer;
+            assert_eq!(datagram.len(), config.mtu);
+
+            reassembler.push_data(&datagram[..]);
+            reassembler.get_next_data(&mut data).unwrap();
+            assert_eq!(&data[..], message);
+        }
+        assert!(reassembler.get_available_data().is_empty());
+    }
+
+    #[test]
+    fn padding_is_detected_on_either_side() {
+        let padded_config = Config {
+            mtu: 64,
+            remission_count: 1,
+            pad_datagrams: true,
+            ..Default::default()
+        };
+        let config = Config {
+            pad_datagrams: false,
+            ..padded_config.clone()
+        };
+
+        for (sender, receiver) in [(&padded_config, &config), (&config, &padded_config)] {
+            let mut reassembler = Reassembler::new(receiver);
+            let mut datagrams = Vec::new();
+            for sequence in 0..3 {
+                let datagram = Retransmit::new(b"message", sequence, sender)
+                    .unwrap()
+                    .buffer;
+                // Each message is sent twice
+                datagrams.push(datagram.clone());
+                datagrams.push(datagram);
+            }
+
+            let messages = reassemble(&mut reassembler, &datagrams[..]);
+            assert_eq!(messages, vec![b"message".to_vec(); 3]);
+            assert_eq!(reassembler.received_messages(), 3);
+            assert_eq!(reassembler.missed_messages(), 0);
+            assert!(reassembler.get_available_data().is_empty());
+        }
+
+        // Unpadded datagrams queued together are not taken as padding
+        let mut reassembler = Reassembler::new(&padded_config);
+        for sequence in 0..2 {
+            reassembler.push_data(&datagram(sequence, b"queued")[..]);
+        }
+        let mut data = Vec::new();
+        for _ in 0..2 {
+            reassembler.get_next_data(&mut data).unwrap();
+            assert_eq!(&data[..], b"queued");
+        }
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn payload_shrinks_by_encryption_overhead() {
+        let mtu = 1024;
+        assert_eq!(
+            max_payload_size(mtu),
+            mtu - RetransmitHeader::size() - FEC_OVERHEAD - 12 - 16
+        );
+
+        let config = Config {
+            mtu,
+            fec_group_size: 1,
+            ..Default::default()
+        };
+        let largest = vec![0u8; max_payload_size(mtu)];
+        let retransmit = Retransmit::new(&largest[..], 0, &config).unwrap();
+        assert_eq!(retransmit.buffer.len(), mtu - FEC_OVERHEAD);
+        let parity = FecEncoder::new(&config)
+            .unwrap()
+            .add(&retransmit, &config)
+            .unwrap()
+            .unwrap();
+        assert_eq!(parity.buffer.len(), mtu);
+        assert!(Retransmit::new(&[0u8; 1000][..], 0, &config).is_err());
+        assert_eq!(
+            Message::get_max_content_size(max_payload_size(min_mtu())),
+            1
+        );
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn encrypted_round_trip() {
+        let config = Config {
+            key: Some([0x42; 32]),
+            ..Default::default()
+        };
+        let retransmit = Retransmit::new(b"secret message", 0, &config).unwrap();
+        assert!(!retransmit
+            .buffer
+            .windows(b"secret".len())
+            .any(|w| w == b"secret"));
+
+        let mut reassembler = Reassembler::new(&config);
+        let mut data = Vec::new();
+        reassembler.push_data(&retransmit.buffer[..]);
+        reassembler.get_next_data(&mut data).unwrap();
+        assert_eq!(&data[..], b"secret message");
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn chunk_under_another_key_is_dropped() {
+        let sender_config = Config {
+            key: Some([0x42; 32]),
+            ..Default::default()
+        };
+        let receiver_config = Config {
+            key: Some([0x24; 32]),
+            ..Default::default()
+        };
+        let forged = Retransmit::new(b"forged", 0, &sender_config).unwrap();
+        let genuine = Retransmit::new(b"genuine", 1, &receiver_config).unwrap();
+
+        let mut reassembler = Reassembler::new(&receiver_config);
+        let mut data = Vec::new();
+        reassembler.push_data(&forged.buffer[..]);
+        reassembler.push_data(&genuine.buffer[..]);
+        reassembler.get_next_data(&mut data).unwrap();
+        assert_eq!(&data[..], b"genuine");
     }
 }
@@ -0,0 +1,577 @@
+//! Helpers to exercise the protocol without a real network
+//!
+//! This module is only available with the `testing` feature. It provides in-memory transports,
+//! one of them simulating a lossy link, and helpers to feed the resulting datagrams to a
+//! `ClientHandler`.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use tokio::sync::mpsc;
+
+use crate::config::HashAlgorithm;
+use crate::connection::{ClientHandler, Sink};
+use crate::messages::Message;
+use crate::retransmit::{Reassembler, Retransmit};
+use crate::udp::{AsyncPacketSink, AsyncPacketSource};
+use crate::utils::fs::FileHasher;
+use crate::{Config, Result};
+
+/// Records every datagram sent through it
+#[derive(Debug, Default)]
+pub struct MemoryTransport {
+    datagrams: Mutex<Vec<Vec<u8>>>,
+}
+
+impl MemoryTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Takes all datagrams sent so far
+    pub fn take_datagrams(&self) -> Vec<Vec<u8>> {
+        std::mem::take(&mut *self.datagrams.lock().unwrap())
+    }
+}
+
+impl AsyncPacketSink for MemoryTransport {
+    fn send(&self, buf: &[u8]) -> impl Future<Output = io::Result<usize>> + Send {
+        self.datagrams.lock().unwrap().push(buf.to_vec());
+        std::future::ready(Ok(buf.len()))
+    }
+}
+
+/// Receives the datagrams pushed through the sender it is built with, to run a `Server` without
+/// a socket
+#[derive(Debug)]
+pub struct MemoryReader {
+    receiver: tokio::sync::Mutex<mpsc::UnboundedReceiver<(SocketAddr, Vec<u8>)>>,
+}
+
+impl MemoryReader {
+    /// Builds a reader and the sender feeding it, receiving fails once every sender is dropped
+    pub fn new() -> (mpsc::UnboundedSender<(SocketAddr, Vec<u8>)>, Self) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let reader = Self {
+            receiver: tokio::sync::Mutex::new(receiver),
+        };
+        (sender, reader)
+    }
+}
+
+impl AsyncPacketSource for MemoryReader {
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let Some((addr, datagram)) = self.receiver.lock().await.recv().await else {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        };
+        // Like with UDP, what does not fit in `buf` is lost
+        let size = datagram.len().min(buf.len());
+        buf[..size].copy_from_slice(&datagram[..size]);
+        Ok((size, addr))
+    }
+}
+
+/// Keeps the files received by a `ClientHandler` in memory, by path
+#[derive(Debug, Default, Clone)]
+pub struct MemorySink {
+    files: Arc<Mutex<HashMap<PathBuf, Vec<u8>>>>,
+}
+
+impl MemorySink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Content of the file stored at `path`, if any
+    pub fn get(&self, path: impl AsRef<Path>) -> Option<Vec<u8>> {
+        self.files.lock().unwrap().get(path.as_ref()).cloned()
+    }
+
+    /// Paths of the files stored, in order
+    pub fn paths(&self) -> Vec<PathBuf> {
+        let mut paths: Vec<_> = self.files.lock().unwrap().keys().cloned().collect();
+        paths.sort();
+        paths
+    }
+}
+
+impl Sink for MemorySink {
+    /// Path of the file
+    type Handle = PathBuf;
+
+    async fn create(&self, _id: u64, path: &Path, size: u64, partial: bool) -> Result<PathBuf> {
+        let mut files = self.files.lock().unwrap();
+        let content = files.entry(path.to_path_buf()).or_default();
+        if !partial {
+            content.clear();
+        }
+        content.resize(size.try_into()?, 0);
+        Ok(path.to_path_buf())
+    }
+
+    async fn write_at(&self, path: &mut PathBuf, offset: u64, bytes: &[u8]) -> Result<()> {
+        let mut files = self.files.lock().unwrap();
+        let content = files
+            .get_mut(path)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        let start: usize = offset.try_into()?;
+        let end = start + bytes.len();
+        if content.len() < end {
+            content.resize(end, 0);
+        }
+        content[start..end].copy_from_slice(bytes);
+        Ok(())
+    }
+
+    async fn finish(&self, _path: PathBuf, _modified: SystemTime) -> Result<()> {
+        Ok(())
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        let mut files = self.files.lock().unwrap();
+        let content = files
+            .remove(from)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        files.insert(to.to_path_buf(), content);
+        Ok(())
+    }
+
+    async fn read_range(&self, path: &Path, offset: u64, size: usize) -> Result<Vec<u8>> {
+        let files = self.files.lock().unwrap();
+        let start: usize = offset.try_into()?;
+        files
+            .get(path)
+            .and_then(|content| content.get(start..start + size))
+            .map(<[u8]>::to_vec)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof).into())
+    }
+
+    async fn hash(&self, path: &Path, algorithm: HashAlgorithm) -> Result<Vec<u8>> {
+        let files = self.files.lock().unwrap();
+        let content = files
+            .get(path)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        let mut hasher = FileHasher::new(algorithm);
+        hasher.update(&content[..]);
+        Ok(hasher.finalize())
+    }
+}
+
+/// Small deterministic PRNG (xorshift64*), good enough to simulate a lossy link
+#[derive(Debug, Clone)]
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // Zero is a fixed point of xorshift
+        Self(seed.max(1))
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.0 = x;
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Uniform value in `[0, 1)`
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Uniform value in `[0, n)`
+    fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+}
+
+#[derive(Debug)]
+struct LossyState {
+    rng: Rng,
+
+    /// Datagrams held back to be reordered
+    pending: Vec<Vec<u8>>,
+
+    /// Number of datagrams dropped so far
+    dropped: usize,
+}
+
+/// Wraps a `AsyncPacketSink` to drop and reorder datagrams
+///
+/// Each datagram is dropped with probability `loss_rate`. Surviving datagrams are held in a
+/// window of `reorder_window` datagrams, from which one is released at random whenever the window
+/// is full. The same seed always yields the same sequence of losses and reorderings.
+#[derive(Debug)]
+pub struct LossyTransport<W> {
+    inner: W,
+    loss_rate: f64,
+    reorder_window: usize,
+    state: Mutex<LossyState>,
+}
+
+impl<W> LossyTransport<W> {
+    pub fn new(inner: W, loss_rate: f64, reorder_window: usize, seed: u64) -> Self {
+        Self {
+            inner,
+            loss_rate,
+            reorder_window,
+            state: Mutex::new(LossyState {
+                rng: Rng::new(seed),
+                pending: Vec::with_capacity(reorder_window + 1),
+                dropped: 0,
+            }),
+        }
+    }
+
+    pub fn inner(&self) -> &W {
+        &self.inner
+    }
+
+    /// Number of datagrams dropped so far
+    pub fn dropped(&self) -> usize {
+        self.state.lock().unwrap().dropped
+    }
+
+    /// Decides the fate of `datagram`, returning the one to forward now, if any
+    fn hold(&self, datagram: &[u8]) -> Option<Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
+
+        if state.rng.next_f64() < self.loss_rate {
+            state.dropped += 1;
+            return None;
+        }
+
+        state.pending.push(datagram.to_vec());
+        if state.pending.len() > self.reorder_window {
+            let len = state.pending.len();
+            let index = state.rng.below(len);
+            Some(state.pending.swap_remove(index))
+        } else {
+            None
+        }
+    }
+
+    fn release(&self) -> Option<Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
+
+        if state.pending.is_empty() {
+            None
+        } else {
+            let len = state.pending.len();
+            let index = state.rng.below(len);
+            Some(state.pending.swap_remove(index))
+        }
+    }
+}
+
+impl<W: AsyncPacketSink + Sync> LossyTransport<W> {
+    /// Forwards datagrams still held back for reordering
+    pub async fn flush(&self) -> io::Result<()> {
+        while let Some(datagram) = self.release() {
+            self.inner.send(&datagram[..]).await?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<W: AsyncPacketSink + Sync> AsyncPacketSink for LossyTransport<W> {
+    fn send(&self, buf: &[u8]) -> impl Future<Output = io::Result<usize>> + Send {
+        let size = buf.len();
+        let forwarded = self.hold(buf);
+
+        async move {
+            if let Some(datagram) = forwarded {
+                self.inner.send(&datagram[..]).await?;
+            }
+            // Like UDP, a lost datagram is not an error for the sender
+            Ok(size)
+        }
+    }
+}
+
+/// Builds a `ClientHandler` storing files under `config.root`
+pub fn client_handler(config: Config) -> ClientHandler {
+    let client_addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+    let (_sender, receiver) = mpsc::channel(1);
+    let (kill_tx, _kill_rx) = mpsc::channel(1);
+
+    ClientHandler::new(
+        client_addr,
+        config.root.clone(),
+        Arc::new(config),
+        receiver,
+        kill_tx,
+    )
+}
+
+/// Builds a `ClientHandler` storing the content of files in `sink`, under `config.root`
+pub fn client_handler_with_sink<S: Sink>(config: Config, sink: S) -> ClientHandler<S> {
+    let client_addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+    let (_sender, receiver) = mpsc::channel(1);
+    let (kill_tx, _kill_rx) = mpsc::channel(1);
+
+    ClientHandler::with_sink(
+        client_addr,
+        config.root.clone(),
+        Arc::new(config),
+        receiver,
+        kill_tx,
+        Arc::new(sink),
+    )
+}
+
+/// Builds the announcement of a regular file, as a client would send it
+pub fn file_message(id: u64, filename: &str, size: u64) -> Message {
+    Message::File {
+        filename: String::from(filename),
+        created: None,
+        modified: SystemTime::now(),
+        size,
+        id,
+        mode: 0o644,
+        compressed: false,
+        partial: false,
+    }
+}
+
+/// Builds the chunk of file `id` holding `content` at `offset`
+pub fn chunk_message(id: u64, offset: u64, content: &[u8]) -> Message {
+    Message::FileChunk {
+        id,
+        offset,
+        content_size: content.len() as u16,
+        crc32: crc32fast::hash(content),
+        content: content.to_vec(),
+    }
+}
+
+/// Serializes `message` into the datagrams a client would send
+pub async fn message_datagrams(message: &Message, config: &Config) -> Result<Vec<Vec<u8>>> {
+    raw_message_datagrams(&message.to_bytes()?[..], config).await
+}
+
+/// Splits the already serialized `raw_message` into the datagrams a client would send
+pub async fn raw_message_datagrams(raw_message: &[u8], config: &Config) -> Result<Vec<Vec<u8>>> {
+    // Shared by every test, which is fine as long as each handler sees increasing sequences
+    static SEQUENCE: AtomicU32 = AtomicU32::new(0);
+    let sequence = SEQUENCE.fetch_add(1, Ordering::Relaxed);
+
+    let transport = MemoryTransport::new();
+    Retransmit::new(raw_message, sequence, config)?
+        .send(&transport)
+        .await?;
+
+    Ok(transport.take_datagrams())
+}
+
+/// Decodes the message carried by each of `datagrams`, which must hold a whole message each
+pub fn decode_datagrams(datagrams: &[Vec<u8>], config: &Config) -> Result<Vec<Message>> {
+    datagrams
+        .iter()
+        .map(|datagram| {
+            let mut reassembler = Reassembler::new(config);
+            reassembler.push_data(&datagram[..]);
+            let mut data = Vec::new();
+            reassembler.get_next_data(&mut data)?;
+            Message::from_bytes(&data[..])
+        })
+        .collect()
+}
+
+/// Feeds `datagrams` to `handler`, returns `true` once the client is done
+pub async fn deliver<S: Sink>(
+    handler: &mut ClientHandler<S>,
+    datagrams: impl IntoIterator<Item = Vec<u8>>,
+) -> bool {
+    for datagram in datagrams {
+        if handler.process_buffer(&datagram[..]).await {
+            return true;
+        }
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::Client;
+    use std::path::PathBuf;
+
+    #[test]
+    fn lossy_transport_is_deterministic() {
+        let fates = |seed| {
+            let transport = LossyTransport::new(MemoryTransport::new(), 0.5, 0, seed);
+            (0..64u8)
+                .map(|i| transport.hold(&[i]).is_some())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(fates(42), fates(42));
+        assert_ne!(fates(42), fates(43));
+    }
+
+    #[tokio::test]
+    async fn transfer_survives_loss_and_reordering() {
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        let content: Vec<u8> = (0..50_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        std::fs::create_dir(source.path().join("dir")).unwrap();
+        std::fs::write(source.path().join("dir/file.bin"), &content[..]).unwrap();
+
+        let client_config = Config {
+            mtu: 1024,
+            remission_count: 8,
+            root: source.path().to_path_buf(),
+            ..Default::default()
+        };
+        let transport = LossyTransport::new(MemoryTransport::new(), 0.2, 4, 0x1a2b_3c4d);
+        let mut client = Client::new_with_config(transport, client_config).unwrap();
+        client.send_hello().await.unwrap();
+        client
+            .send_files(&[PathBuf::from("dir/file.bin")])
+            .await
+            .unwrap();
+        client.send_done().await.unwrap();
+        client.socket().flush().await.unwrap();
+        assert!(client.socket().dropped() > 0);
+
+        let server_config = Config {
+            mtu: 1024,
+            dedup_window: 32,
+            root: destination.path().to_path_buf(),
+            ..Default::default()
+        };
+        let mut handler = client_handler(server_config);
+        let datagrams = client.socket().inner().take_datagrams();
+        assert!(deliver(&mut handler, datagrams).await);
+
+        let received = std::fs::read(destination.path().join("dir/file.bin")).unwrap();
+        assert!(received == content, "File was corrupted in transit");
+        assert_eq!(handler.loss_report(), None);
+    }
+
+    #[tokio::test]
+    async fn high_loss_is_reported() {
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        for i in 0..8 {
+            std::fs::write(source.path().join(format!("file-{}", i)), vec![i; 20_000]).unwrap();
+        }
+        let files: Vec<PathBuf> = (0..8)
+            .map(|i| PathBuf::from(format!("file-{}", i)))
+            .collect();
+
+        let client_config = Config {
+            remission_count: 1,
+            root: source.path().to_path_buf(),
+            ..Default::default()
+        };
+        let transport = LossyTransport::new(MemoryTransport::new(), 0.3, 0, 0x5eed);
+        let mut client = Client::new_with_config(transport, client_config).unwrap();
+        client.send_files(&files[..]).await.unwrap();
+        client.send_done().await.unwrap();
+
+        let mut handler = client_handler(Config {
+            root: destination.path().to_path_buf(),
+            ..Default::default()
+        });
+        deliver(&mut handler, client.socket().inner().take_datagrams()).await;
+
+        let report = handler.loss_report().unwrap();
+        assert!(report.contains("remission_count"), "{}", report);
+    }
+
+    #[tokio::test]
+    async fn files_are_received_in_memory() {
+        let source = tempfile::tempdir().unwrap();
+        let content: Vec<u8> = (0..5000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(source.path().join("file.bin"), &content[..]).unwrap();
+
+        let mut client = Client::new_with_config(
+            MemoryTransport::new(),
+            Config {
+                root: source.path().to_path_buf(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        client
+            .send_files(&[PathBuf::from("file.bin")])
+            .await
+            .unwrap();
+        client.send_done().await.unwrap();
+
+        // Nothing is written under the root
+        let root = source.path().join("received");
+        let sink = MemorySink::new();
+        let mut handler = client_handler_with_sink(
+            Config {
+                root: root.clone(),
+                ..Default::default()
+            },
+            sink.clone(),
+        );
+        assert!(deliver(&mut handler, client.socket().take_datagrams()).await);
+
+        assert_eq!(sink.paths(), [root.join("file.bin")]);
+        assert_eq!(sink.get(root.join("file.bin")), Some(content));
+        assert!(!root.exists());
+    }
+
+    #[tokio::test]
+    #[tracing_test::traced_test]
+    async fn digests_and_chunk_copies_go_through_the_sink() {
+        let source = tempfile::tempdir().unwrap();
+        let content: Vec<u8> = (0..20_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        std::fs::write(source.path().join("a.bin"), &content[..]).unwrap();
+        std::fs::write(source.path().join("b.bin"), &content[..]).unwrap();
+
+        let mut client = Client::new_with_config(
+            MemoryTransport::new(),
+            Config {
+                root: source.path().to_path_buf(),
+                #[cfg(feature = "dedup")]
+                dedup: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        client
+            .send_files(&[PathBuf::from("a.bin"), PathBuf::from("b.bin")])
+            .await
+            .unwrap();
+        client.send_done().await.unwrap();
+
+        let root = source.path().join("received");
+        let sink = MemorySink::new();
+        let mut handler = client_handler_with_sink(
+            Config {
+                root: root.clone(),
+                receiver_hash: Some(HashAlgorithm::Sha256),
+                ..Default::default()
+            },
+            sink.clone(),
+        );
+        assert!(deliver(&mut handler, client.socket().take_datagrams()).await);
+
+        for name in ["a.bin", "b.bin"] {
+            assert_eq!(sink.get(root.join(name)).as_ref(), Some(&content));
+        }
+        assert_eq!(handler.stats().file_digests.len(), 2);
+        assert_eq!(handler.loss_report(), None);
+        assert!(!logs_contain("ERROR"));
+        assert!(!logs_contain("WARN"));
+        #[cfg(feature = "dedup")]
+        assert!(logs_contain("Copying"));
+    }
+}
@@ -1,8 +1,12 @@
 use std::collections::{HashSet, VecDeque};
 use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
 use std::path::{Path, PathBuf};
 
+use sha2::{Digest, Sha256};
+
 use crate::error::Result;
+use crate::messages::ManifestEntry;
 use crate::utils::get_inode;
 
 macro_rules! try_with_message {
@@ -28,27 +32,476 @@ macro_rules! try_with_message {
 
 }
 
+/// An entry found while walking a tree, relative to its root
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Entry {
+    /// Regular file
+    File(PathBuf),
+
+    /// FIFO, character or block device
+    Special(PathBuf),
+
+    /// Empty directory
+    Directory(PathBuf),
+
+    /// Symbolic link, when symlinks are replicated
+    Symlink(PathBuf),
+}
+
+impl Entry {
+    pub fn path(&self) -> &Path {
+        match self {
+            Self::File(path)
+            | Self::Special(path)
+            | Self::Directory(path)
+            | Self::Symlink(path) => path,
+        }
+    }
+
+    pub fn into_path(self) -> PathBuf {
+        match self {
+            Self::File(path)
+            | Self::Special(path)
+            | Self::Directory(path)
+            | Self::Symlink(path) => path,
+        }
+    }
+}
+
+/// What to do with symbolic links found while walking a tree
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Yield the entry the link points to
+    Follow,
+
+    /// Ignore links
+    #[default]
+    Skip,
+
+    /// Yield the link itself, so that it can be recreated
+    Replicate,
+}
+
+impl SymlinkPolicy {
+    pub(crate) fn parse(value: &str) -> Option<Self> {
+        if value.eq_ignore_ascii_case("follow") {
+            Some(Self::Follow)
+        } else if value.eq_ignore_ascii_case("skip") {
+            Some(Self::Skip)
+        } else if value.eq_ignore_ascii_case("replicate") {
+            Some(Self::Replicate)
+        } else {
+            None
+        }
+    }
+}
+
+impl From<bool> for SymlinkPolicy {
+    /// `true` follows links, `false` skips them
+    fn from(follow_symlinks: bool) -> Self {
+        if follow_symlinks {
+            Self::Follow
+        } else {
+            Self::Skip
+        }
+    }
+}
+
+/// Options controlling which entries `walk` yields
+#[derive(Debug, Clone, Default)]
+pub struct WalkOptions {
+    pub symlinks: SymlinkPolicy,
+
+    /// Also yield FIFOs and devices (Unix only)
+    pub include_specials: bool,
+
+    /// Also yield directories without any entry, so that they can be recreated
+    pub include_empty_dirs: bool,
+
+    /// Do not descend into directories deeper than this, `root` being at depth 0
+    pub max_depth: Option<usize>,
+
+    /// Stop the walk before the cumulative size of the files yielded exceeds this
+    pub max_total_bytes: Option<u64>,
+}
+
+/// Include and exclude glob patterns, matched against paths relative to the walked root
+///
+/// As in `.gitignore`, the last pattern matching a path decides and a pattern without any `/`
+/// matches the file name at any depth. A path matching no pattern is accepted, unless an include
+/// pattern was given.
+#[derive(Debug, Clone, Default)]
+pub struct FilterSet {
+    rules: Vec<FilterRule>,
+    has_includes: bool,
+}
+
+#[derive(Debug, Clone)]
+struct FilterRule {
+    pattern: glob::Pattern,
+
+    /// Whether `pattern` is matched against the file name only
+    file_name_only: bool,
+    include: bool,
+}
+
+impl FilterSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Accepts paths matching `pattern`
+    pub fn include(mut self, pattern: &str) -> Result<Self> {
+        self.push(pattern, true)?;
+        self.has_includes = true;
+        Ok(self)
+    }
+
+    /// Rejects paths matching `pattern`
+    pub fn exclude(mut self, pattern: &str) -> Result<Self> {
+        self.push(pattern, false)?;
+        Ok(self)
+    }
+
+    fn push(&mut self, pattern: &str, include: bool) -> Result<()> {
+        self.rules.push(FilterRule {
+            pattern: glob::Pattern::new(pattern)?,
+            file_name_only: !pattern.contains('/'),
+            include,
+        });
+        Ok(())
+    }
+
+    /// Whether `path`, relative to the root, is accepted
+    pub fn matches(&self, path: &Path) -> bool {
+        let options = glob::MatchOptions {
+            require_literal_separator: true,
+            ..Default::default()
+        };
+
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| {
+                if rule.file_name_only {
+                    path.file_name().is_some_and(|name| {
+                        rule.pattern.matches_with(&name.to_string_lossy(), options)
+                    })
+                } else {
+                    rule.pattern.matches_path_with(path, options)
+                }
+            })
+            .map_or(!self.has_includes, |rule| rule.include)
+    }
+
+    /// Builds the filter expected by `find_files` and `walk` when walking `root`
+    pub fn into_filter(self, root: impl AsRef<Path>) -> Result<impl Fn(&Path) -> bool> {
+        let root = absolute_root(root.as_ref())?;
+
+        Ok(move |path: &Path| self.matches(path.strip_prefix(&root).unwrap_or(path)))
+    }
+}
+
+#[cfg(unix)]
+fn is_special(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+
+    let file_type = metadata.file_type();
+    file_type.is_fifo() || file_type.is_char_device() || file_type.is_block_device()
+}
+
+#[cfg(not(unix))]
+fn is_special(_metadata: &fs::Metadata) -> bool {
+    false
+}
+
+/// Walks `root` and returns regular files accepted by `filter`, relative to `root`
+///
+/// Files are sorted by path, so that the order does not depend on the filesystem.
 pub fn find_files(
     root: impl AsRef<Path>,
-    follow_symlinks: bool,
+    symlinks: impl Into<SymlinkPolicy>,
     filter: impl Fn(&Path) -> bool,
 ) -> Result<Vec<PathBuf>> {
-    let mut entries = Vec::new();
-    let mut collected_inodes = HashSet::new();
-    let mut directories_to_visit = VecDeque::new();
-    let root = if root.as_ref().is_absolute() {
-        root.as_ref().to_path_buf()
+    let options = WalkOptions {
+        symlinks: symlinks.into(),
+        ..Default::default()
+    };
+    let entries = walk(root, &options, filter)?;
+    let mut files: Vec<_> = entries.into_iter().map(Entry::into_path).collect();
+    files.sort();
+
+    Ok(files)
+}
+
+/// Differences between a tree and the manifest of the tree it was copied from
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TreeDiff {
+    /// Files of the manifest absent from the tree
+    pub missing: Vec<PathBuf>,
+
+    /// Files of the tree absent from the manifest
+    pub extra: Vec<PathBuf>,
+
+    /// Files whose size or content differ from the manifest
+    pub mismatched: Vec<PathBuf>,
+}
+
+impl TreeDiff {
+    pub fn is_empty(&self) -> bool {
+        self.missing.is_empty() && self.extra.is_empty() && self.mismatched.is_empty()
+    }
+}
+
+/// Compares the regular files under `root` accepted by `filter` with `manifest`
+pub fn compare_tree(
+    root: impl AsRef<Path>,
+    manifest: &[ManifestEntry],
+    filter: impl Fn(&Path) -> bool,
+) -> Result<TreeDiff> {
+    let root = root.as_ref();
+    let mut extra: HashSet<PathBuf> = find_files(root, false, filter)?.into_iter().collect();
+    let mut diff = TreeDiff::default();
+
+    for entry in manifest {
+        let path = PathBuf::from(&entry.path);
+        if !extra.remove(&path) {
+            diff.missing.push(path);
+            continue;
+        }
+
+        let fullname = root.join(&path);
+        let matches = fs::metadata(&fullname)?.len() == entry.size && {
+            let mut file = fs::File::open(&fullname)?;
+            let mut hasher = Sha256::new();
+            let mut buffer = vec![0u8; 64 * 1024];
+            loop {
+                let size = io::Read::read(&mut file, &mut buffer[..])?;
+                if size == 0 {
+                    break;
+                }
+                hasher.update(&buffer[..size]);
+            }
+            hasher.finalize()[..] == entry.digest[..]
+        };
+        if !matches {
+            diff.mismatched.push(path);
+        }
+    }
+    diff.extra = extra.into_iter().collect();
+    diff.extra.sort();
+
+    Ok(diff)
+}
+
+/// Progress of a walk: what was yielded so far and what is left to visit
+#[derive(Debug, Default, PartialEq, Eq)]
+struct WalkState {
+    entries: Vec<Entry>,
+    collected_inodes: HashSet<u64>,
+
+    /// Inodes of the directories visited or queued, so that symlink cycles are not followed
+    visited_directories: HashSet<u64>,
+
+    /// Directories left to visit, with their depth
+    directories_to_visit: VecDeque<(PathBuf, usize)>,
+
+    /// Cumulative size of the files yielded
+    collected_bytes: u64,
+}
+
+impl WalkState {
+    fn new(root: PathBuf) -> Self {
+        Self {
+            visited_directories: get_inode(&root).into_iter().collect(),
+            directories_to_visit: VecDeque::from([(root, 0)]),
+            ..Default::default()
+        }
+    }
+
+    /// Loads a checkpoint written by `save`, one tagged item per line
+    fn load(path: &Path) -> Result<Self> {
+        let invalid_checkpoint = |line: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid checkpoint line {:?}", line),
+            )
+        };
+        let mut state = Self::default();
+
+        for line in BufReader::new(fs::File::open(path)?).lines() {
+            let line = line?;
+            let (tag, value) = line
+                .split_once(' ')
+                .ok_or_else(|| invalid_checkpoint(&line))?;
+            let path = PathBuf::from(value);
+            match tag {
+                "d" => {
+                    // Checkpoints written before depths were tracked hold the path alone
+                    let (depth, path) = match value.split_once(' ') {
+                        Some((depth, path)) if depth.parse::<usize>().is_ok() => {
+                            (depth.parse().unwrap(), PathBuf::from(path))
+                        }
+                        _ => (0, path),
+                    };
+                    state.directories_to_visit.push_back((path, depth));
+                }
+                "b" => {
+                    state.collected_bytes = value.parse().map_err(|_| invalid_checkpoint(&line))?;
+                }
+                "i" => {
+                    let inode = value.parse().map_err(|_| invalid_checkpoint(&line))?;
+                    state.collected_inodes.insert(inode);
+                }
+                "v" => {
+                    let inode = value.parse().map_err(|_| invalid_checkpoint(&line))?;
+                    state.visited_directories.insert(inode);
+                }
+                "f" => state.entries.push(Entry::File(path)),
+                "s" => state.entries.push(Entry::Special(path)),
+                "e" => state.entries.push(Entry::Directory(path)),
+                "l" => state.entries.push(Entry::Symlink(path)),
+                _ => return Err(invalid_checkpoint(&line).into()),
+            }
+        }
+
+        Ok(state)
+    }
+
+    /// Atomically replaces the checkpoint at `path`
+    fn save(&self, path: &Path) -> io::Result<()> {
+        fn write_item(writer: &mut impl Write, tag: &str, path: &Path) -> io::Result<()> {
+            match path.to_str() {
+                Some(value) if !value.contains('\n') => writeln!(writer, "{} {}", tag, value),
+                _ => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Cannot checkpoint {}", path.display()),
+                )),
+            }
+        }
+
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let mut writer = io::BufWriter::new(fs::File::create(&tmp_path)?);
+
+        for (dir, depth) in &self.directories_to_visit {
+            write_item(&mut writer, &format!("d {}", depth), dir)?;
+        }
+        writeln!(writer, "b {}", self.collected_bytes)?;
+        for inode in &self.collected_inodes {
+            writeln!(writer, "i {}", inode)?;
+        }
+        for inode in &self.visited_directories {
+            writeln!(writer, "v {}", inode)?;
+        }
+        for entry in &self.entries {
+            let tag = match entry {
+                Entry::File(_) => "f",
+                Entry::Special(_) => "s",
+                Entry::Directory(_) => "e",
+                Entry::Symlink(_) => "l",
+            };
+            write_item(&mut writer, tag, entry.path())?;
+        }
+        writer.into_inner()?.sync_all()?;
+
+        fs::rename(&tmp_path, path)
+    }
+}
+
+fn absolute_root(root: &Path) -> Result<PathBuf> {
+    if root.is_absolute() {
+        Ok(root.to_path_buf())
     } else {
         let cwd = std::env::current_dir()?;
-        cwd.join(root.as_ref()).canonicalize()?
+        Ok(cwd.join(root).canonicalize()?)
+    }
+}
+
+/// Walks `root` and returns entries accepted by `filter`, relative to `root`
+///
+/// Entries are sorted by path, so that a list of entries can be resumed from an index.
+pub fn walk(
+    root: impl AsRef<Path>,
+    options: &WalkOptions,
+    filter: impl Fn(&Path) -> bool,
+) -> Result<Vec<Entry>> {
+    let root = absolute_root(root.as_ref())?;
+    let mut state = WalkState::new(root.clone());
+    walk_from(&root, &mut state, options, filter, |_| {})?;
+
+    Ok(sorted(state.entries))
+}
+
+fn sorted(mut entries: Vec<Entry>) -> Vec<Entry> {
+    entries.sort_by(|a, b| a.path().cmp(b.path()));
+    entries
+}
+
+/// Walks `root` like `walk`, saving its progress to `checkpoint_path` after each directory
+///
+/// When `checkpoint_path` exists, the walk resumes from it: directories already visited are not
+/// read again and entries already found are returned without being looked up again. The
+/// checkpoint is removed once the walk completes.
+pub fn walk_resumable(
+    root: impl AsRef<Path>,
+    options: &WalkOptions,
+    checkpoint_path: impl AsRef<Path>,
+) -> Result<Vec<Entry>> {
+    let root = absolute_root(root.as_ref())?;
+    let checkpoint_path = checkpoint_path.as_ref();
+    let mut state = if checkpoint_path.exists() {
+        let state = WalkState::load(checkpoint_path)?;
+        tracing::info!(
+            "Resuming walk of {} with {} entries found and {} directories left",
+            root.display(),
+            state.entries.len(),
+            state.directories_to_visit.len()
+        );
+        state
+    } else {
+        WalkState::new(root.clone())
     };
-    directories_to_visit.push_back(root.clone());
 
-    while let Some(dir) = directories_to_visit.pop_front() {
+    walk_from(
+        &root,
+        &mut state,
+        options,
+        |_| true,
+        |state| {
+            if let Err(e) = state.save(checkpoint_path) {
+                tracing::warn!(
+                    "Could not save checkpoint to {}: {}",
+                    checkpoint_path.display(),
+                    e
+                );
+            }
+        },
+    )?;
+    fs::remove_file(checkpoint_path).or_else(|e| match e.kind() {
+        io::ErrorKind::NotFound => Ok(()),
+        _ => Err(e),
+    })?;
+
+    Ok(sorted(state.entries))
+}
+
+/// Visits directories left in `state`, calling `on_directory_done` after each of them
+fn walk_from(
+    root: &Path,
+    state: &mut WalkState,
+    options: &WalkOptions,
+    filter: impl Fn(&Path) -> bool,
+    mut on_directory_done: impl FnMut(&WalkState),
+) -> Result<()> {
+    while let Some((dir, depth)) = state.directories_to_visit.pop_front() {
         let dir_entries =
             try_with_message!(dir.read_dir() => "Could not read directory {}: {e}", dir.display());
+        let mut is_empty = true;
+        let mut budget_exhausted = false;
 
         'next_entry: for entry in dir_entries {
+            is_empty = false;
             let entry = try_with_message!(entry => 'next_entry, "Could not retrieve entry from directory {}: {e}",
                                         dir.display());
 
@@ -65,7 +518,24 @@ pub fn find_files(
                 }
             };
 
-            while follow_symlinks && metadata.is_symlink() {
+            if metadata.is_symlink() {
+                match options.symlinks {
+                    SymlinkPolicy::Follow => {}
+                    SymlinkPolicy::Skip => continue 'next_entry,
+                    SymlinkPolicy::Replicate => {
+                        if filter(&current_entry) {
+                            if let Ok(relative_entry) = current_entry.strip_prefix(root) {
+                                state
+                                    .entries
+                                    .push(Entry::Symlink(relative_entry.to_path_buf()));
+                            }
+                        }
+                        continue 'next_entry;
+                    }
+                }
+            }
+
+            while metadata.is_symlink() {
                 current_entry = match fs::read_link(&current_entry) {
                     Ok(v) => {
                         tracing::debug!("Read link {} -> {}", current_entry.display(), v.display());
@@ -88,9 +558,31 @@ pub fn find_files(
                 );
             }
 
-            let inode = try_with_message!(get_inode(&current_entry) => "Could not get inode for {}: {}", current_entry.display());
-            let entry_is_already_processed = !collected_inodes.insert(inode);
+            let inode = try_with_message!(get_inode(&current_entry) => "Could not get inode for {}: {e}", current_entry.display());
+
+            if metadata.is_dir() {
+                if !state.visited_directories.insert(inode) {
+                    tracing::info!(
+                        "Skipping {} as this directory was already visited, through a symlink \
+                         cycle or another link",
+                        current_entry.display()
+                    );
+                    continue 'next_entry;
+                }
+                match options.max_depth {
+                    Some(max_depth) if depth >= max_depth => tracing::info!(
+                        "Not descending into {} (max_depth = {})",
+                        current_entry.display(),
+                        max_depth
+                    ),
+                    _ => state
+                        .directories_to_visit
+                        .push_back((current_entry, depth + 1)),
+                }
+                continue 'next_entry;
+            }
 
+            let entry_is_already_processed = !state.collected_inodes.insert(inode);
             if entry_is_already_processed {
                 tracing::debug!(
                     "Skipping {} as it has already been visited",
@@ -99,27 +591,346 @@ pub fn find_files(
                 continue 'next_entry;
             }
 
-            if metadata.is_dir() {
-                directories_to_visit.push_back(current_entry);
+            let make_entry = if metadata.is_file() {
+                Entry::File
+            } else if options.include_specials && is_special(&metadata) {
+                Entry::Special
+            } else {
                 continue 'next_entry;
-            }
+            };
 
-            if metadata.is_file() {
-                if filter(&current_entry) {
-                    if let Ok(relative_entry) = current_entry.strip_prefix(&root) {
-                        entries.push(relative_entry.to_path_buf());
+            if filter(&current_entry) {
+                if let Ok(relative_entry) = current_entry.strip_prefix(root) {
+                    let size = if metadata.is_file() {
+                        metadata.len()
                     } else {
-                        tracing::warn!(
-                            "{} is not in {}, skipping",
-                            current_entry.display(),
-                            root.display()
-                        );
+                        0
+                    };
+                    if let Some(max_total_bytes) = options.max_total_bytes {
+                        if state.collected_bytes + size > max_total_bytes {
+                            tracing::warn!(
+                                "Stopping the walk at {} of {} bytes, {} bytes already collected \
+                                 (max_total_bytes = {}), {} directories left unvisited",
+                                current_entry.display(),
+                                size,
+                                state.collected_bytes,
+                                max_total_bytes,
+                                state.directories_to_visit.len()
+                            );
+                            budget_exhausted = true;
+                            break 'next_entry;
+                        }
                     }
+                    state.collected_bytes += size;
+                    state.entries.push(make_entry(relative_entry.to_path_buf()));
+                } else {
+                    tracing::warn!(
+                        "{} is not in {}, skipping",
+                        current_entry.display(),
+                        root.display()
+                    );
                 }
-                continue 'next_entry;
             }
         }
+
+        if is_empty && options.include_empty_dirs && dir != root && filter(&dir) {
+            if let Ok(relative_dir) = dir.strip_prefix(root) {
+                state
+                    .entries
+                    .push(Entry::Directory(relative_dir.to_path_buf()));
+            }
+        }
+
+        if budget_exhausted {
+            state.directories_to_visit.clear();
+        }
+        on_directory_done(state);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filtered_files(filters: FilterSet) -> Vec<PathBuf> {
+        let root = tempfile::tempdir().unwrap();
+        for path in [
+            "notes.txt",
+            "app.log",
+            "src/main.rs",
+            "src/readme.txt",
+            "src/debug.log",
+            "build/out.o",
+            "build/report.txt",
+            "src/build/gen.rs",
+        ] {
+            let path = root.path().join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, b"content").unwrap();
+        }
+
+        let filter = filters.into_filter(root.path()).unwrap();
+        find_files(root.path(), false, filter).unwrap()
+    }
+
+    #[test]
+    fn filters_exclude_logs() {
+        let files = filtered_files(FilterSet::new().exclude("*.log").unwrap());
+        assert_eq!(
+            files,
+            [
+                "build/out.o",
+                "build/report.txt",
+                "notes.txt",
+                "src/build/gen.rs",
+                "src/main.rs",
+                "src/readme.txt",
+            ]
+            .map(PathBuf::from)
+        );
+    }
+
+    #[test]
+    fn filters_exclude_build_directories() {
+        let files = filtered_files(FilterSet::new().exclude("**/build/**").unwrap());
+        assert_eq!(
+            files,
+            [
+                "app.log",
+                "notes.txt",
+                "src/debug.log",
+                "src/main.rs",
+                "src/readme.txt"
+            ]
+            .map(PathBuf::from)
+        );
     }
 
-    Ok(entries)
+    #[test]
+    fn filters_include_only_listed_patterns() {
+        let files = filtered_files(FilterSet::new().include("*.txt").unwrap());
+        assert_eq!(
+            files,
+            ["build/report.txt", "notes.txt", "src/readme.txt"].map(PathBuf::from)
+        );
+
+        // The last matching pattern wins
+        let files = filtered_files(
+            FilterSet::new()
+                .include("*.txt")
+                .unwrap()
+                .exclude("build/**")
+                .unwrap(),
+        );
+        assert_eq!(files, ["notes.txt", "src/readme.txt"].map(PathBuf::from));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn walk_yields_specials_only_when_asked() {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        let root = tempfile::tempdir().unwrap();
+        fs::write(root.path().join("file"), b"content").unwrap();
+        let fifo = CString::new(root.path().join("fifo").as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o644) }, 0);
+
+        let files = find_files(root.path(), false, |_| true).unwrap();
+        assert_eq!(files, vec![PathBuf::from("file")]);
+
+        let options = WalkOptions {
+            include_specials: true,
+            ..Default::default()
+        };
+        let entries = walk(root.path(), &options, |_| true).unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                Entry::Special(PathBuf::from("fifo")),
+                Entry::File(PathBuf::from("file"))
+            ]
+        );
+    }
+
+    #[test]
+    fn walk_yields_empty_dirs_only_when_asked() {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir_all(root.path().join("a/b/empty")).unwrap();
+        fs::write(root.path().join("a/file"), b"content").unwrap();
+
+        let entries = walk(root.path(), &WalkOptions::default(), |_| true).unwrap();
+        assert_eq!(entries, vec![Entry::File(PathBuf::from("a/file"))]);
+
+        let options = WalkOptions {
+            include_empty_dirs: true,
+            ..Default::default()
+        };
+        let entries = walk(root.path(), &options, |_| true).unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                Entry::Directory(PathBuf::from("a/b/empty")),
+                Entry::File(PathBuf::from("a/file"))
+            ]
+        );
+    }
+
+    #[test]
+    fn found_files_are_sorted() {
+        let root = tempfile::tempdir().unwrap();
+        for path in ["m", "b/z", "z", "a", "b/a", "c/d/e", "b0"] {
+            let path = root.path().join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, b"content").unwrap();
+        }
+
+        let files = find_files(root.path(), false, |_| true).unwrap();
+        assert_eq!(
+            files,
+            ["a", "b/a", "b/z", "b0", "c/d/e", "m", "z"].map(PathBuf::from)
+        );
+    }
+
+    #[test]
+    fn walk_stops_at_max_depth() {
+        let root = tempfile::tempdir().unwrap();
+        let mut dir = root.path().to_path_buf();
+        for level in 0..5 {
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join(format!("file{}", level)), b"content").unwrap();
+            dir.push(format!("dir{}", level + 1));
+        }
+
+        let options = WalkOptions {
+            max_depth: Some(2),
+            ..Default::default()
+        };
+        let entries = walk(root.path(), &options, |_| true).unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                Entry::File(PathBuf::from("dir1/dir2/file2")),
+                Entry::File(PathBuf::from("dir1/file1")),
+                Entry::File(PathBuf::from("file0")),
+            ]
+        );
+    }
+
+    #[test]
+    fn walk_stops_at_max_total_bytes() {
+        let root = tempfile::tempdir().unwrap();
+        fs::write(root.path().join("small"), [0u8; 10]).unwrap();
+        fs::create_dir_all(root.path().join("a/b")).unwrap();
+        fs::write(root.path().join("a/large"), [0u8; 1000]).unwrap();
+        fs::write(root.path().join("a/b/other"), [0u8; 10]).unwrap();
+
+        let options = WalkOptions {
+            max_total_bytes: Some(500),
+            ..Default::default()
+        };
+        let entries = walk(root.path(), &options, |_| true).unwrap();
+        assert_eq!(entries, vec![Entry::File(PathBuf::from("small"))]);
+    }
+
+    #[test]
+    fn resumed_walk_skips_emitted_paths() {
+        let root = tempfile::tempdir().unwrap();
+        let work = tempfile::tempdir().unwrap();
+        let checkpoint = work.path().join("checkpoint");
+        fs::write(root.path().join("top"), b"top").unwrap();
+        for dir in ["a", "b"] {
+            fs::create_dir(root.path().join(dir)).unwrap();
+            fs::write(root.path().join(dir).join("file"), b"content").unwrap();
+        }
+
+        // Interrupted walk: only the checkpoint saved after visiting root survives
+        let root_path = absolute_root(root.path()).unwrap();
+        let mut state = WalkState::new(root_path.clone());
+        let mut saved = false;
+        walk_from(
+            &root_path,
+            &mut state,
+            &WalkOptions::default(),
+            |_| true,
+            |state| {
+                if !saved {
+                    state.save(&checkpoint).unwrap();
+                    saved = true;
+                }
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            WalkState::load(&checkpoint).unwrap().entries,
+            vec![Entry::File(PathBuf::from("top"))]
+        );
+
+        // Root was already visited, so neither of these is looked at again
+        fs::remove_file(root.path().join("top")).unwrap();
+        fs::write(root.path().join("late"), b"late").unwrap();
+
+        let entries = walk_resumable(root.path(), &WalkOptions::default(), &checkpoint).unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                Entry::File(PathBuf::from("a/file")),
+                Entry::File(PathBuf::from("b/file")),
+                Entry::File(PathBuf::from("top")),
+            ]
+        );
+        assert!(!checkpoint.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn walk_symlink_policies() {
+        let root = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        fs::create_dir(root.path().join("dir")).unwrap();
+        fs::write(root.path().join("dir/file"), b"content").unwrap();
+        fs::write(outside.path().join("outside"), b"outside").unwrap();
+        std::os::unix::fs::symlink("dir/file", root.path().join("link")).unwrap();
+        std::os::unix::fs::symlink(outside.path(), root.path().join("escape")).unwrap();
+
+        let walk_with = |symlinks| {
+            let options = WalkOptions {
+                symlinks,
+                ..Default::default()
+            };
+            walk(root.path(), &options, |_| true).unwrap()
+        };
+
+        let file = Entry::File(PathBuf::from("dir/file"));
+        // Followed links yield their target once, and only inside root
+        assert_eq!(walk_with(SymlinkPolicy::Follow), vec![file.clone()]);
+        assert_eq!(walk_with(SymlinkPolicy::Skip), vec![file.clone()]);
+        assert_eq!(
+            walk_with(SymlinkPolicy::Replicate),
+            vec![
+                file,
+                Entry::Symlink(PathBuf::from("escape")),
+                Entry::Symlink(PathBuf::from("link")),
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn walk_terminates_on_symlink_cycles() {
+        let root = tempfile::tempdir().unwrap();
+        for dir in ["a", "b"] {
+            fs::create_dir(root.path().join(dir)).unwrap();
+            fs::write(root.path().join(dir).join("file"), b"content").unwrap();
+        }
+        // a -> b -> a, and a link back to root
+        std::os::unix::fs::symlink("../b", root.path().join("a/to_b")).unwrap();
+        std::os::unix::fs::symlink("../a", root.path().join("b/to_a")).unwrap();
+        std::os::unix::fs::symlink("..", root.path().join("a/to_root")).unwrap();
+
+        let files = find_files(root.path(), true, |_| true).unwrap();
+        assert_eq!(files, ["a/file", "b/file"].map(PathBuf::from));
+    }
 }
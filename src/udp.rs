@@ -1,4 +1,6 @@
+use std::future::Future;
 use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::ops::{Deref, DerefMut};
 use std::pin::Pin;
 use std::task::{Context, Poll};
@@ -6,15 +8,78 @@ use std::task::{Context, Poll};
 use tokio::io::AsyncWrite;
 use tokio::net::UdpSocket;
 
+use crate::Config;
+
+/// Something datagrams can be sent to
+pub trait AsyncPacketSink {
+    /// Sends a single datagram, returning the number of bytes sent
+    fn send(&self, buf: &[u8]) -> impl Future<Output = io::Result<usize>> + Send;
+}
+
+/// Something datagrams are received from, along with the address of their sender
+pub trait AsyncPacketSource {
+    /// Receives a single datagram into `buf`, returning its size and sender
+    fn recv_from(
+        &self,
+        buf: &mut [u8],
+    ) -> impl Future<Output = io::Result<(usize, SocketAddr)>> + Send;
+}
+
 #[derive(Debug)]
 pub struct UdpReader(UdpSocket);
 
+/// Applies `recv_buffer_size` and `send_buffer_size` to `s`
+///
+/// Kernels clamp the sizes (and Linux doubles them for its bookkeeping), so the size granted is
+/// logged.
+fn set_buffer_sizes(s: &UdpSocket, config: &Config) -> io::Result<()> {
+    let socket = socket2::SockRef::from(s);
+
+    if let Some(size) = config.recv_buffer_size {
+        socket.set_recv_buffer_size(size)?;
+        let granted = socket.recv_buffer_size()?;
+        if granted < size {
+            tracing::warn!(
+                "Receive buffer of {} bytes requested, only {} granted",
+                size,
+                granted
+            );
+        } else {
+            tracing::info!("Receive buffer of {} bytes ({} requested)", granted, size);
+        }
+    }
+    if let Some(size) = config.send_buffer_size {
+        socket.set_send_buffer_size(size)?;
+        let granted = socket.send_buffer_size()?;
+        if granted < size {
+            tracing::warn!(
+                "Send buffer of {} bytes requested, only {} granted",
+                size,
+                granted
+            );
+        } else {
+            tracing::info!("Send buffer of {} bytes ({} requested)", granted, size);
+        }
+    }
+
+    Ok(())
+}
+
 impl UdpReader {
     /// The socket *must not* be connected
     pub fn new(s: UdpSocket) -> io::Result<Self> {
-        tracing::trace!("UdpReader::new");
+        Self::new_with_config(s, &Config::default())
+    }
+
+    /// The socket *must not* be connected
+    ///
+    /// `recv_buffer_size` and `send_buffer_size` are applied to the socket.
+    pub fn new_with_config(s: UdpSocket, config: &Config) -> io::Result<Self> {
+        tracing::trace!("UdpReader::new_with_config");
         use crate::utils::Shutdown;
 
+        set_buffer_sizes(&s, config)?;
+
         match crate::utils::shutdown(&s, Shutdown::Write) {
             Ok(_) => tracing::debug!("Socket made read-only"),
             Err(e) => {
@@ -23,6 +88,39 @@ impl UdpReader {
         }
         Ok(Self(s))
     }
+
+    /// Binds a socket to the port of `group` and joins it on `iface`
+    ///
+    /// Several receivers on the same host can bind the same group.
+    pub fn bind_multicast(group: SocketAddrV4, iface: Ipv4Addr) -> io::Result<Self> {
+        use socket2::{Domain, Protocol, Socket, Type};
+
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        socket.set_nonblocking(true)?;
+        let addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, group.port()));
+        socket.bind(&addr.into())?;
+
+        let reader = Self::new(UdpSocket::from_std(socket.into())?)?;
+        reader.join_multicast(*group.ip(), iface)?;
+        Ok(reader)
+    }
+
+    /// Receives datagrams sent to `group` on `iface`, `Ipv4Addr::UNSPECIFIED` letting the system
+    /// pick the interface
+    ///
+    /// The socket must be bound to the port the group is sent to.
+    pub fn join_multicast(&self, group: Ipv4Addr, iface: Ipv4Addr) -> io::Result<()> {
+        self.0.join_multicast_v4(group, iface)?;
+        tracing::debug!("Joined multicast group {} on {}", group, iface);
+        Ok(())
+    }
+}
+
+impl AsyncPacketSource for UdpReader {
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.0.recv_from(buf).await
+    }
 }
 
 impl From<UdpSocket> for UdpReader {
@@ -61,21 +159,80 @@ impl DerefMut for UdpReader {
 // }
 
 #[derive(Debug)]
-pub struct UdpWriter(UdpSocket);
+pub struct UdpWriter {
+    socket: UdpSocket,
+
+    /// Datagrams larger than this are refused instead of being fragmented
+    max_datagram_size: Option<usize>,
+}
 
 impl UdpWriter {
     /// The socket *must* be connected
     pub fn new(s: UdpSocket) -> io::Result<Self> {
+        Self::new_with_config(s, &Config::default())
+    }
+
+    /// The socket *must* be connected
+    ///
+    /// With `dont_fragment`, the DF bit is set on outgoing datagrams and sending a datagram larger
+    /// than `mtu` fails with `EMSGSIZE` instead of being fragmented. `recv_buffer_size` and
+    /// `send_buffer_size` are applied to the socket.
+    pub fn new_with_config(s: UdpSocket, config: &Config) -> io::Result<Self> {
         use crate::utils::Shutdown;
 
         crate::utils::shutdown(&s, Shutdown::Read)?;
-        Ok(Self(s))
+        set_buffer_sizes(&s, config)?;
+
+        let max_datagram_size = if config.dont_fragment {
+            let ipv6 = s.local_addr()?.is_ipv6();
+            crate::utils::set_dont_fragment(&s, ipv6)?;
+            tracing::debug!("Don't fragment bit set");
+            Some(config.mtu)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            socket: s,
+            max_datagram_size,
+        })
+    }
+}
+
+impl UdpWriter {
+    /// Connects `s` to multicast `group`, sending through `iface`
+    ///
+    /// Datagrams cross at most `multicast_ttl` routers. Receivers must have joined the group
+    /// before the client starts: nothing tells the client who listens.
+    pub async fn new_multicast(
+        s: UdpSocket,
+        group: SocketAddrV4,
+        iface: Ipv4Addr,
+        config: &Config,
+    ) -> io::Result<Self> {
+        s.set_multicast_ttl_v4(config.multicast_ttl)?;
+        socket2::SockRef::from(&s).set_multicast_if_v4(&iface)?;
+        s.connect(group).await?;
+        tracing::debug!("Sending to multicast group {} through {}", group, iface);
+
+        Self::new_with_config(s, config)
+    }
+}
+
+impl UdpWriter {
+    /// Whether the read half of the socket is shut down, so that nothing can be received through
+    /// it
+    pub fn is_oneway(&self) -> io::Result<bool> {
+        crate::utils::is_read_shutdown(&self.socket)
     }
 }
 
 impl From<UdpSocket> for UdpWriter {
     fn from(u: UdpSocket) -> Self {
-        Self(u)
+        Self {
+            socket: u,
+            max_datagram_size: None,
+        }
     }
 }
 
@@ -83,13 +240,22 @@ impl Deref for UdpWriter {
     type Target = UdpSocket;
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.socket
     }
 }
 
 impl DerefMut for UdpWriter {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+        &mut self.socket
+    }
+}
+
+impl AsyncPacketSink for UdpWriter {
+    async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        match self.max_datagram_size {
+            Some(max_size) if buf.len() > max_size => Err(crate::utils::message_too_long()),
+            _ => self.socket.send(buf).await,
+        }
     }
 }
 
@@ -99,14 +265,192 @@ impl AsyncWrite for UdpWriter {
         cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<Result<usize, io::Error>> {
-        self.0.poll_send(cx, buf)
+        self.socket.poll_send(cx, buf)
     }
 
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
-        self.0.poll_send_ready(cx)
+        self.socket.poll_send_ready(cx)
     }
 
     fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
-        self.0.poll_send_ready(cx)
+        self.socket.poll_send_ready(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::retransmit::Retransmit;
+    use crate::Error;
+
+    #[tokio::test]
+    async fn buffer_sizes_are_applied() {
+        let config = Config {
+            recv_buffer_size: Some(64 * 1024),
+            send_buffer_size: Some(64 * 1024),
+            ..Default::default()
+        };
+
+        let reader =
+            UdpReader::new_with_config(UdpSocket::bind("127.0.0.1:0").await.unwrap(), &config)
+                .unwrap();
+        let granted = socket2::SockRef::from(&*reader).recv_buffer_size().unwrap();
+        assert!(granted >= 64 * 1024, "{} bytes granted", granted);
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(reader.local_addr().unwrap()).await.unwrap();
+        let writer = UdpWriter::new_with_config(socket, &config).unwrap();
+        let granted = socket2::SockRef::from(&*writer).send_buffer_size().unwrap();
+        assert!(granted >= 64 * 1024, "{} bytes granted", granted);
+    }
+
+    #[tokio::test]
+    async fn multicast_reaches_every_receiver() {
+        use crate::messages::Message;
+        use crate::retransmit::Reassembler;
+
+        let group_addr = Ipv4Addr::new(239, 255, 42, 99);
+        let port = UdpSocket::bind("0.0.0.0:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let group = SocketAddrV4::new(group_addr, port);
+        let receivers = [
+            UdpReader::bind_multicast(group, Ipv4Addr::UNSPECIFIED).unwrap(),
+            UdpReader::bind_multicast(group, Ipv4Addr::UNSPECIFIED).unwrap(),
+        ];
+
+        let config = Config {
+            remission_count: 1,
+            ..Default::default()
+        };
+        let socket = UdpSocket::bind("0.0.0.0:0").await.unwrap();
+        socket.set_multicast_loop_v4(true).unwrap();
+        let writer = UdpWriter::new_multicast(socket, group, Ipv4Addr::UNSPECIFIED, &config)
+            .await
+            .unwrap();
+        let hello = Message::Hello {
+            session_id: String::from("multicast"),
+            version: crate::messages::ProtocolVersion::CURRENT,
+        };
+        Retransmit::new(&hello.to_bytes().unwrap()[..], 0, &config)
+            .unwrap()
+            .send(&writer)
+            .await
+            .unwrap();
+
+        for receiver in &receivers {
+            let mut buffer = vec![0u8; config.mtu];
+            let (size, _) = tokio::time::timeout(
+                std::time::Duration::from_secs(5),
+                receiver.recv_from(&mut buffer[..]),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+            let mut reassembler = Reassembler::new(&config);
+            reassembler.push_data(&buffer[..size]);
+            let mut data = Vec::new();
+            reassembler.get_next_data(&mut data).unwrap();
+            assert_eq!(Message::from_bytes(&data[..]).unwrap(), hello);
+        }
+    }
+
+    /// Value of the option `UdpWriter::new_with_config` sets for `dont_fragment`, read back from
+    /// the kernel
+    #[cfg(target_os = "linux")]
+    fn path_mtu_discovery(writer: &UdpWriter, ipv6: bool) -> libc::c_int {
+        use std::os::unix::io::AsRawFd;
+
+        let (level, name) = if ipv6 {
+            (libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER)
+        } else {
+            (libc::IPPROTO_IP, libc::IP_MTU_DISCOVER)
+        };
+        let mut value: libc::c_int = 0;
+        let mut len = std::mem::size_of_val(&value) as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                writer.as_raw_fd(),
+                level,
+                name,
+                (&mut value as *mut libc::c_int).cast(),
+                &mut len,
+            )
+        };
+        assert_eq!(ret, 0, "{}", io::Error::last_os_error());
+        value
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn dont_fragment_sets_the_socket_option() {
+        for (address, ipv6) in [("127.0.0.1:0", false), ("[::1]:0", true)] {
+            // Hosts without IPv6 cannot check it
+            let Ok(receiver) = UdpSocket::bind(address).await else {
+                continue;
+            };
+            for (dont_fragment, expected) in [
+                (false, None),
+                (
+                    true,
+                    Some(if ipv6 {
+                        libc::IPV6_PMTUDISC_DO
+                    } else {
+                        libc::IP_PMTUDISC_DO
+                    }),
+                ),
+            ] {
+                let socket = UdpSocket::bind(address).await.unwrap();
+                socket
+                    .connect(receiver.local_addr().unwrap())
+                    .await
+                    .unwrap();
+                let config = Config {
+                    dont_fragment,
+                    ..Default::default()
+                };
+                let writer = UdpWriter::new_with_config(socket, &config).unwrap();
+                let value = path_mtu_discovery(&writer, ipv6);
+                match expected {
+                    Some(expected) => assert_eq!(value, expected),
+                    None => assert_ne!(value, libc::IP_PMTUDISC_DO),
+                }
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn dont_fragment_refuses_oversized_datagrams() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket
+            .connect(receiver.local_addr().unwrap())
+            .await
+            .unwrap();
+        let config = Config {
+            mtu: 512,
+            remission_count: 1,
+            dont_fragment: true,
+            ..Default::default()
+        };
+        let writer = UdpWriter::new_with_config(socket, &config).unwrap();
+
+        let mut small = Retransmit::new(&[0u8; 256], 0, &config).unwrap();
+        small.send(&writer).await.unwrap();
+
+        // Loopback never fragments below 64 KiB, so only the writer refuses this one
+        let large_config = Config {
+            mtu: 2048,
+            ..config.clone()
+        };
+        let mut large = Retransmit::new(&[0u8; 1024], 1, &large_config).unwrap();
+        match large.send(&writer).await {
+            Err(Error::PayloadTooLarge(_)) => {}
+            other => panic!("Expected PayloadTooLarge, got {:?}", other),
+        }
     }
 }
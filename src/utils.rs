@@ -2,7 +2,10 @@ use std::fmt;
 use std::io;
 use std::path::Path;
 
+pub mod acl;
 pub mod fs;
+#[cfg(feature = "compact")]
+pub mod varint;
 
 #[cfg(target_family = "unix")]
 fn get_unix_inode(path: &Path) -> io::Result<u64> {
@@ -65,41 +68,34 @@ pub fn get_inode(path: &Path) -> io::Result<u64> {
     Ok(inode)
 }
 
+/// Random `u64` from the generator of the operating system
+pub(crate) fn get_random() -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    getrandom::getrandom(&mut bytes[..]).map_err(io::Error::from)?;
+    Ok(u64::from_ne_bytes(bytes))
+}
+
 pub(crate) enum Shutdown {
     Read,
     Write,
 }
 
 #[cfg(target_family = "unix")]
-pub(crate) use unix::{get_random, shutdown};
+pub(crate) use unix::{
+    is_message_too_long, is_read_shutdown, message_too_long, set_dont_fragment, shutdown,
+};
 
 #[cfg(target_os = "windows")]
-pub(crate) use windows::{get_random, shutdown};
+pub(crate) use windows::{
+    is_message_too_long, is_read_shutdown, message_too_long, set_dont_fragment, shutdown,
+};
 
 #[cfg(target_family = "unix")]
 mod unix {
     use super::Shutdown;
     use std::io;
-    use std::io::Read;
-    use std::mem::MaybeUninit;
     use std::os::unix::io::AsRawFd;
 
-    pub(crate) fn get_random<T>() -> MaybeUninit<T> {
-        let mut file =
-            std::fs::File::open("/dev/urandom").expect("No /dev/urandom on an UNIX system?!");
-        let mut value: MaybeUninit<T> = MaybeUninit::zeroed();
-
-        // SAFETY: we own the memory
-        let slice = unsafe {
-            std::slice::from_raw_parts_mut(value.as_mut_ptr().cast(), std::mem::size_of::<T>())
-        };
-
-        file.read(slice)
-            .expect("Could not read from /dev/urandom?!");
-
-        value
-    }
-
     pub(crate) fn shutdown(socket: &impl AsRawFd, how: Shutdown) -> io::Result<()> {
         let fd = socket.as_raw_fd();
         let how = match how {
@@ -109,18 +105,142 @@ mod unix {
 
         let ret = unsafe { libc::shutdown(fd, how) };
         if ret < 0 {
-            Err(io::Error::last_os_error().into())
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Whether the read half of `socket` was shut down
+    ///
+    /// Linux still queues datagrams once the read half is shut down, but reports it with
+    /// `POLLRDHUP`.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub(crate) fn is_read_shutdown(socket: &impl AsRawFd) -> io::Result<bool> {
+        let mut pollfd = libc::pollfd {
+            fd: socket.as_raw_fd(),
+            events: libc::POLLIN | libc::POLLRDHUP,
+            revents: 0,
+        };
+
+        // SAFETY: `pollfd` lives for the duration of the call
+        let ret = unsafe { libc::poll(&mut pollfd, 1, 0) };
+        if ret < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(pollfd.revents & libc::POLLRDHUP != 0)
+        }
+    }
+
+    /// Whether the read half of `socket` was shut down, in which case reads return nothing
+    /// without blocking
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    pub(crate) fn is_read_shutdown(socket: &impl AsRawFd) -> io::Result<bool> {
+        let mut byte = 0u8;
+
+        // SAFETY: `byte` lives for the duration of the call and its size is given
+        let ret = unsafe {
+            libc::recv(
+                socket.as_raw_fd(),
+                (&mut byte as *mut u8).cast(),
+                1,
+                libc::MSG_PEEK | libc::MSG_DONTWAIT,
+            )
+        };
+        match ret {
+            0 => Ok(true),
+            n if n > 0 => Ok(false),
+            _ => {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::WouldBlock {
+                    Ok(false)
+                } else {
+                    Err(e)
+                }
+            }
+        }
+    }
+
+    fn set_int_option(
+        socket: &impl AsRawFd,
+        level: libc::c_int,
+        name: libc::c_int,
+        value: libc::c_int,
+    ) -> io::Result<()> {
+        let fd = socket.as_raw_fd();
+
+        // SAFETY: `value` lives for the duration of the call and its size is given
+        let ret = unsafe {
+            libc::setsockopt(
+                fd,
+                level,
+                name,
+                (&value as *const libc::c_int).cast(),
+                std::mem::size_of_val(&value) as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            Err(io::Error::last_os_error())
         } else {
             Ok(())
         }
     }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub(crate) fn set_dont_fragment(socket: &impl AsRawFd, ipv6: bool) -> io::Result<()> {
+        if ipv6 {
+            set_int_option(
+                socket,
+                libc::IPPROTO_IPV6,
+                libc::IPV6_MTU_DISCOVER,
+                libc::IPV6_PMTUDISC_DO,
+            )
+        } else {
+            set_int_option(
+                socket,
+                libc::IPPROTO_IP,
+                libc::IP_MTU_DISCOVER,
+                libc::IP_PMTUDISC_DO,
+            )
+        }
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd"))]
+    pub(crate) fn set_dont_fragment(socket: &impl AsRawFd, ipv6: bool) -> io::Result<()> {
+        if ipv6 {
+            set_int_option(socket, libc::IPPROTO_IPV6, libc::IPV6_DONTFRAG, 1)
+        } else {
+            set_int_option(socket, libc::IPPROTO_IP, libc::IP_DONTFRAG, 1)
+        }
+    }
+
+    #[cfg(not(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd"
+    )))]
+    pub(crate) fn set_dont_fragment(_socket: &impl AsRawFd, _ipv6: bool) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Cannot set the don't fragment bit on this platform",
+        ))
+    }
+
+    pub(crate) fn message_too_long() -> io::Error {
+        io::Error::from_raw_os_error(libc::EMSGSIZE)
+    }
+
+    pub(crate) fn is_message_too_long(e: &io::Error) -> bool {
+        e.raw_os_error() == Some(libc::EMSGSIZE)
+    }
 }
 
 #[cfg(target_os = "windows")]
 mod windows {
     use super::Shutdown;
     use std::io;
-    use std::mem::MaybeUninit;
     use std::os::windows::io::AsRawSocket;
 
     mod ws2 {
@@ -128,9 +248,49 @@ mod windows {
         #[link(name = "Ws2_32")]
         extern "C" {
             pub fn shutdown(s: SOCKET, how: i32) -> i32;
+            pub fn recv(s: SOCKET, buf: *mut u8, len: i32, flags: i32) -> i32;
+            pub fn setsockopt(s: SOCKET, level: i32, name: i32, value: *const u8, len: i32) -> i32;
         }
     }
 
+    const WSAEMSGSIZE: i32 = 10040;
+
+    pub(crate) fn set_dont_fragment(socket: &impl AsRawSocket, ipv6: bool) -> io::Result<()> {
+        const IPPROTO_IP: i32 = 0;
+        const IPPROTO_IPV6: i32 = 41;
+        const IP_DONTFRAGMENT: i32 = 14;
+        const IPV6_DONTFRAG: i32 = 14;
+        let (level, name) = if ipv6 {
+            (IPPROTO_IPV6, IPV6_DONTFRAG)
+        } else {
+            (IPPROTO_IP, IP_DONTFRAGMENT)
+        };
+        let value: u32 = 1;
+
+        let ret = unsafe {
+            ws2::setsockopt(
+                socket.as_raw_socket() as _,
+                level,
+                name,
+                (&value as *const u32).cast(),
+                std::mem::size_of_val(&value) as i32,
+            )
+        };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    pub(crate) fn message_too_long() -> io::Error {
+        io::Error::from_raw_os_error(WSAEMSGSIZE)
+    }
+
+    pub(crate) fn is_message_too_long(e: &io::Error) -> bool {
+        e.raw_os_error() == Some(WSAEMSGSIZE)
+    }
+
     pub(crate) fn shutdown(socket: &impl AsRawSocket, how: Shutdown) -> io::Result<()> {
         const SD_RECEIVE: i32 = 0;
         const SD_SEND: i32 = 1;
@@ -144,13 +304,28 @@ mod windows {
         if ret == 0 {
             Ok(())
         } else {
-            Err(io::Error::last_os_error().into())
+            Err(io::Error::last_os_error())
         }
     }
 
-    pub(crate) fn get_random<T>() -> MaybeUninit<T> {
-        let value: MaybeUninit<T> = MaybeUninit::uninit();
-        value
+    /// Whether the read half of `socket` was shut down, in which case reads fail with
+    /// `WSAESHUTDOWN`
+    pub(crate) fn is_read_shutdown(socket: &impl AsRawSocket) -> io::Result<bool> {
+        const MSG_PEEK: i32 = 2;
+        const WSAESHUTDOWN: i32 = 10058;
+        let mut byte = 0u8;
+
+        // The socket is non-blocking, so this returns right away
+        let ret = unsafe { ws2::recv(socket.as_raw_socket() as _, &mut byte, 1, MSG_PEEK) };
+        if ret >= 0 {
+            return Ok(false);
+        }
+        let e = io::Error::last_os_error();
+        match e.raw_os_error() {
+            Some(WSAESHUTDOWN) => Ok(true),
+            _ if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            _ => Err(e),
+        }
     }
 }
 
@@ -0,0 +1,141 @@
+//! POSIX ACL (de)serialization
+//!
+//! An ACL is serialized as a sequence of entries, each one being a tag (`u8`), a qualifier (user
+//! or group id, big endian `u32`) and permissions (big endian `u32`).
+
+#[cfg(all(unix, feature = "acl"))]
+pub use imp::{read_acl, write_acl};
+
+#[cfg(not(all(unix, feature = "acl")))]
+pub use unsupported::{read_acl, write_acl};
+
+#[cfg(not(all(unix, feature = "acl")))]
+mod unsupported {
+    use std::io;
+    use std::path::Path;
+
+    fn unsupported() -> io::Error {
+        io::Error::new(io::ErrorKind::Unsupported, "ACL support not compiled in")
+    }
+
+    pub fn read_acl(_path: &Path) -> io::Result<Vec<u8>> {
+        Err(unsupported())
+    }
+
+    pub fn write_acl(_path: &Path, _acl: &[u8]) -> io::Result<()> {
+        Err(unsupported())
+    }
+}
+
+#[cfg(all(unix, feature = "acl"))]
+mod imp {
+    use std::io;
+    use std::path::Path;
+
+    use posix_acl::{ACLError, PosixACL, Qualifier};
+
+    /// Size of a single serialized entry
+    const ENTRY_SIZE: usize = 9;
+
+    fn to_io_error(e: ACLError) -> io::Error {
+        match e.as_io_error().and_then(io::Error::raw_os_error) {
+            Some(errno) => io::Error::from_raw_os_error(errno),
+            None => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+        }
+    }
+
+    fn encode_qualifier(qualifier: Qualifier) -> Option<(u8, u32)> {
+        match qualifier {
+            Qualifier::UserObj => Some((0, 0)),
+            Qualifier::GroupObj => Some((1, 0)),
+            Qualifier::Other => Some((2, 0)),
+            Qualifier::User(uid) => Some((3, uid)),
+            Qualifier::Group(gid) => Some((4, gid)),
+            Qualifier::Mask => Some((5, 0)),
+            Qualifier::Undefined => None,
+        }
+    }
+
+    fn decode_qualifier(tag: u8, id: u32) -> Option<Qualifier> {
+        match tag {
+            0 => Some(Qualifier::UserObj),
+            1 => Some(Qualifier::GroupObj),
+            2 => Some(Qualifier::Other),
+            3 => Some(Qualifier::User(id)),
+            4 => Some(Qualifier::Group(id)),
+            5 => Some(Qualifier::Mask),
+            _ => None,
+        }
+    }
+
+    /// Reads the access ACL of `path` in its serialized form
+    pub fn read_acl(path: &Path) -> io::Result<Vec<u8>> {
+        let acl = PosixACL::read_acl(path).map_err(to_io_error)?;
+        let entries = acl.entries();
+        let mut serialized = Vec::with_capacity(entries.len() * ENTRY_SIZE);
+
+        for entry in entries {
+            let Some((tag, id)) = encode_qualifier(entry.qual) else {
+                tracing::warn!("Skipping undefined ACL entry on {}", path.display());
+                continue;
+            };
+            serialized.push(tag);
+            serialized.extend_from_slice(&id.to_be_bytes()[..]);
+            serialized.extend_from_slice(&entry.perm.to_be_bytes()[..]);
+        }
+
+        Ok(serialized)
+    }
+
+    /// Applies a serialized ACL to `path`
+    pub fn write_acl(path: &Path, serialized: &[u8]) -> io::Result<()> {
+        if !serialized.len().is_multiple_of(ENTRY_SIZE) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid serialized ACL size: {}", serialized.len()),
+            ));
+        }
+
+        let mut acl = PosixACL::empty();
+        for entry in serialized.chunks_exact(ENTRY_SIZE) {
+            let id = u32::from_be_bytes(entry[1..5].try_into().unwrap());
+            let perm = u32::from_be_bytes(entry[5..9].try_into().unwrap());
+            let qualifier = decode_qualifier(entry[0], id).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid ACL tag {}", entry[0]),
+                )
+            })?;
+            acl.set(qualifier, perm);
+        }
+
+        acl.write_acl(path).map_err(to_io_error)
+    }
+}
+
+#[cfg(all(test, target_os = "linux", feature = "acl"))]
+mod tests {
+    use super::*;
+    use posix_acl::{PosixACL, Qualifier, ACL_READ};
+
+    #[test]
+    fn acl_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        let destination = dir.path().join("destination");
+        std::fs::write(&source, b"source").unwrap();
+        std::fs::write(&destination, b"destination").unwrap();
+
+        let mut acl = PosixACL::read_acl(&source).unwrap();
+        acl.set(Qualifier::User(4242), ACL_READ);
+        acl.fix_mask();
+        acl.write_acl(&source).unwrap();
+
+        let serialized = read_acl(&source).unwrap();
+        write_acl(&destination, &serialized[..]).unwrap();
+
+        let restored = PosixACL::read_acl(&destination).unwrap();
+        assert_eq!(restored.get(Qualifier::User(4242)), Some(ACL_READ));
+        assert_eq!(restored.entries(), acl.entries());
+    }
+}
@@ -1,10 +1,32 @@
-use std::path::PathBuf;
+use std::path::Path;
+use std::time::SystemTime;
 
-use tokio::fs::{create_dir_all, symlink_metadata, File};
+use sha2::{Digest, Sha256, Sha512};
+#[cfg(unix)]
+use tokio::fs::set_permissions;
+use tokio::fs::{rename, symlink_metadata, DirBuilder, File};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
 
+use crate::config::HashAlgorithm;
+use crate::messages::SpecialKind;
 use crate::Result;
 
-async fn create_directories(filename: &PathBuf) -> Result<()> {
+/// Creates `dirname` and its parents, with `mode` (less the umask) when given
+async fn create_dir_all(dirname: &Path, mode: Option<u32>) -> std::io::Result<()> {
+    let mut builder = DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    if let Some(mode) = mode {
+        builder.mode(mode);
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+
+    builder.create(dirname).await
+}
+
+/// Creates the missing parents of `filename`, with `dir_mode` when given
+async fn create_directories(filename: &Path, dir_mode: Option<u32>) -> Result<()> {
     let parent = filename.parent().unwrap();
     match symlink_metadata(parent).await {
         Ok(metadata) => {
@@ -13,7 +35,7 @@ async fn create_directories(filename: &PathBuf) -> Result<()> {
         }
         Err(e) => {
             if e.kind() == std::io::ErrorKind::NotFound {
-                create_dir_all(parent).await?;
+                create_dir_all(parent, dir_mode).await?;
                 Ok(())
             } else {
                 Err(e.into())
@@ -22,10 +44,319 @@ async fn create_directories(filename: &PathBuf) -> Result<()> {
     }
 }
 
-pub async fn create_file(filename: &PathBuf, size: u64) -> Result<File> {
-    create_directories(filename).await?;
+/// Creates `filename` with `size` bytes, its missing parents getting `dir_mode` when given
+pub async fn create_file(filename: &Path, size: u64, dir_mode: Option<u32>) -> Result<File> {
+    create_directories(filename, dir_mode).await?;
     let f = File::create(filename).await?;
     f.set_len(size).await?;
 
     Ok(f)
 }
+
+/// Opens `filename` to write over parts of it, creating it if needed, resized to `size`
+pub async fn open_file_for_update(
+    filename: &Path,
+    size: u64,
+    dir_mode: Option<u32>,
+) -> Result<File> {
+    create_directories(filename, dir_mode).await?;
+    let f = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(filename)
+        .await?;
+    f.set_len(size).await?;
+
+    Ok(f)
+}
+
+/// Reads the `size` bytes at `offset` of `filename`
+pub async fn read_range(filename: &Path, offset: u64, size: usize) -> Result<Vec<u8>> {
+    let mut f = File::open(filename).await?;
+    f.seek(std::io::SeekFrom::Start(offset)).await?;
+    let mut content = vec![0u8; size];
+    f.read_exact(&mut content[..]).await?;
+
+    Ok(content)
+}
+
+/// Unix permission bits of a file, 0 on other platforms
+#[cfg(unix)]
+pub fn get_mode(metadata: &std::fs::Metadata) -> u32 {
+    use std::os::unix::fs::MetadataExt;
+
+    metadata.mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+pub fn get_mode(_metadata: &std::fs::Metadata) -> u32 {
+    0
+}
+
+/// Applies Unix permission bits to `filename`, does nothing on other platforms
+#[cfg(unix)]
+pub async fn set_mode(filename: &Path, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    set_permissions(filename, std::fs::Permissions::from_mode(mode)).await?;
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub async fn set_mode(_filename: &Path, _mode: u32) -> Result<()> {
+    Ok(())
+}
+
+/// Sets the modification time of `file`, once it was entirely written
+pub async fn set_modified(file: File, modified: SystemTime) -> Result<()> {
+    let file = file.into_std().await;
+    file.set_modified(modified)?;
+
+    Ok(())
+}
+
+/// Space available to unprivileged users on the filesystem holding `path`
+#[cfg(unix)]
+pub fn available_space(path: &Path) -> std::io::Result<u64> {
+    use std::ffi::CString;
+    use std::mem::MaybeUninit;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: MaybeUninit<libc::statvfs> = MaybeUninit::uninit();
+
+    // SAFETY: `path` is a valid NUL terminated string and `stat` has the right size
+    let ret = unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    // SAFETY: `statvfs` succeeded so `stat` is initialized
+    let stat = unsafe { stat.assume_init() };
+
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Space available to the current user on the volume holding `path`
+#[cfg(windows)]
+pub fn available_space(path: &Path) -> std::io::Result<u64> {
+    use std::os::windows::ffi::OsStrExt;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetDiskFreeSpaceExW(
+            directory_name: *const u16,
+            free_bytes_available: *mut u64,
+            total_bytes: *mut u64,
+            total_free_bytes: *mut u64,
+        ) -> i32;
+    }
+
+    let path: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut available = 0u64;
+
+    // SAFETY: `path` is NUL terminated, optional out parameters may be NULL
+    let ret = unsafe {
+        GetDiskFreeSpaceExW(
+            path.as_ptr(),
+            &mut available,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        )
+    };
+    if ret == 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(available)
+    }
+}
+
+#[cfg(unix)]
+fn make_node(filename: &Path, kind: SpecialKind, rdev: u64) -> std::io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let file_type = match kind {
+        SpecialKind::Fifo => libc::S_IFIFO,
+        SpecialKind::CharDevice => libc::S_IFCHR,
+        SpecialKind::BlockDevice => libc::S_IFBLK,
+    };
+    let path = CString::new(filename.as_os_str().as_bytes())?;
+
+    // SAFETY: `path` is a valid NUL terminated string
+    let ret = unsafe { libc::mknod(path.as_ptr(), file_type | 0o666, rdev as libc::dev_t) };
+    if ret < 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(not(unix))]
+fn make_node(_filename: &Path, _kind: SpecialKind, _rdev: u64) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "Special files are only supported on Unix",
+    ))
+}
+
+pub async fn create_special_file(
+    filename: &Path,
+    kind: SpecialKind,
+    rdev: u64,
+    dir_mode: Option<u32>,
+) -> Result<()> {
+    create_directories(filename, dir_mode).await?;
+    make_node(filename, kind, rdev)?;
+
+    Ok(())
+}
+
+/// Creates `dirname` and its parents, applying `mode` to `dirname` unless it is 0
+///
+/// The missing parents get `dir_mode` when given.
+pub async fn create_directory(dirname: &Path, mode: u32, dir_mode: Option<u32>) -> Result<()> {
+    create_dir_all(dirname, dir_mode).await?;
+    if mode != 0 {
+        set_mode(dirname, mode).await?;
+    }
+
+    Ok(())
+}
+
+/// Creates a symbolic link at `link_path` pointing to `target`
+#[cfg(unix)]
+pub async fn create_symlink(link_path: &Path, target: &Path, dir_mode: Option<u32>) -> Result<()> {
+    create_directories(link_path, dir_mode).await?;
+    tokio::fs::symlink(target, link_path).await?;
+
+    Ok(())
+}
+
+/// Creates a symbolic link at `link_path` pointing to `target`
+///
+/// Windows needs to know whether the target is a directory, which is only known when it
+/// already exists.
+#[cfg(windows)]
+pub async fn create_symlink(link_path: &Path, target: &Path, dir_mode: Option<u32>) -> Result<()> {
+    create_directories(link_path, dir_mode).await?;
+    let resolved_target = link_path.parent().unwrap().join(target);
+    if resolved_target.is_dir() {
+        tokio::fs::symlink_dir(target, link_path).await?;
+    } else {
+        tokio::fs::symlink_file(target, link_path).await?;
+    }
+
+    Ok(())
+}
+
+/// Moves `from` to `to`, creating `to` parent directories if needed
+///
+/// Both paths must be on the same filesystem.
+pub async fn move_file(from: &Path, to: &Path, dir_mode: Option<u32>) -> Result<()> {
+    create_directories(to, dir_mode).await?;
+    rename(from, to).await?;
+
+    Ok(())
+}
+
+/// Digest of a file being computed as its content arrives
+pub enum FileHasher {
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+impl FileHasher {
+    pub fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Sha256 => Self::Sha256(Sha256::new()),
+            HashAlgorithm::Sha512 => Self::Sha512(Sha512::new()),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Sha256(hasher) => hasher.update(data),
+            Self::Sha512(hasher) => hasher.update(data),
+        }
+    }
+
+    pub fn finalize(self) -> Vec<u8> {
+        match self {
+            Self::Sha256(hasher) => hasher.finalize().to_vec(),
+            Self::Sha512(hasher) => hasher.finalize().to_vec(),
+        }
+    }
+}
+
+/// Computes the digest of `filename` in one go
+pub async fn hash_file(filename: &Path, algorithm: HashAlgorithm) -> Result<Vec<u8>> {
+    let mut f = File::open(filename).await?;
+    let mut hasher = FileHasher::new(algorithm);
+    let mut buffer = vec![0u8; 64 * 1024];
+
+    loop {
+        let size = f.read(&mut buffer[..]).await?;
+        if size == 0 {
+            break;
+        }
+        hasher.update(&buffer[..size]);
+    }
+
+    Ok(hasher.finalize())
+}
+
+/// Size of the blocks hashed by `content_id`
+const CONTENT_ID_BLOCK_SIZE: u64 = 4096;
+
+/// Computes an id of `filename` which only depends on its content
+///
+/// The id is derived from the SHA-256 digest of the size, the first and the last blocks of the
+/// file, so it survives renames. Files of the same size sharing their first and last 4 KiB
+/// (including identical copies) get the same id.
+pub async fn content_id(filename: &Path) -> Result<u64> {
+    let mut f = File::open(filename).await?;
+    let size = f.metadata().await?.len();
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; CONTENT_ID_BLOCK_SIZE as usize];
+
+    hasher.update(size.to_be_bytes());
+
+    let first_block_size = size.min(CONTENT_ID_BLOCK_SIZE) as usize;
+    f.read_exact(&mut buffer[..first_block_size]).await?;
+    hasher.update(&buffer[..first_block_size]);
+
+    if size > CONTENT_ID_BLOCK_SIZE {
+        let last_block_offset = size - CONTENT_ID_BLOCK_SIZE;
+        f.seek(std::io::SeekFrom::Start(last_block_offset)).await?;
+        f.read_exact(&mut buffer[..]).await?;
+        hasher.update(&buffer[..]);
+    }
+
+    let digest = hasher.finalize();
+    Ok(u64::from_be_bytes(digest[..8].try_into().unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn content_id_survives_renames() {
+        let dir = tempfile::tempdir().unwrap();
+        let before = dir.path().join("before");
+        let after = dir.path().join("after");
+        let other = dir.path().join("other");
+        let content: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&before, &content[..]).unwrap();
+        std::fs::write(&other, &content[1..]).unwrap();
+
+        let id = content_id(&before).await.unwrap();
+        std::fs::rename(&before, &after).unwrap();
+        assert_eq!(content_id(&after).await.unwrap(), id);
+        assert_ne!(content_id(&other).await.unwrap(), id);
+    }
+}
@@ -0,0 +1,110 @@
+//! LEB128 encoding of unsigned integers, used by the `compact` wire format
+//!
+//! Each byte holds 7 bits of the value, least significant first, its high bit telling whether
+//! more bytes follow.
+
+use std::io;
+
+use nom::error::{ErrorKind, ParseError};
+use nom::{IResult, Needed};
+
+use crate::{Result, Wire};
+
+/// Bytes taken by the largest `u64`
+pub const MAX_LEN: usize = 10;
+
+/// An integer encoded as LEB128
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct VarInt(pub u64);
+
+/// Streaming parser of a LEB128 integer, to combine with other `nom` parsers
+///
+/// Encodings longer than `MAX_LEN` bytes or overflowing a `u64` are refused.
+pub fn parse<'a, E: ParseError<&'a [u8]>>(input: &'a [u8]) -> IResult<&'a [u8], u64, E> {
+    let mut value = 0u64;
+    for (i, byte) in input.iter().copied().enumerate() {
+        if i == MAX_LEN - 1 && byte > 1 {
+            return Err(nom::Err::Error(E::from_error_kind(
+                input,
+                ErrorKind::TooLarge,
+            )));
+        }
+        value |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok((&input[i + 1..], value));
+        }
+    }
+
+    Err(nom::Err::Incomplete(Needed::new(1)))
+}
+
+/// Writes `value` as LEB128, returns the number of bytes written
+pub fn write<W: io::Write>(mut writer: W, mut value: u64) -> io::Result<usize> {
+    let mut buffer = [0u8; MAX_LEN];
+    let mut len = 0;
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buffer[len] = byte;
+            len += 1;
+            break;
+        }
+        buffer[len] = byte | 0x80;
+        len += 1;
+    }
+
+    writer.write_all(&buffer[..len])?;
+    Ok(len)
+}
+
+impl Wire<'_> for VarInt {
+    fn from_wire(input: &[u8]) -> Result<(&[u8], Self)> {
+        let (rest, value) = parse::<nom::error::VerboseError<&[u8]>>(input)?;
+        Ok((rest, Self(value)))
+    }
+
+    fn to_wire<W: io::Write>(&self, writer: W) -> Result<usize> {
+        Ok(write(writer, self.0)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip_at_boundaries() {
+        for (value, len) in [
+            (0, 1),
+            (127, 1),
+            (128, 2),
+            (16383, 2),
+            (16384, 3),
+            (u64::from(u32::MAX), 5),
+            (u64::MAX, MAX_LEN),
+        ] {
+            let mut raw = Vec::new();
+            let size = VarInt(value).to_wire(&mut raw).unwrap();
+            assert_eq!(size, raw.len());
+            assert_eq!(size, len, "{}", value);
+
+            let (rest, decoded) = VarInt::from_wire(&raw[..]).unwrap();
+            assert!(rest.is_empty());
+            assert_eq!(decoded, VarInt(value));
+        }
+    }
+
+    #[test]
+    fn truncated_and_overflowing_encodings_are_refused() {
+        assert!(matches!(
+            VarInt::from_wire(&[0x80, 0x80][..]),
+            Err(crate::Error::Deserialize(nom::Err::Incomplete(_)))
+        ));
+
+        let mut too_large = [0xff; MAX_LEN];
+        too_large[MAX_LEN - 1] = 0x02;
+        assert!(VarInt::from_wire(&too_large[..]).is_err());
+        assert!(VarInt::from_wire(&[0xff; MAX_LEN + 1][..]).is_err());
+    }
+}